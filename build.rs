@@ -0,0 +1,45 @@
+use std::process::Command;
+
+/// Run a command and return its trimmed stdout, or `unknown` if it fails.
+fn capture(cmd: &str, args: &[&str]) -> String {
+    Command::new(cmd)
+        .args(args)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+fn main() {
+    // Recorded in benchmark reports so results can be tied back to the
+    // compiler and commit that produced them.
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    println!("cargo:rustc-env=EMU_TEST_RUSTC_VERSION={}", capture(&rustc, &["-V"]));
+    println!(
+        "cargo:rustc-env=EMU_TEST_GIT_REVISION={}",
+        capture("git", &["describe", "--always", "--dirty"])
+    );
+    println!(
+        "cargo:rustc-env=EMU_TEST_PROFILE={}",
+        std::env::var("PROFILE").unwrap_or_else(|_| "unknown".to_string())
+    );
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
+
+    // The C context switch in `libco/` and the C reference built on it, for
+    // `libco_attempt` and the `c-reference` binary. The switch is only
+    // written for x86_64 System V, so other targets go without.
+    let arch = std::env::var("CARGO_CFG_TARGET_ARCH").unwrap_or_default();
+    let os = std::env::var("CARGO_CFG_TARGET_OS").unwrap_or_default();
+    if arch == "x86_64" && os == "linux" {
+        cc::Build::new()
+            .file("libco/libco.c")
+            .file("c/reference.c")
+            .opt_level(2)
+            .compile("emu_test_c");
+        for path in ["libco/libco.c", "libco/libco.h", "c/reference.c"] {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+}
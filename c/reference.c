@@ -0,0 +1,107 @@
+/*
+  The C version the README compares against: a 6502 running LDA abs,Y out of
+  memory full of 0xb9, like the lda-absy program, on a thread of its own from
+  the context switch in libco/. Next to it runs a second thread standing in
+  for the APU, which only burns 2-cycle instructions. They're kept in step
+  with byuu's signed relative clock: the CPU adds its clocks scaled by the
+  APU's frequency, the APU subtracts its clocks scaled by the CPU's, and
+  whoever gets ahead switches to the other. Every wait checks, like
+  `--sync eager`.
+*/
+
+#include <stdint.h>
+#include <time.h>
+
+#include "../libco/libco.h"
+
+#define CPU_FREQUENCY 21477272
+#define APU_FREQUENCY 24576000
+/* Oscillator clocks per APU cycle. */
+#define APU_CLOCKS_PER_CYCLE 24
+#define STACK_SIZE (64 * 1024)
+
+static cothread_t main_thread, cpu_thread, apu_thread;
+
+/* Positive while the CPU is ahead of the APU. */
+static int64_t relative_clock;
+
+static uint8_t memory[0x10000];
+
+static struct {
+  uint16_t pc;
+  uint8_t a, x, y, p;
+} regs;
+static uint32_t cycles, instruction_count, instructions_left;
+static uint32_t apu_cycles;
+
+static void wait(uint32_t clocks) {
+  cycles += clocks;
+  relative_clock += (int64_t)clocks * APU_FREQUENCY;
+  if(relative_clock > 0) co_switch(apu_thread);
+}
+
+static uint8_t read_memory(uint16_t address) {
+  wait(2);
+  uint8_t data = memory[address];
+  wait(4);
+  return data;
+}
+
+static void execute_instruction(void) {
+  uint8_t opcode = read_memory(regs.pc++);
+  switch(opcode) {
+  case 0xb9: {
+    uint16_t address = read_memory(regs.pc++);
+    address |= read_memory(regs.pc++) << 8;
+    uint16_t effective = address + regs.y;
+    /* The dummy read from the wrong page when indexing crosses one. */
+    if((address ^ effective) & 0xff00) read_memory((address & 0xff00) | (effective & 0x00ff));
+    regs.a = read_memory(effective);
+    regs.p = (regs.p & ~0x82) | (regs.a & 0x80) | (regs.a ? 0 : 0x02);
+    break;
+  }
+  }
+  instruction_count++;
+}
+
+static void cpu_main(void) {
+  while(instructions_left) {
+    execute_instruction();
+    instructions_left--;
+  }
+  co_switch(main_thread);
+}
+
+static void apu_main(void) {
+  for(;;) {
+    apu_cycles += 2;
+    relative_clock -= (int64_t)2 * APU_CLOCKS_PER_CYCLE * CPU_FREQUENCY;
+    if(relative_clock <= 0) co_switch(cpu_thread);
+  }
+}
+
+/* Run count instructions from power on and return the seconds they took. */
+double c_reference_run(uint32_t count, uint32_t* cycles_out, uint32_t* apu_cycles_out) {
+  for(uint32_t i = 0; i < sizeof memory; i++) memory[i] = 0xb9;
+  regs.pc = 0;
+  regs.a = regs.x = regs.y = 0;
+  regs.p = 0x24;
+  relative_clock = 0;
+  cycles = instruction_count = apu_cycles = 0;
+  instructions_left = count;
+
+  main_thread = co_active();
+  cpu_thread = co_create(STACK_SIZE, cpu_main);
+  apu_thread = co_create(STACK_SIZE, apu_main);
+
+  struct timespec start, end;
+  clock_gettime(CLOCK_MONOTONIC, &start);
+  co_switch(cpu_thread);
+  clock_gettime(CLOCK_MONOTONIC, &end);
+
+  co_delete(cpu_thread);
+  co_delete(apu_thread);
+  *cycles_out = cycles;
+  *apu_cycles_out = apu_cycles;
+  return (double)(end.tv_sec - start.tv_sec) + (double)(end.tv_nsec - start.tv_nsec) / 1e9;
+}
@@ -0,0 +1,100 @@
+/*
+  This project's context switch behind the interface in libco.h, for x86_64
+  System V only: a handle is the start of the thread's stack allocation,
+  holding the stack pointer and the callee-saved registers, and co_switch
+  swaps them without ever entering the kernel.
+*/
+
+#include "libco.h"
+
+#include <stdlib.h>
+
+#if !defined(__x86_64__) || defined(_WIN32)
+  #error "the context switch is only written for x86_64 System V"
+#endif
+
+/* rsp, rbp, rbx, r12-r15, then the entry point of a fresh thread. */
+enum { co_rsp, co_rbp, co_rbx, co_r12, co_r13, co_r14, co_r15, co_entry };
+
+static __thread long long co_active_buffer[64];
+static __thread cothread_t co_active_handle = 0;
+
+/* co_swap(to, from): save the caller's registers into from, load to's and
+   continue wherever to left off. */
+void co_swap(cothread_t to, cothread_t from);
+__asm__(
+  ".text\n"
+  ".globl co_swap\n"
+  ".hidden co_swap\n"
+  ".type co_swap, @function\n"
+  "co_swap:\n"
+  "  mov %rsp, (%rsi)\n"
+  "  mov (%rdi), %rsp\n"
+  "  pop %rax\n"
+  "  mov %rbp, 8(%rsi)\n"
+  "  mov %rbx, 16(%rsi)\n"
+  "  mov %r12, 24(%rsi)\n"
+  "  mov %r13, 32(%rsi)\n"
+  "  mov %r14, 40(%rsi)\n"
+  "  mov %r15, 48(%rsi)\n"
+  "  mov 8(%rdi), %rbp\n"
+  "  mov 16(%rdi), %rbx\n"
+  "  mov 24(%rdi), %r12\n"
+  "  mov 32(%rdi), %r13\n"
+  "  mov 40(%rdi), %r14\n"
+  "  mov 48(%rdi), %r15\n"
+  "  jmp *%rax\n"
+  ".size co_swap, .-co_swap\n"
+);
+
+static void crash(void) {
+  abort();  /* called only if a thread's entry point returns */
+}
+
+/* A fresh thread's first co_swap lands here with its own handle in rdi. */
+static void co_entrypoint(cothread_t handle) {
+  long long* buffer = (long long*)handle;
+  void (*entrypoint)(void) = (void (*)(void))buffer[co_entry];
+  entrypoint();
+  abort();  /* there is nothing to return to */
+}
+
+cothread_t co_active(void) {
+  if(!co_active_handle) co_active_handle = &co_active_buffer;
+  return co_active_handle;
+}
+
+cothread_t co_derive(void* memory, unsigned int size, void (*entrypoint)(void)) {
+  cothread_t handle;
+  if(!co_active_handle) co_active_handle = &co_active_buffer;
+
+  if((handle = (cothread_t)memory)) {
+    unsigned int offset = (size & ~15) - 32;
+    long long* p = (long long*)((char*)handle + offset);  /* seek to top of stack */
+    *--p = (long long)crash;                              /* crash if entrypoint returns */
+    *--p = (long long)co_entrypoint;                      /* start of function */
+    ((long long*)handle)[co_rsp] = (long long)p;          /* stack pointer */
+    ((long long*)handle)[co_entry] = (long long)entrypoint;
+  }
+
+  return handle;
+}
+
+cothread_t co_create(unsigned int size, void (*entrypoint)(void)) {
+  void* memory = malloc(size);
+  if(!memory) return (cothread_t)0;
+  return co_derive(memory, size, entrypoint);
+}
+
+void co_delete(cothread_t handle) {
+  free(handle);
+}
+
+void co_switch(cothread_t handle) {
+  cothread_t co_previous_handle = co_active_handle;
+  co_swap(co_active_handle = handle, co_previous_handle);
+}
+
+int co_serializable(void) {
+  return 1;
+}
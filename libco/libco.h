@@ -0,0 +1,35 @@
+/*
+  Cooperative threads with stacks of their own, for the C reference and the
+  libco variant. The functions are named and declared the way byuu's libco
+  does it, so code written against libco reads the same, but this isn't
+  libco: the implementation in libco.c is this project's own, and only for
+  x86_64 System V.
+*/
+
+#ifndef LIBCO_H
+#define LIBCO_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef void* cothread_t;
+
+/* The thread that's running, creating a handle for it on first use. */
+cothread_t co_active(void);
+/* A thread running entrypoint on a stack carved out of memory. */
+cothread_t co_derive(void* memory, unsigned int size, void (*entrypoint)(void));
+/* A thread running entrypoint on a freshly allocated stack. */
+cothread_t co_create(unsigned int size, void (*entrypoint)(void));
+/* Free a thread made by co_create. It must not be running. */
+void co_delete(cothread_t handle);
+/* Suspend the running thread and resume handle. */
+void co_switch(cothread_t handle);
+/* Whether a thread's state can be saved and restored along with its stack. */
+int co_serializable(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
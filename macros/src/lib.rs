@@ -0,0 +1,640 @@
+//! `#[state_machine]` compiles methods written in straight-line style, like
+//! `null_attempt::CPU::execute_instruction`, into resumable state machines
+//! like the hand-written one in `enum_attempt`, so the `enum` approach works
+//! on stable Rust without being a pain to program in.
+//!
+//! It goes on an `impl` block and rewrites the methods in it that are marked
+//! `#[resumable(field: Type, suspend(method, ...))]`. Calls of the listed
+//! methods on `self` are the suspension points. A resumable method gets
+//! `-> bool` added: every call runs until it's about to reach its second
+//! suspension point and returns `false` there, or returns `true` once it has
+//! run to the end, after which the next call starts over. Where it left off
+//! is kept in `self.field`, of a `Type` the macro defines next to the `impl`
+//! block, along with the locals of the method.
+//!
+//! Statements without suspension points are passed through as they are. The
+//! others have to be one of
+//!
+//! * a statement with a single suspension point,
+//! * a block, or an `if`, `match` or `while` whose condition doesn't suspend
+//!   and whose bodies are made of these in turn, which makes `while` the only
+//!   loop that can suspend,
+//! * `return;`.
+//!
+//! Locals declared outside of the statements that are passed through have
+//! to look like `let name: Type = value;`, with a `Copy` type, since they're
+//! copied in and out of `self.field` whenever the method moves on to another
+//! state. `match` arms with suspension points can't bind variables, and
+//! `break` and `continue` can't leave a statement.
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Literal, Span, TokenStream as TokenStream2, TokenTree};
+use quote::{format_ident, quote, ToTokens};
+use std::collections::HashSet;
+use syn::parse::{Parse, ParseStream};
+use syn::punctuated::Punctuated;
+use syn::visit::{self, Visit};
+use syn::{
+    parse_macro_input, parse_quote, Error, Expr, ExprIf, ExprMatch, ExprWhile, FnArg, Ident, ImplItem,
+    ImplItemFn, ItemImpl, Pat, Result, ReturnType, Stmt, Token, Type,
+};
+
+#[proc_macro_attribute]
+pub fn state_machine(attr: TokenStream, item: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        return Error::new(Span::call_site(), "`#[state_machine]` takes no arguments")
+            .to_compile_error()
+            .into();
+    }
+    let mut item = parse_macro_input!(item as ItemImpl);
+    let mut states = vec![];
+    for impl_item in &mut item.items {
+        if let ImplItem::Fn(method) = impl_item {
+            if let Some(i) = method.attrs.iter().position(|a| a.path().is_ident("resumable")) {
+                let attr = method.attrs.remove(i);
+                match attr.parse_args().and_then(|args| compile(args, method)) {
+                    Ok(state) => states.push(state),
+                    Err(err) => return err.to_compile_error().into(),
+                }
+            }
+        }
+    }
+    quote!(#(#states)* #item).into()
+}
+
+/// The arguments of `#[resumable]`.
+struct Resumable {
+    field: Ident,
+    ty: Ident,
+    suspend: Vec<Ident>,
+}
+
+impl Parse for Resumable {
+    fn parse(input: ParseStream) -> Result<Resumable> {
+        let field = input.parse()?;
+        input.parse::<Token![:]>()?;
+        let ty = input.parse()?;
+        input.parse::<Token![,]>()?;
+        let keyword: Ident = input.parse()?;
+        if keyword != "suspend" {
+            return Err(Error::new(keyword.span(), "expected `suspend(...)`"));
+        }
+        let content;
+        syn::parenthesized!(content in input);
+        let suspend = Punctuated::<Ident, Token![,]>::parse_terminated(&content)?;
+        input.parse::<Option<Token![,]>>()?;
+        Ok(Resumable {
+            field,
+            ty,
+            suspend: suspend.into_iter().collect(),
+        })
+    }
+}
+
+/// Turn `method` into a state machine and return the definition of the type
+/// that keeps its state.
+fn compile(args: Resumable, method: &mut ImplItemFn) -> Result<TokenStream2> {
+    let sig = &method.sig;
+    let receiver = match sig.inputs.first() {
+        Some(FnArg::Receiver(receiver)) if receiver.reference.is_some() && receiver.mutability.is_some() => {
+            Some(receiver.self_token)
+        }
+        _ => None,
+    };
+    let this = match receiver {
+        Some(this) if sig.inputs.len() == 1 && sig.asyncness.is_none() && sig.generics.params.is_empty() => this,
+        _ => return Err(Error::new_spanned(sig, "resumable methods take `&mut self` and nothing else")),
+    };
+    if !matches!(sig.output, ReturnType::Default) {
+        return Err(Error::new_spanned(
+            &sig.output,
+            "resumable methods can't return anything, they return whether they finished",
+        ));
+    }
+
+    let mut machine = Machine::new(&args, this);
+    machine.block(&method.block.stmts)?;
+    machine.finish(Exit::Finish);
+
+    let Resumable { field, ty, .. } = &args;
+    let doc = format!(
+        " Where `{}` left off, and the locals it keeps across suspension points.",
+        sig.ident
+    );
+    let fields = machine.locals.iter().map(|local| &local.field);
+    let types = machine.locals.iter().map(|local| &local.ty);
+    let state = quote! {
+        #[doc = #doc]
+        #[derive(Clone, Copy, Default)]
+        struct #ty {
+            state: u32,
+            #(#fields: #types,)*
+        }
+    };
+
+    let suspended = &machine.suspended;
+    let arms = (0..machine.states.len()).map(|n| {
+        let label = Literal::u32_unsuffixed(n as u32);
+        let code = machine.state(n);
+        quote!(#label => { #code })
+    });
+    let body = quote!({
+        let mut #suspended = false;
+        loop {
+            match #this.#field.state {
+                #(#arms)*
+                _ => unreachable!(),
+            }
+        }
+    });
+    method.block = syn::parse2(body)?;
+    method.sig.output = parse_quote!(-> bool);
+    // Every state copies in the locals it mentions, whether it ends up using
+    // them or not, and picks the next state even if its code diverges.
+    let allow = quote!(unused_mut, unused_variables, unused_assignments, unreachable_code);
+    method.attrs.push(parse_quote!(#[allow(#allow)]));
+    Ok(state)
+}
+
+struct Local {
+    name: Ident,
+    /// Where it's kept in between states. Shadowed locals get a field of
+    /// their own.
+    field: Ident,
+    ty: Type,
+}
+
+/// How a state ends.
+enum Exit {
+    Goto(u32),
+    Branch(Expr, u32, u32),
+    Match(Expr, Vec<(Pat, Option<Expr>, u32)>),
+    /// Go on with a state that starts with a suspension point, returning
+    /// first if this call has been through one already.
+    Yield(u32),
+    Finish,
+}
+
+struct State {
+    /// The locals in scope where it starts and where it ends, as indices
+    /// into `Machine::locals`.
+    entry: Vec<usize>,
+    exit_scope: Vec<usize>,
+    stmts: Vec<TokenStream2>,
+    exit: Exit,
+}
+
+struct Machine<'a> {
+    field: &'a Ident,
+    ty: &'a Ident,
+    suspend: &'a [Ident],
+    /// The method's own `self`, for the code the macro adds to it to refer
+    /// to, since a `self` of the macro's wouldn't be the same one when the
+    /// method comes out of a `macro_rules!`.
+    this: Token![self],
+    /// Whether this call has been through a suspension point.
+    suspended: Ident,
+    locals: Vec<Local>,
+    fields: HashSet<String>,
+    scopes: Vec<Vec<usize>>,
+    states: Vec<State>,
+    current: usize,
+}
+
+impl<'a> Machine<'a> {
+    fn new(args: &'a Resumable, this: Token![self]) -> Machine<'a> {
+        let mut machine = Machine {
+            field: &args.field,
+            ty: &args.ty,
+            suspend: &args.suspend,
+            this,
+            suspended: Ident::new("suspended", Span::mixed_site()),
+            locals: vec![],
+            fields: ["state".to_string()].iter().cloned().collect(),
+            scopes: vec![vec![]],
+            states: vec![],
+            current: 0,
+        };
+        let first = machine.new_state();
+        machine.start(first);
+        machine
+    }
+
+    fn new_state(&mut self) -> u32 {
+        self.states.push(State {
+            entry: vec![],
+            exit_scope: vec![],
+            stmts: vec![],
+            exit: Exit::Finish,
+        });
+        (self.states.len() - 1) as u32
+    }
+
+    fn start(&mut self, state: u32) {
+        self.current = state as usize;
+        self.states[self.current].entry = self.visible();
+    }
+
+    fn finish(&mut self, exit: Exit) {
+        let scope = self.visible();
+        let state = &mut self.states[self.current];
+        state.exit = exit;
+        state.exit_scope = scope;
+    }
+
+    fn push(&mut self, stmt: TokenStream2) {
+        self.states[self.current].stmts.push(stmt);
+    }
+
+    /// The locals in scope, leaving out the ones that are shadowed.
+    fn visible(&self) -> Vec<usize> {
+        let mut visible: Vec<usize> = vec![];
+        for &i in self.scopes.iter().flatten() {
+            visible.retain(|&j| self.locals[j].name != self.locals[i].name);
+            visible.push(i);
+        }
+        visible
+    }
+
+    fn save(&self, local: usize) -> TokenStream2 {
+        let Local { name, field, .. } = &self.locals[local];
+        let (this, state) = (self.this, self.field);
+        quote!(#this.#state.#field = #name;)
+    }
+
+    fn block(&mut self, stmts: &[Stmt]) -> Result<()> {
+        stmts.iter().try_for_each(|stmt| self.stmt(stmt))
+    }
+
+    /// `stmts` in a scope of their own, going on with `next` afterwards.
+    fn scoped(&mut self, stmts: &[Stmt], next: u32) -> Result<()> {
+        self.scopes.push(vec![]);
+        self.block(stmts)?;
+        self.finish(Exit::Goto(next));
+        self.scopes.pop();
+        Ok(())
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) -> Result<()> {
+        let found = Find::new(self.suspend, |find| find.visit_stmt(stmt));
+        if found.escapes {
+            return Err(Error::new_spanned(
+                stmt,
+                "`break` and `continue` can't leave a statement of a resumable method",
+            ));
+        }
+        if found.suspensions == 0 && !found.returns {
+            return self.plain(stmt);
+        }
+        if let Stmt::Expr(expr, _) = stmt {
+            match expr {
+                Expr::If(expr) => return self.lower_if(expr),
+                Expr::Match(expr) => return self.lower_match(expr),
+                Expr::While(expr) => return self.lower_while(expr),
+                Expr::Block(expr) if expr.label.is_none() => {
+                    let next = self.new_state();
+                    self.scoped(&expr.block.stmts, next)?;
+                    self.start(next);
+                    return Ok(());
+                }
+                Expr::Return(expr) if expr.expr.is_none() => {
+                    self.finish(Exit::Finish);
+                    // Whatever follows is dead.
+                    let next = self.new_state();
+                    self.start(next);
+                    return Ok(());
+                }
+                _ => {}
+            }
+        }
+        if found.returns {
+            return Err(Error::new_spanned(
+                stmt,
+                "`return` has to be a statement of its own in a resumable method",
+            ));
+        }
+        if found.repeats {
+            return Err(Error::new_spanned(
+                stmt,
+                "only `while` loops can suspend, and only as statements of their own",
+            ));
+        }
+        if found.suspensions > 1 {
+            return Err(Error::new_spanned(stmt, "only one suspension point per statement"));
+        }
+        let next = self.new_state();
+        self.finish(Exit::Yield(next));
+        self.start(next);
+        self.plain(stmt)?;
+        let suspended = &self.suspended;
+        self.push(quote!(#suspended = true;));
+        Ok(())
+    }
+
+    /// Pass `stmt` through, keeping track of the local it declares, if any.
+    fn plain(&mut self, stmt: &Stmt) -> Result<()> {
+        let local = match stmt {
+            Stmt::Local(local) => local,
+            _ => {
+                self.push(stmt.to_token_stream());
+                return Ok(());
+            }
+        };
+        let (name, ty) = match &local.pat {
+            Pat::Type(typed) => match &*typed.pat {
+                Pat::Ident(ident) if ident.by_ref.is_none() && ident.subpat.is_none() => {
+                    (ident.ident.clone(), (*typed.ty).clone())
+                }
+                _ => return Err(Error::new_spanned(&typed.pat, "expected a name")),
+            },
+            _ => {
+                return Err(Error::new_spanned(
+                    &local.pat,
+                    "locals of resumable methods need a type, so they can be kept across suspension points",
+                ))
+            }
+        };
+        let init = match &local.init {
+            Some(init) if init.diverge.is_none() => &init.expr,
+            _ => return Err(Error::new_spanned(local, "locals of resumable methods have to be initialized")),
+        };
+        // Once shadowed, the old one can't be saved by name any more.
+        if let Some(&shadowed) = self.visible().iter().find(|&&i| self.locals[i].name == name) {
+            self.push(self.save(shadowed));
+        }
+        let mut field = name.to_string();
+        let mut n = 1;
+        while !self.fields.insert(field.clone()) {
+            n += 1;
+            field = format!("{}_{}", name, n);
+        }
+        self.locals.push(Local {
+            name: name.clone(),
+            field: format_ident!("{}", field, span = name.span()),
+            ty: ty.clone(),
+        });
+        self.scopes.last_mut().unwrap().push(self.locals.len() - 1);
+        let attrs = &local.attrs;
+        self.push(quote!(#(#attrs)* let mut #name: #ty = #init;));
+        Ok(())
+    }
+
+    /// Conditions are evaluated on the way from one state to the next, so
+    /// they can't suspend.
+    fn condition(&self, cond: &Expr) -> Result<()> {
+        if let Expr::Let(_) = cond {
+            return Err(Error::new_spanned(cond, "`if let` and `while let` can't suspend"));
+        }
+        if Find::new(self.suspend, |find| find.visit_expr(cond)).suspensions > 0 {
+            return Err(Error::new_spanned(
+                cond,
+                "conditions can't suspend, put the result in a local first",
+            ));
+        }
+        Ok(())
+    }
+
+    fn lower_if(&mut self, expr: &ExprIf) -> Result<()> {
+        self.condition(&expr.cond)?;
+        let then = self.new_state();
+        let join = self.new_state();
+        let otherwise = match expr.else_branch {
+            Some(_) => self.new_state(),
+            None => join,
+        };
+        self.finish(Exit::Branch((*expr.cond).clone(), then, otherwise));
+        self.start(then);
+        self.scoped(&expr.then_branch.stmts, join)?;
+        if let Some((_, else_branch)) = &expr.else_branch {
+            self.start(otherwise);
+            match &**else_branch {
+                Expr::Block(block) => self.scoped(&block.block.stmts, join)?,
+                Expr::If(nested) => {
+                    self.lower_if(nested)?;
+                    self.finish(Exit::Goto(join));
+                }
+                other => return Err(Error::new_spanned(other, "expected a block")),
+            }
+        }
+        self.start(join);
+        Ok(())
+    }
+
+    fn lower_match(&mut self, expr: &ExprMatch) -> Result<()> {
+        self.condition(&expr.expr)?;
+        let join = self.new_state();
+        let mut arms = vec![];
+        let mut bodies = vec![];
+        for arm in &expr.arms {
+            if binds(&arm.pat) {
+                return Err(Error::new_spanned(
+                    &arm.pat,
+                    "match arms that suspend can't bind variables",
+                ));
+            }
+            let guard = arm.guard.as_ref().map(|(_, guard)| (**guard).clone());
+            if let Some(guard) = &guard {
+                self.condition(guard)?;
+            }
+            let state = self.new_state();
+            arms.push((arm.pat.clone(), guard, state));
+            bodies.push((state, &*arm.body));
+        }
+        self.finish(Exit::Match((*expr.expr).clone(), arms));
+        for (state, body) in bodies {
+            self.start(state);
+            match body {
+                Expr::Block(block) if block.label.is_none() => self.scoped(&block.block.stmts, join)?,
+                body => {
+                    let stmt = Stmt::Expr(body.clone(), Some(Default::default()));
+                    self.scoped(std::slice::from_ref(&stmt), join)?;
+                }
+            }
+        }
+        self.start(join);
+        Ok(())
+    }
+
+    fn lower_while(&mut self, expr: &ExprWhile) -> Result<()> {
+        if let Some(label) = &expr.label {
+            return Err(Error::new_spanned(label, "loops that suspend can't have labels"));
+        }
+        self.condition(&expr.cond)?;
+        let head = self.new_state();
+        let body = self.new_state();
+        let exit = self.new_state();
+        self.finish(Exit::Goto(head));
+        self.start(head);
+        self.finish(Exit::Branch((*expr.cond).clone(), body, exit));
+        self.start(body);
+        self.scoped(&expr.body.stmts, head)?;
+        self.start(exit);
+        Ok(())
+    }
+
+    /// The code of state `n`: copy in the locals it uses, run it, copy them
+    /// back out and pick the next state.
+    fn state(&self, n: usize) -> TokenStream2 {
+        let state = &self.states[n];
+        let (this, field, ty) = (self.this, self.field, self.ty);
+        let suspended = &self.suspended;
+        let label = |n: &u32| Literal::u32_unsuffixed(*n);
+        let next = match &state.exit {
+            Exit::Goto(next) => {
+                let next = label(next);
+                quote!(#this.#field.state = #next;)
+            }
+            Exit::Branch(cond, then, otherwise) => {
+                let (then, otherwise) = (label(then), label(otherwise));
+                quote!(#this.#field.state = if #cond { #then } else { #otherwise };)
+            }
+            Exit::Match(scrutinee, arms) => {
+                let arms = arms.iter().map(|(pat, guard, next)| {
+                    let guard = guard.as_ref().map(|guard| quote!(if #guard));
+                    let next = label(next);
+                    quote!(#pat #guard => #next,)
+                });
+                quote!(#this.#field.state = match #scrutinee { #(#arms)* };)
+            }
+            Exit::Yield(next) => {
+                let next = label(next);
+                quote!(
+                    #this.#field.state = #next;
+                    if #suspended {
+                        return false;
+                    }
+                )
+            }
+            Exit::Finish => quote!(
+                #this.#field = #ty::default();
+                return true;
+            ),
+        };
+        let stmts = &state.stmts;
+        let mut mentioned = HashSet::new();
+        for tokens in stmts.iter().chain(Some(&next)) {
+            idents(tokens.clone(), &mut mentioned);
+        }
+        let uses = |&&i: &&usize| mentioned.contains(&self.locals[i].name.to_string());
+        let loads = state.entry.iter().filter(uses).map(|&i| {
+            let Local { name, field: saved, .. } = &self.locals[i];
+            quote!(let mut #name = #this.#field.#saved;)
+        });
+        let saves = match state.exit {
+            Exit::Finish => vec![],
+            _ => state.exit_scope.iter().filter(uses).map(|&i| self.save(i)).collect(),
+        };
+        quote!(#(#loads)* #(#stmts)* #(#saves)* #next)
+    }
+}
+
+/// Every identifier in `tokens`.
+fn idents(tokens: TokenStream2, found: &mut HashSet<String>) {
+    for token in tokens {
+        match token {
+            TokenTree::Ident(ident) => {
+                found.insert(ident.to_string());
+            }
+            TokenTree::Group(group) => idents(group.stream(), found),
+            _ => {}
+        }
+    }
+}
+
+/// Whether `pat` binds a variable, going by the convention that constants
+/// and variants are capitalized.
+fn binds(pat: &Pat) -> bool {
+    struct Binds(bool);
+    impl<'ast> Visit<'ast> for Binds {
+        fn visit_pat_ident(&mut self, pat: &'ast syn::PatIdent) {
+            if !pat.ident.to_string().starts_with(char::is_uppercase) {
+                self.0 = true;
+            }
+            visit::visit_pat_ident(self, pat);
+        }
+    }
+    let mut binds = Binds(false);
+    binds.visit_pat(pat);
+    binds.0
+}
+
+/// What a statement does that matters to the state machine. Closures and
+/// nested items run on their own, so they aren't looked into.
+struct Find<'a> {
+    suspend: &'a [Ident],
+    suspensions: usize,
+    returns: bool,
+    /// Whether a `break` or `continue` leaves the statement.
+    escapes: bool,
+    /// Whether a suspension point is in a loop inside the statement, where
+    /// it could be reached more than once.
+    repeats: bool,
+    loops: usize,
+}
+
+impl<'a> Find<'a> {
+    fn new(suspend: &'a [Ident], visit: impl FnOnce(&mut Find<'a>)) -> Find<'a> {
+        let mut find = Find {
+            suspend,
+            suspensions: 0,
+            returns: false,
+            escapes: false,
+            repeats: false,
+            loops: 0,
+        };
+        visit(&mut find);
+        find
+    }
+
+    fn in_loop(&mut self, visit: impl FnOnce(&mut Self)) {
+        self.loops += 1;
+        visit(self);
+        self.loops -= 1;
+    }
+}
+
+impl<'ast> Visit<'ast> for Find<'_> {
+    fn visit_expr_method_call(&mut self, call: &'ast syn::ExprMethodCall) {
+        let on_self = matches!(&*call.receiver, Expr::Path(path) if path.path.is_ident("self"));
+        if on_self && self.suspend.contains(&call.method) {
+            self.suspensions += 1;
+            self.repeats |= self.loops > 0;
+        }
+        visit::visit_expr_method_call(self, call);
+    }
+
+    fn visit_expr_return(&mut self, expr: &'ast syn::ExprReturn) {
+        self.returns = true;
+        visit::visit_expr_return(self, expr);
+    }
+
+    fn visit_expr_break(&mut self, expr: &'ast syn::ExprBreak) {
+        self.escapes |= self.loops == 0;
+        visit::visit_expr_break(self, expr);
+    }
+
+    fn visit_expr_continue(&mut self, expr: &'ast syn::ExprContinue) {
+        self.escapes |= self.loops == 0;
+        visit::visit_expr_continue(self, expr);
+    }
+
+    fn visit_expr_loop(&mut self, expr: &'ast syn::ExprLoop) {
+        self.in_loop(|find| visit::visit_expr_loop(find, expr));
+    }
+
+    fn visit_expr_while(&mut self, expr: &'ast syn::ExprWhile) {
+        self.in_loop(|find| visit::visit_expr_while(find, expr));
+    }
+
+    fn visit_expr_for_loop(&mut self, expr: &'ast syn::ExprForLoop) {
+        self.in_loop(|find| visit::visit_expr_for_loop(find, expr));
+    }
+
+    fn visit_expr_closure(&mut self, _: &'ast syn::ExprClosure) {}
+
+    fn visit_expr_async(&mut self, _: &'ast syn::ExprAsync) {}
+
+    fn visit_item(&mut self, _: &'ast syn::Item) {}
+}
@@ -0,0 +1,288 @@
+//! Runs methods through `#[state_machine]` and checks them against the same
+//! code run straight through: every call has to do exactly what the plain
+//! method does from one suspension point up to the next.
+
+use emu_test_macros::state_machine;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Event {
+    /// A suspension point.
+    Tick(u32),
+    /// Anything else, with a value worth checking.
+    Note(u32),
+}
+
+/// What every call of the state machine should log, going by the log of
+/// the plain method: a call runs up to the suspension point after its first.
+fn calls(log: &[Event]) -> Vec<Vec<Event>> {
+    let mut calls = vec![vec![]];
+    let mut ticked = false;
+    for &event in log {
+        if let Event::Tick(_) = event {
+            if ticked {
+                calls.push(vec![]);
+            }
+            ticked = true;
+        }
+        calls.last_mut().unwrap().push(event);
+    }
+    calls
+}
+
+/// Defines `Machine`, with the method given as its state machine, and
+/// `Straight`, with the same method as it is, and `check`, which compares
+/// the two for an input. The method has to come in whole for its `self` to
+/// be the same `self` as its body's.
+macro_rules! machine {
+    ($($method:tt)*) => {
+        use super::*;
+
+        struct Machine {
+            input: u32,
+            log: Vec<Event>,
+            state: State,
+        }
+
+        #[state_machine]
+        impl Machine {
+            #[resumable(state: State, suspend(tick))]
+            $($method)*
+
+            fn tick(&mut self, n: u32) {
+                self.log.push(Event::Tick(n));
+            }
+
+            fn note(&mut self, n: u32) {
+                self.log.push(Event::Note(n));
+            }
+        }
+
+        struct Straight {
+            input: u32,
+            log: Vec<Event>,
+        }
+
+        impl Straight {
+            $($method)*
+
+            fn tick(&mut self, n: u32) {
+                self.log.push(Event::Tick(n));
+            }
+
+            fn note(&mut self, n: u32) {
+                self.log.push(Event::Note(n));
+            }
+        }
+
+        fn check(input: u32) {
+            let mut straight = Straight { input, log: vec![] };
+            straight.run();
+            let expected = calls(&straight.log);
+            let mut machine = Machine {
+                input,
+                log: vec![],
+                state: State::default(),
+            };
+            // Twice, since it has to start over once it's done.
+            for _ in 0..2 {
+                let mut actual = vec![];
+                loop {
+                    let done = machine.run();
+                    actual.push(std::mem::take(&mut machine.log));
+                    if done {
+                        break;
+                    }
+                    assert!(actual.len() <= expected.len(), "input {}: still going after {:?}", input, actual);
+                }
+                assert_eq!(actual, expected, "input {}", input);
+            }
+        }
+    };
+}
+
+mod straight_line {
+    machine! {
+        fn run(&mut self) {
+            let a: u32 = self.input * 2;
+            self.note(a);
+            self.tick(1);
+            self.note(a);
+            let b: u32 = a + 1;
+            self.tick(2);
+            self.note(a + b);
+            self.tick(3);
+        }
+    }
+
+    #[test]
+    fn locals_survive_suspension() {
+        for input in 0..4 {
+            check(input);
+        }
+    }
+}
+
+mod nested_if {
+    machine! {
+        fn run(&mut self) {
+            let x: u32 = self.input + 1;
+            if self.input < 5 {
+                self.tick(1);
+                if self.input > 1 {
+                    self.tick(2);
+                } else {
+                    self.note(x);
+                    self.tick(3);
+                    self.note(x * 2);
+                }
+            } else if self.input > 4 {
+                self.tick(4);
+            } else {
+                self.note(x);
+            }
+            self.tick(9);
+            self.note(x);
+        }
+    }
+
+    #[test]
+    fn every_branch() {
+        for input in 0..8 {
+            check(input);
+        }
+    }
+}
+
+mod matches {
+    machine! {
+        fn run(&mut self) {
+            match self.input {
+                0 => self.tick(1),
+                1 | 2 => {
+                    let y: u32 = self.input * 10;
+                    self.tick(2);
+                    self.note(y);
+                    if self.input == 2 {
+                        self.tick(3);
+                        self.note(y + 1);
+                    }
+                }
+                _ if self.input > 5 => {
+                    match self.input {
+                        6 => {
+                            self.tick(4);
+                        }
+                        7 => self.note(7),
+                        _ => {}
+                    }
+                    self.tick(5);
+                }
+                _ => {}
+            }
+            self.note(self.input);
+        }
+    }
+
+    #[test]
+    fn every_arm() {
+        for input in 0..9 {
+            check(input);
+        }
+    }
+}
+
+mod loops {
+    machine! {
+        fn run(&mut self) {
+            let mut i: u32 = 0;
+            let mut sum: u32 = 0;
+            while i < self.input {
+                self.tick(i);
+                let square: u32 = i * i;
+                let mut j: u32 = 0;
+                while j < i {
+                    self.tick(100 + j);
+                    sum += j;
+                    j += 1;
+                }
+                // Loops without suspension points are left alone, breaks
+                // and all.
+                for k in 0..10 {
+                    if k == i {
+                        break;
+                    }
+                    sum += k;
+                }
+                self.note(square);
+                i += 1;
+            }
+            self.note(sum);
+        }
+    }
+
+    #[test]
+    fn nested_loops() {
+        for input in 0..5 {
+            check(input);
+        }
+    }
+}
+
+mod shadowing {
+    machine! {
+        fn run(&mut self) {
+            let x: u32 = self.input;
+            self.tick(1);
+            {
+                let x: u32 = x + 10;
+                self.tick(2);
+                self.note(x);
+            }
+            self.note(x);
+            let x: u32 = x + 100;
+            self.tick(3);
+            self.note(x);
+        }
+    }
+
+    #[test]
+    fn shadowed_locals_are_kept_apart() {
+        for input in 0..3 {
+            check(input);
+        }
+    }
+}
+
+mod early_return {
+    machine! {
+        fn run(&mut self) {
+            self.tick(1);
+            if self.input == 3 {
+                self.note(3);
+                return;
+            }
+            let mut i: u32 = 0;
+            while i < self.input {
+                self.tick(2);
+                if i == 5 {
+                    self.tick(3);
+                    return;
+                }
+                i += 1;
+            }
+            self.tick(4);
+        }
+    }
+
+    #[test]
+    fn returns_start_over() {
+        for input in [0, 1, 3, 4, 6, 10] {
+            check(input);
+        }
+    }
+}
+
+#[test]
+fn unsupported_constructs_do_not_compile() {
+    trybuild::TestCases::new().compile_fail("tests/ui/*.rs");
+}
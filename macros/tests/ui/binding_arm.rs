@@ -0,0 +1,24 @@
+use emu_test_macros::state_machine;
+
+#[derive(Default)]
+struct Machine {
+    state: State,
+}
+
+#[state_machine]
+impl Machine {
+    #[resumable(state: State, suspend(tick))]
+    fn run(&mut self) {
+        match 1 {
+            n => {
+                self.tick();
+            }
+        }
+    }
+
+    fn tick(&mut self) -> bool {
+        true
+    }
+}
+
+fn main() {}
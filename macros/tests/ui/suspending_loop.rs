@@ -0,0 +1,23 @@
+use emu_test_macros::state_machine;
+
+#[derive(Default)]
+struct Machine {
+    state: State,
+}
+
+#[state_machine]
+impl Machine {
+    #[resumable(state: State, suspend(tick))]
+    fn run(&mut self) {
+        loop {
+            self.tick();
+            break;
+        }
+    }
+
+    fn tick(&mut self) -> bool {
+        true
+    }
+}
+
+fn main() {}
@@ -0,0 +1,210 @@
+use crate::report::Report;
+use crate::stats;
+
+/// What a comparison concluded about one variant.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// Slower by more than the threshold, and significantly so.
+    Regression,
+    /// Faster by more than the threshold, and significantly so.
+    Improvement,
+    Unchanged,
+    /// The variant isn't in the baseline.
+    New,
+}
+
+#[derive(Clone, Debug)]
+pub struct Comparison {
+    pub name: String,
+    pub baseline_median: Option<f64>,
+    pub current_median: f64,
+    /// Relative change of the median, `0.1` means 10% slower.
+    pub change: Option<f64>,
+    pub p_value: Option<f64>,
+    pub verdict: Verdict,
+}
+
+pub fn load(path: &str) -> Result<Report, String> {
+    let json = std::fs::read_to_string(path).map_err(|e| format!("couldn't read {}: {}", path, e))?;
+    serde_json::from_str(&json).map_err(|e| format!("couldn't parse {}: {}", path, e))
+}
+
+pub fn save(path: &str, report: &Report) -> Result<(), String> {
+    std::fs::write(path, report.to_json()).map_err(|e| format!("couldn't write {}: {}", path, e))
+}
+
+/// Compare every variant of `current` against the same variant in
+/// `baseline`. A variant regresses when its median moved up by more than
+/// `threshold` (a fraction) and the Mann-Whitney U test rejects "same
+/// distribution" at `alpha`.
+pub fn compare(
+    baseline: &Report,
+    current: &Report,
+    threshold: f64,
+    alpha: f64,
+) -> Result<Vec<Comparison>, String> {
+    if baseline.workload.instructions != current.workload.instructions
+        || baseline.workload.description != current.workload.description
+        || baseline.workload.cpu != current.workload.cpu
+        || baseline.workload.sync != current.workload.sync
+    {
+        return Err(format!(
+            "baseline workload ({} instructions of {}, {} sync, {:?}) doesn't match this run \
+             ({} instructions of {}, {} sync, {:?})",
+            baseline.workload.instructions,
+            baseline.workload.cpu,
+            baseline.workload.sync,
+            baseline.workload.description,
+            current.workload.instructions,
+            current.workload.cpu,
+            current.workload.sync,
+            current.workload.description
+        ));
+    }
+    Ok(current
+        .variants
+        .iter()
+        .map(|cur| {
+            let base = match baseline.variants.iter().find(|v| v.name == cur.name) {
+                Some(base) => base,
+                None => {
+                    return Comparison {
+                        name: cur.name.clone(),
+                        baseline_median: None,
+                        current_median: cur.summary.median,
+                        change: None,
+                        p_value: None,
+                        verdict: Verdict::New,
+                    }
+                }
+            };
+            let change = cur.summary.median / base.summary.median - 1.0;
+            let (_, p) = stats::mann_whitney_u(&base.samples, &cur.samples);
+            let verdict = if p >= alpha || change.abs() <= threshold {
+                Verdict::Unchanged
+            } else if change > 0.0 {
+                Verdict::Regression
+            } else {
+                Verdict::Improvement
+            };
+            Comparison {
+                name: cur.name.clone(),
+                baseline_median: Some(base.summary.median),
+                current_median: cur.summary.median,
+                change: Some(change),
+                p_value: Some(p),
+                verdict,
+            }
+        })
+        .collect())
+}
+
+/// Render `comparisons` as a table, one variant per line.
+pub fn table(comparisons: &[Comparison]) -> String {
+    let mut out = format!(
+        "{:<12} {:>12} {:>12} {:>9} {:>9}  {}\n",
+        "variant", "baseline", "current", "change", "p-value", "verdict"
+    );
+    for c in comparisons {
+        let opt = |x: Option<f64>, f: &dyn Fn(f64) -> String| x.map(f).unwrap_or_else(|| "-".into());
+        out += &format!(
+            "{:<12} {:>12} {:>12.6} {:>9} {:>9}  {:?}\n",
+            c.name,
+            opt(c.baseline_median, &|m| format!("{:.6}", m)),
+            c.current_median,
+            opt(c.change, &|x| format!("{:+.1}%", x * 100.0)),
+            opt(c.p_value, &|p| format!("{:.4}", p)),
+            c.verdict
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::{Environment, VariantReport, Workload};
+
+    /// Eight runs with a median of exactly `median`, so the relative changes
+    /// below come out exact.
+    fn samples(median: f64) -> Vec<f64> {
+        [0.98, 0.99, 1.0, 1.0, 1.0, 1.0, 1.01, 1.02].iter().map(|x| x * median).collect()
+    }
+
+    fn report(variants: &[(&str, f64)]) -> Report {
+        Report {
+            workload: Workload {
+                cpu: "6502".to_string(),
+                sync: "eager".to_string(),
+                description: "test".to_string(),
+                instructions: 1000,
+                repetitions: 8,
+                warmup: 0,
+                isolated: false,
+            },
+            environment: Environment {
+                rustc: String::new(),
+                profile: String::new(),
+                cpu_model: String::new(),
+                git_revision: String::new(),
+                os: String::new(),
+                arch: String::new(),
+            },
+            variants: variants
+                .iter()
+                .map(|&(name, median)| {
+                    let samples = samples(median);
+                    VariantReport {
+                        name: name.to_string(),
+                        summary: stats::summarize(&samples),
+                        samples,
+                        cycles: 0,
+                        instructions: 1000,
+                        emulated_mhz: 0.0,
+                        instructions_per_sec: 0.0,
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    fn verdict(base: f64, current: f64, threshold: f64, alpha: f64) -> Verdict {
+        let comparisons = compare(&report(&[("null", base)]), &report(&[("null", current)]), threshold, alpha).unwrap();
+        comparisons[0].verdict
+    }
+
+    #[test]
+    fn threshold_edges() {
+        assert_eq!(verdict(1.0, 1.25, 0.25, 0.05), Verdict::Unchanged, "exactly at the threshold");
+        assert_eq!(verdict(1.0, 1.25, 0.24, 0.05), Verdict::Regression);
+        assert_eq!(verdict(1.0, 0.75, 0.25, 0.05), Verdict::Unchanged);
+        assert_eq!(verdict(1.0, 0.75, 0.24, 0.05), Verdict::Improvement);
+        assert_eq!(verdict(1.0, 1.0, 0.0, 0.05), Verdict::Unchanged);
+    }
+
+    #[test]
+    fn insignificant_changes_are_unchanged() {
+        // Eight against eight samples that don't overlap, with four tied
+        // on each side, is p = 0.000785, so that's significant at 0.001 and
+        // not at 0.0007.
+        assert_eq!(verdict(1.0, 1.5, 0.05, 0.001), Verdict::Regression);
+        assert_eq!(verdict(1.0, 1.5, 0.05, 0.0007), Verdict::Unchanged);
+    }
+
+    #[test]
+    fn new_variants_and_changes() {
+        let comparisons = compare(&report(&[("null", 1.0)]), &report(&[("null", 1.5), ("tokio", 2.0)]), 0.05, 0.05)
+            .unwrap();
+        assert_eq!(comparisons[0].change, Some(0.5));
+        assert_eq!(comparisons[0].baseline_median, Some(1.0));
+        assert_eq!(comparisons[1].verdict, Verdict::New);
+        assert_eq!(comparisons[1].change, None);
+    }
+
+    #[test]
+    fn other_workloads_do_not_compare() {
+        let mut other = report(&[("null", 1.0)]);
+        other.workload.sync = "lazy".to_string();
+        assert!(compare(&report(&[("null", 1.0)]), &other, 0.05, 0.05).is_err());
+    }
+}
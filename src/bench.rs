@@ -0,0 +1,134 @@
+use crate::bus::{Bus, MemoryMap, FAST};
+use crate::cpu::{Constructor, Model, State};
+use crate::programs::{self, Program};
+use crate::scheduler::SyncMode;
+use crate::spc700::{Apu, CpuPorts, Ports, CPU_PORTS_END, CPU_PORTS_START};
+use crate::trace::Sink;
+use std::process::Command;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The outcome of a single timed run.
+#[derive(Clone, Copy, Debug)]
+pub struct Measurement {
+    pub elapsed: Duration,
+    pub state: State,
+}
+
+/// A flat bus and an APU running `programs::apu_image`, connected through
+/// the APU ports if `program` uses them.
+pub fn machine(program: &Program) -> (Box<dyn Bus>, Apu) {
+    let ports = Arc::new(Ports::default());
+    let mut bus = MemoryMap::flat();
+    if program.apu_ports {
+        let device = Box::new(CpuPorts(ports.clone()));
+        bus = bus.mmio(CPU_PORTS_START, CPU_PORTS_END, device, FAST);
+    }
+    let mut apu = Apu::new(ports);
+    apu.load(&programs::apu_image(), programs::APU_START);
+    (Box::new(bus), apu)
+}
+
+/// Time `count` instructions of `program` on a fresh CPU in this process,
+/// including catching the APU up at the end.
+pub fn measure(
+    new: Constructor,
+    model: Model,
+    sync: SyncMode,
+    program: &Program,
+    count: usize,
+) -> Measurement {
+    let (bus, apu) = machine(program);
+    let mut cpu = new(model, sync, bus, apu);
+    program.load(&mut *cpu);
+    let start = Instant::now();
+    cpu.run(count);
+    cpu.synchronize();
+    let elapsed = start.elapsed();
+    Measurement {
+        elapsed,
+        state: cpu.state(),
+    }
+}
+
+/// Run `count` instructions of `program` on a fresh CPU like `measure`,
+/// but untimed and sending every bus access to `sink`.
+pub fn trace(
+    new: Constructor,
+    model: Model,
+    sync: SyncMode,
+    program: &Program,
+    count: usize,
+    sink: Box<dyn Sink>,
+) -> State {
+    let (bus, apu) = machine(program);
+    let mut cpu = new(model, sync, bus, apu);
+    program.load(&mut *cpu);
+    cpu.trace(sink);
+    cpu.run(count);
+    cpu.synchronize();
+    cpu.state()
+}
+
+/// Time `count` instructions in a fresh copy of this binary so variants
+/// can't pollute each other's caches or allocator state. The warm-up runs
+/// happen in the child too, right before the timed run.
+pub fn measure_isolated(
+    name: &str,
+    model: Model,
+    sync: SyncMode,
+    program: &Program,
+    count: usize,
+    warmup: usize,
+) -> Measurement {
+    let exe = std::env::current_exe().expect("couldn't find the benchmark binary");
+    let output = Command::new(exe)
+        .arg("--child")
+        .args(["--variant", name])
+        .args(["--cpu", model.name()])
+        .args(["--sync", sync.name()])
+        .args(["--program", program.name])
+        .args(["--count", &count.to_string()])
+        .args(["--warmup", &warmup.to_string()])
+        .output()
+        .expect("couldn't start child process");
+    if !output.status.success() {
+        panic!(
+            "child process for {} variant failed: {}",
+            name,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    parse_child_line(stdout.trim())
+        .unwrap_or_else(|| panic!("unexpected output from child process: {:?}", stdout))
+}
+
+/// Entry point of the child process started by `measure_isolated`.
+pub fn child(
+    new: Constructor,
+    model: Model,
+    sync: SyncMode,
+    program: &Program,
+    count: usize,
+    warmup: usize,
+) {
+    for _ in 0..warmup {
+        measure(new, model, sync, program, count);
+    }
+    let m = measure(new, model, sync, program, count);
+    println!(
+        "{:?},{}",
+        m.elapsed.as_secs_f64(),
+        serde_json::to_string(&m.state).expect("states always serialize")
+    );
+}
+
+/// The elapsed seconds, then the state as JSON.
+fn parse_child_line(line: &str) -> Option<Measurement> {
+    let (elapsed, state) = line.split_once(',')?;
+    Some(Measurement {
+        elapsed: Duration::from_secs_f64(elapsed.parse().ok()?),
+        state: serde_json::from_str(state).ok()?,
+    })
+}
@@ -0,0 +1,31 @@
+//! The C version from the README, `c/reference.c`, built on the C context
+//! switch in `libco/`. It runs `lda-absy` on the 6502 next to a stand-in APU and prints
+//! the same CSV line per run as `emu-test --format csv`, so the numbers can
+//! be put side by side.
+
+use std::os::raw::c_double;
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+extern "C" {
+    fn c_reference_run(count: u32, cycles: *mut u32, apu_cycles: *mut u32) -> c_double;
+}
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn main() {
+    let count = std::env::args()
+        .nth(1)
+        .map(|n| n.replace('_', "").parse().expect("usage: c-reference [instructions]"))
+        .unwrap_or(5_000_000);
+    let (mut cycles, mut apu_cycles) = (0, 0);
+    // Safety: the C side only writes through the two pointers.
+    let secs = unsafe { c_reference_run(count, &mut cycles, &mut apu_cycles) };
+    eprintln!("{} instructions, {} cycles, {} APU cycles", count, cycles, apu_cycles);
+    println!("c-reference");
+    println!("{:?}", secs);
+}
+
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+fn main() {
+    eprintln!("the C context switch only exists for x86_64 Linux");
+    std::process::exit(1);
+}
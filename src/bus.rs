@@ -0,0 +1,301 @@
+//! What a CPU sees when it touches memory. The variants only ever call
+//! `Bus::read`, `Bus::write`, `Bus::cycles` and `Bus::shared`, so memory-mapped peripherals
+//! can be attached by building a different `MemoryMap` (or implementing
+//! `Bus` directly) without touching the CPU code.
+
+/// Master clock cycles an access takes on the SNES's fast path, which is
+/// what every variant was timed with before the bus was pluggable.
+pub const FAST: u32 = 6;
+/// Master clock cycles of a slow access, e.g. to SNES work RAM.
+pub const SLOW: u32 = 8;
+/// Master clock cycles of an extra slow access, e.g. to the joypad ports.
+pub const XSLOW: u32 = 12;
+
+/// The address space of a CPU. Addresses are 24 bits; the 6502 only uses
+/// the bottom 16.
+pub trait Bus: Send {
+    fn read(&mut self, addr: u32) -> u8;
+
+    fn write(&mut self, addr: u32, data: u8);
+
+    /// Master clock cycles an access to `addr` takes. The CPU waits 2 of
+    /// them before the data moves and the rest after.
+    fn cycles(&self, addr: u32) -> u32 {
+        let _ = addr;
+        FAST
+    }
+
+    /// Whether `addr` holds state another component can see or change, so a
+    /// CPU that runs ahead has to catch the others up before touching it.
+    fn shared(&self, addr: u32) -> bool {
+        let _ = addr;
+        false
+    }
+
+    /// Copy `image` into memory starting at `addr`, without any side
+    /// effects or timing. Unlike `write` this also fills ROM.
+    fn load(&mut self, addr: u32, image: &[u8]);
+}
+
+/// A memory-mapped peripheral.
+pub trait Device: Send {
+    /// `offset` is relative to the start of the region the device is mapped
+    /// at. `None` leaves the open bus value on the data bus.
+    fn read(&mut self, offset: u32) -> Option<u8>;
+
+    fn write(&mut self, offset: u32, data: u8);
+}
+
+enum Backing {
+    Ram(Vec<u8>),
+    Rom(Vec<u8>),
+    Mmio(Box<dyn Device>),
+}
+
+/// An address range, inclusive at both ends. RAM and ROM smaller than the
+/// range are mirrored across it.
+struct Region {
+    start: u32,
+    end: u32,
+    cycles: u32,
+    backing: Backing,
+}
+
+impl Region {
+    fn contains(&self, addr: u32) -> bool {
+        self.start <= addr && addr <= self.end
+    }
+}
+
+/// A `Bus` decoded into regions. Regions added later take precedence over
+/// earlier ones where they overlap, so a device can be mapped on top of a
+/// RAM mirror. Reads from unmapped addresses return whatever was last on the
+/// data bus, like the real hardware.
+pub struct MemoryMap {
+    regions: Vec<Region>,
+    /// The last value read or written.
+    open_bus: u8,
+    /// Cost of accesses no region claims.
+    unmapped_cycles: u32,
+}
+
+impl MemoryMap {
+    pub fn new() -> MemoryMap {
+        MemoryMap {
+            regions: vec![],
+            open_bus: 0,
+            unmapped_cycles: FAST,
+        }
+    }
+
+    /// 64 KiB of RAM mirrored across the whole 24-bit address space, which
+    /// is what the variants had before they took a bus.
+    pub fn flat() -> MemoryMap {
+        MemoryMap::new().ram(0x00_0000, 0xff_ffff, 0x1_0000, FAST)
+    }
+
+    fn map(mut self, start: u32, end: u32, cycles: u32, backing: Backing) -> MemoryMap {
+        assert!(start <= end, "region {:#x}-{:#x} is empty", start, end);
+        self.regions.push(Region {
+            start,
+            end,
+            cycles,
+            backing,
+        });
+        self
+    }
+
+    /// `size` bytes of zeroed RAM at `start..=end`.
+    pub fn ram(self, start: u32, end: u32, size: usize, cycles: u32) -> MemoryMap {
+        self.map(start, end, cycles, Backing::Ram(vec![0; size]))
+    }
+
+    /// ROM at `start..=end`. Writes to it are ignored.
+    pub fn rom(self, start: u32, end: u32, data: Vec<u8>, cycles: u32) -> MemoryMap {
+        self.map(start, end, cycles, Backing::Rom(data))
+    }
+
+    pub fn mmio(self, start: u32, end: u32, device: Box<dyn Device>, cycles: u32) -> MemoryMap {
+        self.map(start, end, cycles, Backing::Mmio(device))
+    }
+
+    fn region(&self, addr: u32) -> Option<&Region> {
+        self.regions.iter().rev().find(|r| r.contains(addr))
+    }
+
+    fn region_mut(&mut self, addr: u32) -> Option<&mut Region> {
+        self.regions.iter_mut().rev().find(|r| r.contains(addr))
+    }
+}
+
+impl Default for MemoryMap {
+    fn default() -> MemoryMap {
+        MemoryMap::new()
+    }
+}
+
+/// Where `addr` ends up in `len` bytes of memory mirrored from `start`.
+fn mirror(start: u32, addr: u32, len: usize) -> usize {
+    let offset = (addr - start) as usize;
+    if len.is_power_of_two() {
+        offset & (len - 1)
+    } else {
+        offset % len
+    }
+}
+
+impl Bus for MemoryMap {
+    fn read(&mut self, addr: u32) -> u8 {
+        let data = match self.region_mut(addr) {
+            Some(Region {
+                start,
+                backing: Backing::Ram(mem) | Backing::Rom(mem),
+                ..
+            }) if !mem.is_empty() => Some(mem[mirror(*start, addr, mem.len())]),
+            Some(Region {
+                start,
+                backing: Backing::Mmio(device),
+                ..
+            }) => device.read(addr - *start),
+            _ => None,
+        };
+        if let Some(data) = data {
+            self.open_bus = data;
+        }
+        self.open_bus
+    }
+
+    fn write(&mut self, addr: u32, data: u8) {
+        self.open_bus = data;
+        match self.region_mut(addr) {
+            Some(Region {
+                start,
+                backing: Backing::Ram(mem),
+                ..
+            }) if !mem.is_empty() => {
+                let i = mirror(*start, addr, mem.len());
+                mem[i] = data;
+            }
+            Some(Region {
+                start,
+                backing: Backing::Mmio(device),
+                ..
+            }) => device.write(addr - *start, data),
+            _ => {}
+        }
+    }
+
+    fn cycles(&self, addr: u32) -> u32 {
+        self.region(addr).map_or(self.unmapped_cycles, |r| r.cycles)
+    }
+
+    /// Every device counts as shared, RAM and ROM only belong to this bus.
+    fn shared(&self, addr: u32) -> bool {
+        matches!(
+            self.region(addr),
+            Some(Region {
+                backing: Backing::Mmio(_),
+                ..
+            })
+        )
+    }
+
+    fn load(&mut self, addr: u32, image: &[u8]) {
+        for (i, &data) in image.iter().enumerate() {
+            let addr = addr + i as u32;
+            if let Some(Region {
+                start,
+                backing: Backing::Ram(mem) | Backing::Rom(mem),
+                ..
+            }) = self.region_mut(addr)
+            {
+                if !mem.is_empty() {
+                    let i = mirror(*start, addr, mem.len());
+                    mem[i] = data;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A device that remembers the last write and only drives the data bus
+    /// on even offsets.
+    struct Latch(u8);
+
+    impl Device for Latch {
+        fn read(&mut self, offset: u32) -> Option<u8> {
+            (offset & 1 == 0).then_some(self.0)
+        }
+
+        fn write(&mut self, _: u32, data: u8) {
+            self.0 = data;
+        }
+    }
+
+    /// 2 KiB of RAM mirrored four times, a 3 byte ROM mirrored across a
+    /// page, a latch at $4000-$4003 and nothing from $4004 up to the ROM.
+    fn map() -> MemoryMap {
+        MemoryMap::new()
+            .ram(0x0000, 0x1fff, 0x800, SLOW)
+            .mmio(0x4000, 0x4003, Box::new(Latch(0)), XSLOW)
+            .rom(0x8000, 0x80ff, vec![0x11, 0x22, 0x33], FAST)
+    }
+
+    #[test]
+    fn mirrors() {
+        let mut bus = map();
+        bus.write(0x1801, 0x5a);
+        assert_eq!(bus.read(0x0001), 0x5a);
+        assert_eq!(bus.read(0x0801), 0x5a);
+        // Memory that isn't a power of two in size mirrors too.
+        assert_eq!(bus.read(0x8003), 0x11);
+        assert_eq!(bus.read(0x80ff), 0x11);
+    }
+
+    #[test]
+    fn unmapped_reads_are_open_bus() {
+        let mut bus = map();
+        assert_eq!(bus.read(0x8001), 0x22);
+        assert_eq!(bus.read(0x5000), 0x22);
+        bus.write(0x0000, 0x99);
+        assert_eq!(bus.read(0x5000), 0x99);
+        // So is a device that leaves the data bus alone.
+        assert_eq!(bus.read(0x4001), 0x99);
+        assert_eq!(bus.read(0x4000), 0x00);
+        assert_eq!(bus.read(0x4001), 0x00);
+    }
+
+    #[test]
+    fn rom_ignores_writes() {
+        let mut bus = map();
+        bus.write(0x8000, 0xff);
+        assert_eq!(bus.read(0x8000), 0x11);
+        // `load` fills it anyway.
+        bus.load(0x80fe, &[0x44, 0x55]);
+        assert_eq!(bus.read(0x8000), 0x55);
+        assert_eq!(bus.read(0x8002), 0x44);
+    }
+
+    #[test]
+    fn regions() {
+        let mut bus = map();
+        assert_eq!(bus.cycles(0x1fff), SLOW);
+        assert_eq!(bus.cycles(0x4002), XSLOW);
+        assert_eq!(bus.cycles(0x8000), FAST);
+        assert_eq!(bus.cycles(0x4004), FAST);
+
+        assert!(bus.shared(0x4000) && bus.shared(0x4003));
+        assert!(!bus.shared(0x0000) && !bus.shared(0x4004) && !bus.shared(0x8000));
+        bus.write(0x4002, 0x77);
+        assert_eq!(bus.read(0x4000), 0x77);
+
+        // A region added later goes on top.
+        let bus = bus.mmio(0x0100, 0x01ff, Box::new(Latch(0)), FAST);
+        assert!(bus.shared(0x0100) && !bus.shared(0x0200));
+        assert_eq!(bus.cycles(0x0100), FAST);
+    }
+}
@@ -0,0 +1,335 @@
+use crate::cpu::Model;
+use crate::scheduler::SyncMode;
+use crate::trace;
+use clap::{App, Arg};
+
+/// How results are written to stdout.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Progress chatter for every run followed by the CSV table and summary
+    /// statistics.
+    Text,
+    /// Only the CSV table, one row per repetition.
+    Csv,
+    /// A JSON report with the samples, derived rates and a description of
+    /// the environment, see `report::Report`.
+    Json,
+}
+
+impl Format {
+    pub const NAMES: &'static [&'static str] = &["text", "csv", "json"];
+
+    fn from_name(name: &str) -> Format {
+        match name {
+            "text" => Format::Text,
+            "csv" => Format::Csv,
+            "json" => Format::Json,
+            _ => unreachable!("clap only accepts known formats"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// Names of the variants to run, in the order they should run.
+    pub variants: Vec<String>,
+    /// Processor every variant emulates.
+    pub model: Model,
+    /// When the CPU switches to the APU.
+    pub sync: SyncMode,
+    /// Name of the program every variant runs.
+    pub program: String,
+    /// Instructions executed per run.
+    pub count: usize,
+    /// Timed runs of every variant.
+    pub repetitions: usize,
+    /// Untimed runs of every variant before the timed ones.
+    pub warmup: usize,
+    pub format: Format,
+    /// Run every timed run in a fresh child process.
+    pub isolate: bool,
+    /// Where to write the raw samples as CSV, one column per variant.
+    pub csv: Option<String>,
+    /// Where to write a self-contained HTML report with charts.
+    pub html: Option<String>,
+    /// Directory to write a bus trace of one more run of every variant to.
+    pub trace: Option<String>,
+    pub trace_format: trace::Format,
+    /// Directory of single-step processor tests to run instead of timing.
+    pub single_step: Option<String>,
+    /// 64 KiB image of a test program to run instead of timing, see
+    /// `functional`.
+    pub image: Option<String>,
+    /// Where the image starts.
+    pub start: Option<u16>,
+    /// Where the image ends up when it passes.
+    pub success: Option<u16>,
+    /// A nestest log to compare every instruction of the image against.
+    pub nestest: Option<String>,
+    /// Set in the child processes started by `--isolate`.
+    pub child: bool,
+    /// Write the JSON report here for later runs to compare against.
+    pub save_baseline: Option<String>,
+    /// Compare against the JSON report stored here.
+    pub baseline: Option<String>,
+    /// Relative change of the median that counts as a regression.
+    pub threshold: f64,
+    /// Significance level of the Mann-Whitney U test.
+    pub alpha: f64,
+}
+
+fn is_number(s: String) -> Result<(), String> {
+    s.replace('_', "")
+        .parse::<usize>()
+        .map(|_| ())
+        .map_err(|e| format!("{}: {}", s, e))
+}
+
+fn is_positive(s: String) -> Result<(), String> {
+    match s.replace('_', "").parse::<usize>() {
+        Ok(0) => Err(format!("{} isn't positive", s)),
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("{}: {}", s, e)),
+    }
+}
+
+fn is_percentage(s: String) -> Result<(), String> {
+    match s.parse::<f64>() {
+        Ok(x) if x.is_finite() && x >= 0.0 => Ok(()),
+        Ok(_) => Err(format!("{} isn't a finite, non-negative percentage", s)),
+        Err(e) => Err(format!("{}: {}", s, e)),
+    }
+}
+
+fn is_probability(s: String) -> Result<(), String> {
+    match s.parse::<f64>() {
+        Ok(x) if x > 0.0 && x < 1.0 => Ok(()),
+        Ok(_) => Err(format!("{} isn't between 0 and 1", s)),
+        Err(e) => Err(format!("{}: {}", s, e)),
+    }
+}
+
+/// A 16-bit address in hex, with or without a `$` or `0x` in front.
+fn address(s: &str) -> Option<u16> {
+    let hex = s.strip_prefix('$').or_else(|| s.strip_prefix("0x")).unwrap_or(s);
+    u16::from_str_radix(hex, 16).ok()
+}
+
+fn is_address(s: String) -> Result<(), String> {
+    address(&s).map(|_| ()).ok_or_else(|| format!("{} isn't a 16-bit address in hex", s))
+}
+
+fn number(s: &str) -> usize {
+    s.replace('_', "").parse().expect("validated by clap")
+}
+
+/// Parse the command line. `variants` is every known variant name in its
+/// default order, `programs` every known program with the default first.
+pub fn parse(variants: &[&str], programs: &[&str]) -> Options {
+    let matches = App::new("emu-test")
+        .about("timing different ways to implement a cycle accurate emulator")
+        .arg(
+            Arg::with_name("variant")
+                .long("variant")
+                .short("v")
+                .help("Variants to run, defaults to all of them")
+                .takes_value(true)
+                .multiple(true)
+                .use_delimiter(true)
+                .possible_values(variants),
+        )
+        .arg(
+            Arg::with_name("cpu")
+                .long("cpu")
+                .help("Processor to emulate")
+                .takes_value(true)
+                .default_value(Model::NAMES[0])
+                .possible_values(Model::NAMES),
+        )
+        .arg(
+            Arg::with_name("sync")
+                .long("sync")
+                .help("When the CPU switches to the APU: on every wait, or only before touching a shared register")
+                .takes_value(true)
+                .default_value(SyncMode::NAMES[0])
+                .possible_values(SyncMode::NAMES),
+        )
+        .arg(
+            Arg::with_name("program")
+                .long("program")
+                .short("p")
+                .help("Program to run; variants that can't run it are skipped")
+                .takes_value(true)
+                .default_value(programs[0])
+                .possible_values(programs),
+        )
+        .arg(
+            Arg::with_name("count")
+                .long("count")
+                .short("n")
+                .help("Instructions to execute per run")
+                .takes_value(true)
+                .default_value("5_000_000")
+                .validator(is_positive),
+        )
+        .arg(
+            Arg::with_name("repetitions")
+                .long("repetitions")
+                .short("r")
+                .help("Timed runs of every variant")
+                .takes_value(true)
+                .default_value("1")
+                .validator(is_positive),
+        )
+        .arg(
+            Arg::with_name("warmup")
+                .long("warmup")
+                .short("w")
+                .help("Untimed runs of every variant before timing starts")
+                .takes_value(true)
+                .default_value("0")
+                .validator(is_number),
+        )
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .short("f")
+                .help("Output format")
+                .takes_value(true)
+                .default_value("text")
+                .possible_values(Format::NAMES),
+        )
+        .arg(
+            Arg::with_name("isolate")
+                .long("isolate")
+                .help("Run every timed run in a fresh child process"),
+        )
+        .arg(
+            Arg::with_name("csv")
+                .long("csv")
+                .help("Also write the samples to this file as CSV")
+                .takes_value(true)
+                .value_name("FILE"),
+        )
+        .arg(
+            Arg::with_name("html")
+                .long("html")
+                .help("Also write an HTML report with charts of the samples to this file")
+                .takes_value(true)
+                .value_name("FILE"),
+        )
+        .arg(
+            Arg::with_name("trace")
+                .long("trace")
+                .help("Also run every variant once more, untimed, writing its bus accesses to a file named after it in this directory")
+                .takes_value(true)
+                .value_name("DIR"),
+        )
+        .arg(
+            Arg::with_name("trace-format")
+                .long("trace-format")
+                .help("Format of the --trace files")
+                .takes_value(true)
+                .default_value(trace::Format::NAMES[0])
+                .possible_values(trace::Format::NAMES),
+        )
+        .arg(
+            Arg::with_name("single-step")
+                .long("single-step")
+                .help("Instead of timing anything, run the variants through Tom Harte's single-step tests for the processor in DIR")
+                .takes_value(true)
+                .value_name("DIR"),
+        )
+        .arg(
+            Arg::with_name("image")
+                .long("image")
+                .help("Instead of timing anything, run the variants through the test program in this flat 64 KiB image until it traps")
+                .takes_value(true)
+                .value_name("FILE"),
+        )
+        .arg(
+            Arg::with_name("start")
+                .long("start")
+                .help("Address in hex the --image starts at")
+                .takes_value(true)
+                .value_name("ADDR")
+                .validator(is_address),
+        )
+        .arg(
+            Arg::with_name("success")
+                .long("success")
+                .help("Address in hex the --image gets to when it passes, needed unless there's a --nestest log")
+                .takes_value(true)
+                .value_name("ADDR")
+                .validator(is_address),
+        )
+        .arg(
+            Arg::with_name("nestest")
+                .long("nestest")
+                .help("Compare the registers before every instruction of the --image against this nestest log")
+                .takes_value(true)
+                .value_name("FILE"),
+        )
+        .arg(
+            Arg::with_name("save-baseline")
+                .long("save-baseline")
+                .help("Write the JSON report to this file to compare later runs against")
+                .takes_value(true)
+                .value_name("FILE"),
+        )
+        .arg(
+            Arg::with_name("baseline")
+                .long("baseline")
+                .help("Compare against a report saved with --save-baseline and exit with an error on regressions")
+                .takes_value(true)
+                .value_name("FILE"),
+        )
+        .arg(
+            Arg::with_name("threshold")
+                .long("threshold")
+                .help("Percentage the median has to move by to count as a regression")
+                .takes_value(true)
+                .default_value("5")
+                .validator(is_percentage),
+        )
+        .arg(
+            Arg::with_name("alpha")
+                .long("alpha")
+                .help("Significance level of the Mann-Whitney U test")
+                .takes_value(true)
+                .default_value("0.05")
+                .validator(is_probability),
+        )
+        .arg(Arg::with_name("child").long("child").hidden(true))
+        .get_matches();
+
+    let variants = match matches.values_of("variant") {
+        Some(names) => names.map(String::from).collect(),
+        None => variants.iter().map(|s| s.to_string()).collect(),
+    };
+    Options {
+        variants,
+        model: Model::from_name(matches.value_of("cpu").unwrap()).expect("validated by clap"),
+        sync: SyncMode::from_name(matches.value_of("sync").unwrap()).expect("validated by clap"),
+        program: matches.value_of("program").unwrap().to_string(),
+        count: number(matches.value_of("count").unwrap()),
+        repetitions: number(matches.value_of("repetitions").unwrap()),
+        warmup: number(matches.value_of("warmup").unwrap()),
+        format: Format::from_name(matches.value_of("format").unwrap()),
+        isolate: matches.is_present("isolate"),
+        csv: matches.value_of("csv").map(String::from),
+        html: matches.value_of("html").map(String::from),
+        trace: matches.value_of("trace").map(String::from),
+        trace_format: trace::Format::from_name(matches.value_of("trace-format").unwrap()).expect("validated by clap"),
+        single_step: matches.value_of("single-step").map(String::from),
+        image: matches.value_of("image").map(String::from),
+        start: matches.value_of("start").map(|s| address(s).expect("validated by clap")),
+        success: matches.value_of("success").map(|s| address(s).expect("validated by clap")),
+        nestest: matches.value_of("nestest").map(String::from),
+        child: matches.is_present("child"),
+        save_baseline: matches.value_of("save-baseline").map(String::from),
+        baseline: matches.value_of("baseline").map(String::from),
+        threshold: matches.value_of("threshold").unwrap().parse::<f64>().unwrap() / 100.0,
+        alpha: matches.value_of("alpha").unwrap().parse().unwrap(),
+    }
+}
@@ -0,0 +1,125 @@
+//! Cooperative threads with stacks of their own, the way byuu's libco does
+//! them on x86_64: switching saves the callee-saved registers on the old
+//! stack, swaps stack pointers and pops them off the new one, so a switch is
+//! a handful of instructions and never enters the kernel. Only the System V
+//! calling convention is handled, so this is x86_64 Linux only.
+
+use std::arch::global_asm;
+use std::cell::Cell;
+use std::rc::Rc;
+
+/// Stack size of every thread `Cothread::new` creates. Only touched pages
+/// get backed by memory, so this is generous.
+const STACK_SIZE: usize = 1 << 20;
+
+global_asm!(
+    ".text",
+    // emu_test_cothread_switch(save: *mut *mut u8, sp: *mut u8)
+    ".globl emu_test_cothread_switch",
+    "emu_test_cothread_switch:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+    // Where a new thread's first switch returns to, with the thread in rbx
+    // and `start` in r12 as set up by `Cothread::new`.
+    ".globl emu_test_cothread_entry",
+    "emu_test_cothread_entry:",
+    "mov rdi, rbx",
+    "jmp r12",
+);
+
+extern "C" {
+    fn emu_test_cothread_switch(save: *mut *mut u8, sp: *mut u8);
+    fn emu_test_cothread_entry();
+}
+
+type Entry = Box<dyn FnOnce(&Cothread)>;
+
+/// A stack, and where its stack pointer was left the last time its thread
+/// switched away.
+pub struct Cothread {
+    sp: Cell<*mut u8>,
+    /// Taken out when the thread first runs.
+    entry: Cell<Option<Entry>>,
+    _stack: Box<[u8]>,
+}
+
+impl Cothread {
+    /// Whatever is running right now, on whatever stack it already has, so
+    /// there's something to switch back to.
+    pub fn current() -> Rc<Cothread> {
+        Rc::new(Cothread {
+            sp: Cell::new(std::ptr::null_mut()),
+            entry: Cell::new(None),
+            _stack: Box::new([]),
+        })
+    }
+
+    /// A thread that calls `f` with itself the first time it's switched to.
+    /// `f` must never return, since there's nothing to return to; it
+    /// switches to another thread instead. Dropping a thread that has
+    /// started leaks whatever is on its stack.
+    pub fn new<F: FnOnce(&Cothread) + 'static>(f: F) -> Rc<Cothread> {
+        let thread = Rc::new(Cothread {
+            sp: Cell::new(std::ptr::null_mut()),
+            entry: Cell::new(Some(Box::new(f))),
+            _stack: vec![0; STACK_SIZE].into_boxed_slice(),
+        });
+        // Lay the stack out like `emu_test_cothread_switch` left it: the six
+        // registers it pops, then the entry point for its `ret`, then a
+        // dummy return address so `start` sees the stack aligned the way a
+        // `call` would leave it.
+        let top = (thread._stack.as_ptr() as usize + STACK_SIZE) & !15;
+        let start: extern "C" fn(*const Cothread) -> ! = start;
+        let entry: unsafe extern "C" fn() = emu_test_cothread_entry;
+        let frame: [usize; 8] = [
+            0,                            // r15
+            0,                            // r14
+            0,                            // r13
+            start as usize,               // r12
+            Rc::as_ptr(&thread) as usize, // rbx
+            0,                            // rbp
+            entry as usize,               // return address
+            0,
+        ];
+        let sp = (top - std::mem::size_of_val(&frame)) as *mut usize;
+        // Safety: the frame fits in the stack and `top` is aligned.
+        unsafe { sp.cast::<[usize; 8]>().write(frame) };
+        thread.sp.set(sp.cast());
+        thread
+    }
+
+    /// Switch from this thread, which has to be the one running, to `to`,
+    /// and return once something switches back.
+    ///
+    /// # Safety
+    ///
+    /// `to` has to be suspended, either fresh from `new` or switched away
+    /// from, and nothing borrowed across the switch may be touched by the
+    /// thread that runs in the meantime.
+    pub unsafe fn switch(&self, to: &Cothread) {
+        emu_test_cothread_switch(self.sp.as_ptr(), to.sp.get());
+    }
+}
+
+extern "C" fn start(thread: *const Cothread) -> ! {
+    // Safety: `new` put the thread itself here, and it's kept alive by
+    // whoever switched to it.
+    let thread = unsafe { &*thread };
+    let f = thread.entry.take().expect("cothread started twice");
+    f(thread);
+    eprintln!("a cothread returned from its entry point");
+    std::process::abort();
+}
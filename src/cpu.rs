@@ -0,0 +1,166 @@
+use crate::bus::Bus;
+use crate::scheduler::SyncMode;
+use crate::spc700::{self, Apu};
+use crate::trace;
+use serde::{Deserialize, Serialize};
+
+/// The SNES master clock in Hz, which is what `Cpu::cycles` counts no matter
+/// which processor is emulated.
+pub const FREQUENCY: u64 = 21_477_272;
+
+/// Which processor the variants emulate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Model {
+    /// The NMOS 6502, see `m6502`.
+    Nmos6502,
+    /// The 65816 in the SNES, the processor byuu's article is about, see
+    /// `w65816`.
+    Wdc65816,
+}
+
+impl Model {
+    pub const NAMES: &'static [&'static str] = &["6502", "65816"];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Model::Nmos6502 => "6502",
+            Model::Wdc65816 => "65816",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Model> {
+        match name {
+            "6502" => Some(Model::Nmos6502),
+            "65816" => Some(Model::Wdc65816),
+            _ => None,
+        }
+    }
+}
+
+/// The architectural state of a CPU, as seen from outside of whatever
+/// scheduling strategy a variant uses. This is the 65816 register file; the
+/// 6502 only uses the low byte of `a`, `x` and `y`, keeps `s` in page 1 and
+/// leaves the rest alone.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Registers {
+    pub pc: u16,
+    pub x: u16,
+    pub y: u16,
+    pub a: u16,
+    pub s: u16,
+    pub p: u8,
+    /// Direct page.
+    pub d: u16,
+    /// Data bank.
+    pub dbr: u8,
+    /// Program bank, the top 8 bits of the 24-bit program counter.
+    pub pbr: u8,
+    /// Emulation mode, always set on the 6502.
+    pub e: bool,
+}
+
+impl Registers {
+    /// The state every variant starts in. `pc` is 0 rather than the reset
+    /// vector so the benchmark doesn't depend on what's stored there.
+    pub fn power_on(model: Model) -> Registers {
+        Registers {
+            s: 0x01fd,
+            p: match model {
+                Model::Nmos6502 => crate::m6502::FLAG_U | crate::m6502::FLAG_I,
+                Model::Wdc65816 => crate::w65816::FLAG_M | crate::w65816::FLAG_X | crate::w65816::FLAG_I,
+            },
+            e: true,
+            ..Registers::default()
+        }
+    }
+}
+
+/// Everything a run leaves behind that should be identical no matter which
+/// variant did the work.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct State {
+    pub registers: Registers,
+    pub cycles: u32,
+    pub instruction_count: u32,
+    pub apu: spc700::State,
+}
+
+/// Common interface implemented by every `*_attempt::CPU` so the harness
+/// can drive them interchangeably.
+pub trait Cpu {
+    /// Execute a single instruction.
+    fn step(&mut self);
+
+    /// Execute `iters` instructions. Variants that need to set up a runtime
+    /// or executor override this so that cost is paid once per run instead
+    /// of once per instruction.
+    fn run(&mut self, iters: usize) {
+        for _ in 0..iters {
+            self.step();
+        }
+    }
+
+    /// Whether `step_cycle` works, which takes stopping in the middle of an
+    /// instruction. Every variant can once `stepping::Stepping` wraps it,
+    /// which is how `VARIANTS` builds them all.
+    fn steps_cycles(&self) -> bool {
+        false
+    }
+
+    /// Give the CPU exactly one more master clock, along with any bus access
+    /// that happens on it, so `cycles` goes up by one. What `step` and `run`
+    /// do isn't counted. Panics unless `steps_cycles`.
+    fn step_cycle(&mut self) {
+        panic!("this variant can't stop in the middle of an instruction");
+    }
+
+    /// Whether every documented instruction of the model is implemented,
+    /// rather than the handful in `programs::STORES`.
+    fn full_isa(&self) -> bool {
+        true
+    }
+
+    fn registers(&self) -> Registers;
+
+    /// Continue from `regs` instead, like a test that sets up a particular
+    /// state.
+    fn set_registers(&mut self, regs: Registers);
+
+    fn cycles(&self) -> u32;
+
+    fn instruction_count(&self) -> u32;
+
+    /// Catch the APU up with the CPU. With `SyncMode::Lazy` the APU can be
+    /// any distance behind after `run`, so this is what makes the state
+    /// comparable; with `SyncMode::Eager` there's nothing left to do.
+    fn synchronize(&mut self);
+
+    /// The APU as of the last time the CPU switched to it.
+    fn apu(&self) -> spc700::State;
+
+    fn state(&self) -> State {
+        State {
+            registers: self.registers(),
+            cycles: self.cycles(),
+            instruction_count: self.instruction_count(),
+            apu: self.apu(),
+        }
+    }
+
+    /// Send every bus access from now on to `sink`, see `trace`.
+    fn trace(&mut self, sink: Box<dyn trace::Sink>);
+
+    /// Load `image` into the bus, starting at address 0, and continue from
+    /// `pc`.
+    fn load(&mut self, image: &[u8], pc: u16);
+
+    /// Put the registers back to how they are at power on and the cycle and
+    /// instruction counts back to 0. Nothing else is reset: the bus and the
+    /// APU keep what they hold, the APU and the scheduler keep their place in
+    /// time, and any trace keeps going.
+    fn reset_registers(&mut self);
+}
+
+/// Builds a fresh CPU of one variant, attached to `bus` and running `apu`
+/// next to it, switching to it as often as `SyncMode` says.
+pub type Constructor = fn(Model, SyncMode, Box<dyn Bus>, Apu) -> Box<dyn Cpu>;
@@ -0,0 +1,66 @@
+//! Just enough of an executor to run async code without a runtime: nothing
+//! ever gets woken, every task is simply polled again, round robin, until
+//! the first one finishes. That's all the `executor` variant needs, and it
+//! leaves the async/await state machines as the only cost.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::ptr;
+use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+/// A future that is pending the first time it's polled and ready the second
+/// time, to give the other tasks a turn.
+pub struct YieldOnce {
+    yielded: bool,
+}
+
+pub fn yield_once() -> YieldOnce {
+    YieldOnce { yielded: false }
+}
+
+impl Future for YieldOnce {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<()> {
+        if self.yielded {
+            Poll::Ready(())
+        } else {
+            // No need to wake anyone, `block_on` polls again regardless.
+            self.yielded = true;
+            Poll::Pending
+        }
+    }
+}
+
+fn noop_clone(_: *const ()) -> RawWaker {
+    noop_raw_waker()
+}
+
+fn noop(_: *const ()) {}
+
+static NOOP_VTABLE: RawWakerVTable = RawWakerVTable::new(noop_clone, noop, noop, noop);
+
+fn noop_raw_waker() -> RawWaker {
+    RawWaker::new(ptr::null(), &NOOP_VTABLE)
+}
+
+/// Poll `main` until it finishes, polling each of `tasks` once whenever it
+/// is pending. The tasks must never finish, since polling a finished future
+/// isn't allowed.
+pub fn block_on<T>(main: impl Future<Output = T>, tasks: &mut [Pin<&mut dyn Future<Output = ()>>]) -> T {
+    // Safety: the vtable functions do nothing at all, so any data pointer
+    // is fine.
+    let waker = unsafe { Waker::from_raw(noop_raw_waker()) };
+    let mut cx = Context::from_waker(&waker);
+    let mut main = Box::pin(main);
+    loop {
+        if let Poll::Ready(result) = main.as_mut().poll(&mut cx) {
+            return result;
+        }
+        for task in tasks.iter_mut() {
+            if task.as_mut().poll(&mut cx).is_ready() {
+                panic!("an executor task finished");
+            }
+        }
+    }
+}
@@ -0,0 +1,254 @@
+//! Runs whole test programs that check the CPU themselves, like Klaus
+//! Dormann's 6502 functional test and nestest. They come as a flat 64 KiB
+//! image that `--image` loads at address 0 and starts at `--start`. When a
+//! test fails it traps, jumping or branching to itself, so the run stops as
+//! soon as an instruction leaves the program counter where it was, and it
+//! only passes if the CPU got to `--success` first. `--nestest` also compares the registers
+//! before every instruction against a log in the format of nestest's, the
+//! way other emulators are checked against Nintendulator.
+
+use crate::bus::{MemoryMap, FAST};
+use crate::cli::Options;
+use crate::cpu::{Constructor, Cpu, Model, Registers};
+use crate::m6502;
+use crate::spc700::{Apu, Ports};
+use crate::MEM_SIZE;
+use std::fs;
+use std::sync::Arc;
+
+/// Dormann's test takes about 30 million instructions, anything that runs
+/// much longer than that without trapping has gone off the rails.
+const MAX_INSTRUCTIONS: u64 = 100_000_000;
+
+/// One line of a nestest log, the state before an instruction. The log
+/// also has the instruction itself and where the PPU is, only the opcode of
+/// which means anything here, see `parse`.
+#[derive(Debug, PartialEq, Eq)]
+struct LogLine {
+    pc: u16,
+    a: u8,
+    x: u8,
+    y: u8,
+    p: u8,
+    sp: u8,
+    /// CPU cycles since power on, if the log has them.
+    cyc: Option<u64>,
+}
+
+impl LogLine {
+    /// Parse a line like
+    /// `C000  4C F5 C5  JMP $C5F5    A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7`
+    /// along with the opcode of the instruction.
+    fn parse(line: &str) -> Option<(u8, LogLine)> {
+        let mut words = line.split_whitespace();
+        let pc = words.next()?;
+        let opcode = words.next()?;
+        let line = LogLine {
+            pc: u16::from_str_radix(pc.trim_start_matches('$').trim_end_matches(':'), 16).ok()?,
+            a: u8::from_str_radix(field(line, "A:")?, 16).ok()?,
+            x: u8::from_str_radix(field(line, "X:")?, 16).ok()?,
+            y: u8::from_str_radix(field(line, "Y:")?, 16).ok()?,
+            p: u8::from_str_radix(field(line, "P:")?, 16).ok()?,
+            sp: u8::from_str_radix(field(line, "SP:")?, 16).ok()?,
+            cyc: match field(line, "CYC:") {
+                Some(cyc) => Some(cyc.parse().ok()?),
+                None => None,
+            },
+        };
+        Some((u8::from_str_radix(opcode, 16).ok()?, line))
+    }
+
+    /// What the log would say for `regs`, with the CPU `cyc` cycles in.
+    fn from_registers(regs: &Registers, cyc: Option<u64>) -> LogLine {
+        LogLine {
+            pc: regs.pc,
+            a: regs.a as u8,
+            x: regs.x as u8,
+            y: regs.y as u8,
+            p: regs.p,
+            sp: regs.s as u8,
+            cyc,
+        }
+    }
+}
+
+/// The same fields the log has, minus the instruction and the PPU.
+impl std::fmt::Display for LogLine {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{:04X} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
+            self.pc, self.a, self.x, self.y, self.p, self.sp
+        )?;
+        if let Some(cyc) = self.cyc {
+            write!(f, " CYC:{}", cyc)?;
+        }
+        Ok(())
+    }
+}
+
+/// The value after ` key` in `line`, up to the next space. The space in
+/// front keeps `P:` from matching `SP:`.
+fn field<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+    let start = line.find(&format!(" {}", key))? + 1 + key.len();
+    line[start..].split_whitespace().next()
+}
+
+/// How `run_image` stopped.
+enum Stop {
+    Trapped,
+    Succeeded,
+}
+
+/// Run until the CPU traps or gets to `success`.
+fn run_image(cpu: &mut dyn Cpu, success: u16) -> Result<Stop, String> {
+    for _ in 0..MAX_INSTRUCTIONS {
+        let pc = cpu.registers().pc;
+        if pc == success {
+            return Ok(Stop::Succeeded);
+        }
+        cpu.step();
+        if cpu.registers().pc == pc {
+            return Ok(Stop::Trapped);
+        }
+    }
+    Err(format!(
+        "still running at ${:04x} after {} instructions",
+        cpu.registers().pc,
+        MAX_INSTRUCTIONS
+    ))
+}
+
+/// Step through `log` comparing the registers before every instruction.
+/// nestest moves on to the undocumented opcodes once it's done with the
+/// rest, which the 6502 here doesn't emulate, so the comparison stops at the
+/// first of them. The number of lines that matched, or the first that
+/// didn't.
+fn compare_log(cpu: &mut dyn Cpu, model: Model, log: &[(u8, LogLine)]) -> Result<usize, String> {
+    let (start_cycles, start_cyc) = (cpu.cycles(), log.first().and_then(|(_, line)| line.cyc));
+    for (i, (opcode, want)) in log.iter().enumerate() {
+        if model == Model::Nmos6502 && !m6502::documented(*opcode) {
+            return Ok(i);
+        }
+        // Every 6502 cycle is a bus access, and every access to `MemoryMap::flat`
+        // takes `FAST` master clocks.
+        let cyc = start_cyc.map(|cyc| cyc + u64::from((cpu.cycles() - start_cycles) / FAST));
+        let got = LogLine::from_registers(&cpu.registers(), cyc.filter(|_| want.cyc.is_some()));
+        if got != *want {
+            return Err(format!("line {}: expected {}, got {}", i + 1, want, got));
+        }
+        cpu.step();
+    }
+    Ok(log.len())
+}
+
+fn read_log(path: &str) -> Result<Vec<(u8, LogLine)>, String> {
+    let log = fs::read_to_string(path).map_err(|e| format!("couldn't read {}: {}", path, e))?;
+    log.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| LogLine::parse(line).ok_or_else(|| format!("{}:{}: not a nestest log line", path, i + 1)))
+        .collect()
+}
+
+/// Run the image in the file `image` on each of `variants`, printing how far
+/// every one of them got. Whether all of them passed.
+pub fn run(image: &str, variants: &[&(&str, Constructor)], opts: &Options) -> Result<bool, String> {
+    let bytes = fs::read(image).map_err(|e| format!("couldn't read {}: {}", image, e))?;
+    if bytes.len() > MEM_SIZE {
+        return Err(format!("{} is bigger than the {} bytes of memory", image, MEM_SIZE));
+    }
+    let log = opts.nestest.as_deref().map(read_log).transpose()?;
+    let start = match (opts.start, &log) {
+        (Some(start), _) => start,
+        (None, Some(log)) if !log.is_empty() => log[0].1.pc,
+        _ => return Err("--image needs --start, or a --nestest log to start where it does".to_string()),
+    };
+    // Tests trap when they pass as well as when they fail, so where the
+    // image ends up is all there is to tell them apart by.
+    if log.is_none() && opts.success.is_none() {
+        return Err("--image needs --success, or a --nestest log to compare against".to_string());
+    }
+
+    let mut all_passed = true;
+    for (name, new) in variants {
+        let apu = Apu::new(Arc::new(Ports::default()));
+        let mut cpu = new(opts.model, opts.sync, Box::new(MemoryMap::flat()), apu);
+        if !cpu.full_isa() {
+            eprintln!("skipping {} variant, it only implements a few instructions", name);
+            continue;
+        }
+        cpu.load(&bytes, start);
+
+        let passed = match &log {
+            Some(log) => match compare_log(&mut *cpu, opts.model, log) {
+                Ok(matched) if matched == log.len() => {
+                    println!("{}: all {} lines of the log match", name, matched);
+                    true
+                }
+                Ok(matched) => {
+                    println!(
+                        "{}: the first {} lines of the log match, line {} is an undocumented opcode",
+                        name,
+                        matched,
+                        matched + 1
+                    );
+                    true
+                }
+                Err(why) => {
+                    println!("{}: {}", name, why);
+                    false
+                }
+            },
+            None => match run_image(&mut *cpu, opts.success.expect("checked above")) {
+                Ok(Stop::Succeeded) => {
+                    println!("{}: passed after {} instructions", name, cpu.instruction_count());
+                    true
+                }
+                Ok(Stop::Trapped) => {
+                    println!(
+                        "{}: trapped after {} instructions at {}",
+                        name,
+                        cpu.instruction_count(),
+                        LogLine::from_registers(&cpu.registers(), None)
+                    );
+                    false
+                }
+                Err(why) => {
+                    println!("{}: {}", name, why);
+                    false
+                }
+            },
+        };
+        all_passed &= passed;
+    }
+    Ok(all_passed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::SyncMode;
+
+    /// A `null` CPU at $0400, with `code` there.
+    fn cpu(code: &[u8]) -> Box<dyn Cpu> {
+        let new = crate::VARIANTS.iter().find(|(name, _)| *name == "null").expect("no null variant").1;
+        let apu = Apu::new(Arc::new(Ports::default()));
+        let mut cpu = new(Model::Nmos6502, SyncMode::Eager, Box::new(MemoryMap::flat()), apu);
+        let mut image = vec![0xea; 0x0400];
+        image.extend_from_slice(code);
+        cpu.load(&image, 0x0400);
+        cpu
+    }
+
+    #[test]
+    fn only_the_success_address_passes() {
+        // LDX #$00; INX; BNE *-1; JMP *
+        let code = [0xa2, 0x00, 0xe8, 0xd0, 0xfd, 0x4c, 0x05, 0x04];
+        assert!(matches!(run_image(&mut *cpu(&code), 0x0405), Ok(Stop::Succeeded)));
+        let mut trapped = cpu(&code);
+        assert!(matches!(run_image(&mut *trapped, 0x0300), Ok(Stop::Trapped)));
+        assert_eq!(trapped.registers().pc, 0x0405);
+        assert_eq!(trapped.instruction_count(), 1 + 2 * 256 + 1);
+    }
+}
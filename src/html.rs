@@ -0,0 +1,505 @@
+use crate::report::{Report, VariantReport};
+use crate::stats;
+use std::fmt::Write;
+
+/// One color per variant, reused in every chart.
+const PALETTE: &[&str] = &[
+    "#1f77b4", "#ff7f0e", "#2ca02c", "#d62728", "#9467bd", "#8c564b", "#e377c2", "#7f7f7f",
+    "#bcbd22", "#17becf",
+];
+
+const WIDTH: f64 = 900.0;
+const SMALL_WIDTH: f64 = 440.0;
+const HEIGHT: f64 = 260.0;
+const MARGIN_LEFT: f64 = 110.0;
+const MARGIN_RIGHT: f64 = 20.0;
+const MARGIN_TOP: f64 = 30.0;
+const MARGIN_BOTTOM: f64 = 40.0;
+
+fn color(i: usize) -> &'static str {
+    PALETTE[i % PALETTE.len()]
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Seconds with a unit that keeps the number readable.
+fn fmt_secs(x: f64) -> String {
+    if x >= 1.0 {
+        format!("{:.3} s", x)
+    } else if x >= 1e-3 {
+        format!("{:.3} ms", x * 1e3)
+    } else if x >= 1e-6 {
+        format!("{:.3} µs", x * 1e6)
+    } else {
+        format!("{:.1} ns", x * 1e9)
+    }
+}
+
+/// Round tick positions covering `lo..=hi`, roughly `n` of them.
+fn linear_ticks(lo: f64, hi: f64, n: usize) -> Vec<f64> {
+    let span = if hi > lo {
+        hi - lo
+    } else {
+        (hi.abs() * 0.1).max(f64::MIN_POSITIVE)
+    };
+    let raw = span / n as f64;
+    let magnitude = 10f64.powf(raw.log10().floor());
+    let step = [1.0, 2.0, 5.0, 10.0]
+        .iter()
+        .map(|m| m * magnitude)
+        .find(|step| span / step <= n as f64)
+        .unwrap_or(10.0 * magnitude);
+    let first = (lo / step).floor() as i64;
+    let last = (hi / step).ceil() as i64;
+    (first..=last).map(|i| i as f64 * step).collect()
+}
+
+/// Maps data values onto pixels along one axis.
+#[derive(Clone, Copy)]
+struct Scale {
+    lo: f64,
+    hi: f64,
+    from: f64,
+    to: f64,
+    log: bool,
+}
+
+impl Scale {
+    fn map(&self, x: f64) -> f64 {
+        let (x, lo, hi) = if self.log {
+            (x.log10(), self.lo.log10(), self.hi.log10())
+        } else {
+            (x, self.lo, self.hi)
+        };
+        let t = if hi > lo { (x - lo) / (hi - lo) } else { 0.5 };
+        self.from + t * (self.to - self.from)
+    }
+
+    fn ticks(&self) -> Vec<f64> {
+        if self.log {
+            let first = self.lo.log10().floor() as i32;
+            let last = self.hi.log10().ceil() as i32;
+            (first..=last).map(|e| 10f64.powi(e)).collect()
+        } else {
+            linear_ticks(self.lo, self.hi, 5)
+        }
+    }
+
+    /// Widen the domain to whole ticks so the axis starts and ends on one.
+    fn nice(mut self) -> Scale {
+        let ticks = self.ticks();
+        self.lo = ticks[0];
+        self.hi = ticks[ticks.len() - 1];
+        self
+    }
+}
+
+fn svg_open(out: &mut String, width: f64, height: f64, title: &str) {
+    let _ = write!(
+        out,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}" font-family="sans-serif" font-size="12">"#,
+        w = width,
+        h = height
+    );
+    let _ = write!(
+        out,
+        r#"<text x="{}" y="18" font-size="14" font-weight="bold">{}</text>"#,
+        MARGIN_LEFT,
+        escape(title)
+    );
+}
+
+/// Draw a vertical value axis with grid lines at `scale`'s ticks.
+fn y_axis(out: &mut String, scale: &Scale, width: f64, label: &dyn Fn(f64) -> String) {
+    for tick in scale.ticks() {
+        let y = scale.map(tick);
+        let _ = write!(
+            out,
+            r##"<line x1="{l}" x2="{r}" y1="{y:.1}" y2="{y:.1}" stroke="#ddd"/><text x="{t}" y="{ty:.1}" text-anchor="end">{label}</text>"##,
+            l = MARGIN_LEFT,
+            r = width - MARGIN_RIGHT,
+            y = y,
+            t = MARGIN_LEFT - 6.0,
+            ty = y + 4.0,
+            label = escape(&label(tick))
+        );
+    }
+}
+
+/// Draw a horizontal value axis with grid lines at `scale`'s ticks.
+fn x_axis(out: &mut String, scale: &Scale, height: f64, label: &dyn Fn(f64) -> String) {
+    for tick in scale.ticks() {
+        let x = scale.map(tick);
+        let _ = write!(
+            out,
+            r##"<line x1="{x:.1}" x2="{x:.1}" y1="{t}" y2="{b}" stroke="#ddd"/><text x="{x:.1}" y="{ty}" text-anchor="middle">{label}</text>"##,
+            x = x,
+            t = MARGIN_TOP,
+            b = height - MARGIN_BOTTOM,
+            ty = height - MARGIN_BOTTOM + 16.0,
+            label = escape(&label(tick))
+        );
+    }
+}
+
+/// Horizontal bars of how many times slower each variant's median is than
+/// the fastest one, on a log axis since the variants are orders of
+/// magnitude apart.
+fn relative_speed(variants: &[VariantReport]) -> String {
+    let fastest = variants
+        .iter()
+        .map(|v| v.summary.median)
+        .fold(f64::INFINITY, f64::min);
+    let ratios = variants
+        .iter()
+        .map(|v| v.summary.median / fastest)
+        .collect::<Vec<_>>();
+    let bar = 24.0;
+    let height = MARGIN_TOP + MARGIN_BOTTOM + bar * variants.len() as f64 * 1.5;
+    let max = ratios.iter().cloned().fold(1.0, f64::max);
+    let scale = Scale {
+        lo: 1.0,
+        hi: max.max(10.0),
+        from: MARGIN_LEFT,
+        to: WIDTH - MARGIN_RIGHT - 60.0,
+        log: true,
+    }
+    .nice();
+
+    let mut out = String::new();
+    svg_open(&mut out, WIDTH, height, "Median time relative to the fastest variant");
+    x_axis(&mut out, &scale, height, &|x| format!("{}×", x));
+    for (i, (v, ratio)) in variants.iter().zip(&ratios).enumerate() {
+        let y = MARGIN_TOP + i as f64 * bar * 1.5 + bar * 0.25;
+        let x = scale.map(*ratio);
+        let _ = write!(
+            out,
+            r#"<text x="{lx}" y="{ty:.1}" text-anchor="end">{name}</text><rect x="{x0}" y="{y:.1}" width="{w:.1}" height="{h}" fill="{c}"/><text x="{vx:.1}" y="{ty:.1}">{ratio:.1}× ({median})</text>"#,
+            lx = MARGIN_LEFT - 6.0,
+            ty = y + bar * 0.7,
+            name = escape(&v.name),
+            x0 = MARGIN_LEFT,
+            y = y,
+            w = (x - MARGIN_LEFT).max(1.0),
+            h = bar,
+            c = color(i),
+            vx = x.max(MARGIN_LEFT) + 6.0,
+            ratio = ratio,
+            median = fmt_secs(v.summary.median)
+        );
+    }
+    out.push_str("</svg>");
+    out
+}
+
+/// Box plots of every variant's samples on a shared log axis. Whiskers
+/// extend to the most extreme samples within 1.5 IQR of the box, anything
+/// further out is drawn as a point.
+fn box_plots(variants: &[VariantReport]) -> String {
+    let row = 36.0;
+    let height = MARGIN_TOP + MARGIN_BOTTOM + row * variants.len() as f64;
+    let all = variants.iter().flat_map(|v| v.samples.iter().cloned());
+    let (lo, hi) = all.fold((f64::INFINITY, 0.0f64), |(lo, hi), x| (lo.min(x), hi.max(x)));
+    let scale = Scale {
+        lo,
+        hi,
+        from: MARGIN_LEFT,
+        to: WIDTH - MARGIN_RIGHT,
+        log: true,
+    }
+    .nice();
+
+    let mut out = String::new();
+    svg_open(&mut out, WIDTH, height, "Distribution of run times");
+    x_axis(&mut out, &scale, height, &|x| fmt_secs(x));
+    for (i, v) in variants.iter().enumerate() {
+        let mut sorted = v.samples.clone();
+        sorted.sort_by(|a, b| a.partial_cmp(b).expect("timings are never NaN"));
+        let q1 = stats::percentile(&sorted, 0.25);
+        let q2 = stats::percentile(&sorted, 0.5);
+        let q3 = stats::percentile(&sorted, 0.75);
+        let iqr = q3 - q1;
+        let lo_whisker = sorted.iter().cloned().find(|&x| x >= q1 - 1.5 * iqr).unwrap_or(q1);
+        let hi_whisker = sorted.iter().rev().cloned().find(|&x| x <= q3 + 1.5 * iqr).unwrap_or(q3);
+        let y = MARGIN_TOP + i as f64 * row;
+        let mid = y + row / 2.0;
+        let c = color(i);
+        let _ = write!(
+            out,
+            r#"<text x="{lx}" y="{ty:.1}" text-anchor="end">{name}</text><line x1="{w0:.1}" x2="{w1:.1}" y1="{mid:.1}" y2="{mid:.1}" stroke="{c}"/><rect x="{b0:.1}" y="{by:.1}" width="{bw:.1}" height="{bh:.1}" fill="{c}" fill-opacity="0.3" stroke="{c}"/><line x1="{m:.1}" x2="{m:.1}" y1="{by:.1}" y2="{be:.1}" stroke="{c}" stroke-width="2"/>"#,
+            lx = MARGIN_LEFT - 6.0,
+            ty = mid + 4.0,
+            name = escape(&v.name),
+            w0 = scale.map(lo_whisker),
+            w1 = scale.map(hi_whisker),
+            mid = mid,
+            c = c,
+            b0 = scale.map(q1),
+            by = y + row * 0.2,
+            bw = (scale.map(q3) - scale.map(q1)).max(1.0),
+            bh = row * 0.6,
+            m = scale.map(q2),
+            be = y + row * 0.8
+        );
+        for &x in sorted.iter().filter(|&&x| x < lo_whisker || x > hi_whisker) {
+            let _ = write!(
+                out,
+                r#"<circle cx="{:.1}" cy="{:.1}" r="2.5" fill="none" stroke="{}"/>"#,
+                scale.map(x),
+                mid,
+                c
+            );
+        }
+    }
+    out.push_str("</svg>");
+    out
+}
+
+/// Time of every repetition in run order, to spot drift or outliers.
+fn run_series(i: usize, v: &VariantReport) -> String {
+    let (lo, hi) = v
+        .samples
+        .iter()
+        .fold((f64::INFINITY, 0.0f64), |(lo, hi), &x| (lo.min(x), hi.max(x)));
+    let y = Scale {
+        lo,
+        hi,
+        from: HEIGHT - MARGIN_BOTTOM,
+        to: MARGIN_TOP,
+        log: false,
+    }
+    .nice();
+    let x = Scale {
+        lo: 1.0,
+        hi: v.samples.len().max(2) as f64,
+        from: MARGIN_LEFT,
+        to: SMALL_WIDTH - MARGIN_RIGHT,
+        log: false,
+    };
+
+    let mut out = String::new();
+    svg_open(&mut out, SMALL_WIDTH, HEIGHT, &format!("{}: run series", v.name));
+    y_axis(&mut out, &y, SMALL_WIDTH, &|t| fmt_secs(t));
+    let _ = write!(
+        out,
+        r#"<text x="{}" y="{}" text-anchor="middle">repetition</text>"#,
+        (MARGIN_LEFT + SMALL_WIDTH - MARGIN_RIGHT) / 2.0,
+        HEIGHT - 8.0
+    );
+    let points = v
+        .samples
+        .iter()
+        .enumerate()
+        .map(|(n, &s)| format!("{:.1},{:.1}", x.map((n + 1) as f64), y.map(s)))
+        .collect::<Vec<_>>();
+    let _ = write!(
+        out,
+        r#"<polyline points="{}" fill="none" stroke="{}" stroke-width="2"/>"#,
+        points.join(" "),
+        color(i)
+    );
+    for p in &points {
+        let (px, py) = p.split_once(',').expect("formatted above");
+        let _ = write!(out, r#"<circle cx="{}" cy="{}" r="2.5" fill="{}"/>"#, px, py, color(i));
+    }
+    out.push_str("</svg>");
+    out
+}
+
+/// Histogram of the samples, with Sturges' rule for the number of bins.
+fn histogram(i: usize, v: &VariantReport) -> String {
+    let (lo, hi) = v
+        .samples
+        .iter()
+        .fold((f64::INFINITY, 0.0f64), |(lo, hi), &x| (lo.min(x), hi.max(x)));
+    let bins = ((v.samples.len() as f64).log2().ceil() as usize + 1).max(1);
+    let width = ((hi - lo) / bins as f64).max(f64::EPSILON);
+    let mut counts = vec![0usize; bins];
+    for &s in &v.samples {
+        let b = (((s - lo) / width) as usize).min(bins - 1);
+        counts[b] += 1;
+    }
+    let max = counts.iter().cloned().max().unwrap_or(1);
+    let x = Scale {
+        lo,
+        hi: lo + width * bins as f64,
+        from: MARGIN_LEFT,
+        to: SMALL_WIDTH - MARGIN_RIGHT,
+        log: false,
+    };
+    let y = Scale {
+        lo: 0.0,
+        hi: max as f64,
+        from: HEIGHT - MARGIN_BOTTOM,
+        to: MARGIN_TOP,
+        log: false,
+    }
+    .nice();
+
+    let mut out = String::new();
+    svg_open(&mut out, SMALL_WIDTH, HEIGHT, &format!("{}: histogram", v.name));
+    y_axis(&mut out, &y, SMALL_WIDTH, &|t| format!("{}", t));
+    for (b, &count) in counts.iter().enumerate() {
+        let x0 = x.map(lo + b as f64 * width);
+        let x1 = x.map(lo + (b + 1) as f64 * width);
+        let _ = write!(
+            out,
+            r#"<rect x="{:.1}" y="{:.1}" width="{:.1}" height="{:.1}" fill="{}" fill-opacity="0.7" stroke="white"/>"#,
+            x0,
+            y.map(count as f64),
+            (x1 - x0).max(1.0),
+            y.map(0.0) - y.map(count as f64),
+            color(i)
+        );
+    }
+    let _ = write!(
+        out,
+        r#"<text x="{l}" y="{t}" text-anchor="start">{lo}</text><text x="{r}" y="{t}" text-anchor="end">{hi}</text>"#,
+        l = MARGIN_LEFT,
+        r = SMALL_WIDTH - MARGIN_RIGHT,
+        t = HEIGHT - MARGIN_BOTTOM + 16.0,
+        lo = escape(&fmt_secs(lo)),
+        hi = escape(&fmt_secs(lo + width * bins as f64))
+    );
+    out.push_str("</svg>");
+    out
+}
+
+/// A self-contained HTML page with every chart inlined as SVG, so it can be
+/// produced on headless machines and opened anywhere.
+pub fn render(report: &Report) -> String {
+    let mut out = String::new();
+    out.push_str(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>emu-test report</title>\
+         <style>body{font-family:sans-serif;margin:2em}table{border-collapse:collapse}\
+         td,th{padding:2px 10px;text-align:right;border-bottom:1px solid #ddd}\
+         td:first-child,th:first-child{text-align:left}svg{margin:8px 0}</style></head><body>\n",
+    );
+    out.push_str("<h1>emu-test report</h1>\n");
+
+    let w = &report.workload;
+    let e = &report.environment;
+    let _ = writeln!(
+        out,
+        "<table><tr><th>workload</th><td>{}</td></tr><tr><th>emulated cpu</th><td>{}</td></tr>\
+         <tr><th>synchronization</th><td>{}</td></tr><tr><th>instructions per run</th><td>{}</td></tr>\
+         <tr><th>repetitions</th><td>{} (warm-up {}, {})</td></tr><tr><th>rustc</th><td>{}</td></tr>\
+         <tr><th>profile</th><td>{}</td></tr><tr><th>cpu</th><td>{}</td></tr>\
+         <tr><th>git revision</th><td>{}</td></tr><tr><th>platform</th><td>{} {}</td></tr></table>",
+        escape(&w.description),
+        escape(&w.cpu),
+        escape(&w.sync),
+        w.instructions,
+        w.repetitions,
+        w.warmup,
+        if w.isolated { "isolated" } else { "in process" },
+        escape(&e.rustc),
+        escape(&e.profile),
+        escape(&e.cpu_model),
+        escape(&e.git_revision),
+        escape(&e.os),
+        escape(&e.arch)
+    );
+
+    if report.variants.is_empty() {
+        out.push_str("<p>No timed runs.</p></body></html>\n");
+        return out;
+    }
+
+    out.push_str("<h2>Summary</h2>\n<table><tr><th>variant</th><th>min</th><th>median</th><th>mean</th><th>stddev</th><th>p95</th><th>emulated MHz</th><th>instructions/s</th></tr>\n");
+    for v in &report.variants {
+        let s = &v.summary;
+        let _ = writeln!(
+            out,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{:.3}</td><td>{:.0}</td></tr>",
+            escape(&v.name),
+            fmt_secs(s.min),
+            fmt_secs(s.median),
+            fmt_secs(s.mean),
+            fmt_secs(s.stddev),
+            fmt_secs(s.p95),
+            v.emulated_mhz,
+            v.instructions_per_sec
+        );
+    }
+    out.push_str("</table>\n<h2>Overview</h2>\n");
+    out.push_str(&relative_speed(&report.variants));
+    out.push('\n');
+    out.push_str(&box_plots(&report.variants));
+    out.push_str("\n<h2>Variants</h2>\n");
+    for (i, v) in report.variants.iter().enumerate() {
+        let _ = writeln!(out, "<div>{}{}</div>", run_series(i, v), histogram(i, v));
+    }
+    out.push_str("</body></html>\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::{Environment, Workload};
+
+    fn variant(name: &str, median: f64) -> VariantReport {
+        let samples = [0.9, 1.0, 1.0, 1.2].iter().map(|x| x * median).collect::<Vec<_>>();
+        VariantReport {
+            name: name.to_string(),
+            summary: stats::summarize(&samples),
+            samples,
+            cycles: 0,
+            instructions: 1000,
+            emulated_mhz: 0.0,
+            instructions_per_sec: 0.0,
+        }
+    }
+
+    #[test]
+    fn every_chart_names_its_variants() {
+        let report = Report {
+            workload: Workload {
+                cpu: "6502".to_string(),
+                sync: "eager".to_string(),
+                description: "test".to_string(),
+                instructions: 1000,
+                repetitions: 4,
+                warmup: 0,
+                isolated: false,
+            },
+            environment: Environment {
+                rustc: String::new(),
+                profile: String::new(),
+                cpu_model: String::new(),
+                git_revision: String::new(),
+                os: String::new(),
+                arch: String::new(),
+            },
+            variants: vec![variant("null", 0.01), variant("<a & \"b\">", 0.5)],
+        };
+        let html = render(&report);
+        let names = ["null", "&lt;a &amp; &quot;b&quot;&gt;"];
+        assert!(!html.contains("<a &"));
+
+        let charts = html
+            .split("<svg")
+            .skip(1)
+            .map(|chart| chart.split("</svg>").next().expect("split always returns something"))
+            .collect::<Vec<_>>();
+        // The relative speeds and the box plots, then a run series and a
+        // histogram per variant.
+        assert_eq!(charts.len(), 2 + 2 * names.len());
+        for chart in &charts[..2] {
+            for name in names {
+                assert!(chart.contains(&format!(">{}</text>", name)), "{} isn't in {}", name, chart);
+            }
+        }
+        for (i, name) in names.iter().enumerate() {
+            for chart in &charts[2 + 2 * i..4 + 2 * i] {
+                assert!(chart.contains(&format!(">{}: ", name)), "{} isn't in {}", name, chart);
+            }
+        }
+    }
+}
@@ -0,0 +1,23 @@
+//! Bindings to the C context switch in `libco/`, which `build.rs` compiles
+//! along with the C reference. It's declared like byuu's libco but isn't
+//! libco's code.
+
+use std::os::raw::{c_uint, c_void};
+
+/// A thread handle.
+pub type Cothread = *mut c_void;
+
+extern "C" {
+    /// The thread that's running, creating a handle for it on first use.
+    pub fn co_active() -> Cothread;
+
+    /// A thread with a `size` byte stack that calls `entrypoint` the first
+    /// time it's switched to. `entrypoint` must never return.
+    pub fn co_create(size: c_uint, entrypoint: extern "C" fn()) -> Cothread;
+
+    /// Free a thread from `co_create`. It must not be running.
+    pub fn co_delete(handle: Cothread);
+
+    /// Suspend the running thread and resume `handle`.
+    pub fn co_switch(handle: Cothread);
+}
@@ -0,0 +1,790 @@
+//! The documented NMOS 6502 instruction set, written once in straight-line
+//! style and expanded into every variant that can suspend in the middle of
+//! an instruction.
+//!
+//! Every cycle of the 6502 is a bus access, so the only suspension points
+//! are memory reads and writes. Dummy accesses (the page-crossing read of
+//! `LDA abs,Y`, the double write of read-modify-write instructions, the
+//! extra stack reads of `RTS`, ...) are performed like real ones so cycle
+//! counts and bus activity match the hardware.
+use crate::cpu::Registers;
+
+pub const FLAG_C: u8 = 0x01;
+pub const FLAG_Z: u8 = 0x02;
+pub const FLAG_I: u8 = 0x04;
+pub const FLAG_D: u8 = 0x08;
+/// Only exists on the stack, in the copy of `p` pushed by `PHP` and `BRK`.
+pub const FLAG_B: u8 = 0x10;
+/// Always reads as set.
+pub const FLAG_U: u8 = 0x20;
+pub const FLAG_V: u8 = 0x40;
+pub const FLAG_N: u8 = 0x80;
+
+pub const IRQ_VECTOR: u16 = 0xfffe;
+
+/// Whether `opcode` is one of the 151 in the data sheet. The others act like
+/// a one byte NOP here, see `execute_6502!`.
+pub fn documented(opcode: u8) -> bool {
+    matches!(
+        opcode,
+        0x00 | 0x01 | 0x05 | 0x06 | 0x08 | 0x09 | 0x0a | 0x0d | 0x0e
+            | 0x10 | 0x11 | 0x15 | 0x16 | 0x18 | 0x19 | 0x1d | 0x1e
+            | 0x20 | 0x21 | 0x24 | 0x25 | 0x26 | 0x28 | 0x29 | 0x2a | 0x2c | 0x2d | 0x2e
+            | 0x30 | 0x31 | 0x35 | 0x36 | 0x38 | 0x39 | 0x3d | 0x3e
+            | 0x40 | 0x41 | 0x45 | 0x46 | 0x48 | 0x49 | 0x4a | 0x4c | 0x4d | 0x4e
+            | 0x50 | 0x51 | 0x55 | 0x56 | 0x58 | 0x59 | 0x5d | 0x5e
+            | 0x60 | 0x61 | 0x65 | 0x66 | 0x68 | 0x69 | 0x6a | 0x6c | 0x6d | 0x6e
+            | 0x70 | 0x71 | 0x75 | 0x76 | 0x78 | 0x79 | 0x7d | 0x7e
+            | 0x81 | 0x84 | 0x85 | 0x86 | 0x88 | 0x8a | 0x8c | 0x8d | 0x8e
+            | 0x90 | 0x91 | 0x94 | 0x95 | 0x96 | 0x98 | 0x99 | 0x9a | 0x9d
+            | 0xa0 | 0xa1 | 0xa2 | 0xa4 | 0xa5 | 0xa6 | 0xa8 | 0xa9 | 0xaa | 0xac | 0xad | 0xae
+            | 0xb0 | 0xb1 | 0xb4 | 0xb5 | 0xb6 | 0xb8 | 0xb9 | 0xba | 0xbc | 0xbd | 0xbe
+            | 0xc0 | 0xc1 | 0xc4 | 0xc5 | 0xc6 | 0xc8 | 0xc9 | 0xca | 0xcc | 0xcd | 0xce
+            | 0xd0 | 0xd1 | 0xd5 | 0xd6 | 0xd8 | 0xd9 | 0xdd | 0xde
+            | 0xe0 | 0xe1 | 0xe4 | 0xe5 | 0xe6 | 0xe8 | 0xe9 | 0xea | 0xec | 0xed | 0xee
+            | 0xf0 | 0xf1 | 0xf5 | 0xf6 | 0xf8 | 0xf9 | 0xfd | 0xfe
+    )
+}
+
+/// The 6502 ALU. Results only ever land in the low byte of `a`, `x` and
+/// `y`, the high bytes stay 0.
+impl Registers {
+    pub fn flag(&self, flag: u8) -> bool {
+        self.p & flag != 0
+    }
+
+    pub fn set_flag(&mut self, flag: u8, on: bool) {
+        if on {
+            self.p |= flag;
+        } else {
+            self.p &= !flag;
+        }
+    }
+
+    fn set_nz(&mut self, v: u8) -> u8 {
+        self.set_flag(FLAG_Z, v == 0);
+        self.set_flag(FLAG_N, v & 0x80 != 0);
+        v
+    }
+
+    /// `p` as `PLP` and `RTI` restore it: `B` doesn't exist in the register
+    /// and `U` always reads as set.
+    pub fn set_p(&mut self, v: u8) {
+        self.p = (v & !FLAG_B) | FLAG_U;
+    }
+
+    pub fn lda(&mut self, v: u8) {
+        self.a = self.set_nz(v) as u16;
+    }
+
+    pub fn ldx(&mut self, v: u8) {
+        self.x = self.set_nz(v) as u16;
+    }
+
+    pub fn ldy(&mut self, v: u8) {
+        self.y = self.set_nz(v) as u16;
+    }
+
+    pub fn and(&mut self, v: u8) {
+        self.lda(self.a as u8 & v);
+    }
+
+    pub fn ora(&mut self, v: u8) {
+        self.lda(self.a as u8 | v);
+    }
+
+    pub fn eor(&mut self, v: u8) {
+        self.lda(self.a as u8 ^ v);
+    }
+
+    fn compare(&mut self, reg: u16, v: u8) {
+        let reg = reg as u8;
+        self.set_flag(FLAG_C, reg >= v);
+        self.set_nz(reg.wrapping_sub(v));
+    }
+
+    pub fn cmp(&mut self, v: u8) {
+        self.compare(self.a, v);
+    }
+
+    pub fn cpx(&mut self, v: u8) {
+        self.compare(self.x, v);
+    }
+
+    pub fn cpy(&mut self, v: u8) {
+        self.compare(self.y, v);
+    }
+
+    pub fn bit(&mut self, v: u8) {
+        self.set_flag(FLAG_Z, self.a as u8 & v == 0);
+        self.set_flag(FLAG_N, v & 0x80 != 0);
+        self.set_flag(FLAG_V, v & 0x40 != 0);
+    }
+
+    /// Add with carry, including the NMOS decimal mode quirks: in decimal
+    /// mode `Z` comes from the binary sum and `N`/`V` from the intermediate
+    /// result before the high nibble is adjusted.
+    pub fn adc(&mut self, v: u8) {
+        let a = self.a & 0xff;
+        let v16 = v as u16;
+        let c = self.flag(FLAG_C) as u16;
+        if self.flag(FLAG_D) {
+            let mut lo = (a & 0x0f) + (v16 & 0x0f) + c;
+            if lo >= 0x0a {
+                lo = ((lo + 0x06) & 0x0f) + 0x10;
+            }
+            let mut r = (a & 0xf0) + (v16 & 0xf0) + lo;
+            self.set_flag(FLAG_Z, (a + v16 + c) & 0xff == 0);
+            self.set_flag(FLAG_N, r & 0x80 != 0);
+            self.set_flag(FLAG_V, !(a ^ v16) & (a ^ r) & 0x80 != 0);
+            if r >= 0xa0 {
+                r += 0x60;
+            }
+            self.set_flag(FLAG_C, r >= 0x100);
+            self.a = r & 0xff;
+        } else {
+            let r = a + v16 + c;
+            self.set_flag(FLAG_C, r > 0xff);
+            self.set_flag(FLAG_V, !(a ^ v16) & (a ^ r) & 0x80 != 0);
+            self.lda(r as u8);
+        }
+    }
+
+    /// Subtract with borrow. The NMOS 6502 sets every flag from the binary
+    /// difference even in decimal mode, only the result is adjusted.
+    pub fn sbc(&mut self, v: u8) {
+        let a = (self.a & 0xff) as i16;
+        let v16 = v as i16;
+        let borrow = 1 - self.flag(FLAG_C) as i16;
+        let r = a - v16 - borrow;
+        self.set_flag(FLAG_C, r >= 0);
+        self.set_flag(FLAG_V, (a ^ v16) & (a ^ r) & 0x80 != 0);
+        self.set_nz(r as u8);
+        if self.flag(FLAG_D) {
+            let mut lo = (a & 0x0f) - (v16 & 0x0f) - borrow;
+            if lo < 0 {
+                lo = ((lo - 0x06) & 0x0f) - 0x10;
+            }
+            let mut r = (a & 0xf0) - (v16 & 0xf0) + lo;
+            if r < 0 {
+                r -= 0x60;
+            }
+            self.a = r as u8 as u16;
+        } else {
+            self.a = r as u8 as u16;
+        }
+    }
+
+    pub fn asl(&mut self, v: u8) -> u8 {
+        self.set_flag(FLAG_C, v & 0x80 != 0);
+        self.set_nz(v << 1)
+    }
+
+    pub fn lsr(&mut self, v: u8) -> u8 {
+        self.set_flag(FLAG_C, v & 0x01 != 0);
+        self.set_nz(v >> 1)
+    }
+
+    pub fn rol(&mut self, v: u8) -> u8 {
+        let c = self.flag(FLAG_C) as u8;
+        self.set_flag(FLAG_C, v & 0x80 != 0);
+        self.set_nz(v << 1 | c)
+    }
+
+    pub fn ror(&mut self, v: u8) -> u8 {
+        let c = self.flag(FLAG_C) as u8;
+        self.set_flag(FLAG_C, v & 0x01 != 0);
+        self.set_nz(v >> 1 | c << 7)
+    }
+
+    pub fn inc(&mut self, v: u8) -> u8 {
+        self.set_nz(v.wrapping_add(1))
+    }
+
+    pub fn dec(&mut self, v: u8) -> u8 {
+        self.set_nz(v.wrapping_sub(1))
+    }
+}
+
+/// Expands to the body of `execute_instruction` for a CPU with a `regs:
+/// Registers` field. `$read!($cpu, addr)` and `$write!($cpu, addr, data)`
+/// must expand to one bus access each, suspending however the variant
+/// suspends. `$cpu` is normally `self`, passed in since a macro can't name
+/// it on its own.
+macro_rules! execute_6502 {
+    ($cpu:ident, $read:ident, $write:ident) => {{
+        use $crate::m6502::*;
+        let opcode = execute_6502!(@fetch $cpu, $read);
+        match opcode {
+            // loads
+            0xa9 => execute_6502!(@load lda, imm, $cpu, $read),
+            0xa5 => execute_6502!(@load lda, zp, $cpu, $read),
+            0xb5 => execute_6502!(@load lda, zpx, $cpu, $read),
+            0xad => execute_6502!(@load lda, abs, $cpu, $read),
+            0xbd => execute_6502!(@load lda, absx, $cpu, $read),
+            0xb9 => execute_6502!(@load lda, absy, $cpu, $read),
+            0xa1 => execute_6502!(@load lda, indx, $cpu, $read),
+            0xb1 => execute_6502!(@load lda, indy, $cpu, $read),
+            0xa2 => execute_6502!(@load ldx, imm, $cpu, $read),
+            0xa6 => execute_6502!(@load ldx, zp, $cpu, $read),
+            0xb6 => execute_6502!(@load ldx, zpy, $cpu, $read),
+            0xae => execute_6502!(@load ldx, abs, $cpu, $read),
+            0xbe => execute_6502!(@load ldx, absy, $cpu, $read),
+            0xa0 => execute_6502!(@load ldy, imm, $cpu, $read),
+            0xa4 => execute_6502!(@load ldy, zp, $cpu, $read),
+            0xb4 => execute_6502!(@load ldy, zpx, $cpu, $read),
+            0xac => execute_6502!(@load ldy, abs, $cpu, $read),
+            0xbc => execute_6502!(@load ldy, absx, $cpu, $read),
+
+            // stores
+            0x85 => execute_6502!(@store a, zp, $cpu, $read, $write),
+            0x95 => execute_6502!(@store a, zpx, $cpu, $read, $write),
+            0x8d => execute_6502!(@store a, abs, $cpu, $read, $write),
+            0x9d => execute_6502!(@store a, absx, $cpu, $read, $write),
+            0x99 => execute_6502!(@store a, absy, $cpu, $read, $write),
+            0x81 => execute_6502!(@store a, indx, $cpu, $read, $write),
+            0x91 => execute_6502!(@store a, indy, $cpu, $read, $write),
+            0x86 => execute_6502!(@store x, zp, $cpu, $read, $write),
+            0x96 => execute_6502!(@store x, zpy, $cpu, $read, $write),
+            0x8e => execute_6502!(@store x, abs, $cpu, $read, $write),
+            0x84 => execute_6502!(@store y, zp, $cpu, $read, $write),
+            0x94 => execute_6502!(@store y, zpx, $cpu, $read, $write),
+            0x8c => execute_6502!(@store y, abs, $cpu, $read, $write),
+
+            // arithmetic and logic
+            0x69 => execute_6502!(@load adc, imm, $cpu, $read),
+            0x65 => execute_6502!(@load adc, zp, $cpu, $read),
+            0x75 => execute_6502!(@load adc, zpx, $cpu, $read),
+            0x6d => execute_6502!(@load adc, abs, $cpu, $read),
+            0x7d => execute_6502!(@load adc, absx, $cpu, $read),
+            0x79 => execute_6502!(@load adc, absy, $cpu, $read),
+            0x61 => execute_6502!(@load adc, indx, $cpu, $read),
+            0x71 => execute_6502!(@load adc, indy, $cpu, $read),
+            0xe9 => execute_6502!(@load sbc, imm, $cpu, $read),
+            0xe5 => execute_6502!(@load sbc, zp, $cpu, $read),
+            0xf5 => execute_6502!(@load sbc, zpx, $cpu, $read),
+            0xed => execute_6502!(@load sbc, abs, $cpu, $read),
+            0xfd => execute_6502!(@load sbc, absx, $cpu, $read),
+            0xf9 => execute_6502!(@load sbc, absy, $cpu, $read),
+            0xe1 => execute_6502!(@load sbc, indx, $cpu, $read),
+            0xf1 => execute_6502!(@load sbc, indy, $cpu, $read),
+            0x29 => execute_6502!(@load and, imm, $cpu, $read),
+            0x25 => execute_6502!(@load and, zp, $cpu, $read),
+            0x35 => execute_6502!(@load and, zpx, $cpu, $read),
+            0x2d => execute_6502!(@load and, abs, $cpu, $read),
+            0x3d => execute_6502!(@load and, absx, $cpu, $read),
+            0x39 => execute_6502!(@load and, absy, $cpu, $read),
+            0x21 => execute_6502!(@load and, indx, $cpu, $read),
+            0x31 => execute_6502!(@load and, indy, $cpu, $read),
+            0x09 => execute_6502!(@load ora, imm, $cpu, $read),
+            0x05 => execute_6502!(@load ora, zp, $cpu, $read),
+            0x15 => execute_6502!(@load ora, zpx, $cpu, $read),
+            0x0d => execute_6502!(@load ora, abs, $cpu, $read),
+            0x1d => execute_6502!(@load ora, absx, $cpu, $read),
+            0x19 => execute_6502!(@load ora, absy, $cpu, $read),
+            0x01 => execute_6502!(@load ora, indx, $cpu, $read),
+            0x11 => execute_6502!(@load ora, indy, $cpu, $read),
+            0x49 => execute_6502!(@load eor, imm, $cpu, $read),
+            0x45 => execute_6502!(@load eor, zp, $cpu, $read),
+            0x55 => execute_6502!(@load eor, zpx, $cpu, $read),
+            0x4d => execute_6502!(@load eor, abs, $cpu, $read),
+            0x5d => execute_6502!(@load eor, absx, $cpu, $read),
+            0x59 => execute_6502!(@load eor, absy, $cpu, $read),
+            0x41 => execute_6502!(@load eor, indx, $cpu, $read),
+            0x51 => execute_6502!(@load eor, indy, $cpu, $read),
+            0xc9 => execute_6502!(@load cmp, imm, $cpu, $read),
+            0xc5 => execute_6502!(@load cmp, zp, $cpu, $read),
+            0xd5 => execute_6502!(@load cmp, zpx, $cpu, $read),
+            0xcd => execute_6502!(@load cmp, abs, $cpu, $read),
+            0xdd => execute_6502!(@load cmp, absx, $cpu, $read),
+            0xd9 => execute_6502!(@load cmp, absy, $cpu, $read),
+            0xc1 => execute_6502!(@load cmp, indx, $cpu, $read),
+            0xd1 => execute_6502!(@load cmp, indy, $cpu, $read),
+            0xe0 => execute_6502!(@load cpx, imm, $cpu, $read),
+            0xe4 => execute_6502!(@load cpx, zp, $cpu, $read),
+            0xec => execute_6502!(@load cpx, abs, $cpu, $read),
+            0xc0 => execute_6502!(@load cpy, imm, $cpu, $read),
+            0xc4 => execute_6502!(@load cpy, zp, $cpu, $read),
+            0xcc => execute_6502!(@load cpy, abs, $cpu, $read),
+            0x24 => execute_6502!(@load bit, zp, $cpu, $read),
+            0x2c => execute_6502!(@load bit, abs, $cpu, $read),
+
+            // read-modify-write
+            0x0a => execute_6502!(@accumulator asl, $cpu, $read),
+            0x06 => execute_6502!(@modify asl, zp, $cpu, $read, $write),
+            0x16 => execute_6502!(@modify asl, zpx, $cpu, $read, $write),
+            0x0e => execute_6502!(@modify asl, abs, $cpu, $read, $write),
+            0x1e => execute_6502!(@modify asl, absx, $cpu, $read, $write),
+            0x4a => execute_6502!(@accumulator lsr, $cpu, $read),
+            0x46 => execute_6502!(@modify lsr, zp, $cpu, $read, $write),
+            0x56 => execute_6502!(@modify lsr, zpx, $cpu, $read, $write),
+            0x4e => execute_6502!(@modify lsr, abs, $cpu, $read, $write),
+            0x5e => execute_6502!(@modify lsr, absx, $cpu, $read, $write),
+            0x2a => execute_6502!(@accumulator rol, $cpu, $read),
+            0x26 => execute_6502!(@modify rol, zp, $cpu, $read, $write),
+            0x36 => execute_6502!(@modify rol, zpx, $cpu, $read, $write),
+            0x2e => execute_6502!(@modify rol, abs, $cpu, $read, $write),
+            0x3e => execute_6502!(@modify rol, absx, $cpu, $read, $write),
+            0x6a => execute_6502!(@accumulator ror, $cpu, $read),
+            0x66 => execute_6502!(@modify ror, zp, $cpu, $read, $write),
+            0x76 => execute_6502!(@modify ror, zpx, $cpu, $read, $write),
+            0x6e => execute_6502!(@modify ror, abs, $cpu, $read, $write),
+            0x7e => execute_6502!(@modify ror, absx, $cpu, $read, $write),
+            0xe6 => execute_6502!(@modify inc, zp, $cpu, $read, $write),
+            0xf6 => execute_6502!(@modify inc, zpx, $cpu, $read, $write),
+            0xee => execute_6502!(@modify inc, abs, $cpu, $read, $write),
+            0xfe => execute_6502!(@modify inc, absx, $cpu, $read, $write),
+            0xc6 => execute_6502!(@modify dec, zp, $cpu, $read, $write),
+            0xd6 => execute_6502!(@modify dec, zpx, $cpu, $read, $write),
+            0xce => execute_6502!(@modify dec, abs, $cpu, $read, $write),
+            0xde => execute_6502!(@modify dec, absx, $cpu, $read, $write),
+
+            // register increments, decrements and transfers
+            0xe8 => execute_6502!(@implied $cpu, $read, {
+                let v = $cpu.regs.inc($cpu.regs.x as u8);
+                $cpu.regs.x = v as u16;
+            }),
+            0xc8 => execute_6502!(@implied $cpu, $read, {
+                let v = $cpu.regs.inc($cpu.regs.y as u8);
+                $cpu.regs.y = v as u16;
+            }),
+            0xca => execute_6502!(@implied $cpu, $read, {
+                let v = $cpu.regs.dec($cpu.regs.x as u8);
+                $cpu.regs.x = v as u16;
+            }),
+            0x88 => execute_6502!(@implied $cpu, $read, {
+                let v = $cpu.regs.dec($cpu.regs.y as u8);
+                $cpu.regs.y = v as u16;
+            }),
+            0xaa => execute_6502!(@implied $cpu, $read, { $cpu.regs.ldx($cpu.regs.a as u8) }),
+            0xa8 => execute_6502!(@implied $cpu, $read, { $cpu.regs.ldy($cpu.regs.a as u8) }),
+            0x8a => execute_6502!(@implied $cpu, $read, { $cpu.regs.lda($cpu.regs.x as u8) }),
+            0x98 => execute_6502!(@implied $cpu, $read, { $cpu.regs.lda($cpu.regs.y as u8) }),
+            0xba => execute_6502!(@implied $cpu, $read, { $cpu.regs.ldx($cpu.regs.s as u8) }),
+            // the only transfer that leaves the flags alone
+            0x9a => execute_6502!(@implied $cpu, $read, { $cpu.regs.s = 0x0100 | $cpu.regs.x }),
+
+            // flags
+            0x18 => execute_6502!(@implied $cpu, $read, { $cpu.regs.set_flag(FLAG_C, false) }),
+            0x38 => execute_6502!(@implied $cpu, $read, { $cpu.regs.set_flag(FLAG_C, true) }),
+            0x58 => execute_6502!(@implied $cpu, $read, { $cpu.regs.set_flag(FLAG_I, false) }),
+            0x78 => execute_6502!(@implied $cpu, $read, { $cpu.regs.set_flag(FLAG_I, true) }),
+            0xd8 => execute_6502!(@implied $cpu, $read, { $cpu.regs.set_flag(FLAG_D, false) }),
+            0xf8 => execute_6502!(@implied $cpu, $read, { $cpu.regs.set_flag(FLAG_D, true) }),
+            0xb8 => execute_6502!(@implied $cpu, $read, { $cpu.regs.set_flag(FLAG_V, false) }),
+            0xea => execute_6502!(@implied $cpu, $read, {}),
+
+            // branches
+            0x10 => execute_6502!(@branch !$cpu.regs.flag(FLAG_N), $cpu, $read),
+            0x30 => execute_6502!(@branch $cpu.regs.flag(FLAG_N), $cpu, $read),
+            0x50 => execute_6502!(@branch !$cpu.regs.flag(FLAG_V), $cpu, $read),
+            0x70 => execute_6502!(@branch $cpu.regs.flag(FLAG_V), $cpu, $read),
+            0x90 => execute_6502!(@branch !$cpu.regs.flag(FLAG_C), $cpu, $read),
+            0xb0 => execute_6502!(@branch $cpu.regs.flag(FLAG_C), $cpu, $read),
+            0xd0 => execute_6502!(@branch !$cpu.regs.flag(FLAG_Z), $cpu, $read),
+            0xf0 => execute_6502!(@branch $cpu.regs.flag(FLAG_Z), $cpu, $read),
+
+            // stack
+            0x48 => {
+                $read!($cpu, $cpu.regs.pc);
+                execute_6502!(@push $cpu.regs.a as u8, $cpu, $write);
+            }
+            0x08 => {
+                $read!($cpu, $cpu.regs.pc);
+                execute_6502!(@push $cpu.regs.p | FLAG_B | FLAG_U, $cpu, $write);
+            }
+            0x68 => {
+                $read!($cpu, $cpu.regs.pc);
+                $read!($cpu, $cpu.regs.s);
+                let v = execute_6502!(@pull $cpu, $read);
+                $cpu.regs.lda(v);
+            }
+            0x28 => {
+                $read!($cpu, $cpu.regs.pc);
+                $read!($cpu, $cpu.regs.s);
+                let v = execute_6502!(@pull $cpu, $read);
+                $cpu.regs.set_p(v);
+            }
+
+            // jumps, subroutines and interrupts
+            0x4c => {
+                let lo = execute_6502!(@fetch $cpu, $read) as u16;
+                let hi = $read!($cpu, $cpu.regs.pc) as u16;
+                $cpu.regs.pc = hi << 8 | lo;
+            }
+            0x6c => {
+                let ptr = execute_6502!(@addr abs, $cpu, $read, read);
+                let lo = $read!($cpu, ptr) as u16;
+                // the high byte never carries into the next page
+                let hi = $read!($cpu, (ptr & 0xff00) | (ptr.wrapping_add(1) & 0x00ff)) as u16;
+                $cpu.regs.pc = hi << 8 | lo;
+            }
+            0x20 => {
+                let lo = execute_6502!(@fetch $cpu, $read) as u16;
+                $read!($cpu, $cpu.regs.s);
+                execute_6502!(@push ($cpu.regs.pc >> 8) as u8, $cpu, $write);
+                execute_6502!(@push $cpu.regs.pc as u8, $cpu, $write);
+                let hi = $read!($cpu, $cpu.regs.pc) as u16;
+                $cpu.regs.pc = hi << 8 | lo;
+            }
+            0x60 => {
+                $read!($cpu, $cpu.regs.pc);
+                $read!($cpu, $cpu.regs.s);
+                let lo = execute_6502!(@pull $cpu, $read) as u16;
+                let hi = execute_6502!(@pull $cpu, $read) as u16;
+                $cpu.regs.pc = hi << 8 | lo;
+                execute_6502!(@fetch $cpu, $read);
+            }
+            0x40 => {
+                $read!($cpu, $cpu.regs.pc);
+                $read!($cpu, $cpu.regs.s);
+                let p = execute_6502!(@pull $cpu, $read);
+                $cpu.regs.set_p(p);
+                let lo = execute_6502!(@pull $cpu, $read) as u16;
+                let hi = execute_6502!(@pull $cpu, $read) as u16;
+                $cpu.regs.pc = hi << 8 | lo;
+            }
+            0x00 => {
+                // the byte after BRK is skipped
+                execute_6502!(@fetch $cpu, $read);
+                execute_6502!(@push ($cpu.regs.pc >> 8) as u8, $cpu, $write);
+                execute_6502!(@push $cpu.regs.pc as u8, $cpu, $write);
+                execute_6502!(@push $cpu.regs.p | FLAG_B | FLAG_U, $cpu, $write);
+                $cpu.regs.set_flag(FLAG_I, true);
+                let lo = $read!($cpu, IRQ_VECTOR) as u16;
+                let hi = $read!($cpu, IRQ_VECTOR + 1) as u16;
+                $cpu.regs.pc = hi << 8 | lo;
+            }
+
+            _ => {
+                // undocumented opcodes aren't emulated, they act like a
+                // one byte NOP
+                $read!($cpu, $cpu.regs.pc);
+            }
+        }
+    }};
+
+    // read the byte at pc and step past it
+    (@fetch $cpu:ident, $read:ident) => {{
+        let data = $read!($cpu, $cpu.regs.pc);
+        $cpu.regs.pc = $cpu.regs.pc.wrapping_add(1);
+        data
+    }};
+
+    // effective address of an addressing mode; `read` only pays for the
+    // dummy read when indexing crosses a page, `write` always does
+    (@addr zp, $cpu:ident, $read:ident, $kind:ident) => {{
+        execute_6502!(@fetch $cpu, $read) as u16
+    }};
+    (@addr zpx, $cpu:ident, $read:ident, $kind:ident) => {{
+        let base = execute_6502!(@fetch $cpu, $read);
+        $read!($cpu, base as u16);
+        base.wrapping_add($cpu.regs.x as u8) as u16
+    }};
+    (@addr zpy, $cpu:ident, $read:ident, $kind:ident) => {{
+        let base = execute_6502!(@fetch $cpu, $read);
+        $read!($cpu, base as u16);
+        base.wrapping_add($cpu.regs.y as u8) as u16
+    }};
+    (@addr abs, $cpu:ident, $read:ident, $kind:ident) => {{
+        let lo = execute_6502!(@fetch $cpu, $read) as u16;
+        let hi = execute_6502!(@fetch $cpu, $read) as u16;
+        hi << 8 | lo
+    }};
+    (@addr absx, $cpu:ident, $read:ident, $kind:ident) => {{
+        let base = execute_6502!(@addr abs, $cpu, $read, $kind);
+        execute_6502!(@index $kind, base, $cpu.regs.x, $cpu, $read)
+    }};
+    (@addr absy, $cpu:ident, $read:ident, $kind:ident) => {{
+        let base = execute_6502!(@addr abs, $cpu, $read, $kind);
+        execute_6502!(@index $kind, base, $cpu.regs.y, $cpu, $read)
+    }};
+    (@addr indx, $cpu:ident, $read:ident, $kind:ident) => {{
+        let zp = execute_6502!(@fetch $cpu, $read);
+        $read!($cpu, zp as u16);
+        let ptr = zp.wrapping_add($cpu.regs.x as u8);
+        let lo = $read!($cpu, ptr as u16) as u16;
+        let hi = $read!($cpu, ptr.wrapping_add(1) as u16) as u16;
+        hi << 8 | lo
+    }};
+    (@addr indy, $cpu:ident, $read:ident, $kind:ident) => {{
+        let zp = execute_6502!(@fetch $cpu, $read);
+        let lo = $read!($cpu, zp as u16) as u16;
+        let hi = $read!($cpu, zp.wrapping_add(1) as u16) as u16;
+        execute_6502!(@index $kind, hi << 8 | lo, $cpu.regs.y, $cpu, $read)
+    }};
+    (@index read, $base:expr, $index:expr, $cpu:ident, $read:ident) => {{
+        let base: u16 = $base;
+        let address = base.wrapping_add($index as u16);
+        // possible penalty cycle when crossing 8-bit page boundaries
+        if address >> 8 != base >> 8 {
+            $read!($cpu, (base & 0xff00) | (address & 0x00ff));
+        }
+        address
+    }};
+    (@index write, $base:expr, $index:expr, $cpu:ident, $read:ident) => {{
+        let base: u16 = $base;
+        let address = base.wrapping_add($index as u16);
+        $read!($cpu, (base & 0xff00) | (address & 0x00ff));
+        address
+    }};
+
+    // instruction shapes
+    (@load $op:ident, imm, $cpu:ident, $read:ident) => {{
+        let v = execute_6502!(@fetch $cpu, $read);
+        $cpu.regs.$op(v);
+    }};
+    (@load $op:ident, $mode:ident, $cpu:ident, $read:ident) => {{
+        let address = execute_6502!(@addr $mode, $cpu, $read, read);
+        let v = $read!($cpu, address);
+        $cpu.regs.$op(v);
+    }};
+    (@store $reg:ident, $mode:ident, $cpu:ident, $read:ident, $write:ident) => {{
+        let address = execute_6502!(@addr $mode, $cpu, $read, write);
+        $write!($cpu, address, $cpu.regs.$reg as u8);
+    }};
+    (@modify $op:ident, $mode:ident, $cpu:ident, $read:ident, $write:ident) => {{
+        let address = execute_6502!(@addr $mode, $cpu, $read, write);
+        let v = $read!($cpu, address);
+        // the unmodified value is written back while the ALU works
+        $write!($cpu, address, v);
+        let v = $cpu.regs.$op(v);
+        $write!($cpu, address, v);
+    }};
+    (@accumulator $op:ident, $cpu:ident, $read:ident) => {{
+        $read!($cpu, $cpu.regs.pc);
+        let v = $cpu.regs.$op($cpu.regs.a as u8);
+        $cpu.regs.a = v as u16;
+    }};
+    (@implied $cpu:ident, $read:ident, $body:block) => {{
+        $read!($cpu, $cpu.regs.pc);
+        $body
+    }};
+    (@branch $cond:expr, $cpu:ident, $read:ident) => {{
+        let offset = execute_6502!(@fetch $cpu, $read) as i8;
+        if $cond {
+            $read!($cpu, $cpu.regs.pc);
+            let from = $cpu.regs.pc;
+            let to = from.wrapping_add(offset as u16);
+            if from >> 8 != to >> 8 {
+                $read!($cpu, (from & 0xff00) | (to & 0x00ff));
+            }
+            $cpu.regs.pc = to;
+        }
+    }};
+    (@push $value:expr, $cpu:ident, $write:ident) => {{
+        let v: u8 = $value;
+        $write!($cpu, $cpu.regs.s, v);
+        $cpu.regs.s = 0x0100 | ($cpu.regs.s.wrapping_sub(1) & 0x00ff);
+    }};
+    (@pull $cpu:ident, $read:ident) => {{
+        $cpu.regs.s = 0x0100 | ($cpu.regs.s.wrapping_add(1) & 0x00ff);
+        $read!($cpu, $cpu.regs.s)
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Model;
+
+    /// Just enough of a CPU to run `execute_6502!` on: 64 KiB of RAM and
+    /// every access an instruction makes, as address, data and whether it
+    /// was a write.
+    struct Cpu {
+        regs: Registers,
+        memory: Vec<u8>,
+        accesses: Vec<(u16, u8, bool)>,
+    }
+
+    macro_rules! bus_read {
+        ($cpu:ident, $addr:expr) => {
+            $cpu.read($addr)
+        };
+    }
+    macro_rules! bus_write {
+        ($cpu:ident, $addr:expr, $data:expr) => {
+            $cpu.write($addr, $data)
+        };
+    }
+
+    impl Cpu {
+        /// `program` at $0200, which is where pc starts.
+        fn new(program: &[u8]) -> Cpu {
+            let mut memory = vec![0; 0x10000];
+            memory[0x0200..0x0200 + program.len()].copy_from_slice(program);
+            Cpu {
+                regs: Registers {
+                    pc: 0x0200,
+                    ..Registers::power_on(Model::Nmos6502)
+                },
+                memory,
+                accesses: vec![],
+            }
+        }
+
+        fn read(&mut self, addr: u16) -> u8 {
+            let data = self.memory[addr as usize];
+            self.accesses.push((addr, data, false));
+            data
+        }
+
+        fn write(&mut self, addr: u16, data: u8) {
+            self.memory[addr as usize] = data;
+            self.accesses.push((addr, data, true));
+        }
+
+        /// Run one instruction and return its accesses.
+        fn step(&mut self) -> Vec<(u16, u8, bool)> {
+            self.accesses.clear();
+            execute_6502!(self, bus_read, bus_write);
+            std::mem::take(&mut self.accesses)
+        }
+
+        /// Just the addresses of the accesses of the next instruction.
+        fn addresses(&mut self) -> Vec<u16> {
+            self.step().into_iter().map(|(addr, _, _)| addr).collect()
+        }
+    }
+
+    fn decimal(a: u8, carry: bool) -> Registers {
+        let mut regs = Registers::power_on(Model::Nmos6502);
+        regs.a = a as u16;
+        regs.set_flag(FLAG_D, true);
+        regs.set_flag(FLAG_C, carry);
+        regs
+    }
+
+    #[test]
+    fn decimal_adc_flags() {
+        // 99 + 01 = 00 with a carry, but Z comes from the binary sum $9a and
+        // N from the intermediate $a0.
+        let mut regs = decimal(0x99, false);
+        regs.adc(0x01);
+        assert_eq!(regs.a, 0x00);
+        assert!(regs.flag(FLAG_C));
+        assert!(!regs.flag(FLAG_Z));
+        assert!(regs.flag(FLAG_N));
+        assert!(!regs.flag(FLAG_V));
+
+        // 79 + 00 + 1 = 80, which overflows going by the intermediate $80.
+        let mut regs = decimal(0x79, true);
+        regs.adc(0x00);
+        assert_eq!(regs.a, 0x80);
+        assert!(!regs.flag(FLAG_C));
+        assert!(regs.flag(FLAG_N));
+        assert!(regs.flag(FLAG_V));
+    }
+
+    #[test]
+    fn decimal_sbc_flags() {
+        // 00 - 01 = 99 with a borrow, flags from the binary $ff.
+        let mut regs = decimal(0x00, true);
+        regs.sbc(0x01);
+        assert_eq!(regs.a, 0x99);
+        assert!(!regs.flag(FLAG_C));
+        assert!(!regs.flag(FLAG_Z));
+        assert!(regs.flag(FLAG_N));
+        assert!(!regs.flag(FLAG_V));
+
+        // 80 - 01 = 79, and the binary $80 - $01 overflows.
+        let mut regs = decimal(0x80, true);
+        regs.sbc(0x01);
+        assert_eq!(regs.a, 0x79);
+        assert!(regs.flag(FLAG_C));
+        assert!(!regs.flag(FLAG_N));
+        assert!(regs.flag(FLAG_V));
+    }
+
+    #[test]
+    fn branch_penalties() {
+        // BNE +2, not taken: opcode and offset only.
+        let mut cpu = Cpu::new(&[0xd0, 0x02]);
+        cpu.regs.set_flag(FLAG_Z, true);
+        assert_eq!(cpu.addresses(), [0x0200, 0x0201]);
+        assert_eq!(cpu.regs.pc, 0x0202);
+
+        // Taken within the page: one more read of the next opcode.
+        let mut cpu = Cpu::new(&[0xd0, 0x02]);
+        assert_eq!(cpu.addresses(), [0x0200, 0x0201, 0x0202]);
+        assert_eq!(cpu.regs.pc, 0x0204);
+
+        // Taken into the next page: another read, with the high byte not
+        // fixed up yet.
+        let mut cpu = Cpu::new(&[]);
+        cpu.memory[0x02fd..0x02ff].copy_from_slice(&[0xd0, 0x01]);
+        cpu.regs.pc = 0x02fd;
+        assert_eq!(cpu.addresses(), [0x02fd, 0x02fe, 0x02ff, 0x0200]);
+        assert_eq!(cpu.regs.pc, 0x0300);
+    }
+
+    #[test]
+    fn indexed_dummy_reads() {
+        // LDA $12ff,Y only reads the wrong page when Y carries into the next.
+        let mut cpu = Cpu::new(&[0xb9, 0xff, 0x12]);
+        assert_eq!(cpu.addresses(), [0x0200, 0x0201, 0x0202, 0x12ff]);
+        let mut cpu = Cpu::new(&[0xb9, 0xff, 0x12]);
+        cpu.regs.y = 1;
+        assert_eq!(cpu.addresses(), [0x0200, 0x0201, 0x0202, 0x1200, 0x1300]);
+
+        // STA $1200,Y always does, before the write.
+        let mut cpu = Cpu::new(&[0x99, 0x00, 0x12]);
+        cpu.regs.y = 1;
+        cpu.regs.a = 0x42;
+        let accesses = cpu.step();
+        assert_eq!(accesses[3..], [(0x1201, 0x00, false), (0x1201, 0x42, true)]);
+    }
+
+    #[test]
+    fn b_and_u_flags() {
+        // PHP pushes B and U, PLP drops B and keeps U.
+        let mut cpu = Cpu::new(&[0x08, 0x28]);
+        cpu.regs.p = FLAG_C;
+        cpu.step();
+        assert_eq!(cpu.memory[0x01fd], FLAG_C | FLAG_B | FLAG_U);
+        cpu.memory[0x01fd] = 0xff;
+        cpu.step();
+        assert_eq!(cpu.regs.p, !FLAG_B);
+
+        // BRK skips a byte, pushes B and U and sets I; RTI restores p
+        // without B.
+        let mut cpu = Cpu::new(&[0x00, 0xea]);
+        cpu.memory[0x0300] = 0x40;
+        cpu.memory[IRQ_VECTOR as usize..][..2].copy_from_slice(&[0x00, 0x03]);
+        cpu.regs.p = FLAG_U | FLAG_C;
+        cpu.step();
+        assert_eq!(cpu.regs.pc, 0x0300);
+        assert!(cpu.regs.flag(FLAG_I));
+        assert_eq!(cpu.memory[0x01fb..=0x01fd], [FLAG_U | FLAG_C | FLAG_B, 0x02, 0x02]);
+        cpu.step();
+        assert_eq!(cpu.regs.pc, 0x0202);
+        assert_eq!(cpu.regs.p, FLAG_U | FLAG_C);
+        assert_eq!(cpu.regs.s, 0x01fd);
+    }
+
+    #[test]
+    fn jmp_indirect_stays_in_the_page() {
+        let mut cpu = Cpu::new(&[0x6c, 0xff, 0x12]);
+        cpu.memory[0x12ff] = 0x34;
+        cpu.memory[0x1200] = 0x56;
+        cpu.memory[0x1300] = 0x78;
+        assert_eq!(cpu.addresses(), [0x0200, 0x0201, 0x0202, 0x12ff, 0x1200]);
+        assert_eq!(cpu.regs.pc, 0x5634);
+    }
+
+    #[test]
+    fn stack_wraps_in_page_1() {
+        // JSR $0300 with s at the bottom of the stack, then RTS.
+        let mut cpu = Cpu::new(&[0x20, 0x00, 0x03]);
+        cpu.memory[0x0300] = 0x60;
+        cpu.regs.s = 0x0100;
+        cpu.step();
+        assert_eq!(cpu.regs.pc, 0x0300);
+        assert_eq!(cpu.regs.s, 0x01fe);
+        // The return address is the last byte of the JSR, high byte first.
+        assert_eq!((cpu.memory[0x0100], cpu.memory[0x01ff]), (0x02, 0x02));
+        cpu.step();
+        assert_eq!(cpu.regs.s, 0x0100);
+        assert_eq!(cpu.regs.pc, 0x0203);
+    }
+}
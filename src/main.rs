@@ -1,45 +1,113 @@
 #![feature(generators, generator_trait)]
 #![allow(dead_code)]
+mod baseline;
+mod bench;
+mod bus;
+mod cli;
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+mod cothread;
+mod cpu;
+mod executor;
+mod functional;
+mod html;
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+mod libco;
+#[macro_use]
+mod m6502;
+mod programs;
+mod report;
+mod scheduler;
+mod single_step;
+mod spc700;
+mod stats;
+mod stepping;
+mod trace;
+#[macro_use]
+mod w65816;
+
+use bus::{Bus, MemoryMap};
+use cli::Format;
+use cpu::{Constructor, Cpu, Model, Registers, State};
+use scheduler::{Scheduler, SyncMode};
+use programs::PROGRAMS;
+use report::{Environment, Report, VariantReport, Workload};
+use spc700::Apu;
+use stepping::{OnThread, Progress, Resumable, Resuming, Stepping, Ticker, Waits};
+
+/// Size of the program images, see `MemoryMap::flat`.
 const MEM_SIZE: usize = 65536;
 
 mod genawaiter_attempt {
     use super::*;
+    use genawaiter::sync::{Gen, GenBoxed};
     use genawaiter::{stack::let_gen, yield_};
+    use std::convert::Infallible;
 
-    struct CPU {
-        pc: u16,
-        x: u8,
-        y: u8,
-        a: u8,
-        apu_counter: u32,
+    pub struct CPU {
+        model: Model,
+        regs: Registers,
+        /// Where the CPU and APU are in time. Goes along to the APU while it
+        /// runs.
+        scheduler: Scheduler,
+        sync: SyncMode,
+        apu: GenBoxed<(Scheduler, spc700::State), Scheduler, Infallible>,
+        apu_state: spc700::State,
         cycles: u32,
         instruction_count: u32,
-        mem: [u8; MEM_SIZE],
+        /// Gets every bus access once `Cpu::trace` has been called.
+        trace: Option<Box<dyn trace::Sink>>,
+        /// Set while `stepping::Stepping` has an instruction in progress,
+        /// see `stepping::pause`.
+        waits: Option<Waits>,
+        bus: Box<dyn Bus>,
     }
 
-    macro_rules! local_join {
-        ($e:expr) => {
+    /// The APU as a generator of its own. It's resumed with the scheduler
+    /// and yields it back once it has caught up.
+    fn apu_thread(mut apu: Apu) -> GenBoxed<(Scheduler, spc700::State), Scheduler, Infallible> {
+        let mut gen = Gen::new_boxed(|co| async move {
+            let mut scheduler = Scheduler::new();
             loop {
-                match $e.async_resume().await {
-                    genawaiter::GeneratorState::Yielded(_) => {}
-                    genawaiter::GeneratorState::Complete(_) => {
-                        break;
-                    }
-                }
+                apu.run(&mut scheduler);
+                scheduler = co.yield_((scheduler, apu.state())).await;
             }
+        });
+        // The argument of the first resume never reaches the generator, so
+        // get that one out of the way.
+        gen.resume_with(Scheduler::new());
+        gen
+    }
+
+    macro_rules! bus_read {
+        ($cpu:ident, $addr:expr) => {
+            $cpu.read_memory(u32::from($addr)).await
+        };
+    }
+    macro_rules! bus_write {
+        ($cpu:ident, $addr:expr, $data:expr) => {
+            $cpu.write_memory(u32::from($addr), $data).await
+        };
+    }
+    macro_rules! bus_idle {
+        ($cpu:ident) => {
+            $cpu.wait(6).await
         };
     }
     impl CPU {
-        pub fn new() -> CPU {
+        pub fn new(model: Model, sync: SyncMode, bus: Box<dyn Bus>, apu: Apu) -> CPU {
+            let apu_state = apu.state();
             CPU {
-                pc: 0,
-                x: 0,
-                y: 0,
-                a: 0,
-                apu_counter: 0,
-                mem: [0xb9; MEM_SIZE],
+                model,
+                regs: Registers::power_on(model),
+                scheduler: Scheduler::snes(),
+                sync,
+                apu: apu_thread(apu),
+                apu_state,
+                bus,
                 cycles: 0,
                 instruction_count: 0,
+                trace: None,
+                waits: None,
             }
         }
         /*
@@ -57,96 +125,239 @@ mod genawaiter_attempt {
         */
         pub async fn execute_instruction(&mut self) {
             //println!("execute instruction");
-            let opcode = self.read_memory(self.pc).await;
-            self.pc += 1;
-            match opcode {
-                // 8-bit accumulator instructions
-                0xb9 => {
-                    //println!("found 0xb9 instruction");
-                    let address: u16 = self.read_memory(self.pc).await.into();
-                    self.pc += 1;
-                    let address: u16 = self.read_memory(self.pc).await as u16 | address << 8;
-                    self.pc += 1;
-                    if address >> 8 != address + self.y as u16 >> 8 {
-                        self.wait(6).await;
-                    }
-                    self.a = self.read_memory(address + self.y as u16).await;
-                }
-                _ => {
-                    // do nothing
-                }
+            match self.model {
+                Model::Nmos6502 => execute_6502!(self, bus_read, bus_write),
+                Model::Wdc65816 => execute_65816!(self, bus_read, bus_write, bus_idle),
             }
             self.instruction_count += 1;
         }
 
-        async fn read_memory(&mut self, addr: u16) -> u8 {
+        async fn read_memory(&mut self, addr: u32) -> u8 {
             //println!("read_memory");
+            let cycles = self.bus.cycles(addr);
             self.wait(2).await;
-            let data = self.mem[addr as usize];
-            self.wait(4).await;
+            if self.sync == SyncMode::Lazy && self.bus.shared(addr) {
+                self.sync_apu();
+            }
+            let data = self.bus.read(addr);
+            trace::record(&mut self.trace, self.cycles, addr, data, trace::Kind::Read);
+            self.wait(cycles - 2).await;
             data
         }
 
+        async fn write_memory(&mut self, addr: u32, data: u8) {
+            //println!("write_memory");
+            let cycles = self.bus.cycles(addr);
+            self.wait(2).await;
+            if self.sync == SyncMode::Lazy && self.bus.shared(addr) {
+                self.sync_apu();
+            }
+            self.bus.write(addr, data);
+            trace::record(&mut self.trace, self.cycles, addr, data, trace::Kind::Write);
+            self.wait(cycles - 2).await;
+        }
+
         async fn wait(&mut self, clock_cycles: u32) {
             //println!("wait for {} cycles", clock_cycles);
-            self.apu_counter += clock_cycles;
-            let_gen!(g, {
-                while self.apu_counter > 0 {
+            match self.waits.clone() {
+                None => self.advance(clock_cycles).await,
+                // `Stepping` stops before every clock.
+                Some(waits) => {
+                    for _ in 0..clock_cycles {
+                        stepping::pause(&waits).await;
+                        self.advance(1).await;
+                    }
+                }
+            }
+        }
+
+        /// Let `clock_cycles` go by, catching the APU up if it should.
+        async fn advance(&mut self, clock_cycles: u32) {
+            self.cycles += clock_cycles;
+            self.scheduler.step(scheduler::CPU, clock_cycles);
+            if self.sync == SyncMode::Eager || self.scheduler.far_ahead(scheduler::CPU) {
+                self.sync_apu();
+            }
+        }
+
+        /// Resume the APU if it's behind.
+        fn sync_apu(&mut self) {
+            if self.scheduler.behind() != scheduler::CPU {
+                match self.apu.resume_with(std::mem::take(&mut self.scheduler)) {
+                    genawaiter::GeneratorState::Yielded((scheduler, state)) => {
+                        self.scheduler = scheduler;
+                        self.apu_state = state;
+                    }
+                    genawaiter::GeneratorState::Complete(never) => match never {},
+                }
+            }
+        }
+    }
+
+    impl Cpu for CPU {
+        fn step(&mut self) {
+            futures::executor::block_on(self.execute_instruction());
+        }
+
+        fn run(&mut self, iters: usize) {
+            use futures::executor::block_on_stream;
+
+            let cpu = self;
+            let_gen!(gen, {
+                loop {
+                    cpu.execute_instruction().await;
+                    //println!("ran for 1 instruction");
+                    //println!("instruction count: {}", cpu.instruction_count);
+                    //println!("cycle count: {}", cpu.cycles);
+
                     yield_!(());
-                    self.apu_counter -= 1;
-                    self.cycles += 1;
-                    //println!("total cycles {}", self.cycles);
                 }
             });
-            local_join!(g);
+
+            let stream = block_on_stream(gen);
+            for _ in stream.take(iters) {}
+        }
+
+        fn registers(&self) -> Registers {
+            self.regs
+        }
+
+        fn set_registers(&mut self, regs: Registers) {
+            self.regs = regs;
+        }
+
+        fn cycles(&self) -> u32 {
+            self.cycles
+        }
+
+        fn instruction_count(&self) -> u32 {
+            self.instruction_count
+        }
+
+        fn synchronize(&mut self) {
+            self.sync_apu();
+        }
+
+        fn apu(&self) -> spc700::State {
+            self.apu_state
+        }
+
+        fn trace(&mut self, sink: Box<dyn trace::Sink>) {
+            self.trace = Some(sink);
+        }
+
+        fn load(&mut self, image: &[u8], pc: u16) {
+            self.bus.load(0, image);
+            self.regs.pc = pc;
+        }
+
+        fn reset_registers(&mut self) {
+            self.regs = Registers::power_on(self.model);
+            self.cycles = 0;
+            self.instruction_count = 0;
         }
     }
 
-    pub fn main(iters: usize) {
-        use futures::executor::block_on_stream;
+    impl Resumable for CPU {
+        type Instruction = Resuming<CPU>;
 
-        let mut cpu = CPU::new();
-        let_gen!(gen, {
-            loop {
+        fn start(mut cpu: Box<CPU>) -> Resuming<CPU> {
+            let waits = Waits::default();
+            cpu.waits = Some(waits.clone());
+            Resuming::new(waits, async move {
                 cpu.execute_instruction().await;
-                //println!("ran for 1 instruction");
-                //println!("instruction count: {}", cpu.instruction_count);
-                //println!("cycle count: {}", cpu.cycles);
-
-                yield_!(());
-            }
-        });
+                cpu.waits = None;
+                cpu
+            })
+        }
 
-        let stream = block_on_stream(gen);
-        for _ in stream.take(iters) {}
+        fn resume(instruction: &mut Resuming<CPU>) -> Progress<CPU> {
+            futures::executor::block_on(futures::future::poll_fn(|cx| instruction.poll(cx)))
+        }
     }
 }
 
 mod tokio_attempt {
     use super::*;
+    use tokio::sync::mpsc;
 
-    struct CPU {
-        pc: u16,
-        x: u8,
-        y: u8,
-        a: u8,
-        apu_counter: u32,
+    pub struct CPU {
+        model: Model,
+        regs: Registers,
+        /// Where the CPU and APU are in time. Goes along to the APU task
+        /// while it runs.
+        scheduler: Scheduler,
+        sync: SyncMode,
+        to_apu: mpsc::Sender<Scheduler>,
+        from_apu: mpsc::Receiver<(Scheduler, spc700::State)>,
+        apu_state: spc700::State,
         cycles: u32,
         instruction_count: u32,
-        mem: [u8; MEM_SIZE],
+        /// Gets every bus access once `Cpu::trace` has been called.
+        trace: Option<Box<dyn trace::Sink>>,
+        /// Set while `stepping::Stepping` has an instruction in progress,
+        /// see `stepping::pause`.
+        waits: Option<Waits>,
+        bus: Box<dyn Bus>,
+        rt: Option<tokio::runtime::Runtime>,
+    }
+
+    macro_rules! bus_read {
+        ($cpu:ident, $addr:expr) => {
+            $cpu.read_memory(u32::from($addr)).await
+        };
+    }
+    macro_rules! bus_write {
+        ($cpu:ident, $addr:expr, $data:expr) => {
+            $cpu.write_memory(u32::from($addr), $data).await
+        };
+    }
+    macro_rules! bus_idle {
+        ($cpu:ident) => {
+            $cpu.wait(6).await
+        };
+    }
+
+    /// The APU as a task of its own. It waits for the CPU to hand over the
+    /// scheduler and hands it back once it has caught up.
+    async fn apu_task(
+        mut apu: Apu,
+        mut from_cpu: mpsc::Receiver<Scheduler>,
+        mut to_cpu: mpsc::Sender<(Scheduler, spc700::State)>,
+    ) {
+        while let Some(mut scheduler) = from_cpu.recv().await {
+            apu.run(&mut scheduler);
+            if to_cpu.send((scheduler, apu.state())).await.is_err() {
+                break;
+            }
+        }
     }
 
     impl CPU {
-        pub fn new() -> CPU {
+        pub fn new(model: Model, sync: SyncMode, bus: Box<dyn Bus>, apu: Apu) -> CPU {
+            let rt = tokio::runtime::Builder::new()
+                .basic_scheduler()
+                .build()
+                .expect("couldn't build a tokio runtime");
+            let (to_apu, from_cpu) = mpsc::channel(1);
+            let (to_cpu, from_apu) = mpsc::channel(1);
+            let apu_state = apu.state();
+            // It only gets to run inside `block_on`, like the CPU.
+            rt.spawn(apu_task(apu, from_cpu, to_cpu));
             CPU {
-                pc: 0,
-                x: 0,
-                y: 0,
-                a: 0,
-                apu_counter: 0,
-                mem: [0xb9; MEM_SIZE],
+                model,
+                regs: Registers::power_on(model),
+                scheduler: Scheduler::snes(),
+                sync,
+                to_apu,
+                from_apu,
+                apu_state,
+                bus,
                 cycles: 0,
                 instruction_count: 0,
+                trace: None,
+                waits: None,
+                rt: Some(rt),
             }
         }
         /*
@@ -164,93 +375,263 @@ mod tokio_attempt {
         */
         pub async fn execute_instruction(&mut self) {
             //println!("execute instruction");
-            let opcode = self.read_memory(self.pc).await;
-            self.pc += 1;
-            match opcode {
-                // 8-bit accumulator instructions
-                0xb9 => {
-                    //println!("found 0xb9 instruction");
-                    let address: u16 = self.read_memory(self.pc).await.into();
-                    self.pc += 1;
-                    let address: u16 = self.read_memory(self.pc).await as u16 | address << 8;
-                    self.pc += 1;
-                    if address >> 8 != address + self.y as u16 >> 8 {
-                        self.wait(6).await;
-                    }
-                    self.a = self.read_memory(address + self.y as u16).await;
-                }
-                _ => {
-                    // do nothing
-                }
+            match self.model {
+                Model::Nmos6502 => execute_6502!(self, bus_read, bus_write),
+                Model::Wdc65816 => execute_65816!(self, bus_read, bus_write, bus_idle),
             }
             self.instruction_count += 1;
         }
 
-        async fn read_memory(&mut self, addr: u16) -> u8 {
+        async fn read_memory(&mut self, addr: u32) -> u8 {
             //println!("read_memory");
+            let cycles = self.bus.cycles(addr);
             self.wait(2).await;
-            let data = self.mem[addr as usize];
-            self.wait(4).await;
+            if self.sync == SyncMode::Lazy && self.bus.shared(addr) {
+                self.sync_apu().await;
+            }
+            let data = self.bus.read(addr);
+            trace::record(&mut self.trace, self.cycles, addr, data, trace::Kind::Read);
+            self.wait(cycles - 2).await;
             data
         }
 
+        async fn write_memory(&mut self, addr: u32, data: u8) {
+            //println!("write_memory");
+            let cycles = self.bus.cycles(addr);
+            self.wait(2).await;
+            if self.sync == SyncMode::Lazy && self.bus.shared(addr) {
+                self.sync_apu().await;
+            }
+            self.bus.write(addr, data);
+            trace::record(&mut self.trace, self.cycles, addr, data, trace::Kind::Write);
+            self.wait(cycles - 2).await;
+        }
+
         async fn wait(&mut self, clock_cycles: u32) {
             //println!("wait for {} cycles", clock_cycles);
-            self.apu_counter += clock_cycles;
-            while self.apu_counter > 0 {
-                tokio::task::yield_now().await;
-                self.apu_counter -= 1;
-                self.cycles += 1;
-                //println!("total cycles {}", self.cycles);
+            match self.waits.clone() {
+                None => self.advance(clock_cycles).await,
+                // `Stepping` stops before every clock.
+                Some(waits) => {
+                    for _ in 0..clock_cycles {
+                        stepping::pause(&waits).await;
+                        self.advance(1).await;
+                    }
+                }
+            }
+        }
+
+        /// Let `clock_cycles` go by, catching the APU up if it should.
+        async fn advance(&mut self, clock_cycles: u32) {
+            self.cycles += clock_cycles;
+            self.scheduler.step(scheduler::CPU, clock_cycles);
+            if self.sync == SyncMode::Eager || self.scheduler.far_ahead(scheduler::CPU) {
+                self.sync_apu().await;
             }
         }
+
+        /// Hand the scheduler to the APU task if the APU is behind and wait
+        /// for it to come back.
+        async fn sync_apu(&mut self) {
+            if self.scheduler.behind() != scheduler::CPU {
+                let scheduler = std::mem::take(&mut self.scheduler);
+                self.to_apu.send(scheduler).await.expect("the APU task stopped");
+                let (scheduler, state) = self.from_apu.recv().await.expect("the APU task stopped");
+                self.scheduler = scheduler;
+                self.apu_state = state;
+            }
+        }
+    }
+
+    impl Cpu for CPU {
+        fn step(&mut self) {
+            self.run(1);
+        }
+
+        fn run(&mut self, iters: usize) {
+            // The runtime is taken out for the duration of the run so that
+            // the future below can borrow the rest of the CPU mutably.
+            let mut rt = self.rt.take().expect("tokio runtime already in use");
+            let cpu = &mut *self;
+            rt.block_on(async move {
+                for _ in 0..iters {
+                    cpu.execute_instruction().await;
+                    //println!("ran for 1 instruction");
+                    //println!("instruction count: {}", cpu.instruction_count);
+                    //println!("cycle count: {}", cpu.cycles);
+
+                    //tokio::task::yield_now().await;
+                }
+            });
+            self.rt = Some(rt);
+        }
+
+        fn registers(&self) -> Registers {
+            self.regs
+        }
+
+        fn set_registers(&mut self, regs: Registers) {
+            self.regs = regs;
+        }
+
+        fn cycles(&self) -> u32 {
+            self.cycles
+        }
+
+        fn instruction_count(&self) -> u32 {
+            self.instruction_count
+        }
+
+        fn synchronize(&mut self) {
+            let mut rt = self.rt.take().expect("tokio runtime already in use");
+            rt.block_on(self.sync_apu());
+            self.rt = Some(rt);
+        }
+
+        fn apu(&self) -> spc700::State {
+            self.apu_state
+        }
+
+        fn trace(&mut self, sink: Box<dyn trace::Sink>) {
+            self.trace = Some(sink);
+        }
+
+        fn load(&mut self, image: &[u8], pc: u16) {
+            self.bus.load(0, image);
+            self.regs.pc = pc;
+        }
+
+        fn reset_registers(&mut self) {
+            self.regs = Registers::power_on(self.model);
+            self.cycles = 0;
+            self.instruction_count = 0;
+        }
+    }
+
+    /// An instruction in progress, along with the runtime it needs to run, which
+    /// goes back to the CPU once the instruction is done.
+    pub struct Instruction {
+        rt: Option<tokio::runtime::Runtime>,
+        resuming: Resuming<CPU>,
     }
 
-    pub fn main(iters: usize) {
-        let mut rt = tokio::runtime::Builder::new()
-            .basic_scheduler()
-            .build()
-            .expect("couldn't build a tokio runtime");
+    impl Resumable for CPU {
+        type Instruction = Instruction;
 
-        let mut cpu = CPU::new();
-        rt.block_on(async move {
-            for _ in 0..iters {
+        fn start(mut cpu: Box<CPU>) -> Instruction {
+            let rt = cpu.rt.take();
+            let waits = Waits::default();
+            cpu.waits = Some(waits.clone());
+            let resuming = Resuming::new(waits, async move {
                 cpu.execute_instruction().await;
-                //println!("ran for 1 instruction");
-                //println!("instruction count: {}", cpu.instruction_count);
-                //println!("cycle count: {}", cpu.cycles);
+                cpu.waits = None;
+                cpu
+            });
+            Instruction { rt, resuming }
+        }
 
-                //tokio::task::yield_now().await;
+        fn resume(instruction: &mut Instruction) -> Progress<CPU> {
+            let rt = instruction.rt.as_mut().expect("tokio runtime already in use");
+            let resuming = &mut instruction.resuming;
+            match rt.block_on(futures::future::poll_fn(|cx| resuming.poll(cx))) {
+                Progress::Done(mut cpu) => {
+                    cpu.rt = instruction.rt.take();
+                    Progress::Done(cpu)
+                }
+                waited => waited,
             }
-        });
+        }
     }
 }
 
 mod async_std_attempt {
     use super::*;
+    use async_std::channel::{self, Receiver, Sender};
 
-    struct CPU {
-        pc: u16,
-        x: u8,
-        y: u8,
-        a: u8,
-        apu_counter: u32,
+    pub struct CPU {
+        model: Model,
+        regs: Registers,
+        /// Where the CPU and APU are in time. Goes along to the APU task
+        /// while it runs.
+        scheduler: Scheduler,
+        sync: SyncMode,
+        to_apu: Sender<Scheduler>,
+        from_apu: Receiver<(Scheduler, spc700::State)>,
+        apu_state: spc700::State,
         cycles: u32,
         instruction_count: u32,
-        mem: [u8; MEM_SIZE],
+        /// Gets every bus access once `Cpu::trace` has been called.
+        trace: Option<Box<dyn trace::Sink>>,
+        /// Set while `stepping::Stepping` has an instruction in progress,
+        /// see `stepping::pause`.
+        waits: Option<Waits>,
+        bus: Box<dyn Bus>,
+    }
+
+    macro_rules! bus_read {
+        ($cpu:ident, $addr:expr) => {
+            $cpu.read_memory(u32::from($addr)).await
+        };
+    }
+    macro_rules! bus_write {
+        ($cpu:ident, $addr:expr, $data:expr) => {
+            $cpu.write_memory(u32::from($addr), $data).await
+        };
+    }
+    macro_rules! bus_idle {
+        ($cpu:ident) => {
+            $cpu.wait(6).await
+        };
+    }
+
+    /// The APU as a task of its own. It waits for the CPU to hand over the
+    /// scheduler and hands it back once it has caught up.
+    async fn apu_task(
+        mut apu: Apu,
+        from_cpu: Receiver<Scheduler>,
+        to_cpu: Sender<(Scheduler, spc700::State)>,
+    ) {
+        while let Ok(mut scheduler) = from_cpu.recv().await {
+            apu.run(&mut scheduler);
+            if to_cpu.send((scheduler, apu.state())).await.is_err() {
+                break;
+            }
+        }
     }
 
     impl CPU {
-        pub fn new() -> CPU {
+        pub fn new(model: Model, sync: SyncMode, bus: Box<dyn Bus>, apu: Apu) -> CPU {
+            let (to_apu, from_cpu) = channel::bounded(1);
+            let (to_cpu, from_apu) = channel::bounded(1);
+            let apu_state = apu.state();
+            async_std::task::spawn(apu_task(apu, from_cpu, to_cpu));
+            CPU {
+                apu_state,
+                ..CPU::detached(model, sync, bus, to_apu, from_apu)
+            }
+        }
+
+        /// A CPU talking to whatever is on the other end of the channels.
+        fn detached(
+            model: Model,
+            sync: SyncMode,
+            bus: Box<dyn Bus>,
+            to_apu: Sender<Scheduler>,
+            from_apu: Receiver<(Scheduler, spc700::State)>,
+        ) -> CPU {
             CPU {
-                pc: 0,
-                x: 0,
-                y: 0,
-                a: 0,
-                apu_counter: 0,
-                mem: [0xb9; MEM_SIZE],
+                model,
+                regs: Registers::power_on(model),
+                scheduler: Scheduler::snes(),
+                sync,
+                to_apu,
+                from_apu,
+                apu_state: spc700::State::default(),
+                bus,
                 cycles: 0,
                 instruction_count: 0,
+                trace: None,
+                waits: None,
             }
         }
         /*
@@ -268,309 +649,512 @@ mod async_std_attempt {
         */
         pub async fn execute_instruction(&mut self) {
             //println!("execute instruction");
-            let opcode = self.read_memory(self.pc).await;
-            self.pc += 1;
-            match opcode {
-                // 8-bit accumulator instructions
-                0xb9 => {
-                    //println!("found 0xb9 instruction");
-                    let address: u16 = self.read_memory(self.pc).await.into();
-                    self.pc += 1;
-                    let address: u16 = self.read_memory(self.pc).await as u16 | address << 8;
-                    self.pc += 1;
-                    if address >> 8 != address + self.y as u16 >> 8 {
-                        self.wait(6).await;
-                    }
-                    self.a = self.read_memory(address + self.y as u16).await;
-                }
-                _ => {
-                    // do nothing
-                }
+            match self.model {
+                Model::Nmos6502 => execute_6502!(self, bus_read, bus_write),
+                Model::Wdc65816 => execute_65816!(self, bus_read, bus_write, bus_idle),
             }
             self.instruction_count += 1;
         }
 
-        async fn read_memory(&mut self, addr: u16) -> u8 {
+        async fn read_memory(&mut self, addr: u32) -> u8 {
             //println!("read_memory");
+            let cycles = self.bus.cycles(addr);
             self.wait(2).await;
-            let data = self.mem[addr as usize];
-            self.wait(4).await;
+            if self.sync == SyncMode::Lazy && self.bus.shared(addr) {
+                self.sync_apu().await;
+            }
+            let data = self.bus.read(addr);
+            trace::record(&mut self.trace, self.cycles, addr, data, trace::Kind::Read);
+            self.wait(cycles - 2).await;
             data
         }
 
+        async fn write_memory(&mut self, addr: u32, data: u8) {
+            //println!("write_memory");
+            let cycles = self.bus.cycles(addr);
+            self.wait(2).await;
+            if self.sync == SyncMode::Lazy && self.bus.shared(addr) {
+                self.sync_apu().await;
+            }
+            self.bus.write(addr, data);
+            trace::record(&mut self.trace, self.cycles, addr, data, trace::Kind::Write);
+            self.wait(cycles - 2).await;
+        }
+
         async fn wait(&mut self, clock_cycles: u32) {
             //println!("wait for {} cycles", clock_cycles);
-            self.apu_counter += clock_cycles;
-            while self.apu_counter > 0 {
-                async_std::task::yield_now().await;
-                self.apu_counter -= 1;
-                self.cycles += 1;
-                //println!("total cycles {}", self.cycles);
+            match self.waits.clone() {
+                None => self.advance(clock_cycles).await,
+                // `Stepping` stops before every clock.
+                Some(waits) => {
+                    for _ in 0..clock_cycles {
+                        stepping::pause(&waits).await;
+                        self.advance(1).await;
+                    }
+                }
+            }
+        }
+
+        /// Let `clock_cycles` go by, catching the APU up if it should.
+        async fn advance(&mut self, clock_cycles: u32) {
+            self.cycles += clock_cycles;
+            self.scheduler.step(scheduler::CPU, clock_cycles);
+            if self.sync == SyncMode::Eager || self.scheduler.far_ahead(scheduler::CPU) {
+                self.sync_apu().await;
+            }
+        }
+
+        /// Hand the scheduler to the APU task if the APU is behind and wait
+        /// for it to come back.
+        async fn sync_apu(&mut self) {
+            if self.scheduler.behind() != scheduler::CPU {
+                let scheduler = std::mem::take(&mut self.scheduler);
+                self.to_apu.send(scheduler).await.expect("the APU task stopped");
+                let (scheduler, state) = self.from_apu.recv().await.expect("the APU task stopped");
+                self.scheduler = scheduler;
+                self.apu_state = state;
             }
         }
     }
 
-    pub fn main(iters: usize) {
-        let task = async_std::task::spawn(async move {
-            let mut cpu = CPU::new();
-            for _ in 0..iters {
+    impl Cpu for CPU {
+        fn step(&mut self) {
+            async_std::task::block_on(self.execute_instruction());
+        }
+
+        fn run(&mut self, iters: usize) {
+            // `spawn` needs a `'static` future, so the CPU is moved into the
+            // task and handed back when it finishes. The stand-in is never
+            // run, so nothing needs to listen on its channels.
+            let (to_apu, _) = channel::bounded(1);
+            let (_, from_apu) = channel::bounded(1);
+            let stand_in = CPU::detached(
+                self.model,
+                self.sync,
+                Box::new(MemoryMap::new()),
+                to_apu,
+                from_apu,
+            );
+            let mut cpu = std::mem::replace(self, stand_in);
+            let task = async_std::task::spawn(async move {
+                for _ in 0..iters {
+                    cpu.execute_instruction().await;
+                    //println!("ran for 1 instruction");
+                    //println!("instruction count: {}", cpu.instruction_count);
+                    //println!("cycle count: {}", cpu.cycles);
+
+                    //async_std::task::yield_now().await;
+                }
+                cpu
+            });
+            *self = async_std::task::block_on(task);
+        }
+
+        fn registers(&self) -> Registers {
+            self.regs
+        }
+
+        fn set_registers(&mut self, regs: Registers) {
+            self.regs = regs;
+        }
+
+        fn cycles(&self) -> u32 {
+            self.cycles
+        }
+
+        fn instruction_count(&self) -> u32 {
+            self.instruction_count
+        }
+
+        fn synchronize(&mut self) {
+            async_std::task::block_on(self.sync_apu());
+        }
+
+        fn apu(&self) -> spc700::State {
+            self.apu_state
+        }
+
+        fn trace(&mut self, sink: Box<dyn trace::Sink>) {
+            self.trace = Some(sink);
+        }
+
+        fn load(&mut self, image: &[u8], pc: u16) {
+            self.bus.load(0, image);
+            self.regs.pc = pc;
+        }
+
+        fn reset_registers(&mut self) {
+            self.regs = Registers::power_on(self.model);
+            self.cycles = 0;
+            self.instruction_count = 0;
+        }
+    }
+
+    impl Resumable for CPU {
+        type Instruction = Resuming<CPU>;
+
+        fn start(mut cpu: Box<CPU>) -> Resuming<CPU> {
+            let waits = Waits::default();
+            cpu.waits = Some(waits.clone());
+            Resuming::new(waits, async move {
                 cpu.execute_instruction().await;
-                //println!("ran for 1 instruction");
-                //println!("instruction count: {}", cpu.instruction_count);
-                //println!("cycle count: {}", cpu.cycles);
+                cpu.waits = None;
+                cpu
+            })
+        }
 
-                //async_std::task::yield_now().await;
-            }
-        });
-        async_std::task::block_on(task);
+        fn resume(instruction: &mut Resuming<CPU>) -> Progress<CPU> {
+            async_std::task::block_on(futures::future::poll_fn(|cx| instruction.poll(cx)))
+        }
     }
 }
 
-mod enum_attempt {
+mod local_pool_attempt {
     use super::*;
+    use futures::channel::mpsc;
+    use futures::executor::LocalPool;
+    use futures::task::LocalSpawnExt;
+    use futures::{SinkExt, StreamExt};
 
-    struct CPU {
-        pc: u16,
-        x: u8,
-        y: u8,
-        a: u8,
-        apu_counter: u32,
+    pub struct CPU {
+        model: Model,
+        regs: Registers,
+        /// Where the CPU and APU are in time. Goes along to the APU task
+        /// while it runs.
+        scheduler: Scheduler,
+        sync: SyncMode,
+        to_apu: mpsc::Sender<Scheduler>,
+        from_apu: mpsc::Receiver<(Scheduler, spc700::State)>,
+        apu_state: spc700::State,
         cycles: u32,
-        cycle: u32,
-        subcycle: u32,
         instruction_count: u32,
-        opcode: u8,
-        address: u16,
-        mem: [u8; MEM_SIZE],
+        /// Gets every bus access once `Cpu::trace` has been called.
+        trace: Option<Box<dyn trace::Sink>>,
+        /// Set while `stepping::Stepping` has an instruction in progress,
+        /// see `stepping::pause`.
+        waits: Option<Waits>,
+        bus: Box<dyn Bus>,
+        pool: Option<LocalPool>,
+    }
+
+    macro_rules! bus_read {
+        ($cpu:ident, $addr:expr) => {
+            $cpu.read_memory(u32::from($addr)).await
+        };
+    }
+    macro_rules! bus_write {
+        ($cpu:ident, $addr:expr, $data:expr) => {
+            $cpu.write_memory(u32::from($addr), $data).await
+        };
+    }
+    macro_rules! bus_idle {
+        ($cpu:ident) => {
+            $cpu.wait(6).await
+        };
+    }
+
+    /// The APU as a task of its own. It waits for the CPU to hand over the
+    /// scheduler and hands it back once it has caught up.
+    async fn apu_task(
+        mut apu: Apu,
+        mut from_cpu: mpsc::Receiver<Scheduler>,
+        mut to_cpu: mpsc::Sender<(Scheduler, spc700::State)>,
+    ) {
+        while let Some(mut scheduler) = from_cpu.next().await {
+            apu.run(&mut scheduler);
+            if to_cpu.send((scheduler, apu.state())).await.is_err() {
+                break;
+            }
+        }
     }
 
     impl CPU {
-        pub fn new() -> CPU {
+        pub fn new(model: Model, sync: SyncMode, bus: Box<dyn Bus>, apu: Apu) -> CPU {
+            let pool = LocalPool::new();
+            let (to_apu, from_cpu) = mpsc::channel(1);
+            let (to_cpu, from_apu) = mpsc::channel(1);
+            let apu_state = apu.state();
+            // It only gets to run inside `run_until`, like the CPU.
+            pool.spawner()
+                .spawn_local(apu_task(apu, from_cpu, to_cpu))
+                .expect("couldn't spawn the APU task");
             CPU {
-                pc: 0,
-                x: 0,
-                y: 0,
-                a: 0,
-                apu_counter: 0,
-                mem: [0xb9; MEM_SIZE],
+                model,
+                regs: Registers::power_on(model),
+                scheduler: Scheduler::snes(),
+                sync,
+                to_apu,
+                from_apu,
+                apu_state,
+                bus,
                 cycles: 0,
-                cycle: 1,
-                subcycle: 1,
-                opcode: 0,
-                address: 0,
                 instruction_count: 0,
+                trace: None,
+                waits: None,
+                pool: Some(pool),
             }
         }
         /*
-                void CPU::executeInstructionCycle() {
-                  if(cycle == 1) {
-                    opcode = readMemory(PC++);
-                    cycle = 2;
-                    return;
-                  }
-                  if(FlagM)
-                  switch(opcode) {  //8-bit accumulator instructions
-                  case 0xb9:
-                    switch(cycle) {
-                    case 2:
-                      switch(subcycle) {
-                      case 1:
-                        subcycle = 2;
-                        return;
-                      case 2:
-                        address = readMemory(PC++);
-                        subcycle = 1;
-                        return;
-                      }
-                    case 3:
-                      switch(subcycle) {
-                      case 1:
-                        subcycle = 2;
-                        return;
-                      case 2:
-                        address = readMemory(PC++) | address << 8;
-                        subcycle = 1;
-                        return;
-                      }
-                    case 4:
-                      //possible penalty cycle when crossing 8-bit page boundaries:
-                      if(address >> 8 != address + Y >> 8) {
-                        return;
-                      }
-                      cycle++;  //cycle 4 not needed; fall through to cycle 5
-                    case 5:
-                      switch(subcycle) {
-                      case 1:
-                        subcycle = 2;
-                        return;
-                      case 2:
-                        A = readMemory(address + Y);
-                        subcycle = 1;
-                        cycle = 0;  //end of instruction; start a new instruction next time
-                        return;
-                      }
-                    }
-                  }
-                }
+        void CPU::executeInstruction() {
+          opcode = readMemory(PC++);
+          if(FlagM)
+          switch(opcode) {  //8-bit accumulator instructions
+          case 0xb9:
+            address = readMemory(PC++);
+            address = readMemory(PC++) | address << 8;
+            if(address >> 8 != address + Y >> 8) wait(6);
+            A = readMemory(address + Y);
+          }
+        }
         */
-        pub fn execute_instruction(&mut self) -> bool {
+        pub async fn execute_instruction(&mut self) {
             //println!("execute instruction");
-            if self.cycle == 1 {
-                //println!("fetch opcode");
-                self.opcode = self.read_memory(self.pc);
-                self.pc += 1;
-                self.cycle = 2;
-                return false;
-            }
-            match self.opcode {
-                // 8-bit accumulator instructions
-                0xb9 => {
-                    //println!("decode 0xb9");
-                    match self.cycle {
-                        2 => match self.subcycle {
-                            1 => {
-                                //println!("waiting");
-                                self.subcycle = 2;
-                                return false;
-                            }
-                            2 => {
-                                //println!("reading byte");
-                                self.address = self.read_memory(self.pc) as u16;
-                                self.pc += 1;
-                                self.subcycle = 1;
-                                self.cycle = 3;
-                                return false;
-                            }
-                            _ => {}
-                        },
-                        3 => match self.subcycle {
-                            1 => {
-                                //println!("waiting to read other byte");
-                                self.subcycle = 2;
-                                return false;
-                            }
-                            2 => {
-                                //println!("reading other byte");
-                                self.address = self.read_memory(self.pc) as u16 | self.address << 8;
-                                self.pc += 1;
-                                self.subcycle = 1;
-                                self.cycle = 4;
-                                return false;
-                            }
-                            _ => {}
-                        },
-                        4 => {
-                            // Taking a bit of liberty here
-                            //println!("cycle 4");
-                            self.cycle += 1;
-                            if self.address >> 8 != self.address + self.y as u16 >> 8 {
-                                return false;
-                            }
-                        }
-                        5 => match self.subcycle {
-                            1 => {
-                                //println!("cycle 5 wait");
-                                self.subcycle = 2;
-                                return false;
-                            }
-                            2 => {
-                                //println!("cycle 5 done");
-                                self.a = self.read_memory(self.address + self.y as u16);
-                                self.subcycle = 1;
-                                self.cycle = 1;
-                                self.instruction_count += 1;
-                                return true;
-                            }
-                            _ => {}
-                        },
-                        _ => {}
-                    }
-                }
-                _ => {
-                    // do nothing
-                }
+            match self.model {
+                Model::Nmos6502 => execute_6502!(self, bus_read, bus_write),
+                Model::Wdc65816 => execute_65816!(self, bus_read, bus_write, bus_idle),
             }
-            false
+            self.instruction_count += 1;
         }
 
-        fn read_memory(&mut self, addr: u16) -> u8 {
+        async fn read_memory(&mut self, addr: u32) -> u8 {
             //println!("read_memory");
-            self.wait(2);
-            let data = self.mem[addr as usize];
-            self.wait(4);
+            let cycles = self.bus.cycles(addr);
+            self.wait(2).await;
+            if self.sync == SyncMode::Lazy && self.bus.shared(addr) {
+                self.sync_apu().await;
+            }
+            let data = self.bus.read(addr);
+            trace::record(&mut self.trace, self.cycles, addr, data, trace::Kind::Read);
+            self.wait(cycles - 2).await;
             data
         }
 
-        fn wait(&mut self, clock_cycles: u32) {
+        async fn write_memory(&mut self, addr: u32, data: u8) {
+            //println!("write_memory");
+            let cycles = self.bus.cycles(addr);
+            self.wait(2).await;
+            if self.sync == SyncMode::Lazy && self.bus.shared(addr) {
+                self.sync_apu().await;
+            }
+            self.bus.write(addr, data);
+            trace::record(&mut self.trace, self.cycles, addr, data, trace::Kind::Write);
+            self.wait(cycles - 2).await;
+        }
+
+        async fn wait(&mut self, clock_cycles: u32) {
             //println!("wait for {} cycles", clock_cycles);
-            self.apu_counter += clock_cycles;
-            while self.apu_counter > 0 {
-                self.apu_counter -= 1;
-                self.cycles += 1;
-                //println!("total cycles {}", self.cycles);
+            match self.waits.clone() {
+                None => self.advance(clock_cycles).await,
+                // `Stepping` stops before every clock.
+                Some(waits) => {
+                    for _ in 0..clock_cycles {
+                        stepping::pause(&waits).await;
+                        self.advance(1).await;
+                    }
+                }
+            }
+        }
+
+        /// Let `clock_cycles` go by, catching the APU up if it should.
+        async fn advance(&mut self, clock_cycles: u32) {
+            self.cycles += clock_cycles;
+            self.scheduler.step(scheduler::CPU, clock_cycles);
+            if self.sync == SyncMode::Eager || self.scheduler.far_ahead(scheduler::CPU) {
+                self.sync_apu().await;
+            }
+        }
+
+        /// Hand the scheduler to the APU task if the APU is behind and wait
+        /// for it to come back.
+        async fn sync_apu(&mut self) {
+            if self.scheduler.behind() != scheduler::CPU {
+                let scheduler = std::mem::take(&mut self.scheduler);
+                self.to_apu.send(scheduler).await.expect("the APU task stopped");
+                let (scheduler, state) = self.from_apu.next().await.expect("the APU task stopped");
+                self.scheduler = scheduler;
+                self.apu_state = state;
             }
         }
     }
 
-    pub fn main(iters: usize) {
-        let mut cpu = CPU::new();
-        for _ in 0..iters {
-            while cpu.execute_instruction() == false {}
-            //println!("ran for 1 instruction");
-            //println!("instruction count: {}", cpu.instruction_count);
-            //println!("cycle count: {}", cpu.cycles);
+    impl Cpu for CPU {
+        fn step(&mut self) {
+            self.run(1);
+        }
+
+        fn run(&mut self, iters: usize) {
+            // The pool is taken out for the duration of the run so that the
+            // future below can borrow the rest of the CPU mutably.
+            let mut pool = self.pool.take().expect("local pool already in use");
+            let cpu = &mut *self;
+            pool.run_until(async move {
+                for _ in 0..iters {
+                    cpu.execute_instruction().await;
+                    //println!("ran for 1 instruction");
+                    //println!("instruction count: {}", cpu.instruction_count);
+                    //println!("cycle count: {}", cpu.cycles);
+                }
+            });
+            self.pool = Some(pool);
+        }
+
+        fn registers(&self) -> Registers {
+            self.regs
+        }
+
+        fn set_registers(&mut self, regs: Registers) {
+            self.regs = regs;
+        }
+
+        fn cycles(&self) -> u32 {
+            self.cycles
+        }
+
+        fn instruction_count(&self) -> u32 {
+            self.instruction_count
+        }
+
+        fn synchronize(&mut self) {
+            let mut pool = self.pool.take().expect("local pool already in use");
+            pool.run_until(self.sync_apu());
+            self.pool = Some(pool);
+        }
+
+        fn apu(&self) -> spc700::State {
+            self.apu_state
+        }
+
+        fn trace(&mut self, sink: Box<dyn trace::Sink>) {
+            self.trace = Some(sink);
+        }
+
+        fn load(&mut self, image: &[u8], pc: u16) {
+            self.bus.load(0, image);
+            self.regs.pc = pc;
+        }
+
+        fn reset_registers(&mut self) {
+            self.regs = Registers::power_on(self.model);
+            self.cycles = 0;
+            self.instruction_count = 0;
         }
     }
-}
 
-mod generator_attempt {
-    use super::*;
-    use std::ops::{Generator, GeneratorState};
-    use std::pin::Pin;
+    /// An instruction in progress, along with the pool it needs to run, which
+    /// goes back to the CPU once the instruction is done.
+    pub struct Instruction {
+        pool: Option<LocalPool>,
+        resuming: Resuming<CPU>,
+    }
 
-    // Stolen from: https://github.com/kylewlacy/lochnes/blob/fbd3824b6be3362f2bc117f7eef9e4e9f34c2918/src/gen_utils.rs
-    #[macro_export]
-    macro_rules! yield_all {
-        ($gen: expr) => {{
-            use std::ops::{Generator, GeneratorState};
-            use std::pin::Pin;
+    impl Resumable for CPU {
+        type Instruction = Instruction;
 
-            let mut gen = $gen;
-            loop {
-                match Pin::new(&mut gen).resume(()) {
-                    GeneratorState::Yielded(yielded) => {
-                        yield yielded;
-                    }
-                    GeneratorState::Complete(result) => {
-                        break result;
-                    }
+        fn start(mut cpu: Box<CPU>) -> Instruction {
+            let pool = cpu.pool.take();
+            let waits = Waits::default();
+            cpu.waits = Some(waits.clone());
+            let resuming = Resuming::new(waits, async move {
+                cpu.execute_instruction().await;
+                cpu.waits = None;
+                cpu
+            });
+            Instruction { pool, resuming }
+        }
+
+        fn resume(instruction: &mut Instruction) -> Progress<CPU> {
+            let pool = instruction.pool.as_mut().expect("local pool already in use");
+            let resuming = &mut instruction.resuming;
+            match pool.run_until(futures::future::poll_fn(|cx| resuming.poll(cx))) {
+                Progress::Done(mut cpu) => {
+                    cpu.pool = instruction.pool.take();
+                    Progress::Done(cpu)
                 }
+                waited => waited,
             }
-        }};
+        }
     }
+}
+
+mod smol_attempt {
+    use super::*;
+    use smol::channel::{self, Receiver, Sender};
+    use smol::LocalExecutor;
 
-    struct CPU {
-        pc: u16,
-        x: u8,
-        y: u8,
-        a: u8,
-        apu_counter: u32,
+    pub struct CPU {
+        model: Model,
+        regs: Registers,
+        /// Where the CPU and APU are in time. Goes along to the APU task
+        /// while it runs.
+        scheduler: Scheduler,
+        sync: SyncMode,
+        to_apu: Sender<Scheduler>,
+        from_apu: Receiver<(Scheduler, spc700::State)>,
+        apu_state: spc700::State,
         cycles: u32,
         instruction_count: u32,
-        mem: [u8; MEM_SIZE],
+        /// Gets every bus access once `Cpu::trace` has been called.
+        trace: Option<Box<dyn trace::Sink>>,
+        /// Set while `stepping::Stepping` has an instruction in progress,
+        /// see `stepping::pause`.
+        waits: Option<Waits>,
+        bus: Box<dyn Bus>,
+        executor: Option<LocalExecutor<'static>>,
+    }
+
+    macro_rules! bus_read {
+        ($cpu:ident, $addr:expr) => {
+            $cpu.read_memory(u32::from($addr)).await
+        };
+    }
+    macro_rules! bus_write {
+        ($cpu:ident, $addr:expr, $data:expr) => {
+            $cpu.write_memory(u32::from($addr), $data).await
+        };
+    }
+    macro_rules! bus_idle {
+        ($cpu:ident) => {
+            $cpu.wait(6).await
+        };
+    }
+
+    /// The APU as a task of its own. It waits for the CPU to hand over the
+    /// scheduler and hands it back once it has caught up.
+    async fn apu_task(
+        mut apu: Apu,
+        from_cpu: Receiver<Scheduler>,
+        to_cpu: Sender<(Scheduler, spc700::State)>,
+    ) {
+        while let Ok(mut scheduler) = from_cpu.recv().await {
+            apu.run(&mut scheduler);
+            if to_cpu.send((scheduler, apu.state())).await.is_err() {
+                break;
+            }
+        }
     }
 
     impl CPU {
-        pub fn new() -> CPU {
+        pub fn new(model: Model, sync: SyncMode, bus: Box<dyn Bus>, apu: Apu) -> CPU {
+            let executor = LocalExecutor::new();
+            let (to_apu, from_cpu) = channel::bounded(1);
+            let (to_cpu, from_apu) = channel::bounded(1);
+            let apu_state = apu.state();
+            // It only gets to run inside `LocalExecutor::run`, like the CPU.
+            executor.spawn(apu_task(apu, from_cpu, to_cpu)).detach();
             CPU {
-                pc: 0,
-                x: 0,
-                y: 0,
-                a: 0,
-                apu_counter: 0,
-                mem: [0xb9; MEM_SIZE],
+                model,
+                regs: Registers::power_on(model),
+                scheduler: Scheduler::snes(),
+                sync,
+                to_apu,
+                from_apu,
+                apu_state,
+                bus,
                 cycles: 0,
                 instruction_count: 0,
+                trace: None,
+                waits: None,
+                executor: Some(executor),
             }
         }
         /*
@@ -586,106 +1170,259 @@ mod generator_attempt {
           }
         }
         */
-        pub fn execute_instruction<'a>(
-            &'a mut self,
-        ) -> impl Generator<Yield = (), Return = ()> + 'a {
-            move || {
-                //println!("execute instruction");
-                let opcode = yield_all!(self.read_memory(self.pc));
-                self.pc += 1;
-                match opcode {
-                    // 8-bit accumulator instructions
-                    0xb9 => {
-                        //println!("found 0xb9 instruction");
-                        let address: u16 = yield_all!(self.read_memory(self.pc)).into();
-                        self.pc += 1;
-                        let address: u16 =
-                            yield_all!(self.read_memory(self.pc)) as u16 | address << 8;
-                        self.pc += 1;
-                        if address >> 8 != address + self.y as u16 >> 8 {
-                            yield_all!(self.wait(6));
-                        }
-                        self.a = yield_all!(self.read_memory(address + self.y as u16));
-                    }
-                    _ => {
-                        // do nothing
+        pub async fn execute_instruction(&mut self) {
+            //println!("execute instruction");
+            match self.model {
+                Model::Nmos6502 => execute_6502!(self, bus_read, bus_write),
+                Model::Wdc65816 => execute_65816!(self, bus_read, bus_write, bus_idle),
+            }
+            self.instruction_count += 1;
+        }
+
+        async fn read_memory(&mut self, addr: u32) -> u8 {
+            //println!("read_memory");
+            let cycles = self.bus.cycles(addr);
+            self.wait(2).await;
+            if self.sync == SyncMode::Lazy && self.bus.shared(addr) {
+                self.sync_apu().await;
+            }
+            let data = self.bus.read(addr);
+            trace::record(&mut self.trace, self.cycles, addr, data, trace::Kind::Read);
+            self.wait(cycles - 2).await;
+            data
+        }
+
+        async fn write_memory(&mut self, addr: u32, data: u8) {
+            //println!("write_memory");
+            let cycles = self.bus.cycles(addr);
+            self.wait(2).await;
+            if self.sync == SyncMode::Lazy && self.bus.shared(addr) {
+                self.sync_apu().await;
+            }
+            self.bus.write(addr, data);
+            trace::record(&mut self.trace, self.cycles, addr, data, trace::Kind::Write);
+            self.wait(cycles - 2).await;
+        }
+
+        async fn wait(&mut self, clock_cycles: u32) {
+            //println!("wait for {} cycles", clock_cycles);
+            match self.waits.clone() {
+                None => self.advance(clock_cycles).await,
+                // `Stepping` stops before every clock.
+                Some(waits) => {
+                    for _ in 0..clock_cycles {
+                        stepping::pause(&waits).await;
+                        self.advance(1).await;
                     }
                 }
-                self.instruction_count += 1;
-                yield;
             }
         }
 
-        fn read_memory<'a>(
-            &'a mut self,
-            addr: u16,
-        ) -> impl Generator<Yield = (), Return = u8> + 'a {
-            move || {
-                //println!("read_memory");
-                yield_all!(self.wait(2));
-                let data = self.mem[addr as usize];
-                yield_all!(self.wait(4));
-                data
+        /// Let `clock_cycles` go by, catching the APU up if it should.
+        async fn advance(&mut self, clock_cycles: u32) {
+            self.cycles += clock_cycles;
+            self.scheduler.step(scheduler::CPU, clock_cycles);
+            if self.sync == SyncMode::Eager || self.scheduler.far_ahead(scheduler::CPU) {
+                self.sync_apu().await;
             }
         }
 
-        fn wait<'a>(
-            &'a mut self,
-            clock_cycles: u32,
-        ) -> impl Generator<Yield = (), Return = ()> + 'a {
-            move || {
-                //println!("wait for {} cycles", clock_cycles);
-                self.apu_counter += clock_cycles;
-                while self.apu_counter > 0 {
-                    yield;
-                    self.apu_counter -= 1;
-                    self.cycles += 1;
-                    //println!("total cycles {}", self.cycles);
-                }
+        /// Hand the scheduler to the APU task if the APU is behind and wait
+        /// for it to come back.
+        async fn sync_apu(&mut self) {
+            if self.scheduler.behind() != scheduler::CPU {
+                let scheduler = std::mem::take(&mut self.scheduler);
+                self.to_apu.send(scheduler).await.expect("the APU task stopped");
+                let (scheduler, state) = self.from_apu.recv().await.expect("the APU task stopped");
+                self.scheduler = scheduler;
+                self.apu_state = state;
             }
         }
     }
 
-    pub fn main(iters: usize) {
-        let mut cpu = CPU::new();
-        for _ in 0..iters {
-            match Pin::new(&mut CPU::execute_instruction(&mut cpu)).resume(()) {
-                GeneratorState::Yielded { .. } => {
-                    //println!("Instruction compeleted");
+    impl Cpu for CPU {
+        fn step(&mut self) {
+            self.run(1);
+        }
+
+        fn run(&mut self, iters: usize) {
+            // The executor is taken out for the duration of the run so that
+            // the future below can borrow the rest of the CPU mutably.
+            let executor = self.executor.take().expect("smol executor already in use");
+            let cpu = &mut *self;
+            smol::block_on(executor.run(async move {
+                for _ in 0..iters {
+                    cpu.execute_instruction().await;
+                    //println!("ran for 1 instruction");
+                    //println!("instruction count: {}", cpu.instruction_count);
+                    //println!("cycle count: {}", cpu.cycles);
                 }
-                GeneratorState::Complete { .. } => {
-                    println!("generator shouldn't return");
+            }));
+            self.executor = Some(executor);
+        }
+
+        fn registers(&self) -> Registers {
+            self.regs
+        }
+
+        fn set_registers(&mut self, regs: Registers) {
+            self.regs = regs;
+        }
+
+        fn cycles(&self) -> u32 {
+            self.cycles
+        }
+
+        fn instruction_count(&self) -> u32 {
+            self.instruction_count
+        }
+
+        fn synchronize(&mut self) {
+            let executor = self.executor.take().expect("smol executor already in use");
+            smol::block_on(executor.run(self.sync_apu()));
+            self.executor = Some(executor);
+        }
+
+        fn apu(&self) -> spc700::State {
+            self.apu_state
+        }
+
+        fn trace(&mut self, sink: Box<dyn trace::Sink>) {
+            self.trace = Some(sink);
+        }
+
+        fn load(&mut self, image: &[u8], pc: u16) {
+            self.bus.load(0, image);
+            self.regs.pc = pc;
+        }
+
+        fn reset_registers(&mut self) {
+            self.regs = Registers::power_on(self.model);
+            self.cycles = 0;
+            self.instruction_count = 0;
+        }
+    }
+
+    /// An instruction in progress, along with the executor it needs to run, which
+    /// goes back to the CPU once the instruction is done.
+    pub struct Instruction {
+        executor: Option<LocalExecutor<'static>>,
+        resuming: Resuming<CPU>,
+    }
+
+    impl Resumable for CPU {
+        type Instruction = Instruction;
+
+        fn start(mut cpu: Box<CPU>) -> Instruction {
+            let executor = cpu.executor.take();
+            let waits = Waits::default();
+            cpu.waits = Some(waits.clone());
+            let resuming = Resuming::new(waits, async move {
+                cpu.execute_instruction().await;
+                cpu.waits = None;
+                cpu
+            });
+            Instruction { executor, resuming }
+        }
+
+        fn resume(instruction: &mut Instruction) -> Progress<CPU> {
+            let executor = instruction.executor.as_ref().expect("smol executor already in use");
+            let resuming = &mut instruction.resuming;
+            match smol::block_on(executor.run(futures::future::poll_fn(|cx| resuming.poll(cx)))) {
+                Progress::Done(mut cpu) => {
+                    cpu.executor = instruction.executor.take();
+                    Progress::Done(cpu)
                 }
+                waited => waited,
             }
         }
     }
 }
 
-mod null_attempt {
+mod executor_attempt {
     use super::*;
+    use crate::executor::{self, yield_once};
+    use std::cell::RefCell;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::rc::Rc;
+
+    /// What the CPU and the APU task hand each other on every yield.
+    #[derive(Default)]
+    struct Handoff {
+        scheduler: Scheduler,
+        apu_state: spc700::State,
+    }
+
+    type Task = Pin<Box<dyn Future<Output = ()>>>;
 
-    struct CPU {
-        pc: u16,
-        x: u8,
-        y: u8,
-        a: u8,
-        apu_counter: u32,
+    pub struct CPU {
+        model: Model,
+        regs: Registers,
+        /// Where the CPU and APU are in time. Goes along to the APU task
+        /// while it runs.
+        scheduler: Scheduler,
+        sync: SyncMode,
+        handoff: Rc<RefCell<Handoff>>,
+        apu_state: spc700::State,
         cycles: u32,
         instruction_count: u32,
-        mem: [u8; MEM_SIZE],
+        /// Gets every bus access once `Cpu::trace` has been called.
+        trace: Option<Box<dyn trace::Sink>>,
+        /// Set while `stepping::Stepping` has an instruction in progress,
+        /// see `stepping::pause`.
+        waits: Option<Waits>,
+        bus: Box<dyn Bus>,
+        apu_task: Option<Task>,
+    }
+
+    macro_rules! bus_read {
+        ($cpu:ident, $addr:expr) => {
+            $cpu.read_memory(u32::from($addr)).await
+        };
+    }
+    macro_rules! bus_write {
+        ($cpu:ident, $addr:expr, $data:expr) => {
+            $cpu.write_memory(u32::from($addr), $data).await
+        };
+    }
+    macro_rules! bus_idle {
+        ($cpu:ident) => {
+            $cpu.wait(6).await
+        };
+    }
+
+    /// The APU as a task of its own. It only gets polled when the CPU has
+    /// yielded, and it catches up and yields back.
+    async fn apu_task(mut apu: Apu, handoff: Rc<RefCell<Handoff>>) {
+        loop {
+            {
+                let mut handoff = handoff.borrow_mut();
+                apu.run(&mut handoff.scheduler);
+                handoff.apu_state = apu.state();
+            }
+            yield_once().await;
+        }
     }
 
     impl CPU {
-        pub fn new() -> CPU {
+        pub fn new(model: Model, sync: SyncMode, bus: Box<dyn Bus>, apu: Apu) -> CPU {
+            let apu_state = apu.state();
+            let handoff = Rc::new(RefCell::new(Handoff::default()));
+            let apu_task = Box::pin(apu_task(apu, handoff.clone()));
             CPU {
-                pc: 0,
-                x: 0,
-                y: 0,
-                a: 0,
-                apu_counter: 0,
-                mem: [0xb9; MEM_SIZE],
+                model,
+                regs: Registers::power_on(model),
+                scheduler: Scheduler::snes(),
+                sync,
+                handoff,
+                apu_state,
+                bus,
                 cycles: 0,
                 instruction_count: 0,
+                trace: None,
+                waits: None,
+                apu_task: Some(apu_task),
             }
         }
         /*
@@ -701,101 +1438,3209 @@ mod null_attempt {
           }
         }
         */
-        pub fn execute_instruction(&mut self) {
+        pub async fn execute_instruction(&mut self) {
             //println!("execute instruction");
-            let opcode = self.read_memory(self.pc);
-            self.pc += 1;
-            match opcode {
-                // 8-bit accumulator instructions
-                0xb9 => {
-                    //println!("found 0xb9 instruction");
-                    let address: u16 = self.read_memory(self.pc).into();
-                    self.pc += 1;
-                    let address: u16 = self.read_memory(self.pc) as u16 | address << 8;
-                    self.pc += 1;
-                    if address >> 8 != address + self.y as u16 >> 8 {
+            match self.model {
+                Model::Nmos6502 => execute_6502!(self, bus_read, bus_write),
+                Model::Wdc65816 => execute_65816!(self, bus_read, bus_write, bus_idle),
+            }
+            self.instruction_count += 1;
+        }
+
+        async fn read_memory(&mut self, addr: u32) -> u8 {
+            //println!("read_memory");
+            let cycles = self.bus.cycles(addr);
+            self.wait(2).await;
+            if self.sync == SyncMode::Lazy && self.bus.shared(addr) {
+                self.sync_apu().await;
+            }
+            let data = self.bus.read(addr);
+            trace::record(&mut self.trace, self.cycles, addr, data, trace::Kind::Read);
+            self.wait(cycles - 2).await;
+            data
+        }
+
+        async fn write_memory(&mut self, addr: u32, data: u8) {
+            //println!("write_memory");
+            let cycles = self.bus.cycles(addr);
+            self.wait(2).await;
+            if self.sync == SyncMode::Lazy && self.bus.shared(addr) {
+                self.sync_apu().await;
+            }
+            self.bus.write(addr, data);
+            trace::record(&mut self.trace, self.cycles, addr, data, trace::Kind::Write);
+            self.wait(cycles - 2).await;
+        }
+
+        async fn wait(&mut self, clock_cycles: u32) {
+            //println!("wait for {} cycles", clock_cycles);
+            match self.waits.clone() {
+                None => self.advance(clock_cycles).await,
+                // `Stepping` stops before every clock.
+                Some(waits) => {
+                    for _ in 0..clock_cycles {
+                        stepping::pause(&waits).await;
+                        self.advance(1).await;
+                    }
+                }
+            }
+        }
+
+        /// Let `clock_cycles` go by, catching the APU up if it should.
+        async fn advance(&mut self, clock_cycles: u32) {
+            self.cycles += clock_cycles;
+            self.scheduler.step(scheduler::CPU, clock_cycles);
+            if self.sync == SyncMode::Eager || self.scheduler.far_ahead(scheduler::CPU) {
+                self.sync_apu().await;
+            }
+        }
+
+        /// Hand the scheduler to the APU task if the APU is behind and yield
+        /// so it gets polled.
+        async fn sync_apu(&mut self) {
+            if self.scheduler.behind() != scheduler::CPU {
+                self.handoff.borrow_mut().scheduler = std::mem::take(&mut self.scheduler);
+                yield_once().await;
+                let mut handoff = self.handoff.borrow_mut();
+                self.scheduler = std::mem::take(&mut handoff.scheduler);
+                self.apu_state = handoff.apu_state;
+            }
+        }
+    }
+
+    impl Cpu for CPU {
+        fn step(&mut self) {
+            self.run(1);
+        }
+
+        fn run(&mut self, iters: usize) {
+            // The APU task is taken out for the duration of the run so that
+            // the future below can borrow the rest of the CPU mutably.
+            let mut apu_task = self.apu_task.take().expect("APU task already in use");
+            let cpu = &mut *self;
+            let cpu_task = async move {
+                for _ in 0..iters {
+                    cpu.execute_instruction().await;
+                    //println!("ran for 1 instruction");
+                    //println!("instruction count: {}", cpu.instruction_count);
+                    //println!("cycle count: {}", cpu.cycles);
+
+                }
+            };
+            executor::block_on(cpu_task, &mut [apu_task.as_mut()]);
+            self.apu_task = Some(apu_task);
+        }
+
+        fn registers(&self) -> Registers {
+            self.regs
+        }
+
+        fn set_registers(&mut self, regs: Registers) {
+            self.regs = regs;
+        }
+
+        fn cycles(&self) -> u32 {
+            self.cycles
+        }
+
+        fn instruction_count(&self) -> u32 {
+            self.instruction_count
+        }
+
+        fn synchronize(&mut self) {
+            let mut apu_task = self.apu_task.take().expect("APU task already in use");
+            executor::block_on(self.sync_apu(), &mut [apu_task.as_mut()]);
+            self.apu_task = Some(apu_task);
+        }
+
+        fn apu(&self) -> spc700::State {
+            self.apu_state
+        }
+
+        fn trace(&mut self, sink: Box<dyn trace::Sink>) {
+            self.trace = Some(sink);
+        }
+
+        fn load(&mut self, image: &[u8], pc: u16) {
+            self.bus.load(0, image);
+            self.regs.pc = pc;
+        }
+
+        fn reset_registers(&mut self) {
+            self.regs = Registers::power_on(self.model);
+            self.cycles = 0;
+            self.instruction_count = 0;
+        }
+    }
+
+    /// An instruction in progress, along with the APU task it needs to run, which
+    /// goes back to the CPU once the instruction is done.
+    pub struct Instruction {
+        apu_task: Option<Task>,
+        resuming: Resuming<CPU>,
+    }
+
+    impl Resumable for CPU {
+        type Instruction = Instruction;
+
+        fn start(mut cpu: Box<CPU>) -> Instruction {
+            let apu_task = cpu.apu_task.take();
+            let waits = Waits::default();
+            cpu.waits = Some(waits.clone());
+            let resuming = Resuming::new(waits, async move {
+                cpu.execute_instruction().await;
+                cpu.waits = None;
+                cpu
+            });
+            Instruction { apu_task, resuming }
+        }
+
+        fn resume(instruction: &mut Instruction) -> Progress<CPU> {
+            let apu_task = instruction.apu_task.as_mut().expect("APU task already in use");
+            let resuming = &mut instruction.resuming;
+            let instruction_task = futures::future::poll_fn(|cx| resuming.poll(cx));
+            match executor::block_on(instruction_task, &mut [apu_task.as_mut()]) {
+                Progress::Done(mut cpu) => {
+                    cpu.apu_task = instruction.apu_task.take();
+                    Progress::Done(cpu)
+                }
+                waited => waited,
+            }
+        }
+    }
+}
+
+mod threads_attempt {
+    use super::*;
+    use std::sync::{Arc, Condvar, Mutex};
+    use std::thread::JoinHandle;
+
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    enum Turn {
+        Cpu,
+        Apu,
+        /// The CPU went away, so the APU thread should too.
+        Quit,
+    }
+
+    /// What the CPU and the APU thread hand each other on every switch.
+    struct Handoff {
+        turn: Turn,
+        scheduler: Scheduler,
+        apu_state: spc700::State,
+    }
+
+    /// Only one of the two threads runs at a time; the other sleeps on
+    /// `changed` until `turn` says it's up.
+    struct Baton {
+        handoff: Mutex<Handoff>,
+        changed: Condvar,
+    }
+
+    pub struct CPU {
+        model: Model,
+        regs: Registers,
+        /// Where the CPU and APU are in time. Goes along to the APU thread
+        /// while it runs.
+        scheduler: Scheduler,
+        sync: SyncMode,
+        baton: Arc<Baton>,
+        /// Only `None` once it's been joined.
+        apu_thread: Option<JoinHandle<()>>,
+        apu_state: spc700::State,
+        cycles: u32,
+        instruction_count: u32,
+        /// Gets every bus access once `Cpu::trace` has been called.
+        trace: Option<Box<dyn trace::Sink>>,
+        /// Set while `stepping::Stepping` has an instruction in progress,
+        /// see `stepping::OnThread`.
+        ticker: Option<Ticker>,
+        bus: Box<dyn Bus>,
+    }
+
+    macro_rules! bus_read {
+        ($cpu:ident, $addr:expr) => {
+            $cpu.read_memory(u32::from($addr))
+        };
+    }
+    macro_rules! bus_write {
+        ($cpu:ident, $addr:expr, $data:expr) => {
+            $cpu.write_memory(u32::from($addr), $data)
+        };
+    }
+    macro_rules! bus_idle {
+        ($cpu:ident) => {
+            $cpu.wait(6)
+        };
+    }
+
+    /// The APU on an OS thread of its own. Every time the CPU hands it the
+    /// baton, it runs until it has caught up and hands it back.
+    fn apu_thread(mut apu: Apu, baton: Arc<Baton>) {
+        let mut handoff = baton.handoff.lock().expect("the CPU thread panicked");
+        loop {
+            handoff = baton
+                .changed
+                .wait_while(handoff, |h| h.turn == Turn::Cpu)
+                .expect("the CPU thread panicked");
+            if handoff.turn == Turn::Quit {
+                return;
+            }
+            apu.run(&mut handoff.scheduler);
+            handoff.apu_state = apu.state();
+            handoff.turn = Turn::Cpu;
+            baton.changed.notify_one();
+        }
+    }
+
+    impl CPU {
+        pub fn new(model: Model, sync: SyncMode, bus: Box<dyn Bus>, apu: Apu) -> CPU {
+            let apu_state = apu.state();
+            let baton = Arc::new(Baton {
+                handoff: Mutex::new(Handoff {
+                    turn: Turn::Cpu,
+                    scheduler: Scheduler::new(),
+                    apu_state,
+                }),
+                changed: Condvar::new(),
+            });
+            let apu_thread = {
+                let baton = baton.clone();
+                std::thread::spawn(move || apu_thread(apu, baton))
+            };
+            CPU {
+                model,
+                regs: Registers::power_on(model),
+                scheduler: Scheduler::snes(),
+                sync,
+                baton,
+                apu_thread: Some(apu_thread),
+                apu_state,
+                bus,
+                cycles: 0,
+                instruction_count: 0,
+                trace: None,
+                ticker: None,
+            }
+        }
+        /*
+        void CPU::executeInstruction() {
+          opcode = readMemory(PC++);
+          if(FlagM)
+          switch(opcode) {  //8-bit accumulator instructions
+          case 0xb9:
+            address = readMemory(PC++);
+            address = readMemory(PC++) | address << 8;
+            if(address >> 8 != address + Y >> 8) wait(6);
+            A = readMemory(address + Y);
+          }
+        }
+        */
+        pub fn execute_instruction(&mut self) {
+            //println!("execute instruction");
+            match self.model {
+                Model::Nmos6502 => execute_6502!(self, bus_read, bus_write),
+                Model::Wdc65816 => execute_65816!(self, bus_read, bus_write, bus_idle),
+            }
+            self.instruction_count += 1;
+        }
+
+        fn read_memory(&mut self, addr: u32) -> u8 {
+            //println!("read_memory");
+            let cycles = self.bus.cycles(addr);
+            self.wait(2);
+            if self.sync == SyncMode::Lazy && self.bus.shared(addr) {
+                self.sync_apu();
+            }
+            let data = self.bus.read(addr);
+            trace::record(&mut self.trace, self.cycles, addr, data, trace::Kind::Read);
+            self.wait(cycles - 2);
+            data
+        }
+
+        fn write_memory(&mut self, addr: u32, data: u8) {
+            //println!("write_memory");
+            let cycles = self.bus.cycles(addr);
+            self.wait(2);
+            if self.sync == SyncMode::Lazy && self.bus.shared(addr) {
+                self.sync_apu();
+            }
+            self.bus.write(addr, data);
+            trace::record(&mut self.trace, self.cycles, addr, data, trace::Kind::Write);
+            self.wait(cycles - 2);
+        }
+
+        fn wait(&mut self, clock_cycles: u32) {
+            //println!("wait for {} cycles", clock_cycles);
+            match self.ticker.take() {
+                None => self.advance(clock_cycles),
+                // `Stepping` stops before every clock.
+                Some(ticker) => {
+                    for _ in 0..clock_cycles {
+                        ticker.tick();
+                        self.advance(1);
+                    }
+                    self.ticker = Some(ticker);
+                }
+            }
+        }
+
+        /// Let `clock_cycles` go by, catching the APU up if it should.
+        fn advance(&mut self, clock_cycles: u32) {
+            self.cycles += clock_cycles;
+            self.scheduler.step(scheduler::CPU, clock_cycles);
+            if self.sync == SyncMode::Eager || self.scheduler.far_ahead(scheduler::CPU) {
+                self.sync_apu();
+            }
+        }
+
+        /// Hand the baton to the APU thread if the APU is behind and sleep
+        /// until it comes back.
+        fn sync_apu(&mut self) {
+            if self.scheduler.behind() != scheduler::CPU {
+                let mut handoff = self.baton.handoff.lock().expect("the APU thread panicked");
+                handoff.scheduler = std::mem::take(&mut self.scheduler);
+                handoff.turn = Turn::Apu;
+                self.baton.changed.notify_one();
+                let mut handoff = self
+                    .baton
+                    .changed
+                    .wait_while(handoff, |h| h.turn == Turn::Apu)
+                    .expect("the APU thread panicked");
+                self.scheduler = std::mem::take(&mut handoff.scheduler);
+                self.apu_state = handoff.apu_state;
+            }
+        }
+    }
+
+    impl Drop for CPU {
+        fn drop(&mut self) {
+            if let Ok(mut handoff) = self.baton.handoff.lock() {
+                handoff.turn = Turn::Quit;
+                self.baton.changed.notify_one();
+            }
+            if let Some(thread) = self.apu_thread.take() {
+                // A panic on the APU thread has been reported already.
+                let _ = thread.join();
+            }
+        }
+    }
+
+    impl Cpu for CPU {
+        fn step(&mut self) {
+            self.execute_instruction();
+            //println!("ran for 1 instruction");
+            //println!("instruction count: {}", self.instruction_count);
+            //println!("cycle count: {}", self.cycles);
+        }
+
+        fn registers(&self) -> Registers {
+            self.regs
+        }
+
+        fn set_registers(&mut self, regs: Registers) {
+            self.regs = regs;
+        }
+
+        fn cycles(&self) -> u32 {
+            self.cycles
+        }
+
+        fn instruction_count(&self) -> u32 {
+            self.instruction_count
+        }
+
+        fn synchronize(&mut self) {
+            self.sync_apu();
+        }
+
+        fn apu(&self) -> spc700::State {
+            self.apu_state
+        }
+
+        fn trace(&mut self, sink: Box<dyn trace::Sink>) {
+            self.trace = Some(sink);
+        }
+
+        fn load(&mut self, image: &[u8], pc: u16) {
+            self.bus.load(0, image);
+            self.regs.pc = pc;
+        }
+
+        fn reset_registers(&mut self) {
+            self.regs = Registers::power_on(self.model);
+            self.cycles = 0;
+            self.instruction_count = 0;
+        }
+    }
+
+    impl Resumable for CPU {
+        type Instruction = OnThread<CPU>;
+
+        fn start(cpu: Box<CPU>) -> OnThread<CPU> {
+            OnThread::new(cpu, |cpu, ticker| {
+                cpu.ticker = Some(ticker);
+                cpu.step();
+                cpu.ticker = None;
+            })
+        }
+
+        fn resume(instruction: &mut OnThread<CPU>) -> Progress<CPU> {
+            instruction.resume()
+        }
+    }
+}
+
+mod enum_attempt {
+    use super::*;
+
+    pub struct CPU {
+        model: Model,
+        regs: Registers,
+        /// Where the CPU and APU are in time.
+        scheduler: Scheduler,
+        sync: SyncMode,
+        apu: Apu,
+        cycles: u32,
+        cycle: u32,
+        subcycle: u32,
+        instruction_count: u32,
+        /// Gets every bus access once `Cpu::trace` has been called.
+        trace: Option<Box<dyn trace::Sink>>,
+        /// Set while `stepping::Stepping` has an instruction in progress,
+        /// see `stepping::OnThread`.
+        ticker: Option<Ticker>,
+        opcode: u8,
+        address: u16,
+        /// The operand of a read-modify-write instruction between its read
+        /// and its write.
+        data: u8,
+        bus: Box<dyn Bus>,
+    }
+
+    impl CPU {
+        pub fn new(model: Model, sync: SyncMode, bus: Box<dyn Bus>, apu: Apu) -> CPU {
+            CPU {
+                model,
+                regs: Registers::power_on(model),
+                scheduler: Scheduler::snes(),
+                sync,
+                apu,
+                bus,
+                cycles: 0,
+                cycle: 1,
+                subcycle: 1,
+                opcode: 0,
+                address: 0,
+                data: 0,
+                instruction_count: 0,
+                trace: None,
+                ticker: None,
+            }
+        }
+        /*
+                void CPU::executeInstructionCycle() {
+                  if(cycle == 1) {
+                    opcode = readMemory(PC++);
+                    cycle = 2;
+                    return;
+                  }
+                  if(FlagM)
+                  switch(opcode) {  //8-bit accumulator instructions
+                  case 0xb9:
+                    switch(cycle) {
+                    case 2:
+                      switch(subcycle) {
+                      case 1:
+                        subcycle = 2;
+                        return;
+                      case 2:
+                        address = readMemory(PC++);
+                        subcycle = 1;
+                        return;
+                      }
+                    case 3:
+                      switch(subcycle) {
+                      case 1:
+                        subcycle = 2;
+                        return;
+                      case 2:
+                        address = readMemory(PC++) | address << 8;
+                        subcycle = 1;
+                        return;
+                      }
+                    case 4:
+                      //possible penalty cycle when crossing 8-bit page boundaries:
+                      if(address >> 8 != address + Y >> 8) {
+                        return;
+                      }
+                      cycle++;  //cycle 4 not needed; fall through to cycle 5
+                    case 5:
+                      switch(subcycle) {
+                      case 1:
+                        subcycle = 2;
+                        return;
+                      case 2:
+                        A = readMemory(address + Y);
+                        subcycle = 1;
+                        cycle = 0;  //end of instruction; start a new instruction next time
+                        return;
+                      }
+                    }
+                  }
+                }
+        */
+        pub fn execute_instruction(&mut self) -> bool {
+            //println!("execute instruction");
+            if self.cycle == 1 {
+                //println!("fetch opcode");
+                self.opcode = self.read_memory(self.regs.pc);
+                self.regs.pc = self.regs.pc.wrapping_add(1);
+                self.cycle = 2;
+                return false;
+            }
+            match self.opcode {
+                // 8-bit accumulator instructions
+                0xb9 => {
+                    //println!("decode 0xb9");
+                    match self.cycle {
+                        2 => match self.subcycle {
+                            1 => {
+                                //println!("waiting");
+                                self.subcycle = 2;
+                                return false;
+                            }
+                            2 => {
+                                //println!("reading byte");
+                                self.address = self.read_memory(self.regs.pc) as u16;
+                                self.regs.pc = self.regs.pc.wrapping_add(1);
+                                self.subcycle = 1;
+                                self.cycle = 3;
+                                return false;
+                            }
+                            _ => {}
+                        },
+                        3 => match self.subcycle {
+                            1 => {
+                                //println!("waiting to read other byte");
+                                self.subcycle = 2;
+                                return false;
+                            }
+                            2 => {
+                                //println!("reading other byte");
+                                self.address |= (self.read_memory(self.regs.pc) as u16) << 8;
+                                self.regs.pc = self.regs.pc.wrapping_add(1);
+                                self.subcycle = 1;
+                                self.cycle = 4;
+                                return false;
+                            }
+                            _ => {}
+                        },
+                        4 => {
+                            //println!("cycle 4");
+                            self.cycle += 1;
+                            // possible penalty cycle when crossing 8-bit page boundaries
+                            if self.address >> 8 != (self.address.wrapping_add(self.regs.y) >> 8) {
+                                self.wait(6);
+                                return false;
+                            }
+                        }
+                        5 => match self.subcycle {
+                            1 => {
+                                //println!("cycle 5 wait");
+                                self.subcycle = 2;
+                                return false;
+                            }
+                            2 => {
+                                //println!("cycle 5 done");
+                                let data = self.read_memory(self.address.wrapping_add(self.regs.y));
+                                self.lda(data);
+                                self.subcycle = 1;
+                                self.cycle = 1;
+                                self.instruction_count += 1;
+                                return true;
+                            }
+                            _ => {}
+                        },
+                        _ => {}
+                    }
+                }
+                // STA abs,Y
+                0x99 => match self.cycle {
+                    2 | 3 => self.absolute_operand(),
+                    4 => match self.subcycle {
+                        1 => {
+                            self.subcycle = 2;
+                            return false;
+                        }
+                        2 => {
+                            // stores always pay for the page crossing
+                            let address = self.address.wrapping_add(self.regs.y);
+                            self.dummy_read(self.address & 0xff00 | address & 0x00ff);
+                            self.subcycle = 1;
+                            self.cycle = 5;
+                            return false;
+                        }
+                        _ => {}
+                    },
+                    5 => match self.subcycle {
+                        1 => {
+                            self.subcycle = 2;
+                            return false;
+                        }
+                        2 => {
+                            let address = self.address.wrapping_add(self.regs.y);
+                            self.write_memory(address, self.regs.a as u8);
+                            self.subcycle = 1;
+                            self.cycle = 1;
+                            self.instruction_count += 1;
+                            return true;
+                        }
+                        _ => {}
+                    },
+                    _ => {}
+                },
+                // INC abs, DEC abs
+                0xee | 0xce => match self.cycle {
+                    2 | 3 => self.absolute_operand(),
+                    4 => match self.subcycle {
+                        1 => {
+                            self.subcycle = 2;
+                            return false;
+                        }
+                        2 => {
+                            self.data = self.read_memory(self.address);
+                            self.subcycle = 1;
+                            self.cycle = 5;
+                            return false;
+                        }
+                        _ => {}
+                    },
+                    5 => match self.subcycle {
+                        1 => {
+                            self.subcycle = 2;
+                            return false;
+                        }
+                        2 => {
+                            // the 6502 writes the unmodified value back while
+                            // it works out the new one
+                            self.dummy_write(self.address, self.data);
+                            self.subcycle = 1;
+                            self.cycle = 6;
+                            return false;
+                        }
+                        _ => {}
+                    },
+                    6 => match self.subcycle {
+                        1 => {
+                            self.subcycle = 2;
+                            return false;
+                        }
+                        2 => {
+                            let data = self.modify(self.data);
+                            self.write_memory(self.address, data);
+                            self.subcycle = 1;
+                            self.cycle = 1;
+                            self.instruction_count += 1;
+                            return true;
+                        }
+                        _ => {}
+                    },
+                    _ => {}
+                },
+                // PHA
+                0x48 => match self.cycle {
+                    2 => match self.subcycle {
+                        1 => {
+                            self.subcycle = 2;
+                            return false;
+                        }
+                        2 => {
+                            self.dummy_read(self.regs.pc);
+                            self.subcycle = 1;
+                            self.cycle = 3;
+                            return false;
+                        }
+                        _ => {}
+                    },
+                    3 => match self.subcycle {
+                        1 => {
+                            self.subcycle = 2;
+                            return false;
+                        }
+                        2 => {
+                            self.write_memory(self.regs.s, self.regs.a as u8);
+                            self.regs.s = 0x0100 | (self.regs.s.wrapping_sub(1) & 0x00ff);
+                            self.subcycle = 1;
+                            self.cycle = 1;
+                            self.instruction_count += 1;
+                            return true;
+                        }
+                        _ => {}
+                    },
+                    _ => {}
+                },
+                // PLA
+                0x68 => match self.cycle {
+                    2 => match self.subcycle {
+                        1 => {
+                            self.subcycle = 2;
+                            return false;
+                        }
+                        2 => {
+                            self.dummy_read(self.regs.pc);
+                            self.subcycle = 1;
+                            self.cycle = 3;
+                            return false;
+                        }
+                        _ => {}
+                    },
+                    3 => match self.subcycle {
+                        1 => {
+                            self.subcycle = 2;
+                            return false;
+                        }
+                        2 => {
+                            self.dummy_read(self.regs.s);
+                            self.subcycle = 1;
+                            self.cycle = 4;
+                            return false;
+                        }
+                        _ => {}
+                    },
+                    4 => match self.subcycle {
+                        1 => {
+                            self.subcycle = 2;
+                            return false;
+                        }
+                        2 => {
+                            self.regs.s = 0x0100 | (self.regs.s.wrapping_add(1) & 0x00ff);
+                            let data = self.read_memory(self.regs.s);
+                            self.lda(data);
+                            self.subcycle = 1;
+                            self.cycle = 1;
+                            self.instruction_count += 1;
+                            return true;
+                        }
+                        _ => {}
+                    },
+                    _ => {}
+                },
+                // `full_isa` keeps the programs that need more away
+                _ => unreachable!("opcode {:#04x} isn't implemented", self.opcode),
+            }
+            false
+        }
+
+        /// Cycles 2 and 3 of the absolute instructions other than 0xb9, which
+        /// spells them out: the operand, low byte first.
+        fn absolute_operand(&mut self) {
+            if self.subcycle == 1 {
+                self.subcycle = 2;
+                return;
+            }
+            let data = self.read_memory(self.regs.pc) as u16;
+            self.regs.pc = self.regs.pc.wrapping_add(1);
+            if self.cycle == 2 {
+                self.address = data;
+            } else {
+                self.address |= data << 8;
+            }
+            self.subcycle = 1;
+            self.cycle += 1;
+        }
+
+        /// The 6502 reads something on every cycle, where the 65816 has
+        /// internal operations that leave the bus alone.
+        fn dummy_read(&mut self, addr: u16) {
+            match self.model {
+                Model::Nmos6502 => {
+                    self.read_memory(addr);
+                }
+                Model::Wdc65816 => self.wait(6),
+            }
+        }
+
+        fn dummy_write(&mut self, addr: u16, data: u8) {
+            match self.model {
+                Model::Nmos6502 => self.write_memory(addr, data),
+                Model::Wdc65816 => self.wait(6),
+            }
+        }
+
+        /// The 65816 keeps the high byte of the accumulator.
+        fn lda(&mut self, data: u8) {
+            match self.model {
+                Model::Nmos6502 => self.regs.lda(data),
+                Model::Wdc65816 => w65816::lda(&mut self.regs, data as u16),
+            }
+        }
+
+        /// The ALU half of INC and DEC. The 65816 never leaves emulation mode
+        /// here, so the memory operand is always 8 bits.
+        fn modify(&mut self, data: u8) -> u8 {
+            match (self.model, self.opcode) {
+                (Model::Nmos6502, 0xee) => self.regs.inc(data),
+                (Model::Nmos6502, _) => self.regs.dec(data),
+                (Model::Wdc65816, 0xee) => w65816::inc(&mut self.regs, data as u16, false) as u8,
+                (Model::Wdc65816, _) => w65816::dec(&mut self.regs, data as u16, false) as u8,
+            }
+        }
+
+        fn read_memory(&mut self, addr: u16) -> u8 {
+            //println!("read_memory");
+            let cycles = self.bus.cycles(addr as u32);
+            self.wait(2);
+            if self.sync == SyncMode::Lazy && self.bus.shared(addr as u32) {
+                self.sync_apu();
+            }
+            let data = self.bus.read(addr as u32);
+            trace::record(&mut self.trace, self.cycles, addr as u32, data, trace::Kind::Read);
+            self.wait(cycles - 2);
+            data
+        }
+
+        fn write_memory(&mut self, addr: u16, data: u8) {
+            //println!("write_memory");
+            let cycles = self.bus.cycles(addr as u32);
+            self.wait(2);
+            if self.sync == SyncMode::Lazy && self.bus.shared(addr as u32) {
+                self.sync_apu();
+            }
+            self.bus.write(addr as u32, data);
+            trace::record(&mut self.trace, self.cycles, addr as u32, data, trace::Kind::Write);
+            self.wait(cycles - 2);
+        }
+
+        fn wait(&mut self, clock_cycles: u32) {
+            //println!("wait for {} cycles", clock_cycles);
+            match self.ticker.take() {
+                None => self.advance(clock_cycles),
+                // `Stepping` stops before every clock.
+                Some(ticker) => {
+                    for _ in 0..clock_cycles {
+                        ticker.tick();
+                        self.advance(1);
+                    }
+                    self.ticker = Some(ticker);
+                }
+            }
+        }
+
+        /// Let `clock_cycles` go by, catching the APU up if it should.
+        fn advance(&mut self, clock_cycles: u32) {
+            self.cycles += clock_cycles;
+            self.scheduler.step(scheduler::CPU, clock_cycles);
+            if self.sync == SyncMode::Eager || self.scheduler.far_ahead(scheduler::CPU) {
+                self.sync_apu();
+            }
+        }
+
+        fn sync_apu(&mut self) {
+            if self.scheduler.behind() != scheduler::CPU {
+                // The APU is a state machine that only ever stops between
+                // its instructions, so catching it up doesn't need any
+                // subcycle bookkeeping on this side.
+                self.apu.run(&mut self.scheduler);
+            }
+        }
+    }
+
+    impl Cpu for CPU {
+        fn step(&mut self) {
+            while !self.execute_instruction() {}
+            //println!("ran for 1 instruction");
+            //println!("instruction count: {}", self.instruction_count);
+            //println!("cycle count: {}", self.cycles);
+        }
+
+        fn full_isa(&self) -> bool {
+            false
+        }
+
+        fn registers(&self) -> Registers {
+            self.regs
+        }
+
+        fn set_registers(&mut self, regs: Registers) {
+            self.regs = regs;
+        }
+
+        fn cycles(&self) -> u32 {
+            self.cycles
+        }
+
+        fn instruction_count(&self) -> u32 {
+            self.instruction_count
+        }
+
+        fn synchronize(&mut self) {
+            self.sync_apu();
+        }
+
+        fn apu(&self) -> spc700::State {
+            self.apu.state()
+        }
+
+        fn trace(&mut self, sink: Box<dyn trace::Sink>) {
+            self.trace = Some(sink);
+        }
+
+        fn load(&mut self, image: &[u8], pc: u16) {
+            self.bus.load(0, image);
+            self.regs.pc = pc;
+        }
+
+        fn reset_registers(&mut self) {
+            self.regs = Registers::power_on(self.model);
+            self.cycles = 0;
+            self.cycle = 1;
+            self.subcycle = 1;
+            self.instruction_count = 0;
+            self.opcode = 0;
+            self.address = 0;
+            self.data = 0;
+        }
+    }
+
+    impl Resumable for CPU {
+        type Instruction = OnThread<CPU>;
+
+        fn start(cpu: Box<CPU>) -> OnThread<CPU> {
+            OnThread::new(cpu, |cpu, ticker| {
+                cpu.ticker = Some(ticker);
+                cpu.step();
+                cpu.ticker = None;
+            })
+        }
+
+        fn resume(instruction: &mut OnThread<CPU>) -> Progress<CPU> {
+            instruction.resume()
+        }
+    }
+}
+
+mod macro_attempt {
+    use super::*;
+    use emu_test_macros::state_machine;
+
+    pub struct CPU {
+        model: Model,
+        regs: Registers,
+        /// Where the CPU and APU are in time.
+        scheduler: Scheduler,
+        sync: SyncMode,
+        apu: Apu,
+        cycles: u32,
+        instruction_count: u32,
+        /// Gets every bus access once `Cpu::trace` has been called.
+        trace: Option<Box<dyn trace::Sink>>,
+        /// Set while `stepping::Stepping` has an instruction in progress,
+        /// see `stepping::OnThread`.
+        ticker: Option<Ticker>,
+        /// How far `execute_instruction` has got, generated by
+        /// `#[state_machine]`.
+        execute: ExecuteInstruction,
+        bus: Box<dyn Bus>,
+    }
+
+    #[state_machine]
+    impl CPU {
+        pub fn new(model: Model, sync: SyncMode, bus: Box<dyn Bus>, apu: Apu) -> CPU {
+            CPU {
+                model,
+                regs: Registers::power_on(model),
+                scheduler: Scheduler::snes(),
+                sync,
+                apu,
+                bus,
+                cycles: 0,
+                instruction_count: 0,
+                trace: None,
+                ticker: None,
+                execute: ExecuteInstruction::default(),
+            }
+        }
+
+        /// The instructions of `enum_attempt`, written the way `null_attempt`
+        /// would and compiled into the same kind of state machine. Every
+        /// call does one bus access or wait and returns whether the
+        /// instruction is done.
+        #[resumable(execute: ExecuteInstruction, suspend(read_memory, write_memory, wait, dummy_read, dummy_write))]
+        pub fn execute_instruction(&mut self) {
+            let opcode: u8 = self.read_memory(self.regs.pc);
+            self.regs.pc = self.regs.pc.wrapping_add(1);
+            match opcode {
+                // LDA abs,Y
+                0xb9 => {
+                    let mut address: u16 = self.read_memory(self.regs.pc) as u16;
+                    self.regs.pc = self.regs.pc.wrapping_add(1);
+                    address |= (self.read_memory(self.regs.pc) as u16) << 8;
+                    self.regs.pc = self.regs.pc.wrapping_add(1);
+                    // possible penalty cycle when crossing 8-bit page boundaries
+                    if address >> 8 != (address.wrapping_add(self.regs.y) >> 8) {
                         self.wait(6);
                     }
-                    self.a = self.read_memory(address + self.y as u16);
+                    let data: u8 = self.read_memory(address.wrapping_add(self.regs.y));
+                    self.lda(data);
+                }
+                // STA abs,Y
+                0x99 => {
+                    let mut address: u16 = self.read_memory(self.regs.pc) as u16;
+                    self.regs.pc = self.regs.pc.wrapping_add(1);
+                    address |= (self.read_memory(self.regs.pc) as u16) << 8;
+                    self.regs.pc = self.regs.pc.wrapping_add(1);
+                    // stores always pay for the page crossing
+                    let indexed: u16 = address.wrapping_add(self.regs.y);
+                    self.dummy_read(address & 0xff00 | indexed & 0x00ff);
+                    self.write_memory(indexed, self.regs.a as u8);
+                }
+                // INC abs, DEC abs
+                0xee | 0xce => {
+                    let mut address: u16 = self.read_memory(self.regs.pc) as u16;
+                    self.regs.pc = self.regs.pc.wrapping_add(1);
+                    address |= (self.read_memory(self.regs.pc) as u16) << 8;
+                    self.regs.pc = self.regs.pc.wrapping_add(1);
+                    let data: u8 = self.read_memory(address);
+                    // the 6502 writes the unmodified value back while it
+                    // works out the new one
+                    self.dummy_write(address, data);
+                    let data: u8 = self.modify(opcode, data);
+                    self.write_memory(address, data);
+                }
+                // PHA
+                0x48 => {
+                    self.dummy_read(self.regs.pc);
+                    self.write_memory(self.regs.s, self.regs.a as u8);
+                    self.regs.s = 0x0100 | (self.regs.s.wrapping_sub(1) & 0x00ff);
+                }
+                // PLA
+                0x68 => {
+                    self.dummy_read(self.regs.pc);
+                    self.dummy_read(self.regs.s);
+                    self.regs.s = 0x0100 | (self.regs.s.wrapping_add(1) & 0x00ff);
+                    let data: u8 = self.read_memory(self.regs.s);
+                    self.lda(data);
+                }
+                // `full_isa` keeps the programs that need more away
+                _ => unreachable!("opcode {:#04x} isn't implemented", opcode),
+            }
+            self.instruction_count += 1;
+        }
+
+        /// The 6502 reads something on every cycle, where the 65816 has
+        /// internal operations that leave the bus alone.
+        fn dummy_read(&mut self, addr: u16) {
+            match self.model {
+                Model::Nmos6502 => {
+                    self.read_memory(addr);
+                }
+                Model::Wdc65816 => self.wait(6),
+            }
+        }
+
+        fn dummy_write(&mut self, addr: u16, data: u8) {
+            match self.model {
+                Model::Nmos6502 => self.write_memory(addr, data),
+                Model::Wdc65816 => self.wait(6),
+            }
+        }
+
+        /// The 65816 keeps the high byte of the accumulator.
+        fn lda(&mut self, data: u8) {
+            match self.model {
+                Model::Nmos6502 => self.regs.lda(data),
+                Model::Wdc65816 => w65816::lda(&mut self.regs, data as u16),
+            }
+        }
+
+        /// The ALU half of INC and DEC.
+        fn modify(&mut self, opcode: u8, data: u8) -> u8 {
+            match (self.model, opcode) {
+                (Model::Nmos6502, 0xee) => self.regs.inc(data),
+                (Model::Nmos6502, _) => self.regs.dec(data),
+                (Model::Wdc65816, 0xee) => w65816::inc(&mut self.regs, data as u16, false) as u8,
+                (Model::Wdc65816, _) => w65816::dec(&mut self.regs, data as u16, false) as u8,
+            }
+        }
+
+        fn read_memory(&mut self, addr: u16) -> u8 {
+            //println!("read_memory");
+            let cycles = self.bus.cycles(addr as u32);
+            self.wait(2);
+            if self.sync == SyncMode::Lazy && self.bus.shared(addr as u32) {
+                self.sync_apu();
+            }
+            let data = self.bus.read(addr as u32);
+            trace::record(&mut self.trace, self.cycles, addr as u32, data, trace::Kind::Read);
+            self.wait(cycles - 2);
+            data
+        }
+
+        fn write_memory(&mut self, addr: u16, data: u8) {
+            //println!("write_memory");
+            let cycles = self.bus.cycles(addr as u32);
+            self.wait(2);
+            if self.sync == SyncMode::Lazy && self.bus.shared(addr as u32) {
+                self.sync_apu();
+            }
+            self.bus.write(addr as u32, data);
+            trace::record(&mut self.trace, self.cycles, addr as u32, data, trace::Kind::Write);
+            self.wait(cycles - 2);
+        }
+
+        fn wait(&mut self, clock_cycles: u32) {
+            //println!("wait for {} cycles", clock_cycles);
+            match self.ticker.take() {
+                None => self.advance(clock_cycles),
+                // `Stepping` stops before every clock.
+                Some(ticker) => {
+                    for _ in 0..clock_cycles {
+                        ticker.tick();
+                        self.advance(1);
+                    }
+                    self.ticker = Some(ticker);
+                }
+            }
+        }
+
+        /// Let `clock_cycles` go by, catching the APU up if it should.
+        fn advance(&mut self, clock_cycles: u32) {
+            self.cycles += clock_cycles;
+            self.scheduler.step(scheduler::CPU, clock_cycles);
+            if self.sync == SyncMode::Eager || self.scheduler.far_ahead(scheduler::CPU) {
+                self.sync_apu();
+            }
+        }
+
+        fn sync_apu(&mut self) {
+            if self.scheduler.behind() != scheduler::CPU {
+                self.apu.run(&mut self.scheduler);
+            }
+        }
+    }
+
+    impl Cpu for CPU {
+        fn step(&mut self) {
+            while !self.execute_instruction() {}
+        }
+
+        fn full_isa(&self) -> bool {
+            false
+        }
+
+        fn registers(&self) -> Registers {
+            self.regs
+        }
+
+        fn set_registers(&mut self, regs: Registers) {
+            self.regs = regs;
+        }
+
+        fn cycles(&self) -> u32 {
+            self.cycles
+        }
+
+        fn instruction_count(&self) -> u32 {
+            self.instruction_count
+        }
+
+        fn synchronize(&mut self) {
+            self.sync_apu();
+        }
+
+        fn apu(&self) -> spc700::State {
+            self.apu.state()
+        }
+
+        fn trace(&mut self, sink: Box<dyn trace::Sink>) {
+            self.trace = Some(sink);
+        }
+
+        fn load(&mut self, image: &[u8], pc: u16) {
+            self.bus.load(0, image);
+            self.regs.pc = pc;
+        }
+
+        fn reset_registers(&mut self) {
+            self.regs = Registers::power_on(self.model);
+            self.cycles = 0;
+            self.instruction_count = 0;
+            self.execute = ExecuteInstruction::default();
+        }
+    }
+
+    impl Resumable for CPU {
+        type Instruction = OnThread<CPU>;
+
+        fn start(cpu: Box<CPU>) -> OnThread<CPU> {
+            OnThread::new(cpu, |cpu, ticker| {
+                cpu.ticker = Some(ticker);
+                cpu.step();
+                cpu.ticker = None;
+            })
+        }
+
+        fn resume(instruction: &mut OnThread<CPU>) -> Progress<CPU> {
+            instruction.resume()
+        }
+    }
+}
+
+mod generator_attempt {
+    use super::*;
+    use std::cell::Cell;
+    use std::convert::Infallible;
+    use std::ops::{Generator, GeneratorState};
+    use std::pin::Pin;
+    use std::rc::Rc;
+
+    // Stolen from: https://github.com/kylewlacy/lochnes/blob/fbd3824b6be3362f2bc117f7eef9e4e9f34c2918/src/gen_utils.rs
+    #[macro_export]
+    macro_rules! yield_all {
+        ($gen: expr) => {{
+            use std::ops::{Generator, GeneratorState};
+            use std::pin::Pin;
+
+            let mut gen = $gen;
+            loop {
+                match Pin::new(&mut gen).resume(()) {
+                    GeneratorState::Yielded(yielded) => {
+                        yield yielded;
+                    }
+                    GeneratorState::Complete(result) => {
+                        break result;
+                    }
+                }
+            }
+        }};
+    }
+
+    pub struct CPU {
+        model: Model,
+        regs: Registers,
+        /// Where the CPU and APU are in time.
+        scheduler: Scheduler,
+        sync: SyncMode,
+        /// Only `None` while `step` is running it.
+        apu: Option<Pin<Box<dyn Generator<Yield = spc700::State, Return = Infallible>>>>,
+        apu_state: spc700::State,
+        cycles: u32,
+        instruction_count: u32,
+        /// Gets every bus access once `Cpu::trace` has been called.
+        trace: Option<Box<dyn trace::Sink>>,
+        /// Set while `stepping::Stepping` has an instruction in progress, so
+        /// it stops before every clock. `wait` sets the flag right before
+        /// yielding there, which is how `stepped` tells those yields apart.
+        pauses: Option<Rc<Cell<bool>>>,
+        bus: Box<dyn Bus>,
+    }
+
+    macro_rules! bus_read {
+        ($cpu:ident, $addr:expr) => {
+            yield_all!($cpu.read_memory(u32::from($addr)))
+        };
+    }
+    macro_rules! bus_write {
+        ($cpu:ident, $addr:expr, $data:expr) => {
+            yield_all!($cpu.write_memory(u32::from($addr), $data))
+        };
+    }
+    macro_rules! bus_idle {
+        ($cpu:ident) => {
+            yield_all!($cpu.wait(6))
+        };
+    }
+
+    /// The APU as a generator of its own, yielding after every instruction.
+    fn apu_thread(mut apu: Apu) -> impl Generator<Yield = spc700::State, Return = Infallible> {
+        move || {
+            loop {
+                apu.execute_instruction();
+                yield apu.state();
+            }
+        }
+    }
+
+    /// Resume the APU until it isn't behind anymore, keeping track of its
+    /// cycles in `scheduler`.
+    fn run_apu(
+        mut apu: Pin<&mut dyn Generator<Yield = spc700::State, Return = Infallible>>,
+        scheduler: &mut Scheduler,
+        apu_state: &mut spc700::State,
+    ) {
+        while scheduler.behind() != scheduler::CPU {
+            match apu.as_mut().resume(()) {
+                GeneratorState::Yielded(state) => {
+                    let cycles = state.cycles.wrapping_sub(apu_state.cycles);
+                    scheduler.step(scheduler::APU, cycles * spc700::CLOCKS_PER_CYCLE);
+                    *apu_state = state;
+                }
+                GeneratorState::Complete(never) => match never {},
+            }
+        }
+    }
+
+    /// `CPU::step` as a generator that owns the CPU, for `Stepping`. It
+    /// yields before every clock of the instruction.
+    fn stepped(mut cpu: Box<CPU>) -> impl Generator<Yield = (), Return = Box<CPU>> {
+        let pauses = Rc::new(Cell::new(false));
+        cpu.pauses = Some(pauses.clone());
+        static move || {
+            let mut apu = cpu.apu.take().expect("APU already in use");
+            let mut scheduler = std::mem::take(&mut cpu.scheduler);
+            let mut apu_state = cpu.apu_state;
+            let mut cycles = cpu.cycles;
+            let sync = cpu.sync;
+            let mut instruction = CPU::execute_instruction(&mut cpu);
+            while let GeneratorState::Yielded(now) = Pin::new(&mut instruction).resume(()) {
+                if pauses.take() {
+                    yield;
+                    continue;
+                }
+                let waited = now.wrapping_sub(cycles);
+                scheduler.step(scheduler::CPU, waited);
+                cycles = now;
+                // The only other yields are the ones in front of shared state
+                // with `SyncMode::Lazy`, right after a clock that yielded.
+                if sync == SyncMode::Eager || waited == 0 || scheduler.far_ahead(scheduler::CPU) {
+                    run_apu(apu.as_mut(), &mut scheduler, &mut apu_state);
+                }
+            }
+            drop(instruction);
+            cpu.apu = Some(apu);
+            cpu.scheduler = scheduler;
+            cpu.apu_state = apu_state;
+            cpu.pauses = None;
+            cpu
+        }
+    }
+
+    impl CPU {
+        pub fn new(model: Model, sync: SyncMode, bus: Box<dyn Bus>, apu: Apu) -> CPU {
+            let apu_state = apu.state();
+            CPU {
+                model,
+                regs: Registers::power_on(model),
+                scheduler: Scheduler::snes(),
+                sync,
+                apu: Some(Box::pin(apu_thread(apu))),
+                apu_state,
+                bus,
+                cycles: 0,
+                instruction_count: 0,
+                trace: None,
+                pauses: None,
+            }
+        }
+        /*
+        void CPU::executeInstruction() {
+          opcode = readMemory(PC++);
+          if(FlagM)
+          switch(opcode) {  //8-bit accumulator instructions
+          case 0xb9:
+            address = readMemory(PC++);
+            address = readMemory(PC++) | address << 8;
+            if(address >> 8 != address + Y >> 8) wait(6);
+            A = readMemory(address + Y);
+          }
+        }
+        */
+        pub fn execute_instruction<'a>(
+            &'a mut self,
+        ) -> impl Generator<Yield = u32, Return = ()> + 'a {
+            move || {
+                //println!("execute instruction");
+                match self.model {
+                Model::Nmos6502 => execute_6502!(self, bus_read, bus_write),
+                Model::Wdc65816 => execute_65816!(self, bus_read, bus_write, bus_idle),
+            }
+                self.instruction_count += 1;
+            }
+        }
+
+        fn read_memory<'a>(
+            &'a mut self,
+            addr: u32,
+        ) -> impl Generator<Yield = u32, Return = u8> + 'a {
+            move || {
+                //println!("read_memory");
+                let cycles = self.bus.cycles(addr);
+                yield_all!(self.wait(2));
+                if self.sync == SyncMode::Lazy && self.bus.shared(addr) {
+                    yield self.cycles;
+                }
+                let data = self.bus.read(addr);
+                trace::record(&mut self.trace, self.cycles, addr, data, trace::Kind::Read);
+                yield_all!(self.wait(cycles - 2));
+                data
+            }
+        }
+
+        fn write_memory<'a>(
+            &'a mut self,
+            addr: u32,
+            data: u8,
+        ) -> impl Generator<Yield = u32, Return = ()> + 'a {
+            move || {
+                //println!("write_memory");
+                let cycles = self.bus.cycles(addr);
+                yield_all!(self.wait(2));
+                if self.sync == SyncMode::Lazy && self.bus.shared(addr) {
+                    yield self.cycles;
+                }
+                self.bus.write(addr, data);
+                trace::record(&mut self.trace, self.cycles, addr, data, trace::Kind::Write);
+                yield_all!(self.wait(cycles - 2));
+            }
+        }
+
+        fn wait<'a>(
+            &'a mut self,
+            clock_cycles: u32,
+        ) -> impl Generator<Yield = u32, Return = ()> + 'a {
+            move || {
+                //println!("wait for {} cycles", clock_cycles);
+                if let Some(pauses) = self.pauses.clone() {
+                    for _ in 0..clock_cycles {
+                        pauses.set(true);
+                        yield self.cycles;
+                        self.cycles += 1;
+                        yield self.cycles;
+                    }
+                } else {
+                    self.cycles += clock_cycles;
+                    if self.sync == SyncMode::Eager {
+                        yield self.cycles;
+                    }
+                }
+            }
+        }
+    }
+
+    impl Cpu for CPU {
+        fn step(&mut self) {
+            // The instruction yields the master clock cycle count whenever
+            // it wants to synchronize, and if that leaves the APU behind,
+            // the APU runs until it has caught up. The APU and the scheduler
+            // are taken out while the instruction borrows the rest of the
+            // CPU.
+            let mut apu = self.apu.take().expect("APU already in use");
+            let mut scheduler = std::mem::take(&mut self.scheduler);
+            let mut apu_state = self.apu_state;
+            let mut cycles = self.cycles;
+            let mut instruction = CPU::execute_instruction(self);
+            while let GeneratorState::Yielded(now) = Pin::new(&mut instruction).resume(()) {
+                scheduler.step(scheduler::CPU, now.wrapping_sub(cycles));
+                cycles = now;
+                run_apu(apu.as_mut(), &mut scheduler, &mut apu_state);
+            }
+            drop(instruction);
+            // Whatever the instruction waited for since it last yielded.
+            scheduler.step(scheduler::CPU, self.cycles.wrapping_sub(cycles));
+            // The waits don't yield with `SyncMode::Lazy`, so this is the
+            // first chance to catch the APU up if the CPU got too far ahead.
+            if scheduler.far_ahead(scheduler::CPU) {
+                run_apu(apu.as_mut(), &mut scheduler, &mut apu_state);
+            }
+            self.apu = Some(apu);
+            self.scheduler = scheduler;
+            self.apu_state = apu_state;
+        }
+
+        fn registers(&self) -> Registers {
+            self.regs
+        }
+
+        fn set_registers(&mut self, regs: Registers) {
+            self.regs = regs;
+        }
+
+        fn cycles(&self) -> u32 {
+            self.cycles
+        }
+
+        fn instruction_count(&self) -> u32 {
+            self.instruction_count
+        }
+
+        fn synchronize(&mut self) {
+            let mut apu = self.apu.take().expect("APU already in use");
+            run_apu(apu.as_mut(), &mut self.scheduler, &mut self.apu_state);
+            self.apu = Some(apu);
+        }
+
+        fn apu(&self) -> spc700::State {
+            self.apu_state
+        }
+
+        fn trace(&mut self, sink: Box<dyn trace::Sink>) {
+            self.trace = Some(sink);
+        }
+
+        fn load(&mut self, image: &[u8], pc: u16) {
+            self.bus.load(0, image);
+            self.regs.pc = pc;
+        }
+
+        fn reset_registers(&mut self) {
+            self.regs = Registers::power_on(self.model);
+            self.cycles = 0;
+            self.instruction_count = 0;
+        }
+    }
+
+    impl Resumable for CPU {
+        type Instruction = Pin<Box<dyn Generator<Yield = (), Return = Box<CPU>>>>;
+
+        fn start(cpu: Box<CPU>) -> Self::Instruction {
+            Box::pin(stepped(cpu))
+        }
+
+        fn resume(instruction: &mut Self::Instruction) -> Progress<CPU> {
+            match instruction.as_mut().resume(()) {
+                GeneratorState::Yielded(()) => Progress::Paused,
+                GeneratorState::Complete(cpu) => Progress::Done(cpu),
+            }
+        }
+    }
+}
+
+mod null_attempt {
+    use super::*;
+
+    pub struct CPU {
+        model: Model,
+        regs: Registers,
+        /// Where the CPU and APU are in time.
+        scheduler: Scheduler,
+        sync: SyncMode,
+        apu: Apu,
+        cycles: u32,
+        instruction_count: u32,
+        /// Gets every bus access once `Cpu::trace` has been called.
+        trace: Option<Box<dyn trace::Sink>>,
+        /// Set while `stepping::Stepping` has an instruction in progress,
+        /// see `stepping::OnThread`.
+        ticker: Option<Ticker>,
+        bus: Box<dyn Bus>,
+    }
+
+    macro_rules! bus_read {
+        ($cpu:ident, $addr:expr) => {
+            $cpu.read_memory(u32::from($addr))
+        };
+    }
+    macro_rules! bus_write {
+        ($cpu:ident, $addr:expr, $data:expr) => {
+            $cpu.write_memory(u32::from($addr), $data)
+        };
+    }
+    macro_rules! bus_idle {
+        ($cpu:ident) => {
+            $cpu.wait(6)
+        };
+    }
+
+    impl CPU {
+        pub fn new(model: Model, sync: SyncMode, bus: Box<dyn Bus>, apu: Apu) -> CPU {
+            CPU {
+                model,
+                regs: Registers::power_on(model),
+                scheduler: Scheduler::snes(),
+                sync,
+                apu,
+                bus,
+                cycles: 0,
+                instruction_count: 0,
+                trace: None,
+                ticker: None,
+            }
+        }
+        /*
+        void CPU::executeInstruction() {
+          opcode = readMemory(PC++);
+          if(FlagM)
+          switch(opcode) {  //8-bit accumulator instructions
+          case 0xb9:
+            address = readMemory(PC++);
+            address = readMemory(PC++) | address << 8;
+            if(address >> 8 != address + Y >> 8) wait(6);
+            A = readMemory(address + Y);
+          }
+        }
+        */
+        pub fn execute_instruction(&mut self) {
+            //println!("execute instruction");
+            match self.model {
+                Model::Nmos6502 => execute_6502!(self, bus_read, bus_write),
+                Model::Wdc65816 => execute_65816!(self, bus_read, bus_write, bus_idle),
+            }
+            self.instruction_count += 1;
+        }
+
+        fn read_memory(&mut self, addr: u32) -> u8 {
+            //println!("read_memory");
+            let cycles = self.bus.cycles(addr);
+            self.wait(2);
+            if self.sync == SyncMode::Lazy && self.bus.shared(addr) {
+                self.sync_apu();
+            }
+            let data = self.bus.read(addr);
+            trace::record(&mut self.trace, self.cycles, addr, data, trace::Kind::Read);
+            self.wait(cycles - 2);
+            data
+        }
+
+        fn write_memory(&mut self, addr: u32, data: u8) {
+            //println!("write_memory");
+            let cycles = self.bus.cycles(addr);
+            self.wait(2);
+            if self.sync == SyncMode::Lazy && self.bus.shared(addr) {
+                self.sync_apu();
+            }
+            self.bus.write(addr, data);
+            trace::record(&mut self.trace, self.cycles, addr, data, trace::Kind::Write);
+            self.wait(cycles - 2);
+        }
+
+        fn wait(&mut self, clock_cycles: u32) {
+            //println!("wait for {} cycles", clock_cycles);
+            match self.ticker.take() {
+                None => self.advance(clock_cycles),
+                // `Stepping` stops before every clock.
+                Some(ticker) => {
+                    for _ in 0..clock_cycles {
+                        ticker.tick();
+                        self.advance(1);
+                    }
+                    self.ticker = Some(ticker);
+                }
+            }
+        }
+
+        /// Let `clock_cycles` go by, catching the APU up if it should.
+        fn advance(&mut self, clock_cycles: u32) {
+            self.cycles += clock_cycles;
+            self.scheduler.step(scheduler::CPU, clock_cycles);
+            if self.sync == SyncMode::Eager || self.scheduler.far_ahead(scheduler::CPU) {
+                self.sync_apu();
+            }
+        }
+
+        fn sync_apu(&mut self) {
+            if self.scheduler.behind() != scheduler::CPU {
+                // Nothing to switch to, the APU just runs in a plain call.
+                self.apu.run(&mut self.scheduler);
+            }
+        }
+    }
+
+    impl Cpu for CPU {
+        fn step(&mut self) {
+            self.execute_instruction();
+            //println!("ran for 1 instruction");
+            //println!("instruction count: {}", self.instruction_count);
+            //println!("cycle count: {}", self.cycles);
+        }
+
+        fn registers(&self) -> Registers {
+            self.regs
+        }
+
+        fn set_registers(&mut self, regs: Registers) {
+            self.regs = regs;
+        }
+
+        fn cycles(&self) -> u32 {
+            self.cycles
+        }
+
+        fn instruction_count(&self) -> u32 {
+            self.instruction_count
+        }
+
+        fn synchronize(&mut self) {
+            self.sync_apu();
+        }
+
+        fn apu(&self) -> spc700::State {
+            self.apu.state()
+        }
+
+        fn trace(&mut self, sink: Box<dyn trace::Sink>) {
+            self.trace = Some(sink);
+        }
+
+        fn load(&mut self, image: &[u8], pc: u16) {
+            self.bus.load(0, image);
+            self.regs.pc = pc;
+        }
+
+        fn reset_registers(&mut self) {
+            self.regs = Registers::power_on(self.model);
+            self.cycles = 0;
+            self.instruction_count = 0;
+        }
+    }
+
+    impl Resumable for CPU {
+        type Instruction = OnThread<CPU>;
+
+        fn start(cpu: Box<CPU>) -> OnThread<CPU> {
+            OnThread::new(cpu, |cpu, ticker| {
+                cpu.ticker = Some(ticker);
+                cpu.step();
+                cpu.ticker = None;
+            })
+        }
+
+        fn resume(instruction: &mut OnThread<CPU>) -> Progress<CPU> {
+            instruction.resume()
+        }
+    }
+}
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+mod cothread_attempt {
+    use super::*;
+    use crate::cothread::Cothread;
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+
+    /// What the CPU and the APU thread hand each other on every switch.
+    #[derive(Default)]
+    struct Handoff {
+        scheduler: Scheduler,
+        apu_state: spc700::State,
+        /// Set when the CPU goes away, so the APU thread drops what it owns.
+        quit: bool,
+    }
+
+    pub struct CPU {
+        model: Model,
+        regs: Registers,
+        /// Where the CPU and APU are in time. Goes along to the APU thread
+        /// while it runs.
+        scheduler: Scheduler,
+        sync: SyncMode,
+        /// Whatever stack the CPU happens to run on, for the APU thread to
+        /// switch back to.
+        cpu_thread: Rc<Cothread>,
+        apu_thread: Rc<Cothread>,
+        handoff: Rc<RefCell<Handoff>>,
+        apu_state: spc700::State,
+        cycles: u32,
+        instruction_count: u32,
+        /// Gets every bus access once `Cpu::trace` has been called.
+        trace: Option<Box<dyn trace::Sink>>,
+        /// While `Stepping` has an instruction in progress, the thread it
+        /// runs on and the one to switch back to before every clock.
+        stepper: Option<(Rc<Cothread>, Rc<Cothread>)>,
+        bus: Box<dyn Bus>,
+    }
+
+    macro_rules! bus_read {
+        ($cpu:ident, $addr:expr) => {
+            $cpu.read_memory(u32::from($addr))
+        };
+    }
+    macro_rules! bus_write {
+        ($cpu:ident, $addr:expr, $data:expr) => {
+            $cpu.write_memory(u32::from($addr), $data)
+        };
+    }
+    macro_rules! bus_idle {
+        ($cpu:ident) => {
+            $cpu.wait(6)
+        };
+    }
+
+    /// The APU on a stack of its own. Every time the CPU switches to it, it
+    /// runs until it has caught up and switches back.
+    fn apu_thread(mut apu: Apu, cpu_thread: Rc<Cothread>, handoff: Rc<RefCell<Handoff>>) -> Rc<Cothread> {
+        Cothread::new(move |me| {
+            while !handoff.borrow().quit {
+                {
+                    let mut handoff = handoff.borrow_mut();
+                    apu.run(&mut handoff.scheduler);
+                    handoff.apu_state = apu.state();
                 }
-                _ => {
-                    // do nothing
+                // Safety: the CPU is suspended in `sync_apu` with nothing
+                // borrowed.
+                unsafe { me.switch(&cpu_thread) };
+            }
+            // This stack is never coming back, so clean up by hand.
+            let back = Rc::as_ptr(&cpu_thread);
+            drop(apu);
+            drop(handoff);
+            drop(cpu_thread);
+            // Safety: the CPU's `drop` is waiting on the other end and
+            // still holds the CPU thread.
+            unsafe { me.switch(&*back) };
+            unreachable!("the APU thread was resumed after quitting");
+        })
+    }
+
+    impl CPU {
+        pub fn new(model: Model, sync: SyncMode, bus: Box<dyn Bus>, apu: Apu) -> CPU {
+            let apu_state = apu.state();
+            let cpu_thread = Cothread::current();
+            let handoff = Rc::new(RefCell::new(Handoff::default()));
+            CPU {
+                model,
+                regs: Registers::power_on(model),
+                scheduler: Scheduler::snes(),
+                sync,
+                apu_thread: apu_thread(apu, cpu_thread.clone(), handoff.clone()),
+                cpu_thread,
+                handoff,
+                apu_state,
+                bus,
+                cycles: 0,
+                instruction_count: 0,
+                trace: None,
+                stepper: None,
+            }
+        }
+        /*
+        void CPU::executeInstruction() {
+          opcode = readMemory(PC++);
+          if(FlagM)
+          switch(opcode) {  //8-bit accumulator instructions
+          case 0xb9:
+            address = readMemory(PC++);
+            address = readMemory(PC++) | address << 8;
+            if(address >> 8 != address + Y >> 8) wait(6);
+            A = readMemory(address + Y);
+          }
+        }
+        */
+        pub fn execute_instruction(&mut self) {
+            //println!("execute instruction");
+            match self.model {
+                Model::Nmos6502 => execute_6502!(self, bus_read, bus_write),
+                Model::Wdc65816 => execute_65816!(self, bus_read, bus_write, bus_idle),
+            }
+            self.instruction_count += 1;
+        }
+
+        fn read_memory(&mut self, addr: u32) -> u8 {
+            //println!("read_memory");
+            let cycles = self.bus.cycles(addr);
+            self.wait(2);
+            if self.sync == SyncMode::Lazy && self.bus.shared(addr) {
+                self.sync_apu();
+            }
+            let data = self.bus.read(addr);
+            trace::record(&mut self.trace, self.cycles, addr, data, trace::Kind::Read);
+            self.wait(cycles - 2);
+            data
+        }
+
+        fn write_memory(&mut self, addr: u32, data: u8) {
+            //println!("write_memory");
+            let cycles = self.bus.cycles(addr);
+            self.wait(2);
+            if self.sync == SyncMode::Lazy && self.bus.shared(addr) {
+                self.sync_apu();
+            }
+            self.bus.write(addr, data);
+            trace::record(&mut self.trace, self.cycles, addr, data, trace::Kind::Write);
+            self.wait(cycles - 2);
+        }
+
+        fn wait(&mut self, clock_cycles: u32) {
+            //println!("wait for {} cycles", clock_cycles);
+            if self.stepper.is_none() {
+                return self.advance(clock_cycles);
+            }
+            // `Stepping` stops before every clock.
+            for _ in 0..clock_cycles {
+                self.pause();
+                self.advance(1);
+            }
+        }
+
+        /// Let `clock_cycles` go by, catching the APU up if it should.
+        fn advance(&mut self, clock_cycles: u32) {
+            self.cycles += clock_cycles;
+            self.scheduler.step(scheduler::CPU, clock_cycles);
+            if self.sync == SyncMode::Eager || self.scheduler.far_ahead(scheduler::CPU) {
+                self.sync_apu();
+            }
+        }
+
+        /// Hand control back to whatever is stepping the CPU.
+        fn pause(&mut self) {
+            if let Some((thread, stepper)) = &self.stepper {
+                // Safety: `Stepped::resume` doesn't touch the CPU, which the
+                // instruction's thread owns.
+                unsafe { thread.switch(stepper) };
+            }
+        }
+
+        /// Switch to the APU thread if the APU is behind, right in the
+        /// middle of whatever the CPU is doing.
+        fn sync_apu(&mut self) {
+            if self.scheduler.behind() != scheduler::CPU {
+                self.handoff.borrow_mut().scheduler = std::mem::take(&mut self.scheduler);
+                // Safety: the APU thread only touches `handoff`, which
+                // isn't borrowed across the switch.
+                unsafe { self.cpu_thread.switch(&self.apu_thread) };
+                let mut handoff = self.handoff.borrow_mut();
+                self.scheduler = std::mem::take(&mut handoff.scheduler);
+                self.apu_state = handoff.apu_state;
+            }
+        }
+    }
+
+    impl Drop for CPU {
+        fn drop(&mut self) {
+            self.handoff.borrow_mut().quit = true;
+            // Safety: as in `sync_apu`.
+            unsafe { self.cpu_thread.switch(&self.apu_thread) };
+        }
+    }
+
+    impl Cpu for CPU {
+        fn step(&mut self) {
+            self.execute_instruction();
+            //println!("ran for 1 instruction");
+            //println!("instruction count: {}", self.instruction_count);
+            //println!("cycle count: {}", self.cycles);
+        }
+
+        fn registers(&self) -> Registers {
+            self.regs
+        }
+
+        fn set_registers(&mut self, regs: Registers) {
+            self.regs = regs;
+        }
+
+        fn cycles(&self) -> u32 {
+            self.cycles
+        }
+
+        fn instruction_count(&self) -> u32 {
+            self.instruction_count
+        }
+
+        fn synchronize(&mut self) {
+            self.sync_apu();
+        }
+
+        fn apu(&self) -> spc700::State {
+            self.apu_state
+        }
+
+        fn trace(&mut self, sink: Box<dyn trace::Sink>) {
+            self.trace = Some(sink);
+        }
+
+        fn load(&mut self, image: &[u8], pc: u16) {
+            self.bus.load(0, image);
+            self.regs.pc = pc;
+        }
+
+        fn reset_registers(&mut self) {
+            self.regs = Registers::power_on(self.model);
+            self.cycles = 0;
+            self.instruction_count = 0;
+        }
+    }
+
+    /// An instruction on a thread of its own, which switches back to
+    /// `stepper` before every clock. `cpu` hands the CPU to the thread when
+    /// it starts and back once the instruction is done.
+    pub struct Stepped {
+        thread: Rc<Cothread>,
+        stepper: Rc<Cothread>,
+        cpu: Rc<Cell<Option<Box<CPU>>>>,
+    }
+
+    impl Resumable for CPU {
+        type Instruction = Stepped;
+
+        fn start(mut cpu: Box<CPU>) -> Stepped {
+            let stepper = Cothread::current();
+            let slot = Rc::new(Cell::new(None));
+            let thread = {
+                let slot = slot.clone();
+                Cothread::new(move |me| {
+                    let mut cpu: Box<CPU> = slot.take().expect("instruction started without its CPU");
+                    cpu.step();
+                    let (_, stepper) = cpu.stepper.take().expect("the stepper went missing");
+                    slot.set(Some(cpu));
+                    // This stack is never coming back, so clean up by hand.
+                    let back = Rc::as_ptr(&stepper);
+                    drop(stepper);
+                    drop(slot);
+                    // Safety: `Stepped::resume` is waiting on the other end
+                    // and still holds the stepper.
+                    unsafe { me.switch(&*back) };
+                    unreachable!("an instruction was resumed after it was done");
+                })
+            };
+            cpu.stepper = Some((thread.clone(), stepper.clone()));
+            slot.set(Some(cpu));
+            Stepped {
+                thread,
+                stepper,
+                cpu: slot,
+            }
+        }
+
+        fn resume(instruction: &mut Stepped) -> Progress<CPU> {
+            // Safety: the instruction's thread is either fresh or suspended
+            // in `pause`, and nothing here is borrowed by it.
+            unsafe { instruction.stepper.switch(&instruction.thread) };
+            match instruction.cpu.take() {
+                Some(cpu) => Progress::Done(cpu),
+                None => Progress::Paused,
+            }
+        }
+    }
+}
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+mod libco_attempt {
+    use super::*;
+    use crate::libco::{co_active, co_create, co_delete, co_switch, Cothread};
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+
+    /// Stack size of the APU thread.
+    const STACK_SIZE: u32 = 1 << 20;
+
+    /// What the CPU and the APU thread hand each other on every switch.
+    #[derive(Default)]
+    struct Handoff {
+        scheduler: Scheduler,
+        apu_state: spc700::State,
+        /// Set when the CPU goes away, so the APU thread drops what it owns.
+        quit: bool,
+        /// Whatever thread the CPU is on, for the APU thread to switch back
+        /// to. That's not always the same one, since `Stepping` runs every
+        /// instruction on a thread of its own.
+        cpu_thread: Option<Cothread>,
+    }
+
+    pub struct CPU {
+        model: Model,
+        regs: Registers,
+        /// Where the CPU and APU are in time. Goes along to the APU thread
+        /// while it runs.
+        scheduler: Scheduler,
+        sync: SyncMode,
+        apu_thread: Cothread,
+        handoff: Rc<RefCell<Handoff>>,
+        apu_state: spc700::State,
+        cycles: u32,
+        instruction_count: u32,
+        /// Gets every bus access once `Cpu::trace` has been called.
+        trace: Option<Box<dyn trace::Sink>>,
+        /// What to switch back to before every clock while `Stepping` has
+        /// an instruction in progress.
+        stepper: Option<Cothread>,
+        bus: Box<dyn Bus>,
+    }
+
+    macro_rules! bus_read {
+        ($cpu:ident, $addr:expr) => {
+            $cpu.read_memory(u32::from($addr))
+        };
+    }
+    macro_rules! bus_write {
+        ($cpu:ident, $addr:expr, $data:expr) => {
+            $cpu.write_memory(u32::from($addr), $data)
+        };
+    }
+    macro_rules! bus_idle {
+        ($cpu:ident) => {
+            $cpu.wait(6)
+        };
+    }
+
+    /// What the APU thread starts out with. libco entry points don't take
+    /// arguments, so it's left here for `apu_main` to pick up.
+    struct ApuThread {
+        apu: Apu,
+        handoff: Rc<RefCell<Handoff>>,
+    }
+
+    thread_local! {
+        static STARTING: Cell<Option<Box<ApuThread>>> = const { Cell::new(None) };
+    }
+
+    /// The APU on a stack of its own. Every time the CPU switches to it, it
+    /// runs until it has caught up and switches back.
+    extern "C" fn apu_main() {
+        let ApuThread { mut apu, handoff } = *STARTING.with(Cell::take).expect("APU thread started without its APU");
+        while !handoff.borrow().quit {
+            {
+                let mut handoff = handoff.borrow_mut();
+                apu.run(&mut handoff.scheduler);
+                handoff.apu_state = apu.state();
+            }
+            let cpu_thread = handoff.borrow().cpu_thread.expect("nothing to switch back to");
+            // Safety: the CPU is suspended in `sync_apu` with nothing
+            // borrowed.
+            unsafe { co_switch(cpu_thread) };
+        }
+        // This stack is never coming back, so clean up by hand.
+        let cpu_thread = handoff.borrow().cpu_thread.expect("nothing to switch back to");
+        drop(apu);
+        drop(handoff);
+        // Safety: the CPU's `drop` is waiting on the other end.
+        unsafe { co_switch(cpu_thread) };
+        unreachable!("the APU thread was resumed after quitting");
+    }
+
+    impl CPU {
+        pub fn new(model: Model, sync: SyncMode, bus: Box<dyn Bus>, apu: Apu) -> CPU {
+            let apu_state = apu.state();
+            let handoff = Rc::new(RefCell::new(Handoff::default()));
+            // Safety: the new thread only runs `apu_main`, which switches
+            // straight back here as long as the scheduler it finds in the
+            // handoff is empty.
+            let apu_thread = unsafe {
+                handoff.borrow_mut().cpu_thread = Some(co_active());
+                let apu_thread = co_create(STACK_SIZE, apu_main);
+                assert!(!apu_thread.is_null(), "couldn't allocate the APU thread");
+                STARTING.with(|s| {
+                    s.set(Some(Box::new(ApuThread {
+                        apu,
+                        handoff: handoff.clone(),
+                    })))
+                });
+                co_switch(apu_thread);
+                apu_thread
+            };
+            CPU {
+                model,
+                regs: Registers::power_on(model),
+                scheduler: Scheduler::snes(),
+                sync,
+                apu_thread,
+                handoff,
+                apu_state,
+                bus,
+                cycles: 0,
+                instruction_count: 0,
+                trace: None,
+                stepper: None,
+            }
+        }
+        /*
+        void CPU::executeInstruction() {
+          opcode = readMemory(PC++);
+          if(FlagM)
+          switch(opcode) {  //8-bit accumulator instructions
+          case 0xb9:
+            address = readMemory(PC++);
+            address = readMemory(PC++) | address << 8;
+            if(address >> 8 != address + Y >> 8) wait(6);
+            A = readMemory(address + Y);
+          }
+        }
+        */
+        pub fn execute_instruction(&mut self) {
+            //println!("execute instruction");
+            match self.model {
+                Model::Nmos6502 => execute_6502!(self, bus_read, bus_write),
+                Model::Wdc65816 => execute_65816!(self, bus_read, bus_write, bus_idle),
+            }
+            self.instruction_count += 1;
+        }
+
+        fn read_memory(&mut self, addr: u32) -> u8 {
+            //println!("read_memory");
+            let cycles = self.bus.cycles(addr);
+            self.wait(2);
+            if self.sync == SyncMode::Lazy && self.bus.shared(addr) {
+                self.sync_apu();
+            }
+            let data = self.bus.read(addr);
+            trace::record(&mut self.trace, self.cycles, addr, data, trace::Kind::Read);
+            self.wait(cycles - 2);
+            data
+        }
+
+        fn write_memory(&mut self, addr: u32, data: u8) {
+            //println!("write_memory");
+            let cycles = self.bus.cycles(addr);
+            self.wait(2);
+            if self.sync == SyncMode::Lazy && self.bus.shared(addr) {
+                self.sync_apu();
+            }
+            self.bus.write(addr, data);
+            trace::record(&mut self.trace, self.cycles, addr, data, trace::Kind::Write);
+            self.wait(cycles - 2);
+        }
+
+        fn wait(&mut self, clock_cycles: u32) {
+            //println!("wait for {} cycles", clock_cycles);
+            if self.stepper.is_none() {
+                return self.advance(clock_cycles);
+            }
+            // `Stepping` stops before every clock.
+            for _ in 0..clock_cycles {
+                self.pause();
+                self.advance(1);
+            }
+        }
+
+        /// Let `clock_cycles` go by, catching the APU up if it should.
+        fn advance(&mut self, clock_cycles: u32) {
+            self.cycles += clock_cycles;
+            self.scheduler.step(scheduler::CPU, clock_cycles);
+            if self.sync == SyncMode::Eager || self.scheduler.far_ahead(scheduler::CPU) {
+                self.sync_apu();
+            }
+        }
+
+        /// Hand control back to whatever is stepping the CPU.
+        fn pause(&mut self) {
+            if let Some(stepper) = self.stepper {
+                // Safety: `Stepped::resume` doesn't touch the CPU, which the
+                // instruction's thread owns.
+                unsafe { co_switch(stepper) };
+            }
+        }
+
+        /// Switch to the APU thread if the APU is behind, right in the
+        /// middle of whatever the CPU is doing.
+        fn sync_apu(&mut self) {
+            if self.scheduler.behind() != scheduler::CPU {
+                {
+                    let mut handoff = self.handoff.borrow_mut();
+                    handoff.scheduler = std::mem::take(&mut self.scheduler);
+                    // Safety: `co_active` only hands out a handle.
+                    handoff.cpu_thread = Some(unsafe { co_active() });
                 }
+                // Safety: the APU thread only touches `handoff`, which
+                // isn't borrowed across the switch.
+                unsafe { co_switch(self.apu_thread) };
+                let mut handoff = self.handoff.borrow_mut();
+                self.scheduler = std::mem::take(&mut handoff.scheduler);
+                self.apu_state = handoff.apu_state;
+            }
+        }
+    }
+
+    impl Drop for CPU {
+        fn drop(&mut self) {
+            {
+                let mut handoff = self.handoff.borrow_mut();
+                handoff.quit = true;
+                // Safety: as in `sync_apu`.
+                handoff.cpu_thread = Some(unsafe { co_active() });
+            }
+            // Safety: as in `sync_apu`, and once it's back the APU thread is
+            // done for good.
+            unsafe {
+                co_switch(self.apu_thread);
+                co_delete(self.apu_thread);
+            }
+        }
+    }
+
+    impl Cpu for CPU {
+        fn step(&mut self) {
+            self.execute_instruction();
+            //println!("ran for 1 instruction");
+            //println!("instruction count: {}", self.instruction_count);
+            //println!("cycle count: {}", self.cycles);
+        }
+
+        fn registers(&self) -> Registers {
+            self.regs
+        }
+
+        fn set_registers(&mut self, regs: Registers) {
+            self.regs = regs;
+        }
+
+        fn cycles(&self) -> u32 {
+            self.cycles
+        }
+
+        fn instruction_count(&self) -> u32 {
+            self.instruction_count
+        }
+
+        fn synchronize(&mut self) {
+            self.sync_apu();
+        }
+
+        fn apu(&self) -> spc700::State {
+            self.apu_state
+        }
+
+        fn trace(&mut self, sink: Box<dyn trace::Sink>) {
+            self.trace = Some(sink);
+        }
+
+        fn load(&mut self, image: &[u8], pc: u16) {
+            self.bus.load(0, image);
+            self.regs.pc = pc;
+        }
+
+        fn reset_registers(&mut self) {
+            self.regs = Registers::power_on(self.model);
+            self.cycles = 0;
+            self.instruction_count = 0;
+        }
+    }
+
+    thread_local! {
+        /// Hands the CPU to an instruction's thread when it starts, and
+        /// back once it's done.
+        static STEPPED: Cell<Option<Box<CPU>>> = const { Cell::new(None) };
+    }
+
+    /// An instruction on a thread of its own, which switches back to the
+    /// CPU's `stepper` before every clock.
+    pub struct Stepped {
+        thread: Cothread,
+        /// Until the instruction starts.
+        cpu: Option<Box<CPU>>,
+    }
+
+    extern "C" fn step_main() {
+        let mut cpu = STEPPED.with(Cell::take).expect("instruction started without its CPU");
+        cpu.step();
+        let stepper = cpu.stepper.take().expect("the stepper went missing");
+        STEPPED.with(|s| s.set(Some(cpu)));
+        // Safety: `Stepped::resume` is waiting on the other end.
+        unsafe { co_switch(stepper) };
+        unreachable!("an instruction was resumed after it was done");
+    }
+
+    impl Resumable for CPU {
+        type Instruction = Stepped;
+
+        fn start(mut cpu: Box<CPU>) -> Stepped {
+            // Safety: the new thread only runs `step_main`.
+            let thread = unsafe { co_create(STACK_SIZE, step_main) };
+            assert!(!thread.is_null(), "couldn't allocate an instruction's thread");
+            // Safety: `co_active` only hands out a handle.
+            cpu.stepper = Some(unsafe { co_active() });
+            Stepped { thread, cpu: Some(cpu) }
+        }
+
+        fn resume(instruction: &mut Stepped) -> Progress<CPU> {
+            if let Some(cpu) = instruction.cpu.take() {
+                STEPPED.with(|s| s.set(Some(cpu)));
+            }
+            // Safety: the instruction's thread is either fresh or suspended
+            // in `pause`, and nothing here is borrowed by it.
+            unsafe { co_switch(instruction.thread) };
+            match STEPPED.with(Cell::take) {
+                Some(cpu) => Progress::Done(cpu),
+                None => Progress::Paused,
+            }
+        }
+    }
+
+    impl Drop for Stepped {
+        fn drop(&mut self) {
+            // Safety: the thread isn't running, and `Stepping` doesn't drop
+            // an instruction before it's done.
+            unsafe { co_delete(self.thread) };
+        }
+    }
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+mod ucontext_attempt {
+    use super::*;
+    use libc::{getcontext, makecontext, swapcontext, ucontext_t};
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+
+    /// Stack size of the APU context.
+    const STACK_SIZE: usize = 1 << 20;
+
+    /// What the CPU and the APU context hand each other on every switch.
+    #[derive(Default)]
+    struct Handoff {
+        scheduler: Scheduler,
+        apu_state: spc700::State,
+        /// Set when the CPU goes away, so the APU context returns.
+        quit: bool,
+    }
+
+    pub struct CPU {
+        model: Model,
+        regs: Registers,
+        /// Where the CPU and APU are in time. Goes along to the APU context
+        /// while it runs.
+        scheduler: Scheduler,
+        sync: SyncMode,
+        /// Where the CPU was when it last switched to the APU. Boxed, like
+        /// the APU's, since glibc points into it.
+        cpu_context: Box<ucontext_t>,
+        apu_context: Box<ucontext_t>,
+        _apu_stack: Box<[u8]>,
+        handoff: Rc<RefCell<Handoff>>,
+        apu_state: spc700::State,
+        cycles: u32,
+        instruction_count: u32,
+        /// Gets every bus access once `Cpu::trace` has been called.
+        trace: Option<Box<dyn trace::Sink>>,
+        /// While `Stepping` has an instruction in progress, the context it
+        /// runs in and the one to switch back to before every clock.
+        stepper: Option<(*mut ucontext_t, *mut ucontext_t)>,
+        bus: Box<dyn Bus>,
+    }
+
+    macro_rules! bus_read {
+        ($cpu:ident, $addr:expr) => {
+            $cpu.read_memory(u32::from($addr))
+        };
+    }
+    macro_rules! bus_write {
+        ($cpu:ident, $addr:expr, $data:expr) => {
+            $cpu.write_memory(u32::from($addr), $data)
+        };
+    }
+    macro_rules! bus_idle {
+        ($cpu:ident) => {
+            $cpu.wait(6)
+        };
+    }
+
+    /// What the APU context starts out with. `makecontext` only passes
+    /// `int`s, so it's left here for `apu_main` to pick up instead.
+    struct ApuThread {
+        apu: Apu,
+        cpu_context: *mut ucontext_t,
+        apu_context: *mut ucontext_t,
+        handoff: Rc<RefCell<Handoff>>,
+    }
+
+    thread_local! {
+        static STARTING: Cell<Option<Box<ApuThread>>> = const { Cell::new(None) };
+    }
+
+    /// The APU on a stack of its own. Every time the CPU switches to it, it
+    /// runs until it has caught up and switches back. Once it returns,
+    /// `uc_link` takes it back to the CPU for the last time.
+    extern "C" fn apu_main() {
+        let ApuThread {
+            mut apu,
+            cpu_context,
+            apu_context,
+            handoff,
+        } = *STARTING.with(Cell::take).expect("APU context started without its APU");
+        while !handoff.borrow().quit {
+            {
+                let mut handoff = handoff.borrow_mut();
+                apu.run(&mut handoff.scheduler);
+                handoff.apu_state = apu.state();
+            }
+            // Safety: the CPU is suspended in `sync_apu` with nothing
+            // borrowed, and both contexts live as long as it does.
+            unsafe { swapcontext(apu_context, cpu_context) };
+        }
+    }
+
+    impl CPU {
+        pub fn new(model: Model, sync: SyncMode, bus: Box<dyn Bus>, apu: Apu) -> CPU {
+            let apu_state = apu.state();
+            let handoff = Rc::new(RefCell::new(Handoff::default()));
+            // Safety: zeroed contexts are only placeholders for `getcontext`
+            // and `swapcontext` to fill in.
+            let mut cpu_context: Box<ucontext_t> = Box::new(unsafe { std::mem::zeroed() });
+            let mut apu_context: Box<ucontext_t> = Box::new(unsafe { std::mem::zeroed() });
+            let mut apu_stack = vec![0; STACK_SIZE].into_boxed_slice();
+            let cpu_ptr: *mut ucontext_t = &mut *cpu_context;
+            let apu_ptr: *mut ucontext_t = &mut *apu_context;
+            // Safety: the APU context gets a stack that lives as long as it
+            // does and only runs `apu_main`, which switches straight back
+            // here as long as the scheduler it finds in the handoff is
+            // empty.
+            unsafe {
+                assert_eq!(getcontext(apu_ptr), 0, "getcontext failed");
+                apu_context.uc_stack.ss_sp = apu_stack.as_mut_ptr().cast();
+                apu_context.uc_stack.ss_size = STACK_SIZE;
+                apu_context.uc_link = cpu_ptr;
+                makecontext(apu_ptr, apu_main, 0);
+                STARTING.with(|s| {
+                    s.set(Some(Box::new(ApuThread {
+                        apu,
+                        cpu_context: cpu_ptr,
+                        apu_context: apu_ptr,
+                        handoff: handoff.clone(),
+                    })))
+                });
+                swapcontext(cpu_ptr, apu_ptr);
+            }
+            CPU {
+                model,
+                regs: Registers::power_on(model),
+                scheduler: Scheduler::snes(),
+                sync,
+                cpu_context,
+                apu_context,
+                _apu_stack: apu_stack,
+                handoff,
+                apu_state,
+                bus,
+                cycles: 0,
+                instruction_count: 0,
+                trace: None,
+                stepper: None,
+            }
+        }
+        /*
+        void CPU::executeInstruction() {
+          opcode = readMemory(PC++);
+          if(FlagM)
+          switch(opcode) {  //8-bit accumulator instructions
+          case 0xb9:
+            address = readMemory(PC++);
+            address = readMemory(PC++) | address << 8;
+            if(address >> 8 != address + Y >> 8) wait(6);
+            A = readMemory(address + Y);
+          }
+        }
+        */
+        pub fn execute_instruction(&mut self) {
+            //println!("execute instruction");
+            match self.model {
+                Model::Nmos6502 => execute_6502!(self, bus_read, bus_write),
+                Model::Wdc65816 => execute_65816!(self, bus_read, bus_write, bus_idle),
             }
             self.instruction_count += 1;
         }
 
-        fn read_memory(&mut self, addr: u16) -> u8 {
+        fn read_memory(&mut self, addr: u32) -> u8 {
             //println!("read_memory");
+            let cycles = self.bus.cycles(addr);
             self.wait(2);
-            let data = self.mem[addr as usize];
-            self.wait(4);
+            if self.sync == SyncMode::Lazy && self.bus.shared(addr) {
+                self.sync_apu();
+            }
+            let data = self.bus.read(addr);
+            trace::record(&mut self.trace, self.cycles, addr, data, trace::Kind::Read);
+            self.wait(cycles - 2);
             data
         }
 
+        fn write_memory(&mut self, addr: u32, data: u8) {
+            //println!("write_memory");
+            let cycles = self.bus.cycles(addr);
+            self.wait(2);
+            if self.sync == SyncMode::Lazy && self.bus.shared(addr) {
+                self.sync_apu();
+            }
+            self.bus.write(addr, data);
+            trace::record(&mut self.trace, self.cycles, addr, data, trace::Kind::Write);
+            self.wait(cycles - 2);
+        }
+
         fn wait(&mut self, clock_cycles: u32) {
             //println!("wait for {} cycles", clock_cycles);
-            self.apu_counter += clock_cycles;
-            while self.apu_counter > 0 {
-                self.apu_counter -= 1;
-                self.cycles += 1;
-                //println!("total cycles {}", self.cycles);
+            if self.stepper.is_none() {
+                return self.advance(clock_cycles);
+            }
+            // `Stepping` stops before every clock.
+            for _ in 0..clock_cycles {
+                self.pause();
+                self.advance(1);
+            }
+        }
+
+        /// Let `clock_cycles` go by, catching the APU up if it should.
+        fn advance(&mut self, clock_cycles: u32) {
+            self.cycles += clock_cycles;
+            self.scheduler.step(scheduler::CPU, clock_cycles);
+            if self.sync == SyncMode::Eager || self.scheduler.far_ahead(scheduler::CPU) {
+                self.sync_apu();
+            }
+        }
+
+        /// Hand control back to whatever is stepping the CPU.
+        fn pause(&mut self) {
+            if let Some((context, stepper)) = self.stepper {
+                // Safety: `Stepped` keeps both contexts alive and doesn't
+                // touch the CPU, which the instruction's context owns.
+                unsafe { swapcontext(context, stepper) };
+            }
+        }
+
+        /// Switch to the APU context if the APU is behind, right in the
+        /// middle of whatever the CPU is doing.
+        fn sync_apu(&mut self) {
+            if self.scheduler.behind() != scheduler::CPU {
+                self.handoff.borrow_mut().scheduler = std::mem::take(&mut self.scheduler);
+                // Safety: the APU context only touches `handoff`, which
+                // isn't borrowed across the switch.
+                unsafe { swapcontext(&mut *self.cpu_context, &*self.apu_context) };
+                let mut handoff = self.handoff.borrow_mut();
+                self.scheduler = std::mem::take(&mut handoff.scheduler);
+                self.apu_state = handoff.apu_state;
             }
         }
     }
 
-    pub fn main(iters: usize) {
-        let mut cpu = CPU::new();
-        for _ in 0..iters {
-            cpu.execute_instruction();
+    impl Drop for CPU {
+        fn drop(&mut self) {
+            self.handoff.borrow_mut().quit = true;
+            // Safety: as in `sync_apu`. This time `apu_main` returns and
+            // comes back through `uc_link`, leaving nothing on its stack.
+            unsafe { swapcontext(&mut *self.cpu_context, &*self.apu_context) };
+        }
+    }
+
+    impl Cpu for CPU {
+        fn step(&mut self) {
+            self.execute_instruction();
             //println!("ran for 1 instruction");
-            //println!("instruction count: {}", cpu.instruction_count);
-            //println!("cycle count: {}", cpu.cycles);
+            //println!("instruction count: {}", self.instruction_count);
+            //println!("cycle count: {}", self.cycles);
+        }
+
+        fn registers(&self) -> Registers {
+            self.regs
+        }
+
+        fn set_registers(&mut self, regs: Registers) {
+            self.regs = regs;
+        }
+
+        fn cycles(&self) -> u32 {
+            self.cycles
+        }
+
+        fn instruction_count(&self) -> u32 {
+            self.instruction_count
+        }
+
+        fn synchronize(&mut self) {
+            self.sync_apu();
+        }
+
+        fn apu(&self) -> spc700::State {
+            self.apu_state
+        }
+
+        fn trace(&mut self, sink: Box<dyn trace::Sink>) {
+            self.trace = Some(sink);
+        }
+
+        fn load(&mut self, image: &[u8], pc: u16) {
+            self.bus.load(0, image);
+            self.regs.pc = pc;
+        }
+
+        fn reset_registers(&mut self) {
+            self.regs = Registers::power_on(self.model);
+            self.cycles = 0;
+            self.instruction_count = 0;
+        }
+    }
+
+    thread_local! {
+        /// Hands the CPU to an instruction's context when it starts, and
+        /// back once it's done.
+        static STEPPED: Cell<Option<Box<CPU>>> = const { Cell::new(None) };
+    }
+
+    /// An instruction in a context of its own, which switches back to
+    /// `stepper` before every clock and returns there through `uc_link`.
+    /// The contexts are boxed since glibc points into them.
+    pub struct Stepped {
+        context: Box<ucontext_t>,
+        stepper: Box<ucontext_t>,
+        _stack: Box<[u8]>,
+        /// Until the instruction starts.
+        cpu: Option<Box<CPU>>,
+    }
+
+    extern "C" fn step_main() {
+        let mut cpu = STEPPED.with(Cell::take).expect("instruction started without its CPU");
+        cpu.step();
+        cpu.stepper = None;
+        STEPPED.with(|s| s.set(Some(cpu)));
+    }
+
+    impl Resumable for CPU {
+        type Instruction = Stepped;
+
+        fn start(mut cpu: Box<CPU>) -> Stepped {
+            // Safety: as in `CPU::new`.
+            let mut context: Box<ucontext_t> = Box::new(unsafe { std::mem::zeroed() });
+            let mut stepper: Box<ucontext_t> = Box::new(unsafe { std::mem::zeroed() });
+            let mut stack = vec![0; STACK_SIZE].into_boxed_slice();
+            // Safety: the context gets a stack that lives as long as it does
+            // and only runs `step_main`.
+            unsafe {
+                assert_eq!(getcontext(&mut *context), 0, "getcontext failed");
+                context.uc_stack.ss_sp = stack.as_mut_ptr().cast();
+                context.uc_stack.ss_size = STACK_SIZE;
+                context.uc_link = &mut *stepper;
+                makecontext(&mut *context, step_main, 0);
+            }
+            cpu.stepper = Some((&mut *context, &mut *stepper));
+            Stepped {
+                context,
+                stepper,
+                _stack: stack,
+                cpu: Some(cpu),
+            }
+        }
+
+        fn resume(instruction: &mut Stepped) -> Progress<CPU> {
+            if let Some(cpu) = instruction.cpu.take() {
+                STEPPED.with(|s| s.set(Some(cpu)));
+            }
+            // Safety: the instruction's context is either fresh or suspended
+            // in `pause`, and nothing here is borrowed by it.
+            unsafe { swapcontext(&mut *instruction.stepper, &*instruction.context) };
+            match STEPPED.with(Cell::take) {
+                Some(cpu) => Progress::Done(cpu),
+                None => Progress::Paused,
+            }
+        }
+    }
+}
+
+/// Every variant the harness knows about, in the order they run by default.
+const VARIANTS: &[(&str, Constructor)] = &[
+    ("genawaiter", |model, sync, bus, apu| {
+        Box::new(Stepping::new(genawaiter_attempt::CPU::new(model, sync, bus, apu)))
+    }),
+    ("tokio", |model, sync, bus, apu| {
+        Box::new(Stepping::new(tokio_attempt::CPU::new(model, sync, bus, apu)))
+    }),
+    ("async-std", |model, sync, bus, apu| {
+        Box::new(Stepping::new(async_std_attempt::CPU::new(model, sync, bus, apu)))
+    }),
+    ("local-pool", |model, sync, bus, apu| {
+        Box::new(Stepping::new(local_pool_attempt::CPU::new(model, sync, bus, apu)))
+    }),
+    ("smol", |model, sync, bus, apu| {
+        Box::new(Stepping::new(smol_attempt::CPU::new(model, sync, bus, apu)))
+    }),
+    ("executor", |model, sync, bus, apu| {
+        Box::new(Stepping::new(executor_attempt::CPU::new(model, sync, bus, apu)))
+    }),
+    ("threads", |model, sync, bus, apu| {
+        Box::new(Stepping::new(threads_attempt::CPU::new(model, sync, bus, apu)))
+    }),
+    ("generator", |model, sync, bus, apu| {
+        Box::new(Stepping::new(generator_attempt::CPU::new(model, sync, bus, apu)))
+    }),
+    ("enum", |model, sync, bus, apu| {
+        Box::new(Stepping::new(enum_attempt::CPU::new(model, sync, bus, apu)))
+    }),
+    ("macro", |model, sync, bus, apu| {
+        Box::new(Stepping::new(macro_attempt::CPU::new(model, sync, bus, apu)))
+    }),
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    ("cothread", |model, sync, bus, apu| {
+        Box::new(Stepping::new(cothread_attempt::CPU::new(model, sync, bus, apu)))
+    }),
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    ("libco", |model, sync, bus, apu| {
+        Box::new(Stepping::new(libco_attempt::CPU::new(model, sync, bus, apu)))
+    }),
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    ("ucontext", |model, sync, bus, apu| {
+        Box::new(Stepping::new(ucontext_attempt::CPU::new(model, sync, bus, apu)))
+    }),
+    ("null", |model, sync, bus, apu| {
+        Box::new(Stepping::new(null_attempt::CPU::new(model, sync, bus, apu)))
+    }),
+];
+
+/// Make sure every variant did the same amount of work. The `null` variant
+/// is the reference since it has no scheduling machinery to get wrong.
+fn check_agreement(results: &[(&str, State)]) -> Result<(), String> {
+    let (ref_name, ref_state) = results
+        .iter()
+        .find(|(name, _)| *name == "null")
+        .unwrap_or(&results[0]);
+    let mismatches = results
+        .iter()
+        .filter(|(_, state)| state != ref_state)
+        .map(|(name, state)| format!("  {}: {:?}", name, state))
+        .collect::<Vec<_>>();
+    if mismatches.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "variants disagree with {} ({:?}):\n{}",
+            ref_name,
+            ref_state,
+            mismatches.join("\n")
+        ))
+    }
+}
+
+fn write_csv(path: &str, names: &[String], rows: &[String]) -> std::io::Result<()> {
+    use std::io::Write;
+    let mut file = std::fs::File::create(path)?;
+    writeln!(file, "{}", names.join(","))?;
+    for row in rows {
+        writeln!(file, "{}", row)?;
+    }
+    Ok(())
+}
+
+/// Trace one run of every variant into `dir`, in a file named after it.
+fn write_traces<'a>(
+    dir: &str,
+    variants: &[&(&'a str, Constructor)],
+    opts: &cli::Options,
+    program: &programs::Program,
+    verbose: bool,
+) -> Result<Vec<(&'a str, State)>, String> {
+    std::fs::create_dir_all(dir).map_err(|e| format!("couldn't create {}: {}", dir, e))?;
+    let format = opts.trace_format;
+    let mut states = vec![];
+    for (name, new) in variants {
+        if verbose {
+            println!("tracing {} variant", name);
         }
+        let path = std::path::Path::new(dir).join(format!("{}.{}", name, format.extension()));
+        let file = std::fs::File::create(&path).map_err(|e| format!("couldn't write {}: {}", path.display(), e))?;
+        let sink = trace::Writer::new(std::io::BufWriter::new(file), format);
+        let out = sink.out();
+        let state = bench::trace(*new, opts.model, opts.sync, program, opts.count, Box::new(sink));
+        let flushed = std::io::Write::flush(&mut *out.lock().expect("the tracer panicked"));
+        flushed.map_err(|e| format!("couldn't write {}: {}", path.display(), e))?;
+        states.push((*name, state));
     }
+    Ok(states)
 }
 
-macro_rules! timeit {
-    ($name:expr, $b:block) => {{
-        let start = std::time::Instant::now();
-        println!("running {} variant:", $name);
-        $b;
-        let end = std::time::Instant::now();
-        let elapsed = end.duration_since(start);
-        println!("elapsed time: {:?}", elapsed);
-        println!("----------------------------");
-        ($name, elapsed)
-    }};
+fn print_summaries(reports: &[VariantReport]) {
+    println!(
+        "{:<12} {:>10} {:>10} {:>10} {:>10} {:>10} {:>23} {:>23} {:>9}",
+        "variant",
+        "min",
+        "median",
+        "mean",
+        "stddev",
+        "p95",
+        "median 95% CI",
+        "mean 95% CI",
+        "MHz"
+    );
+    for report in reports {
+        let s = &report.summary;
+        println!(
+            "{:<12} {:>10.6} {:>10.6} {:>10.6} {:>10.6} {:>10.6} {:>10.6} - {:>10.6} {:>10.6} - {:>10.6} {:>9.3}",
+            report.name,
+            s.min,
+            s.median,
+            s.mean,
+            s.stddev,
+            s.p95,
+            s.median_ci.0,
+            s.median_ci.1,
+            s.mean_ci.0,
+            s.mean_ci.1,
+            report.emulated_mhz
+        );
+    }
 }
 
 fn main() {
-    let count = 5_000_000usize;
-    let times = vec![
-        timeit!("genawaiter", {
-            genawaiter_attempt::main(count);
-        }),
-        timeit!("tokio", {
-            tokio_attempt::main(count);
-        }),
-        timeit!("async-std", {
-            async_std_attempt::main(count);
-        }),
-        timeit!("generator", {
-            generator_attempt::main(count);
-        }),
-        timeit!("enum", {
-            enum_attempt::main(count);
-        }),
-        timeit!("null", {
-            null_attempt::main(count);
-        }),
-    ];
-    let names = times.iter().map(|(x, _)| *x).collect::<Vec<_>>().join(",");
-    println!("{}", names);
-    let row = times
+    let names = VARIANTS.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+    let program_names = PROGRAMS.iter().map(|p| p.name).collect::<Vec<_>>();
+    let opts = cli::parse(&names, &program_names);
+    let verbose = opts.format == Format::Text;
+    let program = PROGRAMS
+        .iter()
+        .find(|p| p.name == opts.program)
+        .expect("clap only accepts known programs");
+    if !program.models.contains(&opts.model) {
+        eprintln!("the {} program doesn't run on the {}", program.name, opts.model.name());
+        std::process::exit(1);
+    }
+    let variants = opts
+        .variants
         .iter()
-        .map(|(_, y)| format!("{:?}", y.as_secs_f64()))
-        .collect::<Vec<_>>()
-        .join(",");
-    println!("{}", row);
+        .map(|name| {
+            VARIANTS
+                .iter()
+                .find(|(n, _)| n == name)
+                .expect("clap only accepts known variants")
+        })
+        .filter(|(name, new)| {
+            let runnable = !program.full_isa || {
+                let (bus, apu) = bench::machine(program);
+                new(opts.model, opts.sync, bus, apu).full_isa()
+            };
+            if !runnable {
+                eprintln!("skipping {} variant, it only implements a few instructions", name);
+            }
+            runnable
+        })
+        .collect::<Vec<_>>();
+    let variant_names = variants.iter().map(|(name, _)| name.to_string()).collect::<Vec<_>>();
+
+    if opts.child {
+        let (_, new) = variants[0];
+        bench::child(*new, opts.model, opts.sync, program, opts.count, opts.warmup);
+        return;
+    }
+
+    if let Some(dir) = &opts.single_step {
+        match single_step::run(dir, &variants, opts.model, opts.sync) {
+            Ok(true) => return,
+            Ok(false) => std::process::exit(1),
+            Err(msg) => {
+                eprintln!("{}", msg);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(image) = &opts.image {
+        match functional::run(image, &variants, &opts) {
+            Ok(true) => return,
+            Ok(false) => std::process::exit(1),
+            Err(msg) => {
+                eprintln!("{}", msg);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if !opts.isolate {
+        for _ in 0..opts.warmup {
+            for (name, new) in &variants {
+                if verbose {
+                    println!("warming up {} variant", name);
+                }
+                bench::measure(*new, opts.model, opts.sync, program, opts.count);
+            }
+        }
+    }
+
+    let mut rows = vec![];
+    let mut columns = vec![vec![]; variants.len()];
+    for _ in 0..opts.repetitions {
+        let measurements = variants
+            .iter()
+            .map(|(name, new)| {
+                if verbose {
+                    println!("running {} variant:", name);
+                }
+                let m = if opts.isolate {
+                    bench::measure_isolated(name, opts.model, opts.sync, program, opts.count, opts.warmup)
+                } else {
+                    bench::measure(*new, opts.model, opts.sync, program, opts.count)
+                };
+                if verbose {
+                    println!("elapsed time: {:?}", m.elapsed);
+                    println!("----------------------------");
+                }
+                (*name, m)
+            })
+            .collect::<Vec<_>>();
+        let states = measurements
+            .iter()
+            .map(|(name, m)| (*name, m.state))
+            .collect::<Vec<_>>();
+        if let Err(msg) = check_agreement(&states) {
+            eprintln!("{}", msg);
+            std::process::exit(1);
+        }
+        for (column, (_, m)) in columns.iter_mut().zip(&measurements) {
+            column.push(*m);
+        }
+        let row = measurements
+            .iter()
+            .map(|(_, m)| format!("{:?}", m.elapsed.as_secs_f64()))
+            .collect::<Vec<_>>()
+            .join(",");
+        rows.push(row);
+    }
+    if let Some(dir) = &opts.trace {
+        let states = match write_traces(dir, &variants, &opts, program, verbose) {
+            Ok(states) => states,
+            Err(msg) => {
+                eprintln!("{}", msg);
+                std::process::exit(1);
+            }
+        };
+        if let Err(msg) = check_agreement(&states) {
+            eprintln!("{}", msg);
+            std::process::exit(1);
+        }
+    }
+    let report = Report {
+        workload: Workload {
+            cpu: opts.model.name().to_string(),
+            sync: opts.sync.name().to_string(),
+            description: program.description.to_string(),
+            instructions: opts.count,
+            repetitions: opts.repetitions,
+            warmup: opts.warmup,
+            isolated: opts.isolate,
+        },
+        environment: Environment::current(),
+        variants: if opts.repetitions > 0 {
+            variant_names
+                .iter()
+                .zip(&columns)
+                .map(|(name, measurements)| VariantReport::new(name, measurements))
+                .collect()
+        } else {
+            vec![]
+        },
+    };
+    match opts.format {
+        Format::Text | Format::Csv => {
+            println!("{}", variant_names.join(","));
+            for row in &rows {
+                println!("{}", row);
+            }
+            if verbose && !report.variants.is_empty() {
+                println!();
+                print_summaries(&report.variants);
+            }
+        }
+        Format::Json => {
+            println!("{}", report.to_json());
+        }
+    }
+    if let Some(path) = &opts.csv {
+        if let Err(e) = write_csv(path, &variant_names, &rows) {
+            eprintln!("couldn't write {}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+    if let Some(path) = &opts.html {
+        if let Err(e) = std::fs::write(path, html::render(&report)) {
+            eprintln!("couldn't write {}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+    if let Some(path) = &opts.save_baseline {
+        if let Err(msg) = baseline::save(path, &report) {
+            eprintln!("{}", msg);
+            std::process::exit(1);
+        }
+    }
+    if let Some(path) = &opts.baseline {
+        let comparisons = baseline::load(path)
+            .and_then(|base| baseline::compare(&base, &report, opts.threshold, opts.alpha));
+        let comparisons = match comparisons {
+            Ok(comparisons) => comparisons,
+            Err(msg) => {
+                eprintln!("{}", msg);
+                std::process::exit(1);
+            }
+        };
+        // Keep stdout machine readable unless it's already chatty.
+        let table = baseline::table(&comparisons);
+        if verbose {
+            println!();
+            print!("{}", table);
+        } else {
+            eprint!("{}", table);
+        }
+        let regressions = comparisons
+            .iter()
+            .filter(|c| c.verdict == baseline::Verdict::Regression)
+            .map(|c| c.name.as_str())
+            .collect::<Vec<_>>();
+        if !regressions.is_empty() {
+            eprintln!("regressions against {}: {}", path, regressions.join(", "));
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variant(name: &str) -> Constructor {
+        VARIANTS.iter().find(|(n, _)| *n == name).expect("no such variant").1
+    }
+
+    /// `lda-absy` takes 24 master clocks an instruction and never touches
+    /// anything shared, so with `SyncMode::Lazy` the CPU would run this
+    /// far past a second ahead of the APU if the scheduler let it.
+    #[test]
+    fn lazy_runs_past_a_second() {
+        let program = &PROGRAMS[0];
+        let count = 2 * cpu::FREQUENCY as usize / 24 + 1000;
+        for name in ["null", "generator", "cothread"] {
+            let eager = bench::measure(variant(name), Model::Nmos6502, SyncMode::Eager, program, count).state;
+            let lazy = bench::measure(variant(name), Model::Nmos6502, SyncMode::Lazy, program, count).state;
+            assert_eq!(lazy, eager, "{} variant", name);
+        }
+    }
+
+    /// Keeps every access it gets where the test can see them.
+    #[derive(Clone, Default)]
+    struct Accesses(std::sync::Arc<std::sync::Mutex<Vec<trace::Access>>>);
+
+    impl trace::Sink for Accesses {
+        fn record(&mut self, access: trace::Access) {
+            self.0.lock().unwrap().push(access);
+        }
+    }
+
+    /// Every variant steps a clock at a time, with each bus access landing
+    /// on the clock it does when the variant runs whole instructions.
+    #[test]
+    fn step_cycle_keeps_to_the_clock() {
+        const CLOCKS: u32 = 20_000;
+        let traced = |cpu: &mut dyn Cpu| {
+            let accesses = Accesses::default();
+            cpu.trace(Box::new(accesses.clone()));
+            accesses.0
+        };
+        for program in [&PROGRAMS[0], &PROGRAMS[2]] {
+            for (name, new) in VARIANTS {
+                let machine = || {
+                    let (bus, apu) = bench::machine(program);
+                    let mut cpu = new(Model::Nmos6502, SyncMode::Eager, bus, apu);
+                    program.load(&mut *cpu);
+                    cpu
+                };
+                let mut cpu = machine();
+                assert!(cpu.steps_cycles(), "{} variant", name);
+                if program.full_isa && !cpu.full_isa() {
+                    continue;
+                }
+                let accesses = traced(&mut *cpu);
+                while cpu.cycles() < CLOCKS {
+                    cpu.step();
+                }
+                let expected = accesses.lock().unwrap().clone();
+
+                let mut cpu = machine();
+                let accesses = traced(&mut *cpu);
+                for clocks in 1..=CLOCKS {
+                    cpu.step_cycle();
+                    assert_eq!(cpu.cycles(), clocks, "{} variant on {}", name, program.name);
+                    let done = expected.iter().take_while(|access| access.cycle <= clocks).count();
+                    assert_eq!(
+                        accesses.lock().unwrap().len(),
+                        done,
+                        "{} variant on {} after {} clocks",
+                        name,
+                        program.name,
+                        clocks
+                    );
+                }
+                let done = expected.iter().take_while(|access| access.cycle <= CLOCKS).count();
+                assert_eq!(*accesses.lock().unwrap(), expected[..done], "{} variant on {}", name, program.name);
+            }
+        }
+    }
 }
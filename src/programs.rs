@@ -0,0 +1,335 @@
+use crate::cpu::{Cpu, Model};
+use crate::MEM_SIZE;
+
+/// A memory image and entry point every variant runs from.
+pub struct Program {
+    pub name: &'static str,
+    /// Recorded in reports so baselines only compare like with like.
+    pub description: &'static str,
+    /// Whether the program uses more than the instructions in `STORES`, the
+    /// only ones every variant implements.
+    pub full_isa: bool,
+    /// Processors the program is written for.
+    pub models: &'static [Model],
+    /// Whether the APU ports are mapped at $2140-$217f. The programs that
+    /// fill all of memory with code would end up executing them otherwise.
+    pub apu_ports: bool,
+    pub start: u16,
+    image: fn() -> Vec<u8>,
+}
+
+impl Program {
+    pub fn image(&self) -> Vec<u8> {
+        (self.image)()
+    }
+
+    pub fn load(&self, cpu: &mut dyn Cpu) {
+        cpu.load(&self.image(), self.start);
+    }
+}
+
+/// Every program the harness knows about, the first one is the default.
+pub const PROGRAMS: &[Program] = &[
+    Program {
+        name: "lda-absy",
+        description: "CPU starts at pc 0 with all of memory filled with 0xb9 (LDA abs,Y)",
+        full_isa: false,
+        models: &[Model::Nmos6502, Model::Wdc65816],
+        apu_ports: false,
+        start: 0,
+        image: || vec![0xb9; MEM_SIZE],
+    },
+    Program {
+        name: "stores",
+        description: "CPU starts at pc 0 with all of memory filled with LDA, PHA, PLA, STA, INC and DEC \
+                      on $01fd, which write back what's already there",
+        full_isa: false,
+        models: &[Model::Nmos6502, Model::Wdc65816],
+        apu_ports: false,
+        start: 0,
+        image: stores,
+    },
+    Program {
+        name: "mix",
+        description: "loop filling a page from an LFSR, summing it, scanning it through \
+                      (zp),Y, a BCD counter and a subroutine using the stack and trading a byte \
+                      with the APU",
+        full_isa: true,
+        models: &[Model::Nmos6502, Model::Wdc65816],
+        apu_ports: true,
+        start: MIX_START,
+        image: mix,
+    },
+    Program {
+        name: "native",
+        description: "65816 native mode loop with 16-bit registers filling two pages from an LFSR, \
+                      summing them through long addressing, copying them with MVN, a BCD counter \
+                      and a long subroutine moving the direct page",
+        full_isa: true,
+        models: &[Model::Wdc65816],
+        apu_ports: false,
+        start: NATIVE_START,
+        image: native,
+    },
+];
+
+/// Repeated over all of memory, using every instruction the `enum` variant
+/// implements. Every instruction only touches $01fd, the
+/// opcode of the `STA` in the block that covers it, and writes back what it
+/// read or undoes what the previous one did, so the code stays intact as pc
+/// wraps around. `s` starts at $fd, so `PHA` pushes onto $01fd too.
+#[rustfmt::skip]
+const STORES: &[u8] = &[
+    0xb9, 0xfd, 0x01,        // LDA $01fd,Y
+    0x48,                    // PHA
+    0x68,                    // PLA
+    0x99, 0xfd, 0x01,        // STA $01fd,Y
+    0xee, 0xfd, 0x01,        // INC $01fd
+    0xce, 0xfd, 0x01,        // DEC $01fd
+];
+
+fn stores() -> Vec<u8> {
+    let mut image = STORES.iter().copied().cycle().take(MEM_SIZE).collect::<Vec<_>>();
+    // 64 KiB is two bytes more than a multiple of the block
+    image[MEM_SIZE - 2..].copy_from_slice(&[0x48, 0x68]);
+    image
+}
+
+const MIX_START: u16 = 0x0200;
+
+/// A loop touching most addressing modes and instruction groups, so the
+/// benchmark isn't dominated by a single opcode. Zero page holds `seed` at
+/// $10, `sum` at $11-$12, `ptr` at $13-$14, `count` at $15 and `bcd` at
+/// $16-$17. The subroutine sends a byte to the APU and mixes its answer into
+/// `seed`, so the result depends on when the two processors synchronize.
+#[rustfmt::skip]
+const MIX: &[u8] = &[
+    0xa2, 0xff,              // 0200 start: LDX #$ff
+    0x9a,                    // 0202 TXS
+    0xd8,                    // 0203 CLD
+    0xa9, 0x01,              // 0204 LDA #$01
+    0x85, 0x10,              // 0206 STA seed
+    0xa9, 0x00,              // 0208 LDA #$00
+    0x85, 0x13,              // 020a STA ptr
+    0xa9, 0x03,              // 020c LDA #$03
+    0x85, 0x14,              // 020e STA ptr+1
+    0xa0, 0x00,              // 0210 loop: LDY #$00
+    0xa5, 0x10,              // 0212 fill: LDA seed
+    0x0a,                    // 0214 ASL A
+    0x90, 0x02,              // 0215 BCC nofb
+    0x49, 0x1d,              // 0217 EOR #$1d
+    0x85, 0x10,              // 0219 nofb: STA seed
+    0x99, 0x00, 0x03,        // 021b STA $0300,Y
+    0xc8,                    // 021e INY
+    0xd0, 0xf1,              // 021f BNE fill
+    0xa9, 0x00,              // 0221 LDA #$00
+    0x85, 0x11,              // 0223 STA sum
+    0x85, 0x12,              // 0225 STA sum+1
+    0xaa,                    // 0227 TAX
+    0x18,                    // 0228 CLC
+    0xbd, 0x00, 0x03,        // 0229 add: LDA $0300,X
+    0x65, 0x11,              // 022c ADC sum
+    0x85, 0x11,              // 022e STA sum
+    0x90, 0x03,              // 0230 BCC nc
+    0xe6, 0x12,              // 0232 INC sum+1
+    0x18,                    // 0234 CLC
+    0xe8,                    // 0235 nc: INX
+    0xd0, 0xf1,              // 0236 BNE add
+    0xa0, 0x00,              // 0238 LDY #$00
+    0x84, 0x15,              // 023a STY count
+    0xb1, 0x13,              // 023c cnt: LDA (ptr),Y
+    0x10, 0x02,              // 023e BPL small
+    0xe6, 0x15,              // 0240 INC count
+    0xd9, 0x01, 0x03,        // 0242 small: CMP $0301,Y
+    0xf0, 0x03,              // 0245 BEQ same
+    0xc8,                    // 0247 INY
+    0xd0, 0xf2,              // 0248 BNE cnt
+    0xf8,                    // 024a same: SED
+    0x18,                    // 024b CLC
+    0xa5, 0x16,              // 024c LDA bcd
+    0x69, 0x01,              // 024e ADC #$01
+    0x85, 0x16,              // 0250 STA bcd
+    0xa5, 0x17,              // 0252 LDA bcd+1
+    0x69, 0x00,              // 0254 ADC #$00
+    0x85, 0x17,              // 0256 STA bcd+1
+    0xd8,                    // 0258 CLD
+    0x20, 0x5f, 0x02,        // 0259 JSR mix
+    0x4c, 0x10, 0x02,        // 025c JMP loop
+    0x08,                    // 025f mix: PHP
+    0xa2, 0x00,              // 0260 LDX #$00
+    0xa1, 0x13,              // 0262 LDA (ptr,X)
+    0x45, 0x11,              // 0264 EOR sum
+    0x4a,                    // 0266 LSR A
+    0x66, 0x12,              // 0267 ROR sum+1
+    0x26, 0x15,              // 0269 ROL count
+    0x24, 0x10,              // 026b BIT seed
+    0x50, 0x03,              // 026d BVC nov
+    0x38,                    // 026f SEC
+    0xe9, 0x03,              // 0270 SBC #$03
+    0xc9, 0x40,              // 0272 nov: CMP #$40
+    0xb0, 0x04,              // 0274 BCS hi
+    0x29, 0x3f,              // 0276 AND #$3f
+    0x09, 0xc0,              // 0278 ORA #$c0
+    0xa8,                    // 027a hi: TAY
+    0x48,                    // 027b PHA
+    0x68,                    // 027c PLA
+    0x88,                    // 027d DEY
+    0x8c, 0x00, 0x04,        // 027e STY $0400
+    0xc0, 0x10,              // 0281 CPY #$10
+    0x8d, 0x40, 0x21,        // 0283 STA $2140
+    0xad, 0x41, 0x21,        // 0286 LDA $2141
+    0x45, 0x10,              // 0289 EOR seed
+    0x09, 0x01,              // 028b ORA #$01
+    0x85, 0x10,              // 028d STA seed
+    0x28,                    // 028f PLP
+    0x60,                    // 0290 RTS
+];
+
+fn mix() -> Vec<u8> {
+    let mut image = vec![0; MEM_SIZE];
+    let start = MIX_START as usize;
+    image[start..start + MIX.len()].copy_from_slice(MIX);
+    image
+}
+
+const NATIVE_START: u16 = 0x8000;
+const NATIVE_SUB: u16 = 0x9000;
+
+/// The `mix` idea for the 65816's native mode. The direct page is moved to
+/// $1000, which holds `seed` at $00-$01, `sum` at $02-$03, `bcd` at $04-$05
+/// and a long `ptr` at $06-$08. Bank $7e mirrors bank 0.
+#[rustfmt::skip]
+const NATIVE: &[u8] = &[
+    0x18,                    // 8000 start: CLC
+    0xfb,                    // 8001 XCE
+    0xc2, 0x30,              // 8002 REP #$30
+    0xa2, 0xff, 0x01,        // 8004 LDX #$01ff
+    0x9a,                    // 8007 TXS
+    0xf4, 0x00, 0x10,        // 8008 PEA $1000
+    0x2b,                    // 800b PLD
+    0xa9, 0x01, 0x00,        // 800c LDA #$0001
+    0x85, 0x00,              // 800f STA seed
+    0xa0, 0x00, 0x00,        // 8011 loop: LDY #$0000
+    0xa5, 0x00,              // 8014 fill: LDA seed
+    0x0a,                    // 8016 ASL A
+    0x90, 0x03,              // 8017 BCC nofb
+    0x49, 0x0b, 0x10,        // 8019 EOR #$100b
+    0x85, 0x00,              // 801c nofb: STA seed
+    0x99, 0x00, 0x20,        // 801e STA $2000,Y
+    0xc8,                    // 8021 INY
+    0xc8,                    // 8022 INY
+    0xc0, 0x00, 0x02,        // 8023 CPY #$0200
+    0xd0, 0xec,              // 8026 BNE fill
+    0xa9, 0x00, 0x00,        // 8028 LDA #$0000
+    0xa2, 0x00, 0x00,        // 802b LDX #$0000
+    0x18,                    // 802e CLC
+    0x7f, 0x00, 0x20, 0x7e,  // 802f add: ADC $7e2000,X
+    0xe8,                    // 8033 INX
+    0xe8,                    // 8034 INX
+    0xe0, 0x00, 0x02,        // 8035 CPX #$0200
+    0xd0, 0xf5,              // 8038 BNE add
+    0x85, 0x02,              // 803a STA sum
+    0xa9, 0xff, 0x01,        // 803c LDA #$01ff
+    0xa2, 0x00, 0x20,        // 803f LDX #$2000
+    0xa0, 0x00, 0x30,        // 8042 LDY #$3000
+    0x54, 0x00, 0x00,        // 8045 MVN $00,$00
+    0xe2, 0x20,              // 8048 SEP #$20
+    0xf8,                    // 804a SED
+    0x18,                    // 804b CLC
+    0xa5, 0x04,              // 804c LDA bcd
+    0x69, 0x01,              // 804e ADC #$01
+    0x85, 0x04,              // 8050 STA bcd
+    0xeb,                    // 8052 XBA
+    0xa5, 0x05,              // 8053 LDA bcd+1
+    0x69, 0x00,              // 8055 ADC #$00
+    0x85, 0x05,              // 8057 STA bcd+1
+    0xd8,                    // 8059 CLD
+    0xc2, 0x20,              // 805a REP #$20
+    0xa9, 0x00, 0x30,        // 805c LDA #$3000
+    0x85, 0x06,              // 805f STA ptr
+    0xa9, 0x7e, 0x00,        // 8061 LDA #$007e
+    0x85, 0x08,              // 8064 STA ptr+2
+    0xa0, 0x10, 0x00,        // 8066 LDY #$0010
+    0xb7, 0x06,              // 8069 LDA [ptr],Y
+    0x48,                    // 806b PHA
+    0x63, 0x01,              // 806c ADC 1,S
+    0x83, 0x01,              // 806e STA 1,S
+    0x68,                    // 8070 PLA
+    0x22, 0x00, 0x90, 0x00,  // 8071 JSL sub
+    0x4c, 0x11, 0x80,        // 8075 JMP loop
+];
+
+#[rustfmt::skip]
+const NATIVE_SUB_CODE: &[u8] = &[
+    0x8b,                    // 9000 sub: PHB
+    0x0b,                    // 9001 PHD
+    0xf4, 0x00, 0x00,        // 9002 PEA $0000
+    0x2b,                    // 9005 PLD
+    0x1a,                    // 9006 INC A
+    0x14, 0x00,              // 9007 TRB $00
+    0x04, 0x02,              // 9009 TSB $02
+    0x2b,                    // 900b PLD
+    0xab,                    // 900c PLB
+    0x6b,                    // 900d RTL
+];
+
+fn native() -> Vec<u8> {
+    let mut image = vec![0; MEM_SIZE];
+    let start = NATIVE_START as usize;
+    image[start..start + NATIVE.len()].copy_from_slice(NATIVE);
+    let sub = NATIVE_SUB as usize;
+    image[sub..sub + NATIVE_SUB_CODE.len()].copy_from_slice(NATIVE_SUB_CODE);
+    image
+}
+
+pub const APU_START: u16 = 0x0200;
+
+/// What the APU runs next to every program: it adds whatever the CPU last
+/// wrote to port 0 into a 16-bit `sum` at $10-$11, multiplies the low bytes
+/// of `sum` and the iteration count `tick` at $12-$13 and answers with the
+/// product divided by 7 on ports 1 and 2, copies direct page plus `sum` to
+/// $0400-$041f and sends a shuffled high byte of `sum` on port 3.
+#[rustfmt::skip]
+const APU: &[u8] = &[
+    0x20,                    // 0200 start: CLRP
+    0xcd, 0xef,              // 0201 MOV X,#$ef
+    0xbd,                    // 0203 MOV SP,X
+    0xe8, 0x00,              // 0204 MOV A,#$00
+    0x8d, 0x00,              // 0206 MOV Y,#$00
+    0xda, 0x10,              // 0208 MOVW sum,YA
+    0xda, 0x12,              // 020a MOVW tick,YA
+    0xe4, 0xf4,              // 020c loop: MOV A,$f4
+    0x8d, 0x00,              // 020e MOV Y,#$00
+    0x7a, 0x10,              // 0210 ADDW YA,sum
+    0xda, 0x10,              // 0212 MOVW sum,YA
+    0x3a, 0x12,              // 0214 INCW tick
+    0xeb, 0x12,              // 0216 MOV Y,tick
+    0xe4, 0x10,              // 0218 MOV A,sum
+    0xcf,                    // 021a MUL YA
+    0xcd, 0x07,              // 021b MOV X,#$07
+    0x9e,                    // 021d DIV YA,X
+    0xc4, 0xf5,              // 021e MOV $f5,A
+    0xcb, 0xf6,              // 0220 MOV $f6,Y
+    0x8d, 0x20,              // 0222 MOV Y,#$20
+    0xcd, 0x00,              // 0224 MOV X,#$00
+    0xbf,                    // 0226 copy: MOV A,(X)+
+    0x60,                    // 0227 CLRC
+    0x84, 0x10,              // 0228 ADC A,sum
+    0xd6, 0xff, 0x03,        // 022a MOV $03ff+Y,A
+    0xfe, 0xf7,              // 022d DBNZ Y,copy
+    0x3f, 0x35, 0x02,        // 022f CALL sub
+    0x5f, 0x0c, 0x02,        // 0232 JMP loop
+    0x2d,                    // 0235 sub: PUSH A
+    0xe4, 0x11,              // 0236 MOV A,sum+1
+    0x9f,                    // 0238 XCN A
+    0x1c,                    // 0239 ASL A
+    0xc4, 0xf7,              // 023a MOV $f7,A
+    0xae,                    // 023c POP A
+    0x6f,                    // 023d RET
+];
+
+pub fn apu_image() -> Vec<u8> {
+    let mut image = vec![0; APU_START as usize + APU.len()];
+    image[APU_START as usize..].copy_from_slice(APU);
+    image
+}
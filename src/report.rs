@@ -0,0 +1,121 @@
+use crate::bench::Measurement;
+use crate::stats::{self, Summary};
+use serde::{Deserialize, Serialize};
+
+/// Everything needed to archive a benchmark run and compare it with runs
+/// from other machines or commits.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Report {
+    pub workload: Workload,
+    pub environment: Environment,
+    pub variants: Vec<VariantReport>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Workload {
+    /// The emulated processor. Reports from before the 65816 only ran the
+    /// 6502.
+    #[serde(default = "default_cpu")]
+    pub cpu: String,
+    /// When the CPU switched to the APU, see `scheduler::SyncMode`. Reports
+    /// from before there was a choice always synchronized eagerly.
+    #[serde(default = "default_sync")]
+    pub sync: String,
+    pub description: String,
+    /// Instructions executed per run.
+    pub instructions: usize,
+    pub repetitions: usize,
+    pub warmup: usize,
+    /// Whether every timed run happened in a fresh child process.
+    pub isolated: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Environment {
+    pub rustc: String,
+    pub profile: String,
+    pub cpu_model: String,
+    pub git_revision: String,
+    pub os: String,
+    pub arch: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VariantReport {
+    pub name: String,
+    /// Wall clock time of every timed run, in seconds.
+    pub samples: Vec<f64>,
+    pub summary: Summary,
+    /// Emulated cycles per run.
+    pub cycles: u64,
+    /// Emulated instructions per run.
+    pub instructions: u64,
+    /// Emulated clock rate at the median time, in MHz.
+    pub emulated_mhz: f64,
+    /// Emulated instructions per second at the median time.
+    pub instructions_per_sec: f64,
+}
+
+impl Environment {
+    pub fn current() -> Environment {
+        Environment {
+            rustc: env!("EMU_TEST_RUSTC_VERSION").to_string(),
+            profile: env!("EMU_TEST_PROFILE").to_string(),
+            cpu_model: cpu_model(),
+            git_revision: env!("EMU_TEST_GIT_REVISION").to_string(),
+            os: std::env::consts::OS.to_string(),
+            arch: std::env::consts::ARCH.to_string(),
+        }
+    }
+}
+
+fn default_cpu() -> String {
+    "6502".to_string()
+}
+
+fn default_sync() -> String {
+    "eager".to_string()
+}
+
+/// The `model name` of the first processor listed in `/proc/cpuinfo`.
+fn cpu_model() -> String {
+    std::fs::read_to_string("/proc/cpuinfo")
+        .ok()
+        .and_then(|info| {
+            info.lines()
+                .find(|line| line.starts_with("model name"))
+                .and_then(|line| line.split_once(':'))
+                .map(|(_, model)| model.trim().to_string())
+        })
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+impl VariantReport {
+    /// `measurements` must not be empty; they all did the same work since
+    /// the harness checks that before building a report.
+    pub fn new(name: &str, measurements: &[Measurement]) -> VariantReport {
+        let samples = measurements
+            .iter()
+            .map(|m| m.elapsed.as_secs_f64())
+            .collect::<Vec<_>>();
+        let summary = stats::summarize(&samples);
+        let state = measurements[0].state;
+        let cycles = state.cycles as u64;
+        let instructions = state.instruction_count as u64;
+        VariantReport {
+            name: name.to_string(),
+            samples,
+            summary,
+            cycles,
+            instructions,
+            emulated_mhz: cycles as f64 / summary.median / 1e6,
+            instructions_per_sec: instructions as f64 / summary.median,
+        }
+    }
+}
+
+impl Report {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("reports always serialize")
+    }
+}
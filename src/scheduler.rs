@@ -0,0 +1,207 @@
+//! Keeps components with different clock rates in step, like the scheduler
+//! in byuu's higan. Every component has a timestamp that advances by a
+//! frequency dependent amount per clock, so comparing two timestamps says
+//! which one is behind no matter how fast either of them runs.
+
+use crate::{cpu, spc700};
+
+/// One second in timestamp units. Normalizing only brings the component
+/// that's furthest behind back to 0, so one that runs ahead could still get
+/// another second past this and overflow. The components sync once they're
+/// `MAX_AHEAD` ahead to keep that from happening, see `far_ahead`.
+const SECOND: u64 = u64::MAX >> 1;
+
+/// How far a component can get ahead of the one furthest behind before it
+/// has to let it catch up, no matter the `SyncMode`.
+const MAX_AHEAD: u64 = SECOND / 2;
+
+/// A component, numbered in the order it was added.
+pub type Id = usize;
+
+/// The components `Scheduler::snes` adds.
+pub const CPU: Id = 0;
+pub const APU: Id = 1;
+
+/// When a component hands control to the ones that are behind it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncMode {
+    /// Every time it waits, so nothing ever gets far ahead. This
+    /// is the worst case for the scheduling machinery.
+    Eager,
+    /// Only right before it touches state the others can see, see
+    /// `Bus::shared`, and once more at the end of a run. Until then it runs
+    /// ahead as far as the scheduler lets it, see `Scheduler::far_ahead`.
+    Lazy,
+}
+
+impl SyncMode {
+    pub const NAMES: &'static [&'static str] = &["eager", "lazy"];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SyncMode::Eager => "eager",
+            SyncMode::Lazy => "lazy",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<SyncMode> {
+        match name {
+            "eager" => Some(SyncMode::Eager),
+            "lazy" => Some(SyncMode::Lazy),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Thread {
+    /// Timestamp units per clock.
+    scalar: u64,
+    timestamp: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Scheduler {
+    threads: Vec<Thread>,
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler { threads: vec![] }
+    }
+
+    /// The CPU clocked by the SNES master clock and the APU clocked by its
+    /// own oscillator, as `CPU` and `APU`.
+    pub fn snes() -> Scheduler {
+        let mut scheduler = Scheduler::new();
+        scheduler.add(cpu::FREQUENCY);
+        scheduler.add(spc700::FREQUENCY);
+        scheduler
+    }
+
+    /// Add a component running at `frequency` Hz, starting at the same time
+    /// as a fresh scheduler.
+    pub fn add(&mut self, frequency: u64) -> Id {
+        self.threads.push(Thread {
+            scalar: SECOND / frequency,
+            timestamp: 0,
+        });
+        self.threads.len() - 1
+    }
+
+    /// Advance component `id` by `clocks` of its own clock.
+    pub fn step(&mut self, id: Id, clocks: u32) {
+        let thread = &mut self.threads[id];
+        thread.timestamp += thread.scalar * u64::from(clocks);
+        if thread.timestamp >= SECOND {
+            self.normalize();
+        }
+    }
+
+    /// The component furthest behind, which is the one that should run.
+    /// Ties go to the one added first.
+    pub fn behind(&self) -> Id {
+        let mut id = 0;
+        for (i, thread) in self.threads.iter().enumerate() {
+            if thread.timestamp < self.threads[id].timestamp {
+                id = i;
+            }
+        }
+        id
+    }
+
+    /// Whether component `id` is so far ahead that it has to let the others
+    /// catch up now, even with `SyncMode::Lazy`.
+    pub fn far_ahead(&self, id: Id) -> bool {
+        let timestamp = self.threads[id].timestamp;
+        // The timestamp alone is enough to rule it out most of the time.
+        timestamp >= MAX_AHEAD && timestamp - self.threads[self.behind()].timestamp >= MAX_AHEAD
+    }
+
+    /// Only the differences between timestamps matter, so take the smallest
+    /// one off all of them.
+    fn normalize(&mut self) {
+        let min = self.threads.iter().map(|t| t.timestamp).min().unwrap_or(0);
+        for thread in &mut self.threads {
+            thread.timestamp -= min;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run `id` on its own until the one behind is `other`, like a variant
+    /// running a component until it has caught up.
+    fn catch_up(scheduler: &mut Scheduler, id: Id, other: Id, clocks: u32) {
+        while scheduler.behind() == id {
+            scheduler.step(id, clocks);
+        }
+        assert_eq!(scheduler.behind(), other);
+    }
+
+    #[test]
+    fn behind_goes_by_time_not_clocks() {
+        let mut scheduler = Scheduler::new();
+        let fast = scheduler.add(4_000);
+        let slow = scheduler.add(1_000);
+        assert_eq!(scheduler.behind(), fast, "ties go to the one added first");
+        scheduler.step(fast, 3);
+        assert_eq!(scheduler.behind(), slow);
+        scheduler.step(slow, 1);
+        assert_eq!(scheduler.behind(), fast);
+        scheduler.step(fast, 1);
+        assert_eq!(scheduler.behind(), fast, "a tie again");
+    }
+
+    #[test]
+    fn normalize_keeps_the_order() {
+        let mut scheduler = Scheduler::snes();
+        // Take turns for a little over a second so the timestamps get
+        // normalized along the way.
+        let mut normalized = false;
+        for _ in 0..spc700::FREQUENCY / 1000 + 100 {
+            let before = scheduler.threads[CPU].timestamp;
+            catch_up(&mut scheduler, APU, CPU, 1_000);
+            catch_up(&mut scheduler, CPU, APU, 6);
+            normalized |= scheduler.threads[CPU].timestamp < before;
+        }
+        assert!(normalized);
+        assert_eq!(scheduler.behind(), APU);
+        // The CPU is less than one step of 6 clocks ahead of the APU.
+        let gap = scheduler.threads[CPU].timestamp - scheduler.threads[APU].timestamp;
+        assert!(gap <= 6 * scheduler.threads[CPU].scalar);
+    }
+
+    #[test]
+    fn running_ahead_past_a_second_does_not_overflow() {
+        let mut scheduler = Scheduler::snes();
+        let mut syncs = 0;
+        // Three seconds of the CPU waiting 6 clocks at a time, letting the
+        // APU catch up only when it has to, the way `SyncMode::Lazy` does.
+        for _ in 0..3 * cpu::FREQUENCY / 6 {
+            scheduler.step(CPU, 6);
+            if scheduler.far_ahead(CPU) {
+                catch_up(&mut scheduler, APU, CPU, 64);
+                syncs += 1;
+            }
+        }
+        assert!(syncs >= 5, "only synced {} times in 3 seconds", syncs);
+        assert!(scheduler.threads.iter().all(|t| t.timestamp < SECOND));
+    }
+
+    #[test]
+    fn far_ahead_is_relative() {
+        let mut scheduler = Scheduler::new();
+        let a = scheduler.add(1_000);
+        let b = scheduler.add(1_000);
+        scheduler.step(a, 499);
+        assert!(!scheduler.far_ahead(a));
+        scheduler.step(a, 2);
+        assert!(scheduler.far_ahead(a));
+        assert!(!scheduler.far_ahead(b));
+        scheduler.step(b, 400);
+        assert!(!scheduler.far_ahead(a));
+    }
+}
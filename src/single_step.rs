@@ -0,0 +1,403 @@
+//! Runs Tom Harte's single-step processor tests: a JSON file per opcode,
+//! each with thousands of instructions given as the registers and memory
+//! before and after, along with what was on the bus on every cycle in
+//! between. The tests are far too big to keep in this repository, so
+//! `--single-step` takes the directory of a local copy for the model being
+//! emulated, the one with `00.json` (6502) or `00.e.json` and `00.n.json`
+//! (65816) in it.
+
+use crate::bus::{Bus, FAST};
+use crate::cpu::{Constructor, Cpu, Model, Registers};
+use crate::m6502;
+use crate::scheduler::SyncMode;
+use crate::spc700::{Apu, Ports};
+use crate::trace::{self, Access, Kind};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+#[derive(Deserialize)]
+struct Test {
+    name: String,
+    initial: Snapshot,
+    #[serde(rename = "final")]
+    after: Snapshot,
+    /// Address, data and what kind of cycle it was, see `expected_cycle`.
+    cycles: Vec<(Option<u32>, Option<u8>, String)>,
+}
+
+/// The registers and whatever memory the instruction touches. The 6502
+/// tests leave out the registers only the 65816 has.
+#[derive(Deserialize)]
+struct Snapshot {
+    pc: u16,
+    s: u16,
+    p: u8,
+    a: u16,
+    x: u16,
+    y: u16,
+    #[serde(default)]
+    d: u16,
+    #[serde(default)]
+    dbr: u8,
+    #[serde(default)]
+    pbr: u8,
+    #[serde(default = "emulation_mode")]
+    e: u8,
+    ram: Vec<(u32, u8)>,
+}
+
+fn emulation_mode() -> u8 {
+    1
+}
+
+impl Snapshot {
+    fn registers(&self, model: Model) -> Registers {
+        let mut regs = Registers {
+            pc: self.pc,
+            x: self.x,
+            y: self.y,
+            a: self.a,
+            s: self.s,
+            p: self.p,
+            d: self.d,
+            dbr: self.dbr,
+            pbr: self.pbr,
+            e: self.e != 0,
+        };
+        if model == Model::Nmos6502 {
+            regs.s |= 0x0100;
+            // The tests have whatever they pushed last in B and U.
+            regs.set_p(self.p);
+        }
+        regs
+    }
+}
+
+/// Memory holding only what the test that's running put there, shared with
+/// the runner so it can set up and check every test on the same CPU.
+/// Everything else reads as 0.
+#[derive(Clone, Default)]
+struct Memory(Arc<Mutex<HashMap<u32, u8>>>);
+
+impl Bus for Memory {
+    fn read(&mut self, addr: u32) -> u8 {
+        let memory = self.0.lock().expect("the runner panicked");
+        memory.get(&addr).copied().unwrap_or(0)
+    }
+
+    fn write(&mut self, addr: u32, data: u8) {
+        self.0.lock().expect("the runner panicked").insert(addr, data);
+    }
+
+    fn load(&mut self, addr: u32, image: &[u8]) {
+        let mut memory = self.0.lock().expect("the runner panicked");
+        for (i, &data) in image.iter().enumerate() {
+            memory.insert(addr + i as u32, data);
+        }
+    }
+}
+
+/// The accesses of the test that's running, shared with the runner like
+/// `Memory`.
+#[derive(Clone, Default)]
+struct Accesses(Arc<Mutex<Vec<Access>>>);
+
+impl trace::Sink for Accesses {
+    fn record(&mut self, access: Access) {
+        self.0.lock().expect("the runner panicked").push(access);
+    }
+}
+
+/// A cycle that used the bus. Internal operations are `None`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct BusCycle {
+    addr: u32,
+    /// Some tests leave the data out, which matches anything.
+    data: Option<u8>,
+    kind: Kind,
+}
+
+/// The 6502 tests call every cycle a `read` or a `write`. The 65816 ones
+/// give the pins instead, a character each, with VDA and VPA first and R/W
+/// fourth; with neither VDA nor VPA the cycle is an internal operation.
+fn expected_cycle((addr, data, pins): &(Option<u32>, Option<u8>, String)) -> Option<BusCycle> {
+    let kind = match pins.as_str() {
+        "read" => Kind::Read,
+        "write" => Kind::Write,
+        pins => {
+            let pins = pins.as_bytes();
+            if pins.first() != Some(&b'd') && pins.get(1) != Some(&b'p') {
+                return None;
+            }
+            if pins.get(3) == Some(&b'w') {
+                Kind::Write
+            } else {
+                Kind::Read
+            }
+        }
+    };
+    Some(BusCycle {
+        addr: (*addr)?,
+        data: *data,
+        kind,
+    })
+}
+
+/// What the CPU did on every cycle, going by when the data of each access
+/// moved. Accesses and idle cycles all take `FAST` master clocks on
+/// `Memory`.
+fn actual_cycles(accesses: &[Access], clocks: u32) -> Vec<Option<BusCycle>> {
+    let mut cycles = vec![None; clocks.div_ceil(FAST) as usize];
+    for access in accesses {
+        let i = (access.cycle / FAST) as usize;
+        if i >= cycles.len() {
+            cycles.resize(i + 1, None);
+        }
+        cycles[i] = Some(BusCycle {
+            addr: access.addr,
+            data: Some(access.data),
+            kind: access.kind,
+        });
+    }
+    cycles
+}
+
+fn describe(cycle: Option<&Option<BusCycle>>) -> String {
+    match cycle {
+        None => "the end of the instruction".to_string(),
+        Some(None) => "an internal operation".to_string(),
+        Some(Some(BusCycle { addr, data, kind })) => {
+            let data = data.map_or("?".to_string(), |data| format!("{:02x}", data));
+            match kind {
+                Kind::Read => format!("a read of {} from {:06x}", data, addr),
+                Kind::Write => format!("a write of {} to {:06x}", data, addr),
+            }
+        }
+    }
+}
+
+fn same_cycle(expected: &Option<BusCycle>, actual: &Option<BusCycle>) -> bool {
+    match (expected, actual) {
+        (Some(expected), Some(actual)) => {
+            expected.addr == actual.addr
+                && expected.kind == actual.kind
+                && (expected.data.is_none() || expected.data == actual.data)
+        }
+        (expected, actual) => expected == actual,
+    }
+}
+
+/// Run a single test, saying where it first went wrong if it did: the first
+/// cycle that differs, then the registers, then memory.
+fn run_test(cpu: &mut dyn Cpu, memory: &Memory, accesses: &Accesses, model: Model, test: &Test) -> Result<(), String> {
+    {
+        let mut memory = memory.0.lock().expect("the CPU panicked");
+        memory.clear();
+        memory.extend(test.initial.ram.iter().copied());
+    }
+    accesses.0.lock().expect("the CPU panicked").clear();
+    cpu.reset_registers();
+    cpu.set_registers(test.initial.registers(model));
+    cpu.step();
+
+    let expected = test.cycles.iter().map(expected_cycle).collect::<Vec<_>>();
+    let actual = actual_cycles(&accesses.0.lock().expect("the CPU panicked"), cpu.cycles());
+    for i in 0..expected.len().max(actual.len()) {
+        let (want, got) = (expected.get(i), actual.get(i));
+        let same = match (want, got) {
+            (Some(want), Some(got)) => same_cycle(want, got),
+            _ => false,
+        };
+        if !same {
+            return Err(format!(
+                "cycle {}: expected {}, got {}",
+                i + 1,
+                describe(want),
+                describe(got)
+            ));
+        }
+    }
+
+    let want = test.after.registers(model);
+    let got = cpu.registers();
+    if want != got {
+        return Err(format!("expected registers {:?}, got {:?}", want, got));
+    }
+
+    let memory = memory.0.lock().expect("the CPU panicked");
+    for &(addr, want) in &test.after.ram {
+        let got = memory.get(&addr).copied().unwrap_or(0);
+        if want != got {
+            return Err(format!("expected {:02x} at {:06x}, got {:02x}", want, addr, got));
+        }
+    }
+    Ok(())
+}
+
+/// Run every file of tests in `dir` on each of `variants`, printing how
+/// many of them passed along with the first failure of every file. Whether
+/// all of them passed.
+pub fn run(dir: &str, variants: &[&(&str, Constructor)], model: Model, sync: SyncMode) -> Result<bool, String> {
+    let mut files = fs::read_dir(dir)
+        .map_err(|e| format!("couldn't read {}: {}", dir, e))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension() == Some("json".as_ref()))
+        .collect::<Vec<PathBuf>>();
+    files.sort();
+    if files.is_empty() {
+        return Err(format!("there are no tests in {}", dir));
+    }
+
+    let mut all_passed = true;
+    for (name, new) in variants {
+        let memory = Memory::default();
+        let accesses = Accesses::default();
+        let apu = Apu::new(Arc::new(Ports::default()));
+        let mut cpu = new(model, sync, Box::new(memory.clone()), apu);
+        if !cpu.full_isa() {
+            eprintln!("skipping {} variant, it only implements a few instructions", name);
+            continue;
+        }
+        cpu.trace(Box::new(accesses.clone()));
+
+        let (mut passed, mut total) = (0, 0);
+        for path in &files {
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+            let opcode = stem.get(..2).and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            if model == Model::Nmos6502 && !matches!(opcode, Some(opcode) if m6502::documented(opcode)) {
+                println!("{} {}: skipped, not a documented opcode", name, stem);
+                continue;
+            }
+            let json = fs::read(path).map_err(|e| format!("couldn't read {}: {}", path.display(), e))?;
+            let tests = serde_json::from_slice::<Vec<Test>>(&json)
+                .map_err(|e| format!("couldn't parse {}: {}", path.display(), e))?;
+            let mut first_failure = None;
+            let mut file_passed = 0;
+            for test in &tests {
+                match run_test(&mut *cpu, &memory, &accesses, model, test) {
+                    Ok(()) => file_passed += 1,
+                    Err(why) => {
+                        first_failure.get_or_insert_with(|| format!("{}: {}", test.name, why));
+                    }
+                }
+            }
+            print!("{} {}: {}/{} passed", name, stem, file_passed, tests.len());
+            match first_failure {
+                Some(failure) => println!(", first failure {}", failure),
+                None => println!(),
+            }
+            passed += file_passed;
+            total += tests.len();
+        }
+        println!("{}: {}/{} passed", name, passed, total);
+        all_passed &= passed == total;
+    }
+    Ok(all_passed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `LDA #$42` on the 6502.
+    const LDA_IMMEDIATE: &str = r#"{
+        "name": "a9 0",
+        "initial": {"pc": 512, "s": 253, "p": 36, "a": 0, "x": 0, "y": 0, "ram": [[512, 169], [513, 66]]},
+        "final": {"pc": 514, "s": 253, "p": 36, "a": 66, "x": 0, "y": 0, "ram": [[512, 169], [513, 66]]},
+        "cycles": [[512, 169, "read"], [513, 66, "read"]]
+    }"#;
+
+    /// `STA $10` on the 65816 in emulation mode, with the low byte of the
+    /// direct page register set so it takes an internal operation.
+    const STA_DIRECT: &str = r#"{
+        "name": "85 e 0",
+        "initial": {"pc": 512, "s": 509, "p": 52, "a": 4660, "x": 0, "y": 0, "d": 1, "dbr": 0, "pbr": 0, "e": 1,
+            "ram": [[512, 133], [513, 16]]},
+        "final": {"pc": 514, "s": 509, "p": 52, "a": 4660, "x": 0, "y": 0, "d": 1, "dbr": 0, "pbr": 0, "e": 1,
+            "ram": [[512, 133], [513, 16], [17, 52]]},
+        "cycles": [[512, 133, "dp-remx-"], [513, 16, "-p-remx-"], [513, null, "---remx-"], [17, 52, "d--wemx-"]]
+    }"#;
+
+    /// Run `json` on the `null` variant.
+    fn run_json(model: Model, json: &str) -> Result<(), String> {
+        let test = serde_json::from_str::<Test>(json).expect("the test doesn't parse");
+        let (_, new) = crate::VARIANTS.iter().find(|(name, _)| *name == "null").expect("there's no null variant");
+        let memory = Memory::default();
+        let accesses = Accesses::default();
+        let apu = Apu::new(Arc::new(Ports::default()));
+        let mut cpu = new(model, SyncMode::Eager, Box::new(memory.clone()), apu);
+        cpu.trace(Box::new(accesses.clone()));
+        run_test(&mut *cpu, &memory, &accesses, model, &test)
+    }
+
+    #[test]
+    fn pins() {
+        let cycle = |pins: &str| expected_cycle(&(Some(0x123456), Some(0x78), pins.to_string()));
+        let read = Some(BusCycle {
+            addr: 0x123456,
+            data: Some(0x78),
+            kind: Kind::Read,
+        });
+        let write = read.map(|read| BusCycle {
+            kind: Kind::Write,
+            ..read
+        });
+        assert_eq!(cycle("read"), read);
+        assert_eq!(cycle("write"), write);
+        assert_eq!(cycle("dp-remx-"), read);
+        assert_eq!(cycle("-p-remx-"), read);
+        assert_eq!(cycle("d--wemx-"), write);
+        assert_eq!(cycle("---remx-"), None);
+        assert_eq!(cycle("---wemx-"), None);
+    }
+
+    #[test]
+    fn bus_cycles_go_by_when_the_data_moves() {
+        let access = |cycle, addr, kind| Access {
+            cycle,
+            addr,
+            data: 0,
+            kind,
+        };
+        // Two reads, an internal operation and a write, with the data moving
+        // 2 master clocks into each.
+        let accesses = [
+            access(2, 0x0200, Kind::Read),
+            access(8, 0x0201, Kind::Read),
+            access(20, 0x0011, Kind::Write),
+        ];
+        let cycles = actual_cycles(&accesses, 4 * FAST);
+        assert_eq!(cycles.len(), 4);
+        assert_eq!(cycles.iter().map(|c| c.map(|c| c.addr)).collect::<Vec<_>>(), [
+            Some(0x0200),
+            Some(0x0201),
+            None,
+            Some(0x0011)
+        ]);
+        // Trailing internal operations still count.
+        assert_eq!(actual_cycles(&accesses[..1], 3 * FAST).len(), 3);
+    }
+
+    #[test]
+    fn vectors() {
+        assert_eq!(run_json(Model::Nmos6502, LDA_IMMEDIATE), Ok(()));
+        assert_eq!(run_json(Model::Wdc65816, STA_DIRECT), Ok(()));
+    }
+
+    #[test]
+    fn first_wrong_cycle() {
+        let wrong = STA_DIRECT.replace("[513, 16, \"-p-remx-\"]", "[513, 17, \"-p-remx-\"]");
+        assert_eq!(
+            run_json(Model::Wdc65816, &wrong),
+            Err("cycle 2: expected a read of 11 from 000201, got a read of 10 from 000201".to_string())
+        );
+        let short = LDA_IMMEDIATE.replace(", [513, 66, \"read\"]", "");
+        assert_eq!(
+            run_json(Model::Nmos6502, &short),
+            Err("cycle 2: expected the end of the instruction, got a read of 42 from 000201".to_string())
+        );
+    }
+}
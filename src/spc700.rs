@@ -0,0 +1,1155 @@
+//! The SPC700, the SNES sound CPU, for the other side of the context
+//! switches. It has its own 64 KiB of RAM and only talks to the main CPU
+//! through four I/O ports each way.
+//!
+//! Unlike `m6502` and `w65816` this core is plain code rather than a macro:
+//! the APU only synchronizes with the CPU between its instructions, so it
+//! never has to suspend in the middle of one. That keeps the results
+//! identical in the variants that can't suspend at all, while the variants
+//! that can still switch to and from a separate coroutine, task or state
+//! machine for every APU instruction the CPU waits on. The cycle layout
+//! follows byuu's higan core; the DSP, timers and IPL ROM aren't emulated.
+use crate::bus::Device;
+use crate::scheduler::{Scheduler, APU};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+
+pub const FLAG_C: u8 = 0x01;
+pub const FLAG_Z: u8 = 0x02;
+pub const FLAG_I: u8 = 0x04;
+/// Half carry, out of bit 3.
+pub const FLAG_H: u8 = 0x08;
+pub const FLAG_B: u8 = 0x10;
+/// Direct page is page 1 instead of page 0.
+pub const FLAG_P: u8 = 0x20;
+pub const FLAG_V: u8 = 0x40;
+pub const FLAG_N: u8 = 0x80;
+
+/// The APU's oscillator, in Hz.
+pub const FREQUENCY: u64 = 24_576_000;
+/// Oscillator clocks per APU cycle.
+pub const CLOCKS_PER_CYCLE: u32 = 24;
+
+/// Where the CPU sees the ports, mirrored every four bytes.
+pub const CPU_PORTS_START: u32 = 0x2140;
+pub const CPU_PORTS_END: u32 = 0x217f;
+/// Where the APU sees them.
+const APU_PORTS_START: u16 = 0x00f4;
+const APU_PORTS_END: u16 = 0x00f7;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Registers {
+    pub pc: u16,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub sp: u8,
+    pub psw: u8,
+}
+
+/// What the APU leaves behind, compared across variants like the CPU's
+/// `cpu::State`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct State {
+    pub registers: Registers,
+    /// APU cycles, not master clock cycles.
+    pub cycles: u32,
+    pub instruction_count: u32,
+}
+
+/// The four bytes each way between the CPU and the APU. A read on either
+/// side returns what the other side last wrote. They're atomics because
+/// some variants run the APU on another thread, but the two sides never
+/// run at the same time.
+#[derive(Default)]
+pub struct Ports {
+    to_apu: [AtomicU8; 4],
+    to_cpu: [AtomicU8; 4],
+}
+
+/// The CPU's side of `Ports`, to be mapped at `CPU_PORTS_START`.
+pub struct CpuPorts(pub Arc<Ports>);
+
+impl Device for CpuPorts {
+    fn read(&mut self, offset: u32) -> Option<u8> {
+        Some(self.0.to_cpu[offset as usize & 3].load(Ordering::Relaxed))
+    }
+
+    fn write(&mut self, offset: u32, data: u8) {
+        self.0.to_apu[offset as usize & 3].store(data, Ordering::Relaxed);
+    }
+}
+
+type Alu = fn(&mut Apu, u8, u8) -> u8;
+type Modify = fn(&mut Apu, u8) -> u8;
+
+/// The binary ALU operations in opcode order: bits 5-7 of `OR`, `AND`,
+/// `EOR`, `CMP`, `ADC` and `SBC` opcodes index this.
+const ALU: [Alu; 6] = [Apu::or, Apu::and, Apu::eor, Apu::cmp, Apu::adc, Apu::sbc];
+/// `CMP`'s index in `ALU`. Its memory forms don't write the result back.
+const CMP: usize = 3;
+/// The same for `ASL`, `ROL`, `LSR`, `ROR`, `DEC` and `INC`.
+const MODIFY: [Modify; 6] = [Apu::asl, Apu::rol, Apu::lsr, Apu::ror, Apu::dec, Apu::inc];
+
+pub struct Apu {
+    pub regs: Registers,
+    ram: Vec<u8>,
+    ports: Arc<Ports>,
+    cycles: u32,
+    instruction_count: u32,
+}
+
+impl Apu {
+    pub fn new(ports: Arc<Ports>) -> Apu {
+        Apu {
+            regs: Registers {
+                sp: 0xef,
+                ..Registers::default()
+            },
+            ram: vec![0; 0x1_0000],
+            ports,
+            cycles: 0,
+            instruction_count: 0,
+        }
+    }
+
+    /// Copy `image` into RAM starting at address 0 and continue from `pc`.
+    pub fn load(&mut self, image: &[u8], pc: u16) {
+        self.ram[..image.len()].copy_from_slice(image);
+        self.regs.pc = pc;
+    }
+
+    pub fn state(&self) -> State {
+        State {
+            registers: self.regs,
+            cycles: self.cycles,
+            instruction_count: self.instruction_count,
+        }
+    }
+
+    /// Run whole instructions for as long as the APU is the component
+    /// furthest behind in `scheduler`.
+    pub fn run(&mut self, scheduler: &mut Scheduler) {
+        while scheduler.behind() == APU {
+            let cycles = self.cycles;
+            self.execute_instruction();
+            scheduler.step(APU, self.cycles.wrapping_sub(cycles) * CLOCKS_PER_CYCLE);
+        }
+    }
+
+    fn read(&mut self, addr: u16) -> u8 {
+        self.cycles += 1;
+        match addr {
+            APU_PORTS_START..=APU_PORTS_END => {
+                self.ports.to_apu[(addr - APU_PORTS_START) as usize].load(Ordering::Relaxed)
+            }
+            _ => self.ram[addr as usize],
+        }
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        self.cycles += 1;
+        match addr {
+            APU_PORTS_START..=APU_PORTS_END => {
+                self.ports.to_cpu[(addr - APU_PORTS_START) as usize].store(data, Ordering::Relaxed)
+            }
+            _ => self.ram[addr as usize] = data,
+        }
+    }
+
+    fn idle(&mut self) {
+        self.cycles += 1;
+    }
+
+    fn flag(&self, flag: u8) -> bool {
+        self.regs.psw & flag != 0
+    }
+
+    fn set_flag(&mut self, flag: u8, on: bool) {
+        if on {
+            self.regs.psw |= flag;
+        } else {
+            self.regs.psw &= !flag;
+        }
+    }
+
+    fn set_nz(&mut self, v: u8) -> u8 {
+        self.set_flag(FLAG_Z, v == 0);
+        self.set_flag(FLAG_N, v & 0x80 != 0);
+        v
+    }
+
+    fn ya(&self) -> u16 {
+        (self.regs.y as u16) << 8 | self.regs.a as u16
+    }
+
+    fn set_ya(&mut self, v: u16) {
+        self.regs.a = v as u8;
+        self.regs.y = (v >> 8) as u8;
+    }
+
+    fn fetch(&mut self) -> u8 {
+        let pc = self.regs.pc;
+        self.regs.pc = pc.wrapping_add(1);
+        self.read(pc)
+    }
+
+    fn fetch16(&mut self) -> u16 {
+        let lo = self.fetch() as u16;
+        lo | (self.fetch() as u16) << 8
+    }
+
+    /// A direct page address, which wraps within the page.
+    fn page(&self, addr: u8) -> u16 {
+        if self.flag(FLAG_P) {
+            0x0100 | addr as u16
+        } else {
+            addr as u16
+        }
+    }
+
+    fn read_dp(&mut self, addr: u8) -> u8 {
+        let addr = self.page(addr);
+        self.read(addr)
+    }
+
+    fn write_dp(&mut self, addr: u8, data: u8) {
+        let addr = self.page(addr);
+        self.write(addr, data)
+    }
+
+    fn push(&mut self, data: u8) {
+        let sp = self.regs.sp;
+        self.regs.sp = sp.wrapping_sub(1);
+        self.write(0x0100 | sp as u16, data);
+    }
+
+    fn pull(&mut self) -> u8 {
+        self.regs.sp = self.regs.sp.wrapping_add(1);
+        let sp = self.regs.sp;
+        self.read(0x0100 | sp as u16)
+    }
+
+    /// The dummy read of the next instruction byte most implied mode
+    /// instructions do.
+    fn read_pc(&mut self) {
+        let pc = self.regs.pc;
+        self.read(pc);
+    }
+
+    fn or(&mut self, x: u8, y: u8) -> u8 {
+        self.set_nz(x | y)
+    }
+
+    fn and(&mut self, x: u8, y: u8) -> u8 {
+        self.set_nz(x & y)
+    }
+
+    fn eor(&mut self, x: u8, y: u8) -> u8 {
+        self.set_nz(x ^ y)
+    }
+
+    /// Returns `x` so it can stand in for the other ALU operations.
+    fn cmp(&mut self, x: u8, y: u8) -> u8 {
+        let r = x as i16 - y as i16;
+        self.set_flag(FLAG_N, r & 0x80 != 0);
+        self.set_flag(FLAG_Z, r as u8 == 0);
+        self.set_flag(FLAG_C, r >= 0);
+        x
+    }
+
+    fn adc(&mut self, x: u8, y: u8) -> u8 {
+        let r = x as u16 + y as u16 + self.flag(FLAG_C) as u16;
+        self.set_flag(FLAG_V, !(x ^ y) & (x ^ r as u8) & 0x80 != 0);
+        self.set_flag(FLAG_H, (x ^ y ^ r as u8) & 0x10 != 0);
+        self.set_flag(FLAG_C, r > 0xff);
+        self.set_nz(r as u8)
+    }
+
+    fn sbc(&mut self, x: u8, y: u8) -> u8 {
+        self.adc(x, !y)
+    }
+
+    fn ld(&mut self, _: u8, y: u8) -> u8 {
+        self.set_nz(y)
+    }
+
+    fn asl(&mut self, x: u8) -> u8 {
+        self.set_flag(FLAG_C, x & 0x80 != 0);
+        self.set_nz(x << 1)
+    }
+
+    fn lsr(&mut self, x: u8) -> u8 {
+        self.set_flag(FLAG_C, x & 0x01 != 0);
+        self.set_nz(x >> 1)
+    }
+
+    fn rol(&mut self, x: u8) -> u8 {
+        let carry = self.flag(FLAG_C) as u8;
+        self.set_flag(FLAG_C, x & 0x80 != 0);
+        self.set_nz(x << 1 | carry)
+    }
+
+    fn ror(&mut self, x: u8) -> u8 {
+        let carry = (self.flag(FLAG_C) as u8) << 7;
+        self.set_flag(FLAG_C, x & 0x01 != 0);
+        self.set_nz(carry | x >> 1)
+    }
+
+    fn inc(&mut self, x: u8) -> u8 {
+        self.set_nz(x.wrapping_add(1))
+    }
+
+    fn dec(&mut self, x: u8) -> u8 {
+        self.set_nz(x.wrapping_sub(1))
+    }
+
+    fn addw(&mut self, x: u16, y: u16) -> u16 {
+        self.set_flag(FLAG_C, false);
+        let lo = self.adc(x as u8, y as u8) as u16;
+        let r = lo | (self.adc((x >> 8) as u8, (y >> 8) as u8) as u16) << 8;
+        self.set_flag(FLAG_Z, r == 0);
+        r
+    }
+
+    fn subw(&mut self, x: u16, y: u16) -> u16 {
+        self.set_flag(FLAG_C, true);
+        let lo = self.sbc(x as u8, y as u8) as u16;
+        let r = lo | (self.sbc((x >> 8) as u8, (y >> 8) as u8) as u16) << 8;
+        self.set_flag(FLAG_Z, r == 0);
+        r
+    }
+
+    fn cmpw(&mut self, x: u16, y: u16) -> u16 {
+        let r = x as i32 - y as i32;
+        self.set_flag(FLAG_N, r & 0x8000 != 0);
+        self.set_flag(FLAG_Z, r as u16 == 0);
+        self.set_flag(FLAG_C, r >= 0);
+        x
+    }
+
+    fn ldw(&mut self, _: u16, y: u16) -> u16 {
+        self.set_flag(FLAG_Z, y == 0);
+        self.set_flag(FLAG_N, y & 0x8000 != 0);
+        y
+    }
+
+    fn immediate_read(&mut self, op: Alu, target: u8) -> u8 {
+        let data = self.fetch();
+        op(self, target, data)
+    }
+
+    fn direct_read(&mut self, op: Alu, target: u8) -> u8 {
+        let addr = self.fetch();
+        let data = self.read_dp(addr);
+        op(self, target, data)
+    }
+
+    fn direct_indexed_read(&mut self, op: Alu, target: u8, index: u8) -> u8 {
+        let addr = self.fetch();
+        self.idle();
+        let data = self.read_dp(addr.wrapping_add(index));
+        op(self, target, data)
+    }
+
+    fn absolute_read(&mut self, op: Alu, target: u8) -> u8 {
+        let addr = self.fetch16();
+        let data = self.read(addr);
+        op(self, target, data)
+    }
+
+    fn absolute_indexed_read(&mut self, op: Alu, index: u8) -> u8 {
+        let addr = self.fetch16();
+        self.idle();
+        let data = self.read(addr.wrapping_add(index as u16));
+        op(self, self.regs.a, data)
+    }
+
+    fn indirect_x_read(&mut self, op: Alu) -> u8 {
+        self.read_pc();
+        let data = self.read_dp(self.regs.x);
+        op(self, self.regs.a, data)
+    }
+
+    /// `[dp+X]`
+    fn indexed_indirect_address(&mut self) -> u16 {
+        let indirect = self.fetch().wrapping_add(self.regs.x);
+        self.idle();
+        let lo = self.read_dp(indirect) as u16;
+        lo | (self.read_dp(indirect.wrapping_add(1)) as u16) << 8
+    }
+
+    /// `[dp]+Y`
+    fn indirect_indexed_address(&mut self) -> u16 {
+        let indirect = self.fetch();
+        let lo = self.read_dp(indirect) as u16;
+        let addr = lo | (self.read_dp(indirect.wrapping_add(1)) as u16) << 8;
+        self.idle();
+        addr.wrapping_add(self.regs.y as u16)
+    }
+
+    fn indexed_indirect_read(&mut self, op: Alu) -> u8 {
+        let addr = self.indexed_indirect_address();
+        let data = self.read(addr);
+        op(self, self.regs.a, data)
+    }
+
+    fn indirect_indexed_read(&mut self, op: Alu) -> u8 {
+        let addr = self.indirect_indexed_address();
+        let data = self.read(addr);
+        op(self, self.regs.a, data)
+    }
+
+    /// `op dp,dp`, the source operand comes first.
+    fn direct_direct(&mut self, alu: usize) {
+        let source = self.fetch();
+        let rhs = self.read_dp(source);
+        let target = self.fetch();
+        let lhs = self.read_dp(target);
+        let r = ALU[alu](self, lhs, rhs);
+        if alu == CMP {
+            self.idle();
+        } else {
+            self.write_dp(target, r);
+        }
+    }
+
+    /// `op dp,#imm`, the immediate comes first.
+    fn direct_immediate(&mut self, alu: usize) {
+        let immediate = self.fetch();
+        let addr = self.fetch();
+        let data = self.read_dp(addr);
+        let r = ALU[alu](self, data, immediate);
+        if alu == CMP {
+            self.idle();
+        } else {
+            self.write_dp(addr, r);
+        }
+    }
+
+    /// `op (X),(Y)`
+    fn indirect_x_indirect_y(&mut self, alu: usize) {
+        self.read_pc();
+        let rhs = self.read_dp(self.regs.y);
+        let lhs = self.read_dp(self.regs.x);
+        let r = ALU[alu](self, lhs, rhs);
+        if alu == CMP {
+            self.idle();
+        } else {
+            self.write_dp(self.regs.x, r);
+        }
+    }
+
+    fn direct_write(&mut self, data: u8) {
+        let addr = self.fetch();
+        self.read_dp(addr);
+        self.write_dp(addr, data);
+    }
+
+    fn direct_indexed_write(&mut self, data: u8, index: u8) {
+        let addr = self.fetch().wrapping_add(index);
+        self.idle();
+        self.read_dp(addr);
+        self.write_dp(addr, data);
+    }
+
+    fn absolute_write(&mut self, data: u8) {
+        let addr = self.fetch16();
+        self.read(addr);
+        self.write(addr, data);
+    }
+
+    fn absolute_indexed_write(&mut self, index: u8) {
+        let addr = self.fetch16();
+        self.idle();
+        let addr = addr.wrapping_add(index as u16);
+        self.read(addr);
+        self.write(addr, self.regs.a);
+    }
+
+    fn indexed_indirect_write(&mut self) {
+        let addr = self.indexed_indirect_address();
+        self.read(addr);
+        self.write(addr, self.regs.a);
+    }
+
+    fn indirect_indexed_write(&mut self) {
+        let addr = self.indirect_indexed_address();
+        self.read(addr);
+        self.write(addr, self.regs.a);
+    }
+
+    fn implied_modify(&mut self, op: Modify, target: u8) -> u8 {
+        self.read_pc();
+        op(self, target)
+    }
+
+    fn direct_modify(&mut self, op: Modify) {
+        let addr = self.fetch();
+        let data = self.read_dp(addr);
+        let r = op(self, data);
+        self.write_dp(addr, r);
+    }
+
+    fn direct_indexed_modify(&mut self, op: Modify) {
+        let addr = self.fetch().wrapping_add(self.regs.x);
+        self.idle();
+        let data = self.read_dp(addr);
+        let r = op(self, data);
+        self.write_dp(addr, r);
+    }
+
+    fn absolute_modify(&mut self, op: Modify) {
+        let addr = self.fetch16();
+        let data = self.read(addr);
+        let r = op(self, data);
+        self.write(addr, r);
+    }
+
+    /// `MOVW YA,dp`, `ADDW` and `SUBW`.
+    fn direct_read_word(&mut self, op: fn(&mut Apu, u16, u16) -> u16) {
+        let addr = self.fetch();
+        let lo = self.read_dp(addr) as u16;
+        self.idle();
+        let data = lo | (self.read_dp(addr.wrapping_add(1)) as u16) << 8;
+        let r = op(self, self.ya(), data);
+        self.set_ya(r);
+    }
+
+    /// `INCW` and `DECW`. The carry out of the low byte goes into the high
+    /// byte between the two writes.
+    fn direct_modify_word(&mut self, adjust: u16) {
+        let addr = self.fetch();
+        let data = (self.read_dp(addr) as u16).wrapping_add(adjust);
+        self.write_dp(addr, data as u8);
+        let data = data.wrapping_add((self.read_dp(addr.wrapping_add(1)) as u16) << 8);
+        self.write_dp(addr.wrapping_add(1), (data >> 8) as u8);
+        self.set_flag(FLAG_Z, data == 0);
+        self.set_flag(FLAG_N, data & 0x8000 != 0);
+    }
+
+    /// Taking a branch costs two extra cycles.
+    fn branch_to(&mut self, displacement: u8, take: bool) {
+        if take {
+            self.idle();
+            self.idle();
+            self.regs.pc = self.regs.pc.wrapping_add(displacement as i8 as u16);
+        }
+    }
+
+    fn branch(&mut self, take: bool) {
+        let displacement = self.fetch();
+        self.branch_to(displacement, take);
+    }
+
+    /// `BBS` and `BBC`.
+    fn branch_bit(&mut self, bit: u8, set: bool) {
+        let addr = self.fetch();
+        let data = self.read_dp(addr);
+        self.idle();
+        let displacement = self.fetch();
+        self.branch_to(displacement, (data >> bit & 1 != 0) == set);
+    }
+
+    /// `SET1` and `CLR1`.
+    fn set_bit(&mut self, bit: u8, set: bool) {
+        let addr = self.fetch();
+        let data = self.read_dp(addr);
+        let data = if set { data | 1 << bit } else { data & !(1 << bit) };
+        self.write_dp(addr, data);
+    }
+
+    /// The bit instructions on the carry, `mem.bit` packs a 13-bit address
+    /// and a bit number into one word.
+    fn absolute_bit(&mut self, opcode: u8) {
+        let operand = self.fetch16();
+        let bit = operand >> 13;
+        let addr = operand & 0x1fff;
+        let data = self.read(addr);
+        let set = data >> bit & 1 != 0;
+        let carry = self.flag(FLAG_C);
+        match opcode {
+            // OR1 C,mem.bit / OR1 C,/mem.bit
+            0x0a | 0x2a => {
+                self.idle();
+                self.set_flag(FLAG_C, carry | (set == (opcode == 0x0a)));
+            }
+            // AND1 C,mem.bit / AND1 C,/mem.bit
+            0x4a | 0x6a => self.set_flag(FLAG_C, carry & (set == (opcode == 0x4a))),
+            // EOR1 C,mem.bit
+            0x8a => {
+                self.idle();
+                self.set_flag(FLAG_C, carry ^ set);
+            }
+            // MOV1 C,mem.bit
+            0xaa => self.set_flag(FLAG_C, set),
+            // MOV1 mem.bit,C
+            0xca => {
+                self.idle();
+                let data = if carry { data | 1 << bit } else { data & !(1 << bit) };
+                self.write(addr, data);
+            }
+            // NOT1 mem.bit
+            _ => self.write(addr, data ^ 1 << bit),
+        }
+    }
+
+    /// `TSET1` and `TCLR1`.
+    fn test_set_bits(&mut self, set: bool) {
+        let addr = self.fetch16();
+        let data = self.read(addr);
+        self.set_nz(self.regs.a.wrapping_sub(data));
+        self.read(addr);
+        let a = self.regs.a;
+        self.write(addr, if set { data | a } else { data & !a });
+    }
+
+    /// `CBNE dp,rel` and `CBNE dp+X,rel`.
+    fn compare_branch(&mut self, index: Option<u8>) {
+        let mut addr = self.fetch();
+        if let Some(index) = index {
+            self.idle();
+            addr = addr.wrapping_add(index);
+        }
+        let data = self.read_dp(addr);
+        self.idle();
+        let displacement = self.fetch();
+        self.branch_to(displacement, self.regs.a != data);
+    }
+
+    fn push_register(&mut self, data: u8) {
+        self.read_pc();
+        self.push(data);
+        self.idle();
+    }
+
+    fn pull_register(&mut self) -> u8 {
+        self.read_pc();
+        self.idle();
+        self.pull()
+    }
+
+    fn push_pc(&mut self) {
+        let pc = self.regs.pc;
+        self.push((pc >> 8) as u8);
+        self.push(pc as u8);
+    }
+
+    fn pull_pc(&mut self) {
+        let lo = self.pull() as u16;
+        self.regs.pc = lo | (self.pull() as u16) << 8;
+    }
+
+    fn vector(&mut self, addr: u16) {
+        let lo = self.read(addr) as u16;
+        self.regs.pc = lo | (self.read(addr.wrapping_add(1)) as u16) << 8;
+    }
+
+    fn transfer(&mut self, from: u8) -> u8 {
+        self.read_pc();
+        self.set_nz(from)
+    }
+
+    /// `SLEEP` and `STOP`. Nothing wakes the APU up again, so the
+    /// instruction just repeats.
+    fn halt(&mut self) {
+        self.read_pc();
+        self.idle();
+        self.regs.pc = self.regs.pc.wrapping_sub(1);
+    }
+
+    pub fn execute_instruction(&mut self) {
+        let opcode = self.fetch();
+        let (a, x, y) = (self.regs.a, self.regs.x, self.regs.y);
+        match opcode {
+            0x00 => self.read_pc(),
+            0x0b | 0x2b | 0x4b | 0x6b | 0x8b | 0xab => self.direct_modify(MODIFY[opcode as usize >> 5]),
+            0x0c | 0x2c | 0x4c | 0x6c | 0x8c | 0xac => self.absolute_modify(MODIFY[opcode as usize >> 5]),
+            0x1b | 0x3b | 0x5b | 0x7b | 0x9b | 0xbb => self.direct_indexed_modify(MODIFY[opcode as usize >> 5]),
+            0x1c => self.regs.a = self.implied_modify(Apu::asl, a),
+            0x3c => self.regs.a = self.implied_modify(Apu::rol, a),
+            0x5c => self.regs.a = self.implied_modify(Apu::lsr, a),
+            0x7c => self.regs.a = self.implied_modify(Apu::ror, a),
+            0x9c => self.regs.a = self.implied_modify(Apu::dec, a),
+            0xbc => self.regs.a = self.implied_modify(Apu::inc, a),
+            0x1d => self.regs.x = self.implied_modify(Apu::dec, x),
+            0x3d => self.regs.x = self.implied_modify(Apu::inc, x),
+            0xdc => self.regs.y = self.implied_modify(Apu::dec, y),
+            0xfc => self.regs.y = self.implied_modify(Apu::inc, y),
+
+            // OR, AND, EOR, CMP, ADC and SBC on A, and their dp,dp,
+            // dp,#imm and (X),(Y) forms.
+            0x04 | 0x24 | 0x44 | 0x64 | 0x84 | 0xa4 => self.regs.a = self.direct_read(ALU[opcode as usize >> 5], a),
+            0x05 | 0x25 | 0x45 | 0x65 | 0x85 | 0xa5 => self.regs.a = self.absolute_read(ALU[opcode as usize >> 5], a),
+            0x06 | 0x26 | 0x46 | 0x66 | 0x86 | 0xa6 => self.regs.a = self.indirect_x_read(ALU[opcode as usize >> 5]),
+            0x07 | 0x27 | 0x47 | 0x67 | 0x87 | 0xa7 => {
+                self.regs.a = self.indexed_indirect_read(ALU[opcode as usize >> 5])
+            }
+            0x08 | 0x28 | 0x48 | 0x68 | 0x88 | 0xa8 => self.regs.a = self.immediate_read(ALU[opcode as usize >> 5], a),
+            0x09 | 0x29 | 0x49 | 0x69 | 0x89 | 0xa9 => self.direct_direct(opcode as usize >> 5),
+            0x14 | 0x34 | 0x54 | 0x74 | 0x94 | 0xb4 => {
+                self.regs.a = self.direct_indexed_read(ALU[opcode as usize >> 5], a, x)
+            }
+            0x15 | 0x35 | 0x55 | 0x75 | 0x95 | 0xb5 => {
+                self.regs.a = self.absolute_indexed_read(ALU[opcode as usize >> 5], x)
+            }
+            0x16 | 0x36 | 0x56 | 0x76 | 0x96 | 0xb6 => {
+                self.regs.a = self.absolute_indexed_read(ALU[opcode as usize >> 5], y)
+            }
+            0x17 | 0x37 | 0x57 | 0x77 | 0x97 | 0xb7 => {
+                self.regs.a = self.indirect_indexed_read(ALU[opcode as usize >> 5])
+            }
+            0x18 | 0x38 | 0x58 | 0x78 | 0x98 | 0xb8 => self.direct_immediate(opcode as usize >> 5),
+            0x19 | 0x39 | 0x59 | 0x79 | 0x99 | 0xb9 => self.indirect_x_indirect_y(opcode as usize >> 5),
+
+            0x0a | 0x2a | 0x4a | 0x6a | 0x8a | 0xaa | 0xca | 0xea => self.absolute_bit(opcode),
+            0x0d => self.push_register(self.regs.psw),
+            0x2d => self.push_register(a),
+            0x4d => self.push_register(x),
+            0x6d => self.push_register(y),
+            0x8e => self.regs.psw = self.pull_register(),
+            0xae => self.regs.a = self.pull_register(),
+            0xce => self.regs.x = self.pull_register(),
+            0xee => self.regs.y = self.pull_register(),
+            0x0e => self.test_set_bits(true),
+            0x4e => self.test_set_bits(false),
+            // BRK
+            0x0f => {
+                self.read_pc();
+                self.push_pc();
+                self.push(self.regs.psw);
+                self.idle();
+                self.vector(0xffde);
+                self.set_flag(FLAG_I, false);
+                self.set_flag(FLAG_B, true);
+            }
+
+            0x10 => self.branch(!self.flag(FLAG_N)),
+            0x30 => self.branch(self.flag(FLAG_N)),
+            0x50 => self.branch(!self.flag(FLAG_V)),
+            0x70 => self.branch(self.flag(FLAG_V)),
+            0x90 => self.branch(!self.flag(FLAG_C)),
+            0xb0 => self.branch(self.flag(FLAG_C)),
+            0xd0 => self.branch(!self.flag(FLAG_Z)),
+            0xf0 => self.branch(self.flag(FLAG_Z)),
+            0x2f => self.branch(true),
+            0x2e => self.compare_branch(None),
+            0xde => self.compare_branch(Some(x)),
+            // DBNZ dp,rel
+            0x6e => {
+                let addr = self.fetch();
+                let data = self.read_dp(addr).wrapping_sub(1);
+                self.write_dp(addr, data);
+                let displacement = self.fetch();
+                self.branch_to(displacement, data != 0);
+            }
+            // DBNZ Y,rel
+            0xfe => {
+                self.read_pc();
+                self.idle();
+                let displacement = self.fetch();
+                self.regs.y = y.wrapping_sub(1);
+                self.branch_to(displacement, self.regs.y != 0);
+            }
+
+            // DECW, INCW, CMPW, ADDW, SUBW, MOVW
+            0x1a => self.direct_modify_word(0xffff),
+            0x3a => self.direct_modify_word(0x0001),
+            0x5a => {
+                let addr = self.fetch();
+                let lo = self.read_dp(addr) as u16;
+                let data = lo | (self.read_dp(addr.wrapping_add(1)) as u16) << 8;
+                self.cmpw(self.ya(), data);
+            }
+            0x7a => self.direct_read_word(Apu::addw),
+            0x9a => self.direct_read_word(Apu::subw),
+            0xba => self.direct_read_word(Apu::ldw),
+            0xda => {
+                let addr = self.fetch();
+                self.read_dp(addr);
+                self.write_dp(addr, a);
+                self.write_dp(addr.wrapping_add(1), y);
+            }
+
+            // CMP X and Y
+            0xc8 => self.cmp_immediate(x),
+            0xad => self.cmp_immediate(y),
+            0x3e => {
+                self.direct_read(Apu::cmp, x);
+            }
+            0x7e => {
+                self.direct_read(Apu::cmp, y);
+            }
+            0x1e => {
+                self.absolute_read(Apu::cmp, x);
+            }
+            0x5e => {
+                self.absolute_read(Apu::cmp, y);
+            }
+
+            // JMP [!abs+X], JMP !abs, CALL, PCALL, RET, RETI
+            0x1f => {
+                let addr = self.fetch16();
+                self.idle();
+                self.vector(addr.wrapping_add(x as u16));
+            }
+            0x5f => self.regs.pc = self.fetch16(),
+            0x3f => {
+                let addr = self.fetch16();
+                self.idle();
+                self.push_pc();
+                self.idle();
+                self.idle();
+                self.regs.pc = addr;
+            }
+            0x4f => {
+                let addr = self.fetch();
+                self.idle();
+                self.push_pc();
+                self.idle();
+                self.regs.pc = 0xff00 | addr as u16;
+            }
+            0x6f => {
+                self.read_pc();
+                self.idle();
+                self.pull_pc();
+            }
+            0x7f => {
+                self.read_pc();
+                self.idle();
+                self.regs.psw = self.pull();
+                self.pull_pc();
+            }
+
+            // Flags
+            0x20 => self.set_flag_implied(FLAG_P, false),
+            0x40 => self.set_flag_implied(FLAG_P, true),
+            0x60 => self.set_flag_implied(FLAG_C, false),
+            0x80 => self.set_flag_implied(FLAG_C, true),
+            0xa0 => {
+                self.set_flag_implied(FLAG_I, true);
+                self.idle();
+            }
+            0xc0 => {
+                self.set_flag_implied(FLAG_I, false);
+                self.idle();
+            }
+            0xe0 => {
+                self.read_pc();
+                self.set_flag(FLAG_H, false);
+                self.set_flag(FLAG_V, false);
+            }
+            0xed => {
+                self.read_pc();
+                self.idle();
+                self.set_flag(FLAG_C, !self.flag(FLAG_C));
+            }
+
+            // MOV to registers
+            0xe8 => self.regs.a = self.immediate_read(Apu::ld, a),
+            0xcd => self.regs.x = self.immediate_read(Apu::ld, x),
+            0x8d => self.regs.y = self.immediate_read(Apu::ld, y),
+            0xe4 => self.regs.a = self.direct_read(Apu::ld, a),
+            0xf8 => self.regs.x = self.direct_read(Apu::ld, x),
+            0xeb => self.regs.y = self.direct_read(Apu::ld, y),
+            0xf4 => self.regs.a = self.direct_indexed_read(Apu::ld, a, x),
+            0xf9 => self.regs.x = self.direct_indexed_read(Apu::ld, x, y),
+            0xfb => self.regs.y = self.direct_indexed_read(Apu::ld, y, x),
+            0xe5 => self.regs.a = self.absolute_read(Apu::ld, a),
+            0xe9 => self.regs.x = self.absolute_read(Apu::ld, x),
+            0xec => self.regs.y = self.absolute_read(Apu::ld, y),
+            0xf5 => self.regs.a = self.absolute_indexed_read(Apu::ld, x),
+            0xf6 => self.regs.a = self.absolute_indexed_read(Apu::ld, y),
+            0xe6 => self.regs.a = self.indirect_x_read(Apu::ld),
+            0xe7 => self.regs.a = self.indexed_indirect_read(Apu::ld),
+            0xf7 => self.regs.a = self.indirect_indexed_read(Apu::ld),
+            // MOV A,(X)+ takes an extra cycle after the read.
+            0xbf => {
+                self.read_pc();
+                let data = self.read_dp(x);
+                self.regs.x = x.wrapping_add(1);
+                self.idle();
+                self.regs.a = self.set_nz(data);
+            }
+            0x5d => self.regs.x = self.transfer(a),
+            0x7d => self.regs.a = self.transfer(x),
+            0x9d => self.regs.x = self.transfer(self.regs.sp),
+            0xdd => self.regs.a = self.transfer(y),
+            0xfd => self.regs.y = self.transfer(a),
+            // MOV SP,X leaves the flags alone.
+            0xbd => {
+                self.read_pc();
+                self.regs.sp = x;
+            }
+
+            // MOV to memory
+            0xc4 => self.direct_write(a),
+            0xd8 => self.direct_write(x),
+            0xcb => self.direct_write(y),
+            0xd4 => self.direct_indexed_write(a, x),
+            0xd9 => self.direct_indexed_write(x, y),
+            0xdb => self.direct_indexed_write(y, x),
+            0xc5 => self.absolute_write(a),
+            0xc9 => self.absolute_write(x),
+            0xcc => self.absolute_write(y),
+            0xd5 => self.absolute_indexed_write(x),
+            0xd6 => self.absolute_indexed_write(y),
+            0xc7 => self.indexed_indirect_write(),
+            0xd7 => self.indirect_indexed_write(),
+            0xc6 => {
+                self.read_pc();
+                self.read_dp(x);
+                self.write_dp(x, a);
+            }
+            // MOV (X)+,A idles where MOV (X),A reads.
+            0xaf => {
+                self.read_pc();
+                self.idle();
+                self.write_dp(x, a);
+                self.regs.x = x.wrapping_add(1);
+            }
+            // MOV dp,dp
+            0xfa => {
+                let source = self.fetch();
+                let data = self.read_dp(source);
+                let target = self.fetch();
+                self.write_dp(target, data);
+            }
+            // MOV dp,#imm
+            0x8f => {
+                let immediate = self.fetch();
+                let addr = self.fetch();
+                self.read_dp(addr);
+                self.write_dp(addr, immediate);
+            }
+
+            // MUL YA
+            0xcf => {
+                self.read_pc();
+                for _ in 0..7 {
+                    self.idle();
+                }
+                self.set_ya(y as u16 * a as u16);
+                // N and Z only look at the high byte.
+                self.set_nz(self.regs.y);
+            }
+            // DIV YA,X
+            0x9e => {
+                self.read_pc();
+                for _ in 0..10 {
+                    self.idle();
+                }
+                self.divide();
+            }
+            // XCN
+            0x9f => {
+                self.read_pc();
+                for _ in 0..3 {
+                    self.idle();
+                }
+                self.regs.a = self.set_nz(a.rotate_left(4));
+            }
+            // DAA
+            0xdf => {
+                self.read_pc();
+                self.idle();
+                let mut a = a;
+                if self.flag(FLAG_C) || a > 0x99 {
+                    a = a.wrapping_add(0x60);
+                    self.set_flag(FLAG_C, true);
+                }
+                if self.flag(FLAG_H) || a & 0x0f > 0x09 {
+                    a = a.wrapping_add(0x06);
+                }
+                self.regs.a = self.set_nz(a);
+            }
+            // DAS
+            0xbe => {
+                self.read_pc();
+                self.idle();
+                let mut a = a;
+                if !self.flag(FLAG_C) || a > 0x99 {
+                    a = a.wrapping_sub(0x60);
+                    self.set_flag(FLAG_C, false);
+                }
+                if !self.flag(FLAG_H) || a & 0x0f > 0x09 {
+                    a = a.wrapping_sub(0x06);
+                }
+                self.regs.a = self.set_nz(a);
+            }
+            0xef | 0xff => self.halt(),
+
+            // TCALL n
+            op if op & 0x0f == 0x01 => {
+                self.read_pc();
+                self.idle();
+                self.push_pc();
+                self.idle();
+                self.vector(0xffde - ((op >> 4) as u16) * 2);
+            }
+            op if op & 0x1f == 0x02 => self.set_bit(op >> 5, true),
+            op if op & 0x1f == 0x12 => self.set_bit(op >> 5, false),
+            op if op & 0x1f == 0x03 => self.branch_bit(op >> 5, true),
+            op if op & 0x1f == 0x13 => self.branch_bit(op >> 5, false),
+            _ => unreachable!("opcode {:#04x} isn't decoded", opcode),
+        }
+        self.instruction_count += 1;
+    }
+
+    fn cmp_immediate(&mut self, target: u8) {
+        self.immediate_read(Apu::cmp, target);
+    }
+
+    /// `CLRP`, `SETP`, `CLRC`, `SETC`, `EI` and `DI`; the last two also idle
+    /// for a cycle afterwards.
+    fn set_flag_implied(&mut self, flag: u8, on: bool) {
+        self.read_pc();
+        self.set_flag(flag, on);
+    }
+
+    /// The quotient of `DIV YA,X` only has 9 bits. When it doesn't fit, the
+    /// hardware produces the odd results below rather than overflowing.
+    fn divide(&mut self) {
+        let (ya, x, y) = (self.ya() as u32, self.regs.x as u32, self.regs.y as u32);
+        self.set_flag(FLAG_H, y & 15 >= x & 15);
+        self.set_flag(FLAG_V, y >= x);
+        if y < x << 1 {
+            self.regs.a = (ya / x) as u8;
+            self.regs.y = (ya % x) as u8;
+        } else {
+            self.regs.a = (255 - (ya - (x << 9)) / (256 - x)) as u8;
+            self.regs.y = (x + (ya - (x << 9)) % (256 - x)) as u8;
+        }
+        self.set_nz(self.regs.a);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::CPU;
+
+    /// An APU with `program` at $0200, which is where pc starts. The rest
+    /// of RAM is zero, which is `NOP`.
+    fn apu(program: &[u8]) -> Apu {
+        let mut apu = Apu::new(Arc::new(Ports::default()));
+        apu.ram[0x0200..0x0200 + program.len()].copy_from_slice(program);
+        apu.regs.pc = 0x0200;
+        apu
+    }
+
+    fn flags(apu: &Apu) -> [bool; 5] {
+        [FLAG_N, FLAG_V, FLAG_H, FLAG_Z, FLAG_C].map(|flag| apu.flag(flag))
+    }
+
+    #[test]
+    fn adc_and_sbc_flags() {
+        let mut apu = apu(&[]);
+        // N, V, H, Z, C
+        assert_eq!(apu.adc(0x7f, 0x01), 0x80);
+        assert_eq!(flags(&apu), [true, true, true, false, false]);
+        assert_eq!(apu.adc(0xff, 0x01), 0x00);
+        assert_eq!(flags(&apu), [false, false, true, true, true]);
+        // The carry goes in.
+        assert_eq!(apu.adc(0x10, 0x20), 0x31);
+        assert_eq!(flags(&apu), [false, false, false, false, false]);
+
+        // Carry set means no borrow, and it stays set if nothing borrows.
+        apu.set_flag(FLAG_C, true);
+        assert_eq!(apu.sbc(0x80, 0x01), 0x7f);
+        assert_eq!(flags(&apu), [false, true, false, false, true]);
+        assert_eq!(apu.sbc(0x00, 0x01), 0xff);
+        assert_eq!(flags(&apu), [true, false, false, false, false]);
+        // H is set when nothing borrows out of bit 4, and the borrow goes in.
+        assert_eq!(apu.sbc(0x1f, 0x01), 0x1d);
+        assert_eq!(flags(&apu), [false, false, true, false, true]);
+
+        // CMP leaves V and H alone and returns its left side.
+        apu.set_flag(FLAG_V, true);
+        assert_eq!(apu.cmp(0x40, 0x40), 0x40);
+        assert_eq!(flags(&apu), [false, true, true, true, true]);
+        assert_eq!(apu.cmp(0x40, 0x41), 0x40);
+        assert_eq!(flags(&apu), [true, true, true, false, false]);
+    }
+
+    #[test]
+    fn word_arithmetic() {
+        let mut apu = apu(&[
+            0x7a, 0x10, // ADDW YA,$10
+            0x7a, 0x10, // ADDW YA,$10
+            0x9a, 0x10, // SUBW YA,$10
+            0x5a, 0x12, // CMPW YA,$12
+        ]);
+        apu.ram[0x10..0x14].copy_from_slice(&[0x01, 0x00, 0x00, 0x10]);
+        apu.set_ya(0x0fff);
+        // ADDW ignores the carry coming in.
+        apu.set_flag(FLAG_C, true);
+        // N, V, H, Z, C
+        apu.execute_instruction();
+        assert_eq!(apu.ya(), 0x1000);
+        assert_eq!(flags(&apu), [false, false, true, false, false]);
+        assert_eq!(apu.cycles, 5);
+
+        // Z looks at the whole word, not the high byte.
+        apu.set_ya(0xffff);
+        apu.execute_instruction();
+        assert_eq!(apu.ya(), 0x0000);
+        assert_eq!(flags(&apu), [false, false, true, true, true]);
+
+        apu.execute_instruction();
+        assert_eq!(apu.ya(), 0xffff);
+        assert_eq!(flags(&apu), [true, false, false, false, false]);
+        assert_eq!(apu.cycles, 15);
+
+        apu.set_ya(0x1000);
+        apu.execute_instruction();
+        assert_eq!(apu.ya(), 0x1000);
+        assert!(apu.flag(FLAG_Z) && apu.flag(FLAG_C) && !apu.flag(FLAG_N));
+        assert_eq!(apu.cycles, 19);
+    }
+
+    #[test]
+    fn multiply_and_divide() {
+        let mut apu = apu(&[
+            0xcf, // MUL YA
+            0x9e, // DIV YA,X
+        ]);
+        apu.regs.y = 0x12;
+        apu.regs.a = 0x34;
+        apu.execute_instruction();
+        assert_eq!(apu.ya(), 0x03a8);
+        // N and Z come from Y.
+        assert!(!apu.flag(FLAG_N) && !apu.flag(FLAG_Z));
+        assert_eq!(apu.cycles, 9);
+
+        apu.set_ya(0x0123);
+        apu.regs.x = 0x10;
+        apu.execute_instruction();
+        assert_eq!((apu.regs.a, apu.regs.y), (0x12, 0x03));
+        assert!(!apu.flag(FLAG_V));
+        assert_eq!(apu.cycles, 21);
+    }
+
+    #[test]
+    fn run_stops_once_caught_up() {
+        // Nothing runs while the CPU is the one behind, ties included.
+        let mut scheduler = Scheduler::snes();
+        let mut apu = apu(&[]);
+        apu.run(&mut scheduler);
+        assert_eq!(apu.state().instruction_count, 0);
+
+        scheduler.step(CPU, 1000);
+        apu.run(&mut scheduler);
+        assert_eq!(scheduler.behind(), CPU);
+        let instructions = apu.state().instruction_count;
+        assert!(instructions > 0);
+        // Every NOP takes 2 cycles, and one fewer would have left the APU
+        // behind.
+        assert_eq!(apu.state().cycles, 2 * instructions);
+        let mut fewer = Scheduler::snes();
+        fewer.step(CPU, 1000);
+        fewer.step(APU, 2 * (instructions - 1) * CLOCKS_PER_CYCLE);
+        assert_eq!(fewer.behind(), APU);
+    }
+}
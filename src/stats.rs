@@ -0,0 +1,242 @@
+use serde::{Deserialize, Serialize};
+
+/// Summary statistics over a set of timing samples, in seconds.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Summary {
+    pub samples: usize,
+    pub min: f64,
+    pub median: f64,
+    pub mean: f64,
+    pub stddev: f64,
+    pub p95: f64,
+    /// 95% bootstrap confidence interval of the median.
+    pub median_ci: (f64, f64),
+    /// 95% bootstrap confidence interval of the mean.
+    pub mean_ci: (f64, f64),
+}
+
+/// Bootstrap resamples used for the confidence intervals.
+const RESAMPLES: usize = 10_000;
+
+/// Small xorshift generator so the bootstrap doesn't need a dependency. The
+/// seed is fixed so the same samples always produce the same intervals.
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+/// Linear interpolation between the closest ranks. `sorted` must be sorted
+/// and non-empty, `p` is in `0.0..=1.0`.
+pub fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = p * (sorted.len() - 1) as f64;
+    let lo = rank.floor() as usize;
+    let hi = rank.ceil() as usize;
+    sorted[lo] + (sorted[hi] - sorted[lo]) * (rank - lo as f64)
+}
+
+pub fn mean(samples: &[f64]) -> f64 {
+    samples.iter().sum::<f64>() / samples.len() as f64
+}
+
+/// Sample standard deviation, zero when there's only one sample.
+pub fn stddev(samples: &[f64]) -> f64 {
+    if samples.len() < 2 {
+        return 0.0;
+    }
+    let m = mean(samples);
+    let var = samples.iter().map(|x| (x - m) * (x - m)).sum::<f64>() / (samples.len() - 1) as f64;
+    var.sqrt()
+}
+
+fn sorted(samples: &[f64]) -> Vec<f64> {
+    let mut sorted = samples.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).expect("timings are never NaN"));
+    sorted
+}
+
+/// 95% percentile bootstrap interval of `statistic` over `samples`.
+fn bootstrap<F: Fn(&[f64]) -> f64>(samples: &[f64], statistic: F) -> (f64, f64) {
+    let mut rng = XorShift(0x2545_f491_4f6c_dd1d);
+    let mut resample = vec![0.0; samples.len()];
+    let mut estimates = (0..RESAMPLES)
+        .map(|_| {
+            for x in resample.iter_mut() {
+                *x = samples[rng.below(samples.len())];
+            }
+            statistic(&resample)
+        })
+        .collect::<Vec<_>>();
+    estimates.sort_by(|a, b| a.partial_cmp(b).expect("timings are never NaN"));
+    (percentile(&estimates, 0.025), percentile(&estimates, 0.975))
+}
+
+pub fn median(samples: &[f64]) -> f64 {
+    percentile(&sorted(samples), 0.5)
+}
+
+/// Summarize `samples`, which must not be empty.
+pub fn summarize(samples: &[f64]) -> Summary {
+    let sorted = sorted(samples);
+    Summary {
+        samples: samples.len(),
+        min: sorted[0],
+        median: percentile(&sorted, 0.5),
+        mean: mean(samples),
+        stddev: stddev(samples),
+        p95: percentile(&sorted, 0.95),
+        median_ci: bootstrap(samples, median),
+        mean_ci: bootstrap(samples, mean),
+    }
+}
+
+/// Complementary error function, Numerical Recipes' Chebyshev fit with a
+/// fractional error below 1.2e-7 everywhere.
+fn erfc(x: f64) -> f64 {
+    let z = x.abs();
+    let t = 1.0 / (1.0 + 0.5 * z);
+    let r = t * (-z * z - 1.265_512_23
+        + t * (1.000_023_68
+            + t * (0.374_091_96
+                + t * (0.096_784_18
+                    + t * (-0.186_288_06
+                        + t * (0.278_868_07
+                            + t * (-1.135_203_98
+                                + t * (1.488_515_87 + t * (-0.822_152_23 + t * 0.170_872_77)))))))))
+        .exp();
+    if x >= 0.0 {
+        r
+    } else {
+        2.0 - r
+    }
+}
+
+/// Two-sided Mann-Whitney U test of whether `a` and `b` come from the same
+/// distribution. Returns `U` for `a` and the p-value, using the normal
+/// approximation with tie and continuity corrections, which is reasonable
+/// from around eight samples per side.
+pub fn mann_whitney_u(a: &[f64], b: &[f64]) -> (f64, f64) {
+    let (n1, n2) = (a.len() as f64, b.len() as f64);
+    let mut all = a
+        .iter()
+        .map(|&x| (x, true))
+        .chain(b.iter().map(|&x| (x, false)))
+        .collect::<Vec<_>>();
+    all.sort_by(|x, y| x.0.partial_cmp(&y.0).expect("timings are never NaN"));
+
+    // Average ranks over runs of ties, remembering the tie sizes for the
+    // variance correction.
+    let mut rank_sum_a = 0.0;
+    let mut tie_term = 0.0;
+    let mut i = 0;
+    while i < all.len() {
+        let mut j = i;
+        while j + 1 < all.len() && all[j + 1].0 == all[i].0 {
+            j += 1;
+        }
+        let rank = (i + j) as f64 / 2.0 + 1.0;
+        rank_sum_a += rank * all[i..=j].iter().filter(|(_, in_a)| *in_a).count() as f64;
+        let t = (j - i + 1) as f64;
+        tie_term += t * t * t - t;
+        i = j + 1;
+    }
+
+    let u = rank_sum_a - n1 * (n1 + 1.0) / 2.0;
+    let n = n1 + n2;
+    let mean = n1 * n2 / 2.0;
+    let var = n1 * n2 / 12.0 * ((n + 1.0) - tie_term / (n * (n - 1.0)));
+    if var <= 0.0 {
+        return (u, 1.0);
+    }
+    let z = ((u - mean).abs() - 0.5).max(0.0) / var.sqrt();
+    (u, erfc(z / std::f64::consts::SQRT_2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(actual: f64, expected: f64, tolerance: f64) -> bool {
+        (actual - expected).abs() <= tolerance * expected.abs().max(1.0)
+    }
+
+    #[test]
+    fn percentiles_interpolate() {
+        let sorted = [1.0, 2.0, 3.0, 4.0];
+        assert_eq!(percentile(&sorted, 0.0), 1.0);
+        assert_eq!(percentile(&sorted, 0.5), 2.5);
+        assert_eq!(percentile(&sorted, 1.0), 4.0);
+        assert!(close(percentile(&sorted, 0.95), 3.85, 1e-12));
+        assert_eq!(median(&[3.0, 1.0, 2.0]), 2.0);
+    }
+
+    #[test]
+    fn stddev_is_the_sample_one() {
+        assert!(close(stddev(&[2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0]), 2.138_089_935, 1e-9));
+        assert_eq!(stddev(&[1.5]), 0.0);
+    }
+
+    #[test]
+    fn erfc_matches_known_values() {
+        assert!(close(erfc(0.0), 1.0, 1.2e-7));
+        assert!(close(erfc(0.5), 0.479_500_122, 1.2e-7));
+        assert!(close(erfc(2.0), 0.004_677_734_981, 1.2e-7));
+        assert!(close(erfc(-1.0), 1.842_700_793, 1.2e-7));
+    }
+
+    #[test]
+    fn mann_whitney_separated_samples() {
+        let a = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0];
+        let b = [9.0, 10.0, 11.0, 12.0, 13.0, 14.0, 15.0, 16.0];
+        let (u, p) = mann_whitney_u(&a, &b);
+        assert_eq!(u, 0.0);
+        assert!(close(p, 0.000_939_105_7, 1e-5), "p = {}", p);
+        // U for the other side is n1 * n2 - U, the p-value is the same.
+        let (u, p_swapped) = mann_whitney_u(&b, &a);
+        assert_eq!(u, 64.0);
+        assert!(close(p_swapped, p, 1e-12));
+    }
+
+    #[test]
+    fn mann_whitney_with_ties() {
+        let a = [1.0, 2.0, 2.0, 3.0, 5.0, 8.0, 8.0, 9.0];
+        let b = [2.0, 3.0, 4.0, 6.0, 8.0, 10.0, 11.0, 12.0];
+        let (u, p) = mann_whitney_u(&a, &b);
+        assert_eq!(u, 19.5);
+        assert!(close(p, 0.204_556_757, 1e-5), "p = {}", p);
+    }
+
+    #[test]
+    fn mann_whitney_identical_samples() {
+        let a = [1.0; 8];
+        assert_eq!(mann_whitney_u(&a, &a), (32.0, 1.0));
+        let b = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0];
+        let (_, p) = mann_whitney_u(&b, &b);
+        assert!(p > 0.99, "p = {}", p);
+    }
+
+    #[test]
+    fn bootstrap_is_deterministic_and_brackets_the_estimate() {
+        let samples = [1.0, 1.1, 0.9, 1.05, 0.95, 1.2, 0.8, 1.0, 1.02, 0.98];
+        let summary = summarize(&samples);
+        assert_eq!(summary.samples, 10);
+        assert_eq!(summary.min, 0.8);
+        assert!(summary.median_ci.0 <= summary.median && summary.median <= summary.median_ci.1);
+        assert!(summary.mean_ci.0 <= summary.mean && summary.mean <= summary.mean_ci.1);
+        let again = summarize(&samples);
+        assert_eq!(again.median_ci, summary.median_ci);
+        assert_eq!(again.mean_ci, summary.mean_ci);
+        assert_eq!(summarize(&[2.0; 5]).mean_ci, (2.0, 2.0));
+    }
+}
@@ -0,0 +1,265 @@
+//! Running the variants a master clock at a time. While an instruction is
+//! being stepped, `wait` goes through its clocks one at a time and stops
+//! before every one: the coroutine and async variants yield, the ones with
+//! stacks of their own switch back to the caller's, and the rest run the
+//! instruction on an OS thread of its own, see `OnThread`. Their `step` and
+//! `run` hold on to the CPU until a whole instruction is done, so `Stepping`
+//! keeps an instruction in progress around in between calls to `step_cycle`
+//! instead, by handing the CPU over to it.
+
+use crate::cpu::{Cpu, Registers, State};
+use crate::executor;
+use crate::spc700;
+use crate::trace;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::task::{Context, Poll};
+
+/// A CPU whose instructions can stop at every wait and be resumed later,
+/// with the instruction owning the CPU in the meantime.
+pub trait Resumable: Cpu + Sized {
+    type Instruction;
+
+    /// Begin the next instruction, without running any of it yet. It has to
+    /// stop before every clock, no matter the `SyncMode`.
+    fn start(cpu: Box<Self>) -> Self::Instruction;
+
+    /// Run `instruction` until it's about to start another clock, or to its
+    /// end. The first time, that's before any clock at all, and after that
+    /// it's a clock and whatever happens on it, like the bus access that
+    /// ends there. It's never resumed again once it's done.
+    fn resume(instruction: &mut Self::Instruction) -> Progress<Self>;
+}
+
+pub enum Progress<C> {
+    /// The instruction is about to start another clock.
+    Paused,
+    /// The instruction is done and gives the CPU back.
+    Done(Box<C>),
+}
+
+/// Gives a `Resumable` CPU a `step_cycle` that stops at every clock
+/// rather than after every instruction. Everything else finishes the
+/// instruction in progress, if there is one, and is then left to the CPU.
+pub struct Stepping<C: Resumable> {
+    /// Handed over to `instruction` while there is one.
+    cpu: Option<Box<C>>,
+    instruction: Option<C::Instruction>,
+    /// The CPU as it was before the instruction in progress, except for the
+    /// cycles, which include every clock so far. The accessors only have
+    /// this to go on while the instruction has the CPU.
+    before: State,
+    full_isa: bool,
+}
+
+impl<C: Resumable> Stepping<C> {
+    pub fn new(cpu: C) -> Stepping<C> {
+        Stepping {
+            before: cpu.state(),
+            full_isa: cpu.full_isa(),
+            cpu: Some(Box::new(cpu)),
+            instruction: None,
+        }
+    }
+
+    /// The CPU, once the instruction in progress is done.
+    fn cpu(&mut self) -> &mut C {
+        if let Some(mut instruction) = self.instruction.take() {
+            loop {
+                if let Progress::Done(cpu) = C::resume(&mut instruction) {
+                    self.cpu = Some(cpu);
+                    break;
+                }
+            }
+        }
+        self.cpu.as_mut().expect("the CPU went missing")
+    }
+}
+
+impl<C: Resumable> Cpu for Stepping<C> {
+    fn step(&mut self) {
+        self.cpu().step();
+    }
+
+    fn run(&mut self, iters: usize) {
+        self.cpu().run(iters);
+    }
+
+    fn step_cycle(&mut self) {
+        if let Some(cpu) = self.cpu.take() {
+            self.before = cpu.state();
+            let mut instruction = C::start(cpu);
+            // Up to its first clock, so the resume below runs that.
+            if let Progress::Done(_) = C::resume(&mut instruction) {
+                panic!("an instruction went by without a clock");
+            }
+            self.instruction = Some(instruction);
+        }
+        let instruction = self.instruction.as_mut().expect("the instruction went missing");
+        match C::resume(instruction) {
+            Progress::Paused => self.before.cycles += 1,
+            Progress::Done(cpu) => {
+                self.instruction = None;
+                self.cpu = Some(cpu);
+            }
+        }
+    }
+
+    fn steps_cycles(&self) -> bool {
+        true
+    }
+
+    fn full_isa(&self) -> bool {
+        self.full_isa
+    }
+
+    fn registers(&self) -> Registers {
+        self.cpu.as_ref().map_or(self.before.registers, |cpu| cpu.registers())
+    }
+
+    fn set_registers(&mut self, regs: Registers) {
+        self.cpu().set_registers(regs);
+    }
+
+    fn cycles(&self) -> u32 {
+        self.cpu.as_ref().map_or(self.before.cycles, |cpu| cpu.cycles())
+    }
+
+    fn instruction_count(&self) -> u32 {
+        self.cpu.as_ref().map_or(self.before.instruction_count, |cpu| cpu.instruction_count())
+    }
+
+    fn synchronize(&mut self) {
+        self.cpu().synchronize();
+    }
+
+    fn apu(&self) -> spc700::State {
+        self.cpu.as_ref().map_or(self.before.apu, |cpu| cpu.apu())
+    }
+
+    fn trace(&mut self, sink: Box<dyn trace::Sink>) {
+        self.cpu().trace(sink);
+    }
+
+    fn load(&mut self, image: &[u8], pc: u16) {
+        self.cpu().load(image, pc);
+    }
+
+    fn reset_registers(&mut self) {
+        self.cpu().reset_registers();
+    }
+}
+
+/// An instruction in progress still has the CPU, along with whatever it
+/// runs on, so it's finished rather than dropped halfway.
+impl<C: Resumable> Drop for Stepping<C> {
+    fn drop(&mut self) {
+        self.cpu();
+    }
+}
+
+/// Set by an async CPU being stepped before it yields ahead of a clock, which
+/// is how `Resuming::poll` tells that apart from waiting for the APU. It's
+/// an `Arc` because `async_std_attempt` sends its CPU to other threads.
+pub type Waits = Arc<AtomicBool>;
+
+/// What `wait` does before every clock while the CPU is being stepped.
+pub async fn pause(waits: &Waits) {
+    waits.store(true, Ordering::Relaxed);
+    executor::yield_once().await;
+}
+
+/// An instruction of an async CPU, as a future that owns the CPU.
+pub struct Resuming<C> {
+    waits: Waits,
+    future: Pin<Box<dyn Future<Output = Box<C>>>>,
+}
+
+impl<C> Resuming<C> {
+    /// `future` has to run a single instruction, on a CPU that `pause`s with
+    /// `waits` before every clock.
+    pub fn new(waits: Waits, future: impl Future<Output = Box<C>> + 'static) -> Resuming<C> {
+        Resuming {
+            waits,
+            future: Box::pin(future),
+        }
+    }
+
+    /// Poll the instruction once. It's only pending while it's waiting for
+    /// something other than the clock, like the APU task, so this belongs
+    /// in whatever also runs the variant's other tasks.
+    pub fn poll(&mut self, cx: &mut Context) -> Poll<Progress<C>> {
+        match self.future.as_mut().poll(cx) {
+            Poll::Ready(cpu) => Poll::Ready(Progress::Done(cpu)),
+            Poll::Pending if self.waits.swap(false, Ordering::Relaxed) => Poll::Ready(Progress::Paused),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// An instruction of a CPU that can only stop in the middle of one by
+/// blocking, run on an OS thread of its own. The CPU `tick`s its `Ticker`
+/// before every clock, which blocks until the instruction is resumed.
+pub struct OnThread<C> {
+    thread: Option<JoinHandle<Box<C>>>,
+    ticks: Receiver<()>,
+    resume: SyncSender<()>,
+}
+
+/// The CPU's end of an `OnThread`.
+pub struct Ticker {
+    ticks: SyncSender<()>,
+    resume: Receiver<()>,
+}
+
+impl Ticker {
+    /// Hand control back to whatever is stepping the CPU, and return once it
+    /// gives it another clock.
+    pub fn tick(&self) {
+        self.ticks.send(()).expect("the stepper went away");
+        self.resume.recv().expect("the stepper went away");
+    }
+}
+
+impl<C: Send + 'static> OnThread<C> {
+    /// Run `instruction` on `cpu` once the instruction is first resumed. It
+    /// has to drop the `Ticker` by the time it's done, since that's how
+    /// `resume` tells the end of the instruction from the end of a clock.
+    pub fn new(mut cpu: Box<C>, instruction: impl FnOnce(&mut C, Ticker) + Send + 'static) -> OnThread<C> {
+        let (ticks, ticked) = mpsc::sync_channel(0);
+        let (resume, resumed) = mpsc::sync_channel(0);
+        let thread = std::thread::spawn(move || {
+            if resumed.recv().is_ok() {
+                instruction(
+                    &mut cpu,
+                    Ticker {
+                        ticks,
+                        resume: resumed,
+                    },
+                );
+            }
+            cpu
+        });
+        OnThread {
+            thread: Some(thread),
+            ticks: ticked,
+            resume,
+        }
+    }
+
+    pub fn resume(&mut self) -> Progress<C> {
+        self.resume.send(()).expect("the stepped CPU panicked");
+        if self.ticks.recv().is_ok() {
+            return Progress::Paused;
+        }
+        let thread = self.thread.take().expect("resumed after it was done");
+        match thread.join() {
+            Ok(cpu) => Progress::Done(cpu),
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    }
+}
@@ -0,0 +1,241 @@
+//! A record of every bus access a CPU makes, with the master clock cycle it
+//! happened on. That's enough to check the timing of an instruction against
+//! the data sheet, like the page-cross penalty landing between the operand
+//! fetch and the final read of `LDA abs,Y`, and to diff two variants.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Read,
+    Write,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Access {
+    /// `Cpu::cycles` when the data moved, which is 2 master clock cycles
+    /// into the access.
+    pub cycle: u32,
+    pub addr: u32,
+    pub data: u8,
+    pub kind: Kind,
+}
+
+/// One access per line, the same as `Format::Text` writes.
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            Kind::Read => 'R',
+            Kind::Write => 'W',
+        };
+        write!(f, "{:>10} {} {:06x} {:02x}", self.cycle, kind, self.addr, self.data)
+    }
+}
+
+/// Bytes per access in `Format::Binary`.
+const RECORD_SIZE: usize = 9;
+
+impl Access {
+    /// The cycle as 4 little-endian bytes, the address as 3, the data, and
+    /// 0 for a read or 1 for a write.
+    fn to_bytes(self) -> [u8; RECORD_SIZE] {
+        let cycle = self.cycle.to_le_bytes();
+        let addr = self.addr.to_le_bytes();
+        let kind = match self.kind {
+            Kind::Read => 0,
+            Kind::Write => 1,
+        };
+        [cycle[0], cycle[1], cycle[2], cycle[3], addr[0], addr[1], addr[2], self.data, kind]
+    }
+
+    fn from_bytes(bytes: [u8; RECORD_SIZE]) -> Option<Access> {
+        Some(Access {
+            cycle: u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            addr: u32::from_le_bytes([bytes[4], bytes[5], bytes[6], 0]),
+            data: bytes[7],
+            kind: match bytes[8] {
+                0 => Kind::Read,
+                1 => Kind::Write,
+                _ => return None,
+            },
+        })
+    }
+}
+
+/// How `Writer` writes a trace.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Fixed size little-endian records, see `Access::to_bytes`.
+    Binary,
+    /// Lines like `Access` displays, for reading and diffing.
+    Text,
+}
+
+impl Format {
+    pub const NAMES: &'static [&'static str] = &["text", "binary"];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Binary => "binary",
+            Format::Text => "text",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "binary" => Some(Format::Binary),
+            "text" => Some(Format::Text),
+            _ => None,
+        }
+    }
+
+    /// What files in this format are named with.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Binary => "bin",
+            Format::Text => "txt",
+        }
+    }
+}
+
+/// Where a CPU sends its accesses once `Cpu::trace` has been called. It
+/// moves along with the CPU, which `async_std_attempt` sends to other
+/// threads.
+pub trait Sink: Send {
+    fn record(&mut self, access: Access);
+}
+
+/// Record an access if the CPU is being traced. This is what the variants
+/// call right after the data moves, so it has to cost next to nothing when
+/// `trace` is `None`.
+#[inline]
+pub fn record(trace: &mut Option<Box<dyn Sink>>, cycle: u32, addr: u32, data: u8, kind: Kind) {
+    if let Some(sink) = trace {
+        sink.record(Access {
+            cycle,
+            addr,
+            data,
+            kind,
+        });
+    }
+}
+
+/// Writes the accesses to `out` as they happen. Give it something buffered,
+/// since every access is a separate write. The CPU keeps the `Writer` until
+/// it's dropped, where an error flushing the buffer would go unnoticed, so
+/// `out` is shared with whoever made it to flush once the CPU is done.
+pub struct Writer<W> {
+    out: Arc<Mutex<W>>,
+    format: Format,
+}
+
+impl<W: Write + Send> Writer<W> {
+    pub fn new(out: W, format: Format) -> Writer<W> {
+        Writer {
+            out: Arc::new(Mutex::new(out)),
+            format,
+        }
+    }
+
+    pub fn out(&self) -> Arc<Mutex<W>> {
+        self.out.clone()
+    }
+}
+
+impl<W: Write + Send> Sink for Writer<W> {
+    /// A trace with holes in it would be misleading, so this gives up on the
+    /// first error.
+    fn record(&mut self, access: Access) {
+        let mut out = self.out.lock().expect("the tracer panicked");
+        let result = match self.format {
+            Format::Binary => out.write_all(&access.to_bytes()),
+            Format::Text => writeln!(out, "{}", access),
+        };
+        result.expect("couldn't write the bus trace");
+    }
+}
+
+/// Read back a trace written with `Format::Binary`.
+pub fn read_binary(mut input: impl Read) -> io::Result<Vec<Access>> {
+    let mut bytes = vec![];
+    input.read_to_end(&mut bytes)?;
+    if bytes.len() % RECORD_SIZE != 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "trace ends in the middle of an access"));
+    }
+    bytes
+        .chunks_exact(RECORD_SIZE)
+        .map(|chunk| {
+            let mut record = [0; RECORD_SIZE];
+            record.copy_from_slice(chunk);
+            Access::from_bytes(record)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "unknown kind of access in trace"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accesses() -> Vec<Access> {
+        vec![
+            Access {
+                cycle: 2,
+                addr: 0x000400,
+                data: 0xb9,
+                kind: Kind::Read,
+            },
+            Access {
+                cycle: 0xdead_beef,
+                addr: 0xffffff,
+                data: 0xff,
+                kind: Kind::Write,
+            },
+            Access {
+                cycle: 0,
+                addr: 0,
+                data: 0,
+                kind: Kind::Write,
+            },
+        ]
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let mut writer = Writer::new(vec![], Format::Binary);
+        let out = writer.out();
+        for access in accesses() {
+            writer.record(access);
+        }
+        drop(writer);
+        let bytes = out.lock().unwrap().clone();
+        assert_eq!(bytes.len(), accesses().len() * RECORD_SIZE);
+        assert_eq!(read_binary(&bytes[..]).unwrap(), accesses());
+    }
+
+    #[test]
+    fn binary_errors() {
+        let bytes = accesses().into_iter().flat_map(Access::to_bytes).collect::<Vec<_>>();
+        let truncated = read_binary(&bytes[..bytes.len() - 1]).unwrap_err();
+        assert_eq!(truncated.kind(), io::ErrorKind::InvalidData);
+        let mut bad_kind = bytes.clone();
+        bad_kind[RECORD_SIZE - 1] = 2;
+        assert_eq!(read_binary(&bad_kind[..]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn text_lines() {
+        let mut writer = Writer::new(vec![], Format::Text);
+        let out = writer.out();
+        for access in accesses() {
+            writer.record(access);
+        }
+        let text = String::from_utf8(out.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            text,
+            "         2 R 000400 b9\n3735928559 W ffffff ff\n         0 W 000000 00\n"
+        );
+    }
+}
@@ -0,0 +1,1270 @@
+//! The 65816 as found in the SNES: emulation and native mode, 8/16-bit
+//! accumulator and index registers selected by the M and X flags, 24-bit
+//! addresses built from the data and program bank registers, a relocatable
+//! direct page and a 16-bit stack pointer.
+//!
+//! Like `m6502` this is written once in straight-line style and expanded into
+//! every variant. The cycle layout follows byuu's bsnes core. Unlike the
+//! 6502, the 65816 has internal operations that don't touch the bus, so the
+//! macro suspends on idle cycles too.
+use crate::cpu::Registers;
+pub use crate::m6502::{FLAG_C, FLAG_D, FLAG_I, FLAG_N, FLAG_V, FLAG_Z};
+
+/// 8-bit index registers. Doubles as the break flag in emulation mode.
+pub const FLAG_X: u8 = 0x10;
+/// 8-bit accumulator and memory accesses.
+pub const FLAG_M: u8 = 0x20;
+
+pub const COP_VECTOR_NATIVE: u32 = 0xffe4;
+pub const BRK_VECTOR_NATIVE: u32 = 0xffe6;
+pub const COP_VECTOR_EMULATION: u32 = 0xfff4;
+pub const BRK_VECTOR_EMULATION: u32 = 0xfffe;
+
+pub fn m8(r: &Registers) -> bool {
+    r.p & FLAG_M != 0
+}
+
+pub fn x8(r: &Registers) -> bool {
+    r.p & FLAG_X != 0
+}
+
+/// The 24-bit address of the next instruction byte.
+pub fn pc24(r: &Registers) -> u32 {
+    (r.pbr as u32) << 16 | r.pc as u32
+}
+
+/// `addr` in the data bank. Indexing can carry into the next bank.
+pub fn bank(r: &Registers, addr: u32) -> u32 {
+    ((r.dbr as u32) << 16).wrapping_add(addr) & 0xff_ffff
+}
+
+/// A direct page address. In emulation mode with the low byte of `d` clear
+/// it wraps within the page like the 6502's zero page does.
+pub fn direct(r: &Registers, offset: u16) -> u32 {
+    if r.e && r.d & 0x00ff == 0 {
+        (r.d | (offset & 0x00ff)) as u32
+    } else {
+        r.d.wrapping_add(offset) as u32
+    }
+}
+
+/// A direct page address without the emulation mode page wrapping, as used
+/// by the instructions the 6502 doesn't have.
+pub fn direct_n(r: &Registers, offset: u16) -> u32 {
+    r.d.wrapping_add(offset) as u32
+}
+
+pub fn stack(r: &Registers, offset: u16) -> u32 {
+    r.s.wrapping_add(offset) as u32
+}
+
+fn mask(wide: bool) -> u16 {
+    if wide {
+        0xffff
+    } else {
+        0x00ff
+    }
+}
+
+fn sign(wide: bool) -> u16 {
+    if wide {
+        0x8000
+    } else {
+        0x0080
+    }
+}
+
+/// Set N and Z from `v`, which is 8 or 16 bits wide.
+pub fn nz(r: &mut Registers, v: u16, wide: bool) -> u16 {
+    let v = v & mask(wide);
+    r.set_flag(FLAG_Z, v == 0);
+    r.set_flag(FLAG_N, v & sign(wide) != 0);
+    v
+}
+
+/// Write the accumulator. With an 8-bit accumulator the high byte (`B`)
+/// is left alone.
+fn set_a(r: &mut Registers, v: u16) {
+    if m8(r) {
+        r.a = (r.a & 0xff00) | (v & 0x00ff);
+    } else {
+        r.a = v;
+    }
+}
+
+/// `p` as `PLP`, `RTI`, `REP` and `SEP` set it. Emulation mode forces 8-bit
+/// registers, and switching to 8-bit index registers clears their high
+/// bytes.
+pub fn set_p(r: &mut Registers, v: u8) {
+    r.p = v;
+    if r.e {
+        r.p |= FLAG_M | FLAG_X;
+    }
+    if x8(r) {
+        r.x &= 0x00ff;
+        r.y &= 0x00ff;
+    }
+}
+
+/// Exchange the carry and emulation flags.
+pub fn xce(r: &mut Registers) {
+    let c = r.flag(FLAG_C);
+    r.set_flag(FLAG_C, r.e);
+    r.e = c;
+    if r.e {
+        set_p(r, r.p);
+        r.s = 0x0100 | (r.s & 0x00ff);
+    }
+}
+
+pub fn lda(r: &mut Registers, v: u16) {
+    let v = nz(r, v, !m8(r));
+    set_a(r, v);
+}
+
+pub fn ldx(r: &mut Registers, v: u16) {
+    r.x = nz(r, v, !x8(r));
+}
+
+pub fn ldy(r: &mut Registers, v: u16) {
+    r.y = nz(r, v, !x8(r));
+}
+
+pub fn and(r: &mut Registers, v: u16) {
+    lda(r, r.a & v);
+}
+
+pub fn ora(r: &mut Registers, v: u16) {
+    lda(r, r.a | v);
+}
+
+pub fn eor(r: &mut Registers, v: u16) {
+    lda(r, r.a ^ v);
+}
+
+fn compare(r: &mut Registers, reg: u16, v: u16, wide: bool) {
+    let m = mask(wide);
+    r.set_flag(FLAG_C, reg & m >= v & m);
+    nz(r, reg.wrapping_sub(v), wide);
+}
+
+pub fn cmp(r: &mut Registers, v: u16) {
+    compare(r, r.a, v, !m8(r));
+}
+
+pub fn cpx(r: &mut Registers, v: u16) {
+    compare(r, r.x, v, !x8(r));
+}
+
+pub fn cpy(r: &mut Registers, v: u16) {
+    compare(r, r.y, v, !x8(r));
+}
+
+pub fn bit(r: &mut Registers, v: u16) {
+    let wide = !m8(r);
+    r.set_flag(FLAG_Z, r.a & v & mask(wide) == 0);
+    r.set_flag(FLAG_N, v & sign(wide) != 0);
+    r.set_flag(FLAG_V, v & (sign(wide) >> 1) != 0);
+}
+
+/// `BIT #imm` only sets Z.
+pub fn bit_immediate(r: &mut Registers, v: u16) {
+    r.set_flag(FLAG_Z, r.a & v & mask(!m8(r)) == 0);
+}
+
+/// Decimal adjustment of the digit at `shift` and everything below it.
+fn adjust(result: i32, shift: u32, subtract: bool) -> i32 {
+    if subtract {
+        if result < 0x10 << shift {
+            return result - (0x6 << shift);
+        }
+    } else if result >= 0xa << shift {
+        return result + (0x6 << shift);
+    }
+    result
+}
+
+/// `ADC` and `SBC`. Unlike the NMOS 6502, decimal mode works a digit at a
+/// time and leaves valid N, Z and C flags; V still comes from the result
+/// before the top digit is adjusted.
+fn add(r: &mut Registers, data: u16, subtract: bool) {
+    let wide = !m8(r);
+    let bits = if wide { 16 } else { 8 };
+    let m = mask(wide) as i32;
+    let a = r.a as i32 & m;
+    let data = if subtract { !data as i32 & m } else { data as i32 & m };
+    let mut result = if r.flag(FLAG_D) {
+        let mut result = 0;
+        let mut carry = r.flag(FLAG_C) as i32;
+        for shift in (0..bits).step_by(4) {
+            let digit = 0xf << shift;
+            result = (a & digit) + (data & digit) + (carry << shift) + (result & ((1 << shift) - 1));
+            if shift + 4 < bits {
+                result = adjust(result, shift, subtract);
+                carry = (result >= 0x10 << shift) as i32;
+            }
+        }
+        result
+    } else {
+        a + data + r.flag(FLAG_C) as i32
+    };
+    r.set_flag(FLAG_V, !(a ^ data) & (a ^ result) & sign(wide) as i32 != 0);
+    if r.flag(FLAG_D) {
+        result = adjust(result, bits - 4, subtract);
+    }
+    r.set_flag(FLAG_C, result > m);
+    lda(r, result as u16);
+}
+
+pub fn adc(r: &mut Registers, v: u16) {
+    add(r, v, false);
+}
+
+pub fn sbc(r: &mut Registers, v: u16) {
+    add(r, v, true);
+}
+
+pub fn asl(r: &mut Registers, v: u16, wide: bool) -> u16 {
+    r.set_flag(FLAG_C, v & sign(wide) != 0);
+    nz(r, v << 1, wide)
+}
+
+pub fn lsr(r: &mut Registers, v: u16, wide: bool) -> u16 {
+    r.set_flag(FLAG_C, v & 1 != 0);
+    nz(r, (v & mask(wide)) >> 1, wide)
+}
+
+pub fn rol(r: &mut Registers, v: u16, wide: bool) -> u16 {
+    let c = r.flag(FLAG_C) as u16;
+    r.set_flag(FLAG_C, v & sign(wide) != 0);
+    nz(r, v << 1 | c, wide)
+}
+
+pub fn ror(r: &mut Registers, v: u16, wide: bool) -> u16 {
+    let c = if r.flag(FLAG_C) { sign(wide) } else { 0 };
+    r.set_flag(FLAG_C, v & 1 != 0);
+    nz(r, (v & mask(wide)) >> 1 | c, wide)
+}
+
+pub fn inc(r: &mut Registers, v: u16, wide: bool) -> u16 {
+    nz(r, v.wrapping_add(1), wide)
+}
+
+pub fn dec(r: &mut Registers, v: u16, wide: bool) -> u16 {
+    nz(r, v.wrapping_sub(1), wide)
+}
+
+/// Test and set bits: Z from `a & v`, then the bits of `a` get set in `v`.
+pub fn tsb(r: &mut Registers, v: u16, wide: bool) -> u16 {
+    r.set_flag(FLAG_Z, r.a & v & mask(wide) == 0);
+    (v | r.a) & mask(wide)
+}
+
+/// Test and reset bits: Z from `a & v`, then the bits of `a` get cleared in
+/// `v`.
+pub fn trb(r: &mut Registers, v: u16, wide: bool) -> u16 {
+    r.set_flag(FLAG_Z, r.a & v & mask(wide) == 0);
+    v & !r.a & mask(wide)
+}
+
+/// Expands to the body of `execute_instruction` for a CPU with a `regs:
+/// Registers` field, see `execute_6502!`. `$read!($cpu, addr)` and
+/// `$write!($cpu, addr, data)` take 24-bit addresses, `$idle!($cpu)` is an
+/// internal operation that doesn't touch the bus.
+///
+/// The sub-rules take the four names as one `[$c, $r, $w, $i]` group.
+macro_rules! execute_65816 {
+    ($cpu:ident, $read:ident, $write:ident, $idle:ident) => {
+        execute_65816!(@run [$cpu, $read, $write, $idle])
+    };
+
+    (@run [$c:ident, $r:ident, $w:ident, $i:ident]) => {{
+        use $crate::w65816::*;
+        let opcode = execute_65816!(@fetch [$c, $r, $w, $i]);
+        match opcode {
+            // accumulator group
+            0x01 => execute_65816!(@load ora, m, indx, [$c, $r, $w, $i]),
+            0x03 => execute_65816!(@load ora, m, sr, [$c, $r, $w, $i]),
+            0x05 => execute_65816!(@load ora, m, dp, [$c, $r, $w, $i]),
+            0x07 => execute_65816!(@load ora, m, indl, [$c, $r, $w, $i]),
+            0x09 => execute_65816!(@load ora, m, imm, [$c, $r, $w, $i]),
+            0x0d => execute_65816!(@load ora, m, abs, [$c, $r, $w, $i]),
+            0x0f => execute_65816!(@load ora, m, long, [$c, $r, $w, $i]),
+            0x11 => execute_65816!(@load ora, m, indy, [$c, $r, $w, $i]),
+            0x12 => execute_65816!(@load ora, m, ind, [$c, $r, $w, $i]),
+            0x13 => execute_65816!(@load ora, m, sry, [$c, $r, $w, $i]),
+            0x15 => execute_65816!(@load ora, m, dpx, [$c, $r, $w, $i]),
+            0x17 => execute_65816!(@load ora, m, indly, [$c, $r, $w, $i]),
+            0x19 => execute_65816!(@load ora, m, absy, [$c, $r, $w, $i]),
+            0x1d => execute_65816!(@load ora, m, absx, [$c, $r, $w, $i]),
+            0x1f => execute_65816!(@load ora, m, longx, [$c, $r, $w, $i]),
+            0x21 => execute_65816!(@load and, m, indx, [$c, $r, $w, $i]),
+            0x23 => execute_65816!(@load and, m, sr, [$c, $r, $w, $i]),
+            0x25 => execute_65816!(@load and, m, dp, [$c, $r, $w, $i]),
+            0x27 => execute_65816!(@load and, m, indl, [$c, $r, $w, $i]),
+            0x29 => execute_65816!(@load and, m, imm, [$c, $r, $w, $i]),
+            0x2d => execute_65816!(@load and, m, abs, [$c, $r, $w, $i]),
+            0x2f => execute_65816!(@load and, m, long, [$c, $r, $w, $i]),
+            0x31 => execute_65816!(@load and, m, indy, [$c, $r, $w, $i]),
+            0x32 => execute_65816!(@load and, m, ind, [$c, $r, $w, $i]),
+            0x33 => execute_65816!(@load and, m, sry, [$c, $r, $w, $i]),
+            0x35 => execute_65816!(@load and, m, dpx, [$c, $r, $w, $i]),
+            0x37 => execute_65816!(@load and, m, indly, [$c, $r, $w, $i]),
+            0x39 => execute_65816!(@load and, m, absy, [$c, $r, $w, $i]),
+            0x3d => execute_65816!(@load and, m, absx, [$c, $r, $w, $i]),
+            0x3f => execute_65816!(@load and, m, longx, [$c, $r, $w, $i]),
+            0x41 => execute_65816!(@load eor, m, indx, [$c, $r, $w, $i]),
+            0x43 => execute_65816!(@load eor, m, sr, [$c, $r, $w, $i]),
+            0x45 => execute_65816!(@load eor, m, dp, [$c, $r, $w, $i]),
+            0x47 => execute_65816!(@load eor, m, indl, [$c, $r, $w, $i]),
+            0x49 => execute_65816!(@load eor, m, imm, [$c, $r, $w, $i]),
+            0x4d => execute_65816!(@load eor, m, abs, [$c, $r, $w, $i]),
+            0x4f => execute_65816!(@load eor, m, long, [$c, $r, $w, $i]),
+            0x51 => execute_65816!(@load eor, m, indy, [$c, $r, $w, $i]),
+            0x52 => execute_65816!(@load eor, m, ind, [$c, $r, $w, $i]),
+            0x53 => execute_65816!(@load eor, m, sry, [$c, $r, $w, $i]),
+            0x55 => execute_65816!(@load eor, m, dpx, [$c, $r, $w, $i]),
+            0x57 => execute_65816!(@load eor, m, indly, [$c, $r, $w, $i]),
+            0x59 => execute_65816!(@load eor, m, absy, [$c, $r, $w, $i]),
+            0x5d => execute_65816!(@load eor, m, absx, [$c, $r, $w, $i]),
+            0x5f => execute_65816!(@load eor, m, longx, [$c, $r, $w, $i]),
+            0x61 => execute_65816!(@load adc, m, indx, [$c, $r, $w, $i]),
+            0x63 => execute_65816!(@load adc, m, sr, [$c, $r, $w, $i]),
+            0x65 => execute_65816!(@load adc, m, dp, [$c, $r, $w, $i]),
+            0x67 => execute_65816!(@load adc, m, indl, [$c, $r, $w, $i]),
+            0x69 => execute_65816!(@load adc, m, imm, [$c, $r, $w, $i]),
+            0x6d => execute_65816!(@load adc, m, abs, [$c, $r, $w, $i]),
+            0x6f => execute_65816!(@load adc, m, long, [$c, $r, $w, $i]),
+            0x71 => execute_65816!(@load adc, m, indy, [$c, $r, $w, $i]),
+            0x72 => execute_65816!(@load adc, m, ind, [$c, $r, $w, $i]),
+            0x73 => execute_65816!(@load adc, m, sry, [$c, $r, $w, $i]),
+            0x75 => execute_65816!(@load adc, m, dpx, [$c, $r, $w, $i]),
+            0x77 => execute_65816!(@load adc, m, indly, [$c, $r, $w, $i]),
+            0x79 => execute_65816!(@load adc, m, absy, [$c, $r, $w, $i]),
+            0x7d => execute_65816!(@load adc, m, absx, [$c, $r, $w, $i]),
+            0x7f => execute_65816!(@load adc, m, longx, [$c, $r, $w, $i]),
+            0x81 => execute_65816!(@store $c.regs.a, m, indx, [$c, $r, $w, $i]),
+            0x83 => execute_65816!(@store $c.regs.a, m, sr, [$c, $r, $w, $i]),
+            0x85 => execute_65816!(@store $c.regs.a, m, dp, [$c, $r, $w, $i]),
+            0x87 => execute_65816!(@store $c.regs.a, m, indl, [$c, $r, $w, $i]),
+            0x8d => execute_65816!(@store $c.regs.a, m, abs, [$c, $r, $w, $i]),
+            0x8f => execute_65816!(@store $c.regs.a, m, long, [$c, $r, $w, $i]),
+            0x91 => execute_65816!(@store $c.regs.a, m, indy, [$c, $r, $w, $i]),
+            0x92 => execute_65816!(@store $c.regs.a, m, ind, [$c, $r, $w, $i]),
+            0x93 => execute_65816!(@store $c.regs.a, m, sry, [$c, $r, $w, $i]),
+            0x95 => execute_65816!(@store $c.regs.a, m, dpx, [$c, $r, $w, $i]),
+            0x97 => execute_65816!(@store $c.regs.a, m, indly, [$c, $r, $w, $i]),
+            0x99 => execute_65816!(@store $c.regs.a, m, absy, [$c, $r, $w, $i]),
+            0x9d => execute_65816!(@store $c.regs.a, m, absx, [$c, $r, $w, $i]),
+            0x9f => execute_65816!(@store $c.regs.a, m, longx, [$c, $r, $w, $i]),
+            0xa1 => execute_65816!(@load lda, m, indx, [$c, $r, $w, $i]),
+            0xa3 => execute_65816!(@load lda, m, sr, [$c, $r, $w, $i]),
+            0xa5 => execute_65816!(@load lda, m, dp, [$c, $r, $w, $i]),
+            0xa7 => execute_65816!(@load lda, m, indl, [$c, $r, $w, $i]),
+            0xa9 => execute_65816!(@load lda, m, imm, [$c, $r, $w, $i]),
+            0xad => execute_65816!(@load lda, m, abs, [$c, $r, $w, $i]),
+            0xaf => execute_65816!(@load lda, m, long, [$c, $r, $w, $i]),
+            0xb1 => execute_65816!(@load lda, m, indy, [$c, $r, $w, $i]),
+            0xb2 => execute_65816!(@load lda, m, ind, [$c, $r, $w, $i]),
+            0xb3 => execute_65816!(@load lda, m, sry, [$c, $r, $w, $i]),
+            0xb5 => execute_65816!(@load lda, m, dpx, [$c, $r, $w, $i]),
+            0xb7 => execute_65816!(@load lda, m, indly, [$c, $r, $w, $i]),
+            0xb9 => execute_65816!(@load lda, m, absy, [$c, $r, $w, $i]),
+            0xbd => execute_65816!(@load lda, m, absx, [$c, $r, $w, $i]),
+            0xbf => execute_65816!(@load lda, m, longx, [$c, $r, $w, $i]),
+            0xc1 => execute_65816!(@load cmp, m, indx, [$c, $r, $w, $i]),
+            0xc3 => execute_65816!(@load cmp, m, sr, [$c, $r, $w, $i]),
+            0xc5 => execute_65816!(@load cmp, m, dp, [$c, $r, $w, $i]),
+            0xc7 => execute_65816!(@load cmp, m, indl, [$c, $r, $w, $i]),
+            0xc9 => execute_65816!(@load cmp, m, imm, [$c, $r, $w, $i]),
+            0xcd => execute_65816!(@load cmp, m, abs, [$c, $r, $w, $i]),
+            0xcf => execute_65816!(@load cmp, m, long, [$c, $r, $w, $i]),
+            0xd1 => execute_65816!(@load cmp, m, indy, [$c, $r, $w, $i]),
+            0xd2 => execute_65816!(@load cmp, m, ind, [$c, $r, $w, $i]),
+            0xd3 => execute_65816!(@load cmp, m, sry, [$c, $r, $w, $i]),
+            0xd5 => execute_65816!(@load cmp, m, dpx, [$c, $r, $w, $i]),
+            0xd7 => execute_65816!(@load cmp, m, indly, [$c, $r, $w, $i]),
+            0xd9 => execute_65816!(@load cmp, m, absy, [$c, $r, $w, $i]),
+            0xdd => execute_65816!(@load cmp, m, absx, [$c, $r, $w, $i]),
+            0xdf => execute_65816!(@load cmp, m, longx, [$c, $r, $w, $i]),
+            0xe1 => execute_65816!(@load sbc, m, indx, [$c, $r, $w, $i]),
+            0xe3 => execute_65816!(@load sbc, m, sr, [$c, $r, $w, $i]),
+            0xe5 => execute_65816!(@load sbc, m, dp, [$c, $r, $w, $i]),
+            0xe7 => execute_65816!(@load sbc, m, indl, [$c, $r, $w, $i]),
+            0xe9 => execute_65816!(@load sbc, m, imm, [$c, $r, $w, $i]),
+            0xed => execute_65816!(@load sbc, m, abs, [$c, $r, $w, $i]),
+            0xef => execute_65816!(@load sbc, m, long, [$c, $r, $w, $i]),
+            0xf1 => execute_65816!(@load sbc, m, indy, [$c, $r, $w, $i]),
+            0xf2 => execute_65816!(@load sbc, m, ind, [$c, $r, $w, $i]),
+            0xf3 => execute_65816!(@load sbc, m, sry, [$c, $r, $w, $i]),
+            0xf5 => execute_65816!(@load sbc, m, dpx, [$c, $r, $w, $i]),
+            0xf7 => execute_65816!(@load sbc, m, indly, [$c, $r, $w, $i]),
+            0xf9 => execute_65816!(@load sbc, m, absy, [$c, $r, $w, $i]),
+            0xfd => execute_65816!(@load sbc, m, absx, [$c, $r, $w, $i]),
+            0xff => execute_65816!(@load sbc, m, longx, [$c, $r, $w, $i]),
+
+            // index registers and the other loads and stores
+            0xa0 => execute_65816!(@load ldy, x, imm, [$c, $r, $w, $i]),
+            0xa4 => execute_65816!(@load ldy, x, dp, [$c, $r, $w, $i]),
+            0xac => execute_65816!(@load ldy, x, abs, [$c, $r, $w, $i]),
+            0xb4 => execute_65816!(@load ldy, x, dpx, [$c, $r, $w, $i]),
+            0xbc => execute_65816!(@load ldy, x, absx, [$c, $r, $w, $i]),
+            0xa2 => execute_65816!(@load ldx, x, imm, [$c, $r, $w, $i]),
+            0xa6 => execute_65816!(@load ldx, x, dp, [$c, $r, $w, $i]),
+            0xae => execute_65816!(@load ldx, x, abs, [$c, $r, $w, $i]),
+            0xb6 => execute_65816!(@load ldx, x, dpy, [$c, $r, $w, $i]),
+            0xbe => execute_65816!(@load ldx, x, absy, [$c, $r, $w, $i]),
+            0xc0 => execute_65816!(@load cpy, x, imm, [$c, $r, $w, $i]),
+            0xc4 => execute_65816!(@load cpy, x, dp, [$c, $r, $w, $i]),
+            0xcc => execute_65816!(@load cpy, x, abs, [$c, $r, $w, $i]),
+            0xe0 => execute_65816!(@load cpx, x, imm, [$c, $r, $w, $i]),
+            0xe4 => execute_65816!(@load cpx, x, dp, [$c, $r, $w, $i]),
+            0xec => execute_65816!(@load cpx, x, abs, [$c, $r, $w, $i]),
+            0x89 => execute_65816!(@load bit_immediate, m, imm, [$c, $r, $w, $i]),
+            0x24 => execute_65816!(@load bit, m, dp, [$c, $r, $w, $i]),
+            0x2c => execute_65816!(@load bit, m, abs, [$c, $r, $w, $i]),
+            0x34 => execute_65816!(@load bit, m, dpx, [$c, $r, $w, $i]),
+            0x3c => execute_65816!(@load bit, m, absx, [$c, $r, $w, $i]),
+            0x84 => execute_65816!(@store $c.regs.y, x, dp, [$c, $r, $w, $i]),
+            0x8c => execute_65816!(@store $c.regs.y, x, abs, [$c, $r, $w, $i]),
+            0x94 => execute_65816!(@store $c.regs.y, x, dpx, [$c, $r, $w, $i]),
+            0x86 => execute_65816!(@store $c.regs.x, x, dp, [$c, $r, $w, $i]),
+            0x8e => execute_65816!(@store $c.regs.x, x, abs, [$c, $r, $w, $i]),
+            0x96 => execute_65816!(@store $c.regs.x, x, dpy, [$c, $r, $w, $i]),
+            0x64 => execute_65816!(@store 0, m, dp, [$c, $r, $w, $i]),
+            0x74 => execute_65816!(@store 0, m, dpx, [$c, $r, $w, $i]),
+            0x9c => execute_65816!(@store 0, m, abs, [$c, $r, $w, $i]),
+            0x9e => execute_65816!(@store 0, m, absx, [$c, $r, $w, $i]),
+
+            // read-modify-write
+            0x0a => execute_65816!(@accumulator asl, [$c, $r, $w, $i]),
+            0x06 => execute_65816!(@modify asl, dp, [$c, $r, $w, $i]),
+            0x0e => execute_65816!(@modify asl, abs, [$c, $r, $w, $i]),
+            0x16 => execute_65816!(@modify asl, dpx, [$c, $r, $w, $i]),
+            0x1e => execute_65816!(@modify asl, absx, [$c, $r, $w, $i]),
+            0x4a => execute_65816!(@accumulator lsr, [$c, $r, $w, $i]),
+            0x46 => execute_65816!(@modify lsr, dp, [$c, $r, $w, $i]),
+            0x4e => execute_65816!(@modify lsr, abs, [$c, $r, $w, $i]),
+            0x56 => execute_65816!(@modify lsr, dpx, [$c, $r, $w, $i]),
+            0x5e => execute_65816!(@modify lsr, absx, [$c, $r, $w, $i]),
+            0x2a => execute_65816!(@accumulator rol, [$c, $r, $w, $i]),
+            0x26 => execute_65816!(@modify rol, dp, [$c, $r, $w, $i]),
+            0x2e => execute_65816!(@modify rol, abs, [$c, $r, $w, $i]),
+            0x36 => execute_65816!(@modify rol, dpx, [$c, $r, $w, $i]),
+            0x3e => execute_65816!(@modify rol, absx, [$c, $r, $w, $i]),
+            0x6a => execute_65816!(@accumulator ror, [$c, $r, $w, $i]),
+            0x66 => execute_65816!(@modify ror, dp, [$c, $r, $w, $i]),
+            0x6e => execute_65816!(@modify ror, abs, [$c, $r, $w, $i]),
+            0x76 => execute_65816!(@modify ror, dpx, [$c, $r, $w, $i]),
+            0x7e => execute_65816!(@modify ror, absx, [$c, $r, $w, $i]),
+            0x1a => execute_65816!(@accumulator inc, [$c, $r, $w, $i]),
+            0xe6 => execute_65816!(@modify inc, dp, [$c, $r, $w, $i]),
+            0xee => execute_65816!(@modify inc, abs, [$c, $r, $w, $i]),
+            0xf6 => execute_65816!(@modify inc, dpx, [$c, $r, $w, $i]),
+            0xfe => execute_65816!(@modify inc, absx, [$c, $r, $w, $i]),
+            0x3a => execute_65816!(@accumulator dec, [$c, $r, $w, $i]),
+            0xc6 => execute_65816!(@modify dec, dp, [$c, $r, $w, $i]),
+            0xce => execute_65816!(@modify dec, abs, [$c, $r, $w, $i]),
+            0xd6 => execute_65816!(@modify dec, dpx, [$c, $r, $w, $i]),
+            0xde => execute_65816!(@modify dec, absx, [$c, $r, $w, $i]),
+            0x04 => execute_65816!(@modify tsb, dp, [$c, $r, $w, $i]),
+            0x0c => execute_65816!(@modify tsb, abs, [$c, $r, $w, $i]),
+            0x14 => execute_65816!(@modify trb, dp, [$c, $r, $w, $i]),
+            0x1c => execute_65816!(@modify trb, abs, [$c, $r, $w, $i]),
+
+            // register increments, decrements and transfers
+            0xe8 => execute_65816!(@implied [$c, $r, $w, $i], {
+                let (v, wide) = ($c.regs.x, !x8(&$c.regs));
+                $c.regs.x = inc(&mut $c.regs, v, wide);
+            }),
+            0xc8 => execute_65816!(@implied [$c, $r, $w, $i], {
+                let (v, wide) = ($c.regs.y, !x8(&$c.regs));
+                $c.regs.y = inc(&mut $c.regs, v, wide);
+            }),
+            0xca => execute_65816!(@implied [$c, $r, $w, $i], {
+                let (v, wide) = ($c.regs.x, !x8(&$c.regs));
+                $c.regs.x = dec(&mut $c.regs, v, wide);
+            }),
+            0x88 => execute_65816!(@implied [$c, $r, $w, $i], {
+                let (v, wide) = ($c.regs.y, !x8(&$c.regs));
+                $c.regs.y = dec(&mut $c.regs, v, wide);
+            }),
+            0xaa => execute_65816!(@implied [$c, $r, $w, $i], {
+                let v = $c.regs.a;
+                ldx(&mut $c.regs, v);
+            }),
+            0xa8 => execute_65816!(@implied [$c, $r, $w, $i], {
+                let v = $c.regs.a;
+                ldy(&mut $c.regs, v);
+            }),
+            0x8a => execute_65816!(@implied [$c, $r, $w, $i], {
+                let v = $c.regs.x;
+                lda(&mut $c.regs, v);
+            }),
+            0x98 => execute_65816!(@implied [$c, $r, $w, $i], {
+                let v = $c.regs.y;
+                lda(&mut $c.regs, v);
+            }),
+            0x9b => execute_65816!(@implied [$c, $r, $w, $i], {
+                let v = $c.regs.x;
+                ldy(&mut $c.regs, v);
+            }),
+            0xbb => execute_65816!(@implied [$c, $r, $w, $i], {
+                let v = $c.regs.y;
+                ldx(&mut $c.regs, v);
+            }),
+            0xba => execute_65816!(@implied [$c, $r, $w, $i], {
+                let v = $c.regs.s;
+                ldx(&mut $c.regs, v);
+            }),
+            0x9a => execute_65816!(@implied [$c, $r, $w, $i], {
+                $c.regs.s = if $c.regs.e { 0x0100 | ($c.regs.x & 0x00ff) } else { $c.regs.x };
+            }),
+            0x1b => execute_65816!(@implied [$c, $r, $w, $i], {
+                $c.regs.s = if $c.regs.e { 0x0100 | ($c.regs.a & 0x00ff) } else { $c.regs.a };
+            }),
+            0x3b => execute_65816!(@implied [$c, $r, $w, $i], {
+                let v = $c.regs.s;
+                $c.regs.a = nz(&mut $c.regs, v, true);
+            }),
+            0x5b => execute_65816!(@implied [$c, $r, $w, $i], {
+                let v = $c.regs.a;
+                $c.regs.d = nz(&mut $c.regs, v, true);
+            }),
+            0x7b => execute_65816!(@implied [$c, $r, $w, $i], {
+                let v = $c.regs.d;
+                $c.regs.a = nz(&mut $c.regs, v, true);
+            }),
+            0xeb => {
+                $i!($c);
+                $i!($c);
+                let v = $c.regs.a.rotate_left(8);
+                $c.regs.a = v;
+                nz(&mut $c.regs, v, false);
+            }
+
+            // flags and modes
+            0x18 => execute_65816!(@implied [$c, $r, $w, $i], { $c.regs.set_flag(FLAG_C, false) }),
+            0x38 => execute_65816!(@implied [$c, $r, $w, $i], { $c.regs.set_flag(FLAG_C, true) }),
+            0x58 => execute_65816!(@implied [$c, $r, $w, $i], { $c.regs.set_flag(FLAG_I, false) }),
+            0x78 => execute_65816!(@implied [$c, $r, $w, $i], { $c.regs.set_flag(FLAG_I, true) }),
+            0xd8 => execute_65816!(@implied [$c, $r, $w, $i], { $c.regs.set_flag(FLAG_D, false) }),
+            0xf8 => execute_65816!(@implied [$c, $r, $w, $i], { $c.regs.set_flag(FLAG_D, true) }),
+            0xb8 => execute_65816!(@implied [$c, $r, $w, $i], { $c.regs.set_flag(FLAG_V, false) }),
+            0xfb => execute_65816!(@implied [$c, $r, $w, $i], { xce(&mut $c.regs) }),
+            0xc2 => {
+                let v = execute_65816!(@fetch [$c, $r, $w, $i]);
+                $i!($c);
+                let p = $c.regs.p & !v;
+                set_p(&mut $c.regs, p);
+            }
+            0xe2 => {
+                let v = execute_65816!(@fetch [$c, $r, $w, $i]);
+                $i!($c);
+                let p = $c.regs.p | v;
+                set_p(&mut $c.regs, p);
+            }
+            0xea => execute_65816!(@implied [$c, $r, $w, $i], {}),
+            0x42 => {
+                // WDM, reserved for a 16-bit opcode space that never came
+                execute_65816!(@fetch [$c, $r, $w, $i]);
+            }
+            0xcb | 0xdb => {
+                // WAI and STP: nothing raises interrupts or resets, so they
+                // wait forever by executing themselves again
+                $i!($c);
+                $i!($c);
+                $c.regs.pc = $c.regs.pc.wrapping_sub(1);
+            }
+
+            // branches
+            0x10 => execute_65816!(@branch !$c.regs.flag(FLAG_N), [$c, $r, $w, $i]),
+            0x30 => execute_65816!(@branch $c.regs.flag(FLAG_N), [$c, $r, $w, $i]),
+            0x50 => execute_65816!(@branch !$c.regs.flag(FLAG_V), [$c, $r, $w, $i]),
+            0x70 => execute_65816!(@branch $c.regs.flag(FLAG_V), [$c, $r, $w, $i]),
+            0x90 => execute_65816!(@branch !$c.regs.flag(FLAG_C), [$c, $r, $w, $i]),
+            0xb0 => execute_65816!(@branch $c.regs.flag(FLAG_C), [$c, $r, $w, $i]),
+            0xd0 => execute_65816!(@branch !$c.regs.flag(FLAG_Z), [$c, $r, $w, $i]),
+            0xf0 => execute_65816!(@branch $c.regs.flag(FLAG_Z), [$c, $r, $w, $i]),
+            0x80 => execute_65816!(@branch true, [$c, $r, $w, $i]),
+            0x82 => {
+                let offset = execute_65816!(@fetch16 [$c, $r, $w, $i]);
+                $i!($c);
+                $c.regs.pc = $c.regs.pc.wrapping_add(offset);
+            }
+
+            // stack
+            0x48 => {
+                $i!($c);
+                if !m8(&$c.regs) {
+                    execute_65816!(@push ($c.regs.a >> 8) as u8, [$c, $r, $w, $i]);
+                }
+                execute_65816!(@push $c.regs.a as u8, [$c, $r, $w, $i]);
+            }
+            0xda => {
+                $i!($c);
+                if !x8(&$c.regs) {
+                    execute_65816!(@push ($c.regs.x >> 8) as u8, [$c, $r, $w, $i]);
+                }
+                execute_65816!(@push $c.regs.x as u8, [$c, $r, $w, $i]);
+            }
+            0x5a => {
+                $i!($c);
+                if !x8(&$c.regs) {
+                    execute_65816!(@push ($c.regs.y >> 8) as u8, [$c, $r, $w, $i]);
+                }
+                execute_65816!(@push $c.regs.y as u8, [$c, $r, $w, $i]);
+            }
+            0x08 => {
+                $i!($c);
+                execute_65816!(@push $c.regs.p, [$c, $r, $w, $i]);
+            }
+            0x8b => {
+                $i!($c);
+                execute_65816!(@push $c.regs.dbr, [$c, $r, $w, $i]);
+            }
+            0x4b => {
+                $i!($c);
+                execute_65816!(@push $c.regs.pbr, [$c, $r, $w, $i]);
+            }
+            0x0b => {
+                $i!($c);
+                execute_65816!(@push_n ($c.regs.d >> 8) as u8, [$c, $r, $w, $i]);
+                execute_65816!(@push_n $c.regs.d as u8, [$c, $r, $w, $i]);
+                execute_65816!(@fix_s $c);
+            }
+            0x68 => {
+                $i!($c);
+                $i!($c);
+                let v = execute_65816!(@pull_width m, [$c, $r, $w, $i]);
+                lda(&mut $c.regs, v);
+            }
+            0xfa => {
+                $i!($c);
+                $i!($c);
+                let v = execute_65816!(@pull_width x, [$c, $r, $w, $i]);
+                ldx(&mut $c.regs, v);
+            }
+            0x7a => {
+                $i!($c);
+                $i!($c);
+                let v = execute_65816!(@pull_width x, [$c, $r, $w, $i]);
+                ldy(&mut $c.regs, v);
+            }
+            0x28 => {
+                $i!($c);
+                $i!($c);
+                let v = execute_65816!(@pull [$c, $r, $w, $i]);
+                set_p(&mut $c.regs, v);
+            }
+            0xab => {
+                $i!($c);
+                $i!($c);
+                let v = execute_65816!(@pull_n [$c, $r, $w, $i]);
+                $c.regs.dbr = nz(&mut $c.regs, v as u16, false) as u8;
+                execute_65816!(@fix_s $c);
+            }
+            0x2b => {
+                $i!($c);
+                $i!($c);
+                let lo = execute_65816!(@pull_n [$c, $r, $w, $i]) as u16;
+                let hi = execute_65816!(@pull_n [$c, $r, $w, $i]) as u16;
+                $c.regs.d = nz(&mut $c.regs, hi << 8 | lo, true);
+                execute_65816!(@fix_s $c);
+            }
+            0xf4 => {
+                let v = execute_65816!(@fetch16 [$c, $r, $w, $i]);
+                execute_65816!(@push_n (v >> 8) as u8, [$c, $r, $w, $i]);
+                execute_65816!(@push_n v as u8, [$c, $r, $w, $i]);
+                execute_65816!(@fix_s $c);
+            }
+            0xd4 => {
+                let u = execute_65816!(@fetch [$c, $r, $w, $i]) as u16;
+                execute_65816!(@idle2 [$c, $r, $w, $i]);
+                let lo = $r!($c, direct_n(&$c.regs, u));
+                let hi = $r!($c, direct_n(&$c.regs, u + 1));
+                execute_65816!(@push_n hi, [$c, $r, $w, $i]);
+                execute_65816!(@push_n lo, [$c, $r, $w, $i]);
+                execute_65816!(@fix_s $c);
+            }
+            0x62 => {
+                let offset = execute_65816!(@fetch16 [$c, $r, $w, $i]);
+                $i!($c);
+                let v = $c.regs.pc.wrapping_add(offset);
+                execute_65816!(@push_n (v >> 8) as u8, [$c, $r, $w, $i]);
+                execute_65816!(@push_n v as u8, [$c, $r, $w, $i]);
+                execute_65816!(@fix_s $c);
+            }
+
+            // block moves
+            0x54 => execute_65816!(@move 1, [$c, $r, $w, $i]),
+            0x44 => execute_65816!(@move -1, [$c, $r, $w, $i]),
+
+            // jumps, subroutines and interrupts
+            0x4c => {
+                $c.regs.pc = execute_65816!(@fetch16 [$c, $r, $w, $i]);
+            }
+            0x5c => {
+                let v = execute_65816!(@fetch24 [$c, $r, $w, $i]);
+                $c.regs.pc = v as u16;
+                $c.regs.pbr = (v >> 16) as u8;
+            }
+            0x6c => {
+                let ptr = execute_65816!(@fetch16 [$c, $r, $w, $i]);
+                let lo = $r!($c, ptr as u32) as u16;
+                let hi = $r!($c, ptr.wrapping_add(1) as u32) as u16;
+                $c.regs.pc = hi << 8 | lo;
+            }
+            0x7c => {
+                let ptr = execute_65816!(@fetch16 [$c, $r, $w, $i]).wrapping_add($c.regs.x);
+                $i!($c);
+                let program_bank = ($c.regs.pbr as u32) << 16;
+                let lo = $r!($c, program_bank | ptr as u32) as u16;
+                let hi = $r!($c, program_bank | ptr.wrapping_add(1) as u32) as u16;
+                $c.regs.pc = hi << 8 | lo;
+            }
+            0xdc => {
+                let ptr = execute_65816!(@fetch16 [$c, $r, $w, $i]);
+                let lo = $r!($c, ptr as u32) as u16;
+                let hi = $r!($c, ptr.wrapping_add(1) as u32) as u16;
+                let b = $r!($c, ptr.wrapping_add(2) as u32);
+                $c.regs.pc = hi << 8 | lo;
+                $c.regs.pbr = b;
+            }
+            0x20 => {
+                let target = execute_65816!(@fetch16 [$c, $r, $w, $i]);
+                $i!($c);
+                let ret = $c.regs.pc.wrapping_sub(1);
+                execute_65816!(@push (ret >> 8) as u8, [$c, $r, $w, $i]);
+                execute_65816!(@push ret as u8, [$c, $r, $w, $i]);
+                $c.regs.pc = target;
+            }
+            0x22 => {
+                let target = execute_65816!(@fetch16 [$c, $r, $w, $i]);
+                execute_65816!(@push_n $c.regs.pbr, [$c, $r, $w, $i]);
+                $i!($c);
+                let b = execute_65816!(@fetch [$c, $r, $w, $i]);
+                let ret = $c.regs.pc.wrapping_sub(1);
+                execute_65816!(@push_n (ret >> 8) as u8, [$c, $r, $w, $i]);
+                execute_65816!(@push_n ret as u8, [$c, $r, $w, $i]);
+                $c.regs.pc = target;
+                $c.regs.pbr = b;
+                execute_65816!(@fix_s $c);
+            }
+            0xfc => {
+                let lo = execute_65816!(@fetch [$c, $r, $w, $i]) as u16;
+                execute_65816!(@push_n ($c.regs.pc >> 8) as u8, [$c, $r, $w, $i]);
+                execute_65816!(@push_n $c.regs.pc as u8, [$c, $r, $w, $i]);
+                let hi = execute_65816!(@fetch [$c, $r, $w, $i]) as u16;
+                $i!($c);
+                let ptr = (hi << 8 | lo).wrapping_add($c.regs.x);
+                let program_bank = ($c.regs.pbr as u32) << 16;
+                let lo = $r!($c, program_bank | ptr as u32) as u16;
+                let hi = $r!($c, program_bank | ptr.wrapping_add(1) as u32) as u16;
+                $c.regs.pc = hi << 8 | lo;
+                execute_65816!(@fix_s $c);
+            }
+            0x60 => {
+                $i!($c);
+                $i!($c);
+                let lo = execute_65816!(@pull [$c, $r, $w, $i]) as u16;
+                let hi = execute_65816!(@pull [$c, $r, $w, $i]) as u16;
+                $i!($c);
+                $c.regs.pc = (hi << 8 | lo).wrapping_add(1);
+            }
+            0x6b => {
+                $i!($c);
+                $i!($c);
+                let lo = execute_65816!(@pull_n [$c, $r, $w, $i]) as u16;
+                let hi = execute_65816!(@pull_n [$c, $r, $w, $i]) as u16;
+                let b = execute_65816!(@pull_n [$c, $r, $w, $i]);
+                $c.regs.pc = (hi << 8 | lo).wrapping_add(1);
+                $c.regs.pbr = b;
+                execute_65816!(@fix_s $c);
+            }
+            0x40 => {
+                $i!($c);
+                $i!($c);
+                let p = execute_65816!(@pull [$c, $r, $w, $i]);
+                set_p(&mut $c.regs, p);
+                let lo = execute_65816!(@pull [$c, $r, $w, $i]) as u16;
+                let hi = execute_65816!(@pull [$c, $r, $w, $i]) as u16;
+                if !$c.regs.e {
+                    $c.regs.pbr = execute_65816!(@pull [$c, $r, $w, $i]);
+                }
+                $c.regs.pc = hi << 8 | lo;
+            }
+            0x00 => execute_65816!(@interrupt BRK_VECTOR_NATIVE, BRK_VECTOR_EMULATION, [$c, $r, $w, $i]),
+            0x02 => execute_65816!(@interrupt COP_VECTOR_NATIVE, COP_VECTOR_EMULATION, [$c, $r, $w, $i]),
+        }
+    }};
+
+    // instruction stream
+    (@fetch [$c:ident, $r:ident, $w:ident, $i:ident]) => {{
+        let data = $r!($c, pc24(&$c.regs));
+        $c.regs.pc = $c.regs.pc.wrapping_add(1);
+        data
+    }};
+    (@fetch16 [$c:ident, $r:ident, $w:ident, $i:ident]) => {{
+        let lo = execute_65816!(@fetch [$c, $r, $w, $i]) as u16;
+        let hi = execute_65816!(@fetch [$c, $r, $w, $i]) as u16;
+        hi << 8 | lo
+    }};
+    (@fetch24 [$c:ident, $r:ident, $w:ident, $i:ident]) => {{
+        let lo = execute_65816!(@fetch16 [$c, $r, $w, $i]) as u32;
+        let hi = execute_65816!(@fetch [$c, $r, $w, $i]) as u32;
+        hi << 16 | lo
+    }};
+
+    // penalty cycles: direct page not aligned to a page, and indexing that
+    // crosses a page or uses 16-bit index registers
+    (@idle2 [$c:ident, $r:ident, $w:ident, $i:ident]) => {
+        if $c.regs.d & 0x00ff != 0 {
+            $i!($c);
+        }
+    };
+    (@idle4 read, $from:expr, $to:expr, [$c:ident, $r:ident, $w:ident, $i:ident]) => {{
+        let (from, to): (u16, u16) = ($from, $to);
+        if !x8(&$c.regs) || from >> 8 != to >> 8 {
+            $i!($c);
+        }
+    }};
+    (@idle4 $kind:ident, $from:expr, $to:expr, [$c:ident, $r:ident, $w:ident, $i:ident]) => {
+        $i!($c)
+    };
+
+    (@wide m, $c:ident) => {
+        !m8(&$c.regs)
+    };
+    (@wide x, $c:ident) => {
+        !x8(&$c.regs)
+    };
+
+    // addresses of the low and high byte of an operand; `read` only pays
+    // for indexing when it crosses a page, `write` and `modify` always do
+    (@ea dp, $k:ident, [$c:ident, $r:ident, $w:ident, $i:ident]) => {{
+        let u = execute_65816!(@fetch [$c, $r, $w, $i]) as u16;
+        execute_65816!(@idle2 [$c, $r, $w, $i]);
+        (direct(&$c.regs, u), direct(&$c.regs, u + 1))
+    }};
+    (@ea dpx, $k:ident, [$c:ident, $r:ident, $w:ident, $i:ident]) => {{
+        let u = execute_65816!(@fetch [$c, $r, $w, $i]) as u16;
+        execute_65816!(@idle2 [$c, $r, $w, $i]);
+        $i!($c);
+        let offset = u.wrapping_add($c.regs.x);
+        (direct(&$c.regs, offset), direct(&$c.regs, offset.wrapping_add(1)))
+    }};
+    (@ea dpy, $k:ident, [$c:ident, $r:ident, $w:ident, $i:ident]) => {{
+        let u = execute_65816!(@fetch [$c, $r, $w, $i]) as u16;
+        execute_65816!(@idle2 [$c, $r, $w, $i]);
+        $i!($c);
+        let offset = u.wrapping_add($c.regs.y);
+        (direct(&$c.regs, offset), direct(&$c.regs, offset.wrapping_add(1)))
+    }};
+    (@ea abs, $k:ident, [$c:ident, $r:ident, $w:ident, $i:ident]) => {{
+        let v = execute_65816!(@fetch16 [$c, $r, $w, $i]) as u32;
+        (bank(&$c.regs, v), bank(&$c.regs, v + 1))
+    }};
+    (@ea absx, $k:ident, [$c:ident, $r:ident, $w:ident, $i:ident]) => {{
+        let v = execute_65816!(@fetch16 [$c, $r, $w, $i]);
+        execute_65816!(@idle4 $k, v, v.wrapping_add($c.regs.x), [$c, $r, $w, $i]);
+        let address = v as u32 + $c.regs.x as u32;
+        (bank(&$c.regs, address), bank(&$c.regs, address + 1))
+    }};
+    (@ea absy, $k:ident, [$c:ident, $r:ident, $w:ident, $i:ident]) => {{
+        let v = execute_65816!(@fetch16 [$c, $r, $w, $i]);
+        execute_65816!(@idle4 $k, v, v.wrapping_add($c.regs.y), [$c, $r, $w, $i]);
+        let address = v as u32 + $c.regs.y as u32;
+        (bank(&$c.regs, address), bank(&$c.regs, address + 1))
+    }};
+    (@ea long, $k:ident, [$c:ident, $r:ident, $w:ident, $i:ident]) => {{
+        let v = execute_65816!(@fetch24 [$c, $r, $w, $i]);
+        (v, (v + 1) & 0xff_ffff)
+    }};
+    (@ea longx, $k:ident, [$c:ident, $r:ident, $w:ident, $i:ident]) => {{
+        let v = execute_65816!(@fetch24 [$c, $r, $w, $i]) + $c.regs.x as u32;
+        (v & 0xff_ffff, (v + 1) & 0xff_ffff)
+    }};
+    (@ea ind, $k:ident, [$c:ident, $r:ident, $w:ident, $i:ident]) => {{
+        let u = execute_65816!(@fetch [$c, $r, $w, $i]) as u16;
+        execute_65816!(@idle2 [$c, $r, $w, $i]);
+        let lo = $r!($c, direct(&$c.regs, u)) as u32;
+        let hi = $r!($c, direct(&$c.regs, u + 1)) as u32;
+        let v = hi << 8 | lo;
+        (bank(&$c.regs, v), bank(&$c.regs, v + 1))
+    }};
+    (@ea indx, $k:ident, [$c:ident, $r:ident, $w:ident, $i:ident]) => {{
+        let u = execute_65816!(@fetch [$c, $r, $w, $i]) as u16;
+        execute_65816!(@idle2 [$c, $r, $w, $i]);
+        $i!($c);
+        let offset = u.wrapping_add($c.regs.x);
+        let lo = $r!($c, direct(&$c.regs, offset)) as u32;
+        let hi = $r!($c, direct(&$c.regs, offset.wrapping_add(1))) as u32;
+        let v = hi << 8 | lo;
+        (bank(&$c.regs, v), bank(&$c.regs, v + 1))
+    }};
+    (@ea indy, $k:ident, [$c:ident, $r:ident, $w:ident, $i:ident]) => {{
+        let u = execute_65816!(@fetch [$c, $r, $w, $i]) as u16;
+        execute_65816!(@idle2 [$c, $r, $w, $i]);
+        let lo = $r!($c, direct(&$c.regs, u)) as u16;
+        let hi = $r!($c, direct(&$c.regs, u + 1)) as u16;
+        let v = hi << 8 | lo;
+        execute_65816!(@idle4 $k, v, v.wrapping_add($c.regs.y), [$c, $r, $w, $i]);
+        let address = v as u32 + $c.regs.y as u32;
+        (bank(&$c.regs, address), bank(&$c.regs, address + 1))
+    }};
+    (@ea indl, $k:ident, [$c:ident, $r:ident, $w:ident, $i:ident]) => {{
+        let v = execute_65816!(@pointer24 [$c, $r, $w, $i]);
+        (v, (v + 1) & 0xff_ffff)
+    }};
+    (@ea indly, $k:ident, [$c:ident, $r:ident, $w:ident, $i:ident]) => {{
+        let v = execute_65816!(@pointer24 [$c, $r, $w, $i]) + $c.regs.y as u32;
+        (v & 0xff_ffff, (v + 1) & 0xff_ffff)
+    }};
+    (@ea sr, $k:ident, [$c:ident, $r:ident, $w:ident, $i:ident]) => {{
+        let u = execute_65816!(@fetch [$c, $r, $w, $i]) as u16;
+        $i!($c);
+        (stack(&$c.regs, u), stack(&$c.regs, u + 1))
+    }};
+    (@ea sry, $k:ident, [$c:ident, $r:ident, $w:ident, $i:ident]) => {{
+        let u = execute_65816!(@fetch [$c, $r, $w, $i]) as u16;
+        $i!($c);
+        let lo = $r!($c, stack(&$c.regs, u)) as u32;
+        let hi = $r!($c, stack(&$c.regs, u + 1)) as u32;
+        $i!($c);
+        let address = (hi << 8 | lo) + $c.regs.y as u32;
+        (bank(&$c.regs, address), bank(&$c.regs, address + 1))
+    }};
+    (@pointer24 [$c:ident, $r:ident, $w:ident, $i:ident]) => {{
+        let u = execute_65816!(@fetch [$c, $r, $w, $i]) as u16;
+        execute_65816!(@idle2 [$c, $r, $w, $i]);
+        let lo = $r!($c, direct_n(&$c.regs, u)) as u32;
+        let mid = $r!($c, direct_n(&$c.regs, u + 1)) as u32;
+        let hi = $r!($c, direct_n(&$c.regs, u + 2)) as u32;
+        hi << 16 | mid << 8 | lo
+    }};
+
+    // instruction shapes
+    (@load $op:ident, $width:ident, imm, [$c:ident, $r:ident, $w:ident, $i:ident]) => {{
+        let v = if execute_65816!(@wide $width, $c) {
+            execute_65816!(@fetch16 [$c, $r, $w, $i])
+        } else {
+            execute_65816!(@fetch [$c, $r, $w, $i]) as u16
+        };
+        $op(&mut $c.regs, v);
+    }};
+    (@load $op:ident, $width:ident, $mode:ident, [$c:ident, $r:ident, $w:ident, $i:ident]) => {{
+        let (lo, hi) = execute_65816!(@ea $mode, read, [$c, $r, $w, $i]);
+        let mut v = $r!($c, lo) as u16;
+        if execute_65816!(@wide $width, $c) {
+            let hi = $r!($c, hi) as u16;
+            v |= hi << 8;
+        }
+        $op(&mut $c.regs, v);
+    }};
+    (@store $value:expr, $width:ident, $mode:ident, [$c:ident, $r:ident, $w:ident, $i:ident]) => {{
+        let (lo, hi) = execute_65816!(@ea $mode, write, [$c, $r, $w, $i]);
+        let v: u16 = $value;
+        $w!($c, lo, v as u8);
+        if execute_65816!(@wide $width, $c) {
+            $w!($c, hi, (v >> 8) as u8);
+        }
+    }};
+    (@modify $op:ident, $mode:ident, [$c:ident, $r:ident, $w:ident, $i:ident]) => {{
+        let (lo, hi) = execute_65816!(@ea $mode, modify, [$c, $r, $w, $i]);
+        let wide = !m8(&$c.regs);
+        let mut v = $r!($c, lo) as u16;
+        if wide {
+            let hi = $r!($c, hi) as u16;
+            v |= hi << 8;
+        }
+        $i!($c);
+        let v = $op(&mut $c.regs, v, wide);
+        if wide {
+            $w!($c, hi, (v >> 8) as u8);
+        }
+        $w!($c, lo, v as u8);
+    }};
+    (@accumulator $op:ident, [$c:ident, $r:ident, $w:ident, $i:ident]) => {{
+        $i!($c);
+        let wide = !m8(&$c.regs);
+        let a = $c.regs.a;
+        let v = $op(&mut $c.regs, a, wide);
+        $c.regs.a = if wide { v } else { ($c.regs.a & 0xff00) | v };
+    }};
+    (@implied [$c:ident, $r:ident, $w:ident, $i:ident], $body:block) => {{
+        $i!($c);
+        $body
+    }};
+    (@branch $cond:expr, [$c:ident, $r:ident, $w:ident, $i:ident]) => {{
+        let offset = execute_65816!(@fetch [$c, $r, $w, $i]) as i8;
+        if $cond {
+            let from = $c.regs.pc;
+            let to = from.wrapping_add(offset as u16);
+            // only emulation mode pays for crossing a page
+            if $c.regs.e && from >> 8 != to >> 8 {
+                $i!($c);
+            }
+            $i!($c);
+            $c.regs.pc = to;
+        }
+    }};
+    (@move $step:expr, [$c:ident, $r:ident, $w:ident, $i:ident]) => {{
+        let target = execute_65816!(@fetch [$c, $r, $w, $i]);
+        let source = execute_65816!(@fetch [$c, $r, $w, $i]);
+        $c.regs.dbr = target;
+        let v = $r!($c, (source as u32) << 16 | $c.regs.x as u32);
+        $w!($c, (target as u32) << 16 | $c.regs.y as u32, v);
+        $i!($c);
+        let step: i16 = $step;
+        let index_mask = if x8(&$c.regs) { 0x00ff } else { 0xffff };
+        $c.regs.x = $c.regs.x.wrapping_add(step as u16) & index_mask;
+        $c.regs.y = $c.regs.y.wrapping_add(step as u16) & index_mask;
+        $i!($c);
+        // one byte per execution, until the count in `a` runs out
+        let count = $c.regs.a;
+        $c.regs.a = count.wrapping_sub(1);
+        if count != 0 {
+            $c.regs.pc = $c.regs.pc.wrapping_sub(3);
+        }
+    }};
+    (@interrupt $native:expr, $emulation:expr, [$c:ident, $r:ident, $w:ident, $i:ident]) => {{
+        // signature byte
+        execute_65816!(@fetch [$c, $r, $w, $i]);
+        if !$c.regs.e {
+            execute_65816!(@push $c.regs.pbr, [$c, $r, $w, $i]);
+        }
+        execute_65816!(@push ($c.regs.pc >> 8) as u8, [$c, $r, $w, $i]);
+        execute_65816!(@push $c.regs.pc as u8, [$c, $r, $w, $i]);
+        execute_65816!(@push $c.regs.p, [$c, $r, $w, $i]);
+        $c.regs.set_flag(FLAG_I, true);
+        $c.regs.set_flag(FLAG_D, false);
+        $c.regs.pbr = 0;
+        let vector = if $c.regs.e { $emulation } else { $native };
+        let lo = $r!($c, vector) as u16;
+        let hi = $r!($c, vector + 1) as u16;
+        $c.regs.pc = hi << 8 | lo;
+    }};
+
+    // the stack; the `_n` versions are used by instructions the 6502 doesn't
+    // have and can leave page 1 in emulation mode until `@fix_s`
+    (@push $value:expr, [$c:ident, $r:ident, $w:ident, $i:ident]) => {{
+        let v: u8 = $value;
+        $w!($c, $c.regs.s as u32, v);
+        $c.regs.s = if $c.regs.e {
+            0x0100 | ($c.regs.s.wrapping_sub(1) & 0x00ff)
+        } else {
+            $c.regs.s.wrapping_sub(1)
+        };
+    }};
+    (@pull [$c:ident, $r:ident, $w:ident, $i:ident]) => {{
+        $c.regs.s = if $c.regs.e {
+            0x0100 | ($c.regs.s.wrapping_add(1) & 0x00ff)
+        } else {
+            $c.regs.s.wrapping_add(1)
+        };
+        $r!($c, $c.regs.s as u32)
+    }};
+    (@pull_width $width:ident, [$c:ident, $r:ident, $w:ident, $i:ident]) => {{
+        let mut v = execute_65816!(@pull [$c, $r, $w, $i]) as u16;
+        if execute_65816!(@wide $width, $c) {
+            let hi = execute_65816!(@pull [$c, $r, $w, $i]) as u16;
+            v |= hi << 8;
+        }
+        v
+    }};
+    (@push_n $value:expr, [$c:ident, $r:ident, $w:ident, $i:ident]) => {{
+        let v: u8 = $value;
+        $w!($c, $c.regs.s as u32, v);
+        $c.regs.s = $c.regs.s.wrapping_sub(1);
+    }};
+    (@pull_n [$c:ident, $r:ident, $w:ident, $i:ident]) => {{
+        $c.regs.s = $c.regs.s.wrapping_add(1);
+        $r!($c, $c.regs.s as u32)
+    }};
+    (@fix_s $c:ident) => {
+        if $c.regs.e {
+            $c.regs.s = 0x0100 | ($c.regs.s & 0x00ff);
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Model;
+    use std::collections::HashMap;
+
+    /// Just enough of a CPU to run `execute_65816!` on: 16 MiB of memory
+    /// that reads 0 until it's written, and every access an instruction
+    /// makes, as address, data and whether it was a write.
+    struct Cpu {
+        regs: Registers,
+        memory: HashMap<u32, u8>,
+        accesses: Vec<(u32, u8, bool)>,
+    }
+
+    macro_rules! bus_read {
+        ($cpu:ident, $addr:expr) => {
+            $cpu.read($addr)
+        };
+    }
+    macro_rules! bus_write {
+        ($cpu:ident, $addr:expr, $data:expr) => {
+            $cpu.write($addr, $data)
+        };
+    }
+    macro_rules! bus_idle {
+        ($cpu:ident) => {{}};
+    }
+
+    impl Cpu {
+        /// `program` at $00:8000, which is where pc starts, in emulation
+        /// mode.
+        fn new(program: &[u8]) -> Cpu {
+            let mut cpu = Cpu {
+                regs: Registers {
+                    pc: 0x8000,
+                    ..Registers::power_on(Model::Wdc65816)
+                },
+                memory: HashMap::new(),
+                accesses: vec![],
+            };
+            cpu.load(0x8000, program);
+            cpu
+        }
+
+        /// The same in native mode with 8-bit registers.
+        fn native(program: &[u8]) -> Cpu {
+            let mut cpu = Cpu::new(program);
+            cpu.regs.e = false;
+            cpu
+        }
+
+        fn load(&mut self, addr: u32, data: &[u8]) {
+            for (i, &byte) in data.iter().enumerate() {
+                self.memory.insert(addr + i as u32, byte);
+            }
+        }
+
+        fn read(&mut self, addr: u32) -> u8 {
+            let data = self.memory.get(&addr).copied().unwrap_or(0);
+            self.accesses.push((addr, data, false));
+            data
+        }
+
+        fn write(&mut self, addr: u32, data: u8) {
+            self.memory.insert(addr, data);
+            self.accesses.push((addr, data, true));
+        }
+
+        /// Run one instruction and return its accesses.
+        fn step(&mut self) -> Vec<(u32, u8, bool)> {
+            self.accesses.clear();
+            execute_65816!(self, bus_read, bus_write, bus_idle);
+            std::mem::take(&mut self.accesses)
+        }
+
+        /// Run `count` instructions.
+        fn run(&mut self, count: usize) {
+            for _ in 0..count {
+                self.step();
+            }
+        }
+    }
+
+    #[rustfmt::skip]
+    #[test]
+    fn xce_and_register_widths() {
+        let mut cpu = Cpu::new(&[
+            0x18,             // CLC
+            0xfb,             // XCE
+            0xc2, 0x30,       // REP #$30
+            0xa2, 0x34, 0x12, // LDX #$1234
+            0xa9, 0xcd, 0xab, // LDA #$abcd
+            0xe2, 0x10,       // SEP #$10
+            0x38,             // SEC
+            0xfb,             // XCE
+        ]);
+        cpu.run(2);
+        assert!(!cpu.regs.e);
+        assert!(cpu.regs.flag(FLAG_C), "C gets the old E");
+        cpu.run(3);
+        assert_eq!((cpu.regs.a, cpu.regs.x), (0xabcd, 0x1234));
+        cpu.run(1);
+        assert_eq!(cpu.regs.x, 0x0034, "8-bit index registers lose their high bytes");
+        assert_eq!(cpu.regs.a, 0xabcd, "but the accumulator keeps it");
+        cpu.regs.s = 0x1234;
+        cpu.run(2);
+        assert!(cpu.regs.e);
+        assert!(m8(&cpu.regs) && x8(&cpu.regs));
+        assert_eq!(cpu.regs.s, 0x0134);
+        assert_eq!(cpu.regs.a, 0xabcd);
+    }
+
+    #[test]
+    fn direct_page_wraps_in_emulation_mode() {
+        // LDA $ff,X with X = 1, so the offset is $100.
+        let program = [0xb5, 0xff];
+        let read = |mut cpu: Cpu, d: u16| {
+            cpu.regs.d = d;
+            cpu.regs.x = 1;
+            cpu.step().last().unwrap().0
+        };
+        assert_eq!(read(Cpu::new(&program), 0x0200), 0x0200);
+        assert_eq!(read(Cpu::new(&program), 0x0201), 0x0301, "only with DL = 0");
+        assert_eq!(read(Cpu::native(&program), 0x0200), 0x0300, "only in emulation mode");
+    }
+
+    #[test]
+    fn bank_registers() {
+        let mut cpu = Cpu::new(&[]);
+        cpu.regs.pbr = 0x12;
+        cpu.regs.dbr = 0x7e;
+        cpu.regs.y = 2;
+        // LDA $1234, LDA $ffff,Y and LDA $123456 out of bank $12
+        cpu.load(0x12_8000, &[0xad, 0x34, 0x12, 0xb9, 0xff, 0xff, 0xaf, 0x56, 0x34, 0x12]);
+        let accesses = cpu.step();
+        assert_eq!(accesses[0].0, 0x12_8000);
+        assert_eq!(accesses.last().unwrap().0, 0x7e_1234);
+        assert_eq!(cpu.step().last().unwrap().0, 0x7f_0001, "indexing carries into the next bank");
+        assert_eq!(cpu.step().last().unwrap().0, 0x12_3456);
+        assert_eq!(pc24(&cpu.regs), 0x12_800a);
+    }
+
+    #[test]
+    fn stack_pointer_width() {
+        // PHA with s at the bottom of page 1.
+        let push = |mut cpu: Cpu| {
+            cpu.regs.s = 0x0100;
+            cpu.regs.a = 0x1234;
+            let accesses = cpu.step();
+            (accesses[1..].to_vec(), cpu.regs.s)
+        };
+        assert_eq!(push(Cpu::new(&[0x48])), (vec![(0x0100, 0x34, true)], 0x01ff));
+        assert_eq!(push(Cpu::native(&[0x48])), (vec![(0x0100, 0x34, true)], 0x00ff));
+        let mut cpu = Cpu::native(&[0x48]);
+        cpu.regs.p &= !FLAG_M;
+        assert_eq!(push(cpu), (vec![(0x0100, 0x12, true), (0x00ff, 0x34, true)], 0x00fe));
+    }
+
+    #[test]
+    fn decimal_16_bit() {
+        let mut regs = Registers::power_on(Model::Wdc65816);
+        regs.e = false;
+        regs.p = FLAG_D;
+        let run = |op: fn(&mut Registers, u16), a: u16, v: u16, carry: bool| {
+            let mut regs = regs;
+            regs.a = a;
+            regs.set_flag(FLAG_C, carry);
+            op(&mut regs, v);
+            (regs.a, regs.flag(FLAG_C), regs.flag(FLAG_Z), regs.flag(FLAG_N))
+        };
+        assert_eq!(run(adc, 0x1999, 0x0001, false), (0x2000, false, false, false));
+        assert_eq!(run(adc, 0x9999, 0x0000, true), (0x0000, true, true, false));
+        assert_eq!(run(adc, 0x4567, 0x4321, true), (0x8889, false, false, true));
+        assert_eq!(run(sbc, 0x1000, 0x0001, true), (0x0999, true, false, false));
+        assert_eq!(run(sbc, 0x0000, 0x0001, true), (0x9999, false, false, true));
+        assert_eq!(run(sbc, 0x5000, 0x1234, false), (0x3765, true, false, false));
+    }
+}